@@ -0,0 +1,76 @@
+#[macro_use]
+extern crate criterion;
+extern crate flate2;
+extern crate riplog;
+
+mod synthetic;
+
+use std::sync::Arc;
+
+use criterion::{Criterion, Throughput};
+use riplog::apache::{self, BinaryApacheLogRecord};
+use riplog::format::LogFormat;
+use riplog::nginx::{self, BinaryNginxLogRecord, NginxFormat};
+use riplog::route::RouteNormalizer;
+
+const LINE: &[u8] = b"203.0.113.42 - alice [16/Oct/2026:10:00:01 +0000] \"GET /api/users/12345/avatar?size=large&format=png HTTP/1.1\" 200 48213 \"https://example.com/profile/12345\" \"Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0 Safari/537.36\" 0.042 0.040\n";
+
+// The byte at a time scanner index_of used before memchr, kept to compare against.
+fn naive_index_of(vec: &[u8], char: u8) -> Option<usize> {
+    let mut idx = 0;
+    while idx < vec.len() {
+        if vec[idx] == char {
+            return Some(idx);
+        }
+        idx += 1;
+    }
+    None
+}
+
+// Walks a line field by field the way the nginx parser does, returning the number of fields.
+fn split_fields(line: &[u8], index_of: fn(&[u8], u8) -> Option<usize>) -> usize {
+    let mut working = line;
+    let mut fields = 0;
+    while let Some(idx) = index_of(working, b' ') {
+        working = &working[idx + 1..];
+        fields += 1;
+    }
+    fields
+}
+
+fn bench_index_of(c: &mut Criterion) {
+    let mut group = c.benchmark_group("split_fields");
+    group.bench_function("byte_at_a_time", |b| b.iter(|| split_fields(criterion::black_box(LINE), naive_index_of)));
+    group.bench_function("memchr", |b| b.iter(|| split_fields(criterion::black_box(LINE), nginx::index_of)));
+    group.finish();
+}
+
+fn bench_read_record(c: &mut Criterion) {
+    let format = NginxFormat { geoip: None, routes: Arc::new(RouteNormalizer::default()) };
+    let mut record = format.empty_record();
+    c.bench_function("nginx_read_record", |b| b.iter(|| format.read_record(criterion::black_box(LINE), &mut record)));
+}
+
+// Parses lines of varying content, rather than the same line over and over, so that branches on
+// the content of fields are measured as well.
+fn bench_read_log_record_binary(c: &mut Criterion) {
+    let nginx_lines = synthetic::nginx_lines(10000, 1);
+    let apache_lines = synthetic::apache_lines(10000, 1);
+    let mut group = c.benchmark_group("read_log_record_binary");
+    group.throughput(Throughput::Bytes(nginx_lines.len() as u64));
+    group.bench_function("nginx", |b| {
+        let lines = synthetic::split_lines(&nginx_lines);
+        let mut record = BinaryNginxLogRecord::empty();
+        b.iter(|| lines.iter().filter(|line| nginx::read_log_record_binary(line, &mut record)).count())
+    });
+    group.throughput(Throughput::Bytes(apache_lines.len() as u64));
+    group.bench_function("apache", |b| {
+        let lines = synthetic::split_lines(&apache_lines);
+        let mut record = BinaryApacheLogRecord::empty();
+        b.iter(|| lines.iter().filter(|line| apache::read_log_record_binary(line, &mut record)).count())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_index_of, bench_read_record, bench_read_log_record_binary);
+criterion_main!(benches);
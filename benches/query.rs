@@ -0,0 +1,159 @@
+#[macro_use]
+extern crate criterion;
+extern crate flate2;
+extern crate riplog;
+
+mod synthetic;
+
+use std::env;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use criterion::{Criterion, Throughput};
+use riplog::format::LogFormat;
+use riplog::input;
+use riplog::nginx::NginxFormat;
+use riplog::output::OutputOptions;
+use riplog::prefilter::LinePrefilter;
+use riplog::query::QueryEvaluator;
+use riplog::route::RouteNormalizer;
+use riplog::parse_query;
+
+const LINES: usize = 20000;
+// Lines of the files scanned end to end, about 35MB
+const SCANNED_LINES: usize = 200000;
+
+const FILTERS: &[(&str, &str)] = &[
+    ("int", "status >= 500"),
+    ("text", "method = \"POST\""),
+    ("regex", "path ~ r\"^/api/users/[0-9]+$\""),
+    ("in", "status in (404, 500, 502)"),
+    ("and_or", "(status >= 500 || status = 404) && method != \"GET\""),
+    ("date", "date > d\"10-16-2026 03:00:00 +0000\""),
+    ("cidr", "ip in_cidr (\"10.0.0.0/8\", \"2001:db8::/32\")"),
+    ("expression", "bytes / 1024 > 100"),
+];
+
+const GROUPINGS: &[(&str, &str)] = &[
+    ("status", "group status | show count(*)"),
+    ("route_reducers", "group route | show count(*), sum(bytes), avg(request_time), max(request_time)"),
+    ("time", "group time(1m) | show count(*)"),
+    ("columns", "group ip, method, status_class | show count(*), count_distinct(path)"),
+    ("expression", "group bucket(bytes, 1000) | show count(*)"),
+];
+
+// Lines of the file scanned by the large scans, about 1.8GB, only written and scanned when
+// RIPLOG_BENCH_LARGE is set
+const LARGE_LINES: usize = 10_000_000;
+
+const LARGE_SCANS: &[(&str, &str)] = &[
+    ("filter_group", "status = 500 | group method | show count(*)"),
+    ("group_limit", "group ip | show count(*), sum(bytes) | limit 3"),
+];
+
+const SCANS: &[(&str, &str)] = &[
+    ("aggregate", "status >= 500 | group route | show count(*), avg(request_time)"),
+    ("lines", "path ~ r\"wp-login\" | show ip, path, status"),
+];
+
+fn nginx_format() -> NginxFormat {
+    NginxFormat { geoip: None, routes: Arc::new(RouteNormalizer::default()) }
+}
+
+fn create_evaluator<F: LogFormat>(query: &str, format: &F) -> QueryEvaluator<F::Record> {
+    let query = parse_query(query.to_owned()).expect("benchmark queries are valid");
+    QueryEvaluator::<F::Record>::new(query, format.table_definition(), &OutputOptions::default(), Box::new(io::sink()))
+}
+
+// Evaluates a query over lines as the binary does, results being rendered and thrown away. Lines are
+// only prefiltered when asked to, to measure the evaluation of every line otherwise.
+fn evaluate<F: LogFormat>(query: &str, format: &F, lines: &[u8], prefiltered: bool) -> u64 {
+    let mut evaluator = create_evaluator(query, format);
+    let prefilter = if prefiltered { evaluator.line_prefilter(&format.verbatim_columns()) } else { LinePrefilter::new(None, evaluator.table_definition(), &[]) };
+    let mut record = format.empty_record();
+    format.project_record(evaluator.required_columns(), &mut record);
+    evaluator.evaluate_batch(lines, format, &prefilter, &mut record);
+    evaluator.finalize();
+    evaluator.stats().matched
+}
+
+// Maps an uncompressed file, or decompresses one on a background thread, as the binary reads them.
+fn scan_file<F: LogFormat>(query: &str, format: &F, file: &Path) -> u64 {
+    let mut evaluator = create_evaluator(query, format);
+    let prefilter = evaluator.line_prefilter(&format.verbatim_columns());
+    let mut record = format.empty_record();
+    format.project_record(evaluator.required_columns(), &mut record);
+    match input::map_input(file).expect("benchmark logs are readable") {
+        Some(data) => {
+            evaluator.evaluate_batch(&data, format, &prefilter, &mut record);
+        },
+        None => {
+            for chunk in input::open_background_input(file, 0) {
+                evaluator.evaluate_batch(&chunk.expect("benchmark logs are readable").data, format, &prefilter, &mut record);
+            }
+        },
+    }
+    evaluator.finalize();
+    evaluator.stats().matched
+}
+
+fn bench_filters(c: &mut Criterion) {
+    let format = nginx_format();
+    let lines = synthetic::nginx_lines(LINES, 2);
+    let mut group = c.benchmark_group("filter");
+    group.throughput(Throughput::Elements(LINES as u64));
+    for &(name, filter) in FILTERS {
+        let query = format!("{} | show count(*)", filter);
+        group.bench_function(name, |b| b.iter(|| evaluate(&query, &format, &lines, false)));
+    }
+    // Lines lacking the text compared with are skipped before they're parsed
+    group.bench_function("text_prefiltered", |b| b.iter(|| evaluate("method = \"POST\" | show count(*)", &format, &lines, true)));
+    group.finish();
+}
+
+fn bench_grouping(c: &mut Criterion) {
+    let format = nginx_format();
+    let lines = synthetic::nginx_lines(LINES, 3);
+    let mut group = c.benchmark_group("group");
+    group.throughput(Throughput::Elements(LINES as u64));
+    for &(name, query) in GROUPINGS {
+        group.bench_function(name, |b| b.iter(|| evaluate(query, &format, &lines, false)));
+    }
+    group.finish();
+}
+
+fn bench_scan(c: &mut Criterion) {
+    let format = nginx_format();
+    let lines = synthetic::nginx_lines(SCANNED_LINES, 4);
+    let plain = synthetic::write_log("access.log", &lines).expect("benchmark logs are written");
+    let gzipped = synthetic::write_log("access.log.gz", &lines).expect("benchmark logs are written");
+    let mut group = c.benchmark_group("scan");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(lines.len() as u64));
+    for &(name, query) in SCANS {
+        group.bench_function(format!("{}_plain", name), |b| b.iter(|| scan_file(query, &format, &plain)));
+        group.bench_function(format!("{}_gzip", name), |b| b.iter(|| scan_file(query, &format, &gzipped)));
+    }
+    group.finish();
+}
+
+// Scans of a 10M line log, mapped as the binary maps logs no longer written to, measuring reading records
+// at the scale of real logs rather than of the processor's caches.
+fn bench_large_scan(c: &mut Criterion) {
+    if env::var_os("RIPLOG_BENCH_LARGE").is_none() {
+        return;
+    }
+    let format = nginx_format();
+    let file = synthetic::write_large_nginx_log("large-access.log", LARGE_LINES, 5).expect("benchmark logs are written");
+    let mut group = c.benchmark_group("large_scan");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(LARGE_LINES as u64));
+    for &(name, query) in LARGE_SCANS {
+        group.bench_function(name, |b| b.iter(|| scan_file(query, &format, &file)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_filters, bench_grouping, bench_scan, bench_large_scan);
+criterion_main!(benches);
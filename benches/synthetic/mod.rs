@@ -0,0 +1,145 @@
+// Synthetic access logs for the benchmarks, generated from a seed so that every run measures the same
+// lines. Values are spread the way they are in real logs: most requests succeed, a few clients and
+// routes make most of the requests, and sizes vary over several orders of magnitude.
+
+// Each benchmark uses some of the generators
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+const IPS: &[&str] = &["10.0.0.1", "10.0.0.7", "10.0.3.14", "192.168.1.20", "192.168.1.31", "203.0.113.42", "198.51.100.7", "8.8.8.8", "2001:db8::1"];
+const USERS: &[&str] = &["-", "-", "-", "alice", "bob"];
+const METHODS: &[&str] = &["GET", "GET", "GET", "GET", "POST", "POST", "PUT", "DELETE", "HEAD"];
+const PATHS: &[&str] = &["/", "/index.html", "/api/users/{id}", "/api/users/{id}/avatar", "/api/orders", "/api/orders/{id}", "/static/app.js",
+                         "/static/app.css", "/login", "/wp-login.php", "/health"];
+const QUERIES: &[&str] = &["", "", "", "?page=2", "?size=large&format=png", "?q=riplog"];
+const STATUSES: &[u16] = &[200, 200, 200, 200, 200, 200, 201, 204, 301, 304, 304, 400, 404, 404, 500, 502, 503];
+const REFERRERS: &[&str] = &["-", "-", "https://example.com/", "https://example.com/profile/12345", "https://www.google.com/"];
+const AGENTS: &[&str] = &["Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0 Safari/537.36",
+                          "Mozilla/5.0 (Macintosh; Intel Mac OS X 14_1) Gecko/20100101 Firefox/120.0", "curl/8.4.0",
+                          "Googlebot/2.1 (+http://www.google.com/bot.html)", "python-requests/2.31.0"];
+
+pub struct Generator {
+    state: u64,
+}
+
+impl Generator {
+    pub fn new(seed: u64) -> Generator {
+        Generator { state: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1 }
+    }
+
+    // xorshift64*
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+
+    // Earlier items are picked more often than later ones
+    fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        let first = self.below(items.len() as u64);
+        let second = self.below(items.len() as u64);
+        items[first.min(second) as usize]
+    }
+
+    fn request(&mut self) -> (String, String) {
+        let path = self.pick(PATHS).replace("{id}", &self.below(100000).to_string());
+        (path, self.pick(QUERIES).to_owned())
+    }
+
+    fn bytes(&mut self, status: u16) -> u64 {
+        if status == 204 || status == 304 { 0 } else { 10u64.pow(self.below(6) as u32 + 1) / 2 + self.below(5000) }
+    }
+}
+
+// Lines in nginx's combined format followed by the request and upstream times, a second apart from
+// 2026-10-16 00:00:00 UTC.
+pub fn nginx_lines(count: usize, seed: u64) -> Vec<u8> {
+    let mut lines = Vec::with_capacity(count * 250);
+    write_nginx_lines(&mut lines, count, seed).unwrap();
+    lines
+}
+
+fn write_nginx_lines<W: Write>(out: &mut W, count: usize, seed: u64) -> io::Result<()> {
+    let mut generator = Generator::new(seed);
+    for idx in 0..count {
+        let (path, query) = generator.request();
+        let status = generator.pick(STATUSES);
+        let time = generator.below(2000) as f64 / 1000.0;
+        writeln!(out, "{} - {} [{}] \"{} {}{} HTTP/1.1\" {} {} \"{}\" \"{}\" {:.3} {:.3}",
+                 generator.pick(IPS), generator.pick(USERS), log_time(idx), generator.pick(METHODS), path, query, status, generator.bytes(status),
+                 generator.pick(REFERRERS), generator.pick(AGENTS), time, time)?;
+    }
+    Ok(())
+}
+
+// Writes count nginx lines straight to a file of the temporary directory, for logs too large to be
+// held in memory. A file already holding as many lines, from an earlier run, is reused.
+pub fn write_large_nginx_log(name: &str, count: usize, seed: u64) -> io::Result<PathBuf> {
+    let path = ::std::env::temp_dir().join(format!("riplog-bench-{}", name));
+    let lines = File::open(&path).map(|f| io::BufReader::new(f).split(b'\n').count()).unwrap_or(0);
+    if lines != count {
+        let mut out = io::BufWriter::new(File::create(&path)?);
+        write_nginx_lines(&mut out, count, seed)?;
+        out.flush()?;
+    }
+    File::options().write(true).open(&path)?.set_modified(SystemTime::now() - Duration::from_secs(3600))?;
+    Ok(path)
+}
+
+// Lines in apache's combined format with the virtual host first, as written by vhost_combined.
+pub fn apache_lines(count: usize, seed: u64) -> Vec<u8> {
+    let mut generator = Generator::new(seed);
+    let mut lines = Vec::with_capacity(count * 250);
+    for idx in 0..count {
+        let (path, query) = generator.request();
+        let status = generator.pick(STATUSES);
+        writeln!(lines, "{}:443 {} - {} [{}] \"{} {}{} HTTP/1.1\" {} {} \"{}\" \"{}\"",
+                 generator.pick(&["example.com", "api.example.com", "static.example.com"]), generator.pick(IPS), generator.pick(USERS), log_time(idx),
+                 generator.pick(METHODS), path, query, status, generator.bytes(status), generator.pick(REFERRERS), generator.pick(AGENTS)).unwrap();
+    }
+    lines
+}
+
+fn log_time(seconds: usize) -> String {
+    let (day, hour, minute, second) = (16 + seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60, seconds % 60);
+    format!("{:02}/Oct/2026:{:02}:{:02}:{:02} +0000", day.min(31), hour, minute, second)
+}
+
+// Writes lines to a file of the temporary directory, gzipped when its name ends in .gz. Files are
+// dated an hour back, as logs no longer written to are, so that plain ones are mapped rather than
+// streamed.
+pub fn write_log(name: &str, lines: &[u8]) -> io::Result<PathBuf> {
+    let path = ::std::env::temp_dir().join(format!("riplog-bench-{}", name));
+    write_file(&path, lines)?;
+    File::options().write(true).open(&path)?.set_modified(SystemTime::now() - Duration::from_secs(3600))?;
+    Ok(path)
+}
+
+fn write_file(path: &Path, lines: &[u8]) -> io::Result<()> {
+    let file = File::create(path)?;
+    if path.extension().map(|e| e == "gz").unwrap_or(false) {
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        encoder.write_all(lines)?;
+        encoder.finish().map(|_| ())
+    } else {
+        let mut file = file;
+        file.write_all(lines)
+    }
+}
+
+// Each line of a block of lines, as read_record is given them.
+pub fn split_lines(lines: &[u8]) -> Vec<&[u8]> {
+    lines.split(|b| *b == b'\n').filter(|l| !l.is_empty()).collect()
+}
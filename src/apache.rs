@@ -0,0 +1,247 @@
+use std::path::Path;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use nginx::{self, BinaryNginxLogRecord, index_of, or_empty, empty_opt};
+use table::{TableDefinition, ColumnDefinition};
+use format::LogFormat;
+use discovery;
+use route::RouteNormalizer;
+
+pub struct ApacheFormat {
+    pub routes: Arc<RouteNormalizer>,
+}
+
+impl LogFormat for ApacheFormat {
+    type Record = BinaryApacheLogRecord;
+
+    fn empty_record(&self) -> BinaryApacheLogRecord {
+        let mut record = BinaryApacheLogRecord::empty();
+        record.fields.routes = self.routes.clone();
+        record
+    }
+
+    fn read_record(&self, line: &[u8], record: &mut BinaryApacheLogRecord) -> bool {
+        read_log_record_binary(line, record)
+    }
+
+    fn table_definition(&self) -> TableDefinition<BinaryApacheLogRecord> {
+        create_apache_log_record_table_definition()
+    }
+
+    fn verbatim_columns(&self) -> Vec<&'static str> {
+        vec!["vhost", "ip", "username", "method", "path", "query", "status", "bytes", "referrer", "user_agent"]
+    }
+
+    fn is_log_file(file: &Path) -> bool {
+        discovery::is_access_log(file)
+    }
+}
+
+// Parses apache common and combined log lines, optionally prefixed with a virtual host as written
+// by the vhost_common/vhost_combined formats. Trailing fields missing from the common format are
+// left empty, and lines without a timestamp, quoted request and status are rejected.
+pub fn read_log_record_binary(line: &[u8], record: &mut BinaryApacheLogRecord) -> bool {
+    let empty: &[u8] = &[];
+    let working = trim_line_ending(line);
+
+    let brace_idx = match index_of(working, b'[') {
+        Some(idx) => idx,
+        None => return false,
+    };
+    let mut prefix = Vec::with_capacity(4);
+    let mut prefix_working = &working[0..brace_idx];
+    while !prefix_working.is_empty() {
+        let (token, rest) = next_field(prefix_working, b' ');
+        if !token.is_empty() {
+            prefix.push(token);
+        }
+        prefix_working = rest;
+    }
+    let (vhost, ip, username) =
+        if prefix.len() >= 4 {
+            (prefix[0], prefix[1], prefix[3])
+        } else {
+            (empty, *prefix.get(0).unwrap_or(&empty), *prefix.get(2).unwrap_or(&empty))
+        };
+
+    let working = &working[brace_idx+1..];
+    let date_end = match index_of(working, b']') {
+        Some(idx) if is_log_timestamp(&working[0..idx]) => idx,
+        _ => return false,
+    };
+    let (date, working) = (&working[0..date_end], skip_spaces(&working[date_end+1..]));
+
+    if working.first() != Some(&b'"') || index_of(&working[1..], b'"').is_none() {
+        return false;
+    }
+    let (request, working) = next_quoted_field(working);
+    let (method, path, query) = nginx::split_request(request);
+    let working = skip_spaces(working);
+
+    let (status, working) = next_field(working, b' ');
+    if status.len() != 3 || !status.iter().all(|b| b.is_ascii_digit()) {
+        return false;
+    }
+    let (bytes, working) = next_field(working, b' ');
+    let working = skip_spaces(working);
+
+    let (referrer, working) = next_quoted_field(working);
+    let working = skip_spaces(working);
+    let (user_agent, _) = next_quoted_field(working);
+
+    record.vhost.clear();
+    record.vhost.extend_from_slice(or_empty(vhost));
+    record.parsed_vhost = None;
+
+    record.fields.set_fields(&[ip, or_empty(username), date, method, path, query, status,
+                               or_empty(bytes), or_empty(referrer), or_empty(user_agent)]);
+    true
+}
+
+// dd/Mon/yyyy:hh:mm:ss followed by the zone, the shape of %t. Values are parsed when the date is read.
+fn is_log_timestamp(date: &[u8]) -> bool {
+    date.len() >= 20 && date[2] == b'/' && date[6] == b'/' && date[11] == b':' && date[14] == b':' && date[17] == b':'
+        && date[0..2].iter().chain(&date[7..11]).all(|b| b.is_ascii_digit())
+}
+
+fn next_field(working: &[u8], delimiter: u8) -> (&[u8], &[u8]) {
+    match index_of(working, delimiter) {
+        Some(idx) => (&working[0..idx], &working[idx+1..]),
+        None => (working, &[]),
+    }
+}
+
+fn next_quoted_field(working: &[u8]) -> (&[u8], &[u8]) {
+    if working.first() == Some(&b'"') {
+        next_field(&working[1..], b'"')
+    } else {
+        next_field(working, b' ')
+    }
+}
+
+fn skip_spaces(working: &[u8]) -> &[u8] {
+    let mut idx = 0;
+    while idx < working.len() && working[idx] == b' ' {
+        idx += 1;
+    }
+    &working[idx..]
+}
+
+fn trim_line_ending(working: &[u8]) -> &[u8] {
+    let mut len = working.len();
+    while len > 0 && (working[len-1] == b'\n' || working[len-1] == b'\r') {
+        len -= 1;
+    }
+    &working[0..len]
+}
+
+#[derive(Debug, Clone)]
+pub struct BinaryApacheLogRecord {
+    pub vhost: Vec<u8>,
+    pub fields: BinaryNginxLogRecord,
+    parsed_vhost: Option<Option<String>>,
+}
+
+impl BinaryApacheLogRecord {
+    pub fn empty() -> BinaryApacheLogRecord {
+        BinaryApacheLogRecord {
+            vhost: Vec::new(),
+            fields: BinaryNginxLogRecord::empty(),
+            parsed_vhost: None,
+        }
+    }
+
+    pub fn parsed_vhost(&mut self) -> Option<&str> {
+        if self.parsed_vhost.is_none() {
+            self.parsed_vhost =
+                if self.vhost.len() < 1 { Some(None) }
+                else { Some(Some(String::from_utf8_lossy(&self.vhost).to_string())) };
+        }
+        self.parsed_vhost.as_ref().unwrap().as_ref().map(|s| s.as_str())
+    }
+}
+
+pub fn create_apache_log_record_table_definition() -> TableDefinition<BinaryApacheLogRecord> {
+    let columns = vec![
+            ColumnDefinition::Text { name: "vhost".to_owned(),
+                                     size: 20,
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryApacheLogRecord, _| empty_opt(&r.vhost),
+                                     extractor: |r: &mut BinaryApacheLogRecord, _| r.parsed_vhost() },
+            ColumnDefinition::Ip { name: "ip".to_owned(),
+                                   size: 15,
+                                   field: 0,
+                                   binary_extractor: |r: &BinaryApacheLogRecord, _| empty_opt(r.fields.ip()),
+                                   extractor: |r: &mut BinaryApacheLogRecord, _| Some(r.fields.parsed_ip()),
+                                   address_extractor: |r: &mut BinaryApacheLogRecord, _| r.fields.parsed_address() },
+            ColumnDefinition::Text { name: "username".to_owned(),
+                                     size: 5,
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryApacheLogRecord, _| empty_opt(r.fields.username()),
+                                     extractor: |r: &mut BinaryApacheLogRecord, _| r.fields.parsed_username() },
+            ColumnDefinition::Date { name: "date".to_owned(),
+                                     size: 26,
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryApacheLogRecord, _| empty_opt(r.fields.date()),
+                                     extractor: |r: &mut BinaryApacheLogRecord, _| r.fields.parsed_date() },
+            ColumnDefinition::Text { name: "method".to_owned(),
+                                     size: 5,
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryApacheLogRecord, _| empty_opt(r.fields.method()),
+                                     extractor: |r: &mut BinaryApacheLogRecord, _| r.fields.parsed_method() },
+            ColumnDefinition::Text { name: "path".to_owned(),
+                                     size: 20,
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryApacheLogRecord, _| empty_opt(r.fields.path()),
+                                     extractor: |r: &mut BinaryApacheLogRecord, _| Some(r.fields.parsed_path()) },
+            ColumnDefinition::Text { name: "query".to_owned(),
+                                     size: 50,
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryApacheLogRecord, _| empty_opt(r.fields.query()),
+                                     extractor: |r: &mut BinaryApacheLogRecord, _| r.fields.parsed_query() },
+            ColumnDefinition::Integer { name: "status".to_owned(),
+                                        size: 3,
+                                        field: 0,
+                                        binary_extractor: |r: &BinaryApacheLogRecord, _| empty_opt(r.fields.status()),
+                                        extractor: |r: &mut BinaryApacheLogRecord, _| r.fields.parsed_status() },
+            ColumnDefinition::Integer { name: "bytes".to_owned(),
+                                        size: 10,
+                                        field: 0,
+                                        binary_extractor: |r: &BinaryApacheLogRecord, _| empty_opt(r.fields.bytes()),
+                                        extractor: |r: &mut BinaryApacheLogRecord, _| r.fields.parsed_bytes() },
+            ColumnDefinition::Text { name: "referrer".to_owned(),
+                                     size: 50,
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryApacheLogRecord, _| empty_opt(r.fields.referrer()),
+                                     extractor: |r: &mut BinaryApacheLogRecord, _| r.fields.parsed_referrer() },
+            ColumnDefinition::Text { name: "user_agent".to_owned(),
+                                     size: 50,
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryApacheLogRecord, _| empty_opt(r.fields.user_agent()),
+                                     extractor: |r: &mut BinaryApacheLogRecord, _| r.fields.parsed_user_agent() },
+            ColumnDefinition::Text { name: "route".to_owned(),
+                                     size: 20,
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryApacheLogRecord, _| r.fields.route_bytes(),
+                                     extractor: |r: &mut BinaryApacheLogRecord, _| Some(r.fields.parsed_route()) },
+            ColumnDefinition::Text { name: "status_class".to_owned(),
+                                     size: 3,
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryApacheLogRecord, _| r.fields.status_class().map(|c| c.as_bytes()),
+                                     extractor: |r: &mut BinaryApacheLogRecord, _| r.fields.status_class() },
+        ];
+
+    let mut column_map = HashMap::new();
+    let mut ordering = Vec::new();
+
+    for c in columns {
+        ordering.push(c.name().to_owned());
+        column_map.insert(c.name().to_string(), c);
+    }
+
+    TableDefinition {
+        column_map: column_map,
+        ordered_columns: ordering,
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::rc::Rc;
+
+use aws::{AwsFormat, AwsLog, BinaryAwsLogRecord};
+use chrono::prelude::*;
+use format::LogFormat;
+use json::{self, BinaryJsonLogRecord, JsonColumn, JsonColumnKind, JsonFormat};
+use nginx::{BinaryNginxLogRecord, NginxFormat};
+use syslog::{BinarySyslogRecord, SyslogFormat};
+use table::{ColumnDefinition, TableDefinition};
+
+const FORMAT_COLUMN: &str = "format";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DetectedFormat {
+    Nginx,
+    Json,
+    Syslog,
+    Alb,
+}
+
+// Formats tried, in order, when sniffing a file. Syslog timestamps, json braces and the request type
+// starting ALB lines are told apart from the address starting nginx lines, which are read least strictly.
+const DETECTED_FORMATS: [DetectedFormat; 4] = [DetectedFormat::Json, DetectedFormat::Syslog, DetectedFormat::Alb, DetectedFormat::Nginx];
+
+const ALB_TYPES: &[&[u8]] = &[b"http ", b"https ", b"h2 ", b"grpcs ", b"ws ", b"wss "];
+
+impl DetectedFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DetectedFormat::Nginx => "nginx",
+            DetectedFormat::Json => "json",
+            DetectedFormat::Syslog => "syslog",
+            DetectedFormat::Alb => "alb",
+        }
+    }
+}
+
+// Columns named differently by the formats, by the name they're unified under: the column of syslog
+// and ALB records it's read from, and the json keys read besides its own name, the first key present
+// being used. Nginx columns keep their names.
+struct ColumnAlias {
+    name: &'static str,
+    syslog: Option<&'static str>,
+    alb: Option<&'static str>,
+    json: &'static [&'static str],
+}
+
+const COLUMN_ALIASES: &[ColumnAlias] = &[
+    ColumnAlias { name: "date", syslog: Some("timestamp"), alb: Some("time"), json: &["time", "timestamp", "@timestamp", "ts"] },
+    ColumnAlias { name: "host", syslog: None, alb: None, json: &["hostname"] },
+    ColumnAlias { name: "ip", syslog: None, alb: Some("client_ip"), json: &["remote_addr", "client_ip"] },
+    ColumnAlias { name: "app", syslog: None, alb: None, json: &["service", "logger"] },
+    ColumnAlias { name: "severity", syslog: None, alb: None, json: &["level"] },
+    ColumnAlias { name: "method", syslog: None, alb: None, json: &["request_method"] },
+    ColumnAlias { name: "path", syslog: None, alb: None, json: &["uri", "request_uri", "url"] },
+    ColumnAlias { name: "status", syslog: None, alb: Some("elb_status_code"), json: &["status_code"] },
+    ColumnAlias { name: "bytes", syslog: None, alb: Some("sent_bytes"), json: &["body_bytes_sent", "size"] },
+    ColumnAlias { name: "user_agent", syslog: None, alb: None, json: &["http_user_agent"] },
+    ColumnAlias { name: "message", syslog: None, alb: None, json: &["msg"] },
+];
+
+// Logs of several formats read in one run, e.g. a directory of nginx access logs, json application
+// logs, syslog files and ALB logs. The format of a file is sniffed from its first line of one of the formats,
+// the file's other lines being read as that format only, so that those that don't parse are counted
+// as such. The schema is the union of the columns of the nginx, syslog and ALB formats, columns of the
+// same name (or alias) being one column, null for formats without it, with a format column telling
+// which format each record was read as. Json lines are read by the keys of the schema's columns.
+pub struct AutoFormat {
+    nginx: NginxFormat,
+    json: JsonFormat,
+    syslog: SyslogFormat,
+    alb: AwsFormat,
+    sources: Rc<UnifiedSources>,
+}
+
+// The columns of the schema, and where each is read from by its position. A column of a format whose
+// type differs from that of the schema's column of its name isn't read, being null for that format.
+struct UnifiedSources {
+    // Names and sizes of the columns
+    columns: Vec<(String, usize)>,
+    nginx: Vec<Option<ColumnDefinition<BinaryNginxLogRecord>>>,
+    syslog: Vec<Option<ColumnDefinition<BinarySyslogRecord>>>,
+    alb: Vec<Option<ColumnDefinition<BinaryAwsLogRecord>>>,
+    json: Vec<Vec<usize>>,
+    // Types of the columns, as kind_name gives them, taken from the first format having each
+    kinds: Vec<&'static str>,
+}
+
+impl UnifiedSources {
+    // Position of the schema's column of a name, which is added when there's none yet. Columns are as
+    // wide as the widest of the formats'.
+    fn column(&mut self, name: &str, kind: &'static str, size: usize) -> usize {
+        if let Some(idx) = self.columns.iter().position(|c| c.0 == name) {
+            self.columns[idx].1 = self.columns[idx].1.max(size);
+            return idx;
+        }
+        self.columns.push((name.to_owned(), size));
+        self.kinds.push(kind);
+        self.nginx.push(None);
+        self.syslog.push(None);
+        self.alb.push(None);
+        self.json.push(Vec::new());
+        self.columns.len() - 1
+    }
+
+    // Adds the columns of a format under their unified names, taking them out of its definition.
+    fn add<T, F>(&mut self, mut definition: TableDefinition<T>, alias: F, sources: fn(&mut UnifiedSources) -> &mut Vec<Option<ColumnDefinition<T>>>)
+        where F: Fn(&ColumnAlias) -> Option<&'static str> {
+        for name in &definition.ordered_columns {
+            let column = definition.column_map.remove(name).unwrap();
+            let unified = COLUMN_ALIASES.iter().find(|a| alias(a) == Some(name.as_str())).map(|a| a.name).unwrap_or(name);
+            let idx = self.column(unified, column.kind_name(), *column.get_size());
+            if self.kinds[idx] == column.kind_name() {
+                sources(self)[idx] = Some(column);
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AutoRecord {
+    detected: Option<DetectedFormat>,
+    nginx: BinaryNginxLogRecord,
+    json: BinaryJsonLogRecord,
+    syslog: BinarySyslogRecord,
+    alb: BinaryAwsLogRecord,
+    sources: Rc<UnifiedSources>,
+}
+
+impl AutoFormat {
+    pub fn new(nginx: NginxFormat) -> AutoFormat {
+        let syslog = SyslogFormat::new();
+        let alb = AwsFormat::new(AwsLog::Alb);
+        let mut sources = UnifiedSources { columns: Vec::new(), nginx: Vec::new(), syslog: Vec::new(), alb: Vec::new(), json: Vec::new(), kinds: Vec::new() };
+        sources.add(nginx.table_definition(), |_| None, |s| &mut s.nginx);
+        sources.add(syslog.table_definition(), |a| a.syslog, |s| &mut s.syslog);
+        sources.add(alb.table_definition(), |a| a.alb, |s| &mut s.alb);
+        let mut json_columns: Vec<JsonColumn> = Vec::new();
+        for (idx, &(ref name, _)) in sources.columns.iter().enumerate() {
+            let aliases = COLUMN_ALIASES.iter().find(|a| a.name == name).map(|a| a.json).unwrap_or(&[]);
+            for key in ::std::iter::once(name.as_str()).chain(aliases.iter().cloned()) {
+                sources.json[idx].push(json_columns.len());
+                json_columns.push(JsonColumn { name: json::column_name(key), key: key.to_owned(), kind: JsonColumnKind::Text });
+            }
+        }
+        AutoFormat { nginx: nginx, json: JsonFormat { columns: json_columns }, syslog: syslog, alb: alb, sources: Rc::new(sources) }
+    }
+
+    fn read_as(&self, detected: DetectedFormat, line: &[u8], record: &mut AutoRecord) -> bool {
+        match detected {
+            DetectedFormat::Nginx => self.nginx.read_record(line, &mut record.nginx),
+            DetectedFormat::Json => is_json_object(line) && self.json.read_record(line, &mut record.json),
+            DetectedFormat::Syslog => self.syslog.read_record(line, &mut record.syslog),
+            DetectedFormat::Alb => is_alb_line(line) && self.alb.read_record(line, &mut record.alb),
+        }
+    }
+}
+
+fn is_json_object(line: &[u8]) -> bool {
+    line.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{')
+}
+
+// ALB lines are read only when they start with the type of the request, as the ALB tokenizer reads
+// most lines of enough words
+fn is_alb_line(line: &[u8]) -> bool {
+    ALB_TYPES.iter().any(|t| line.starts_with(t))
+}
+
+impl LogFormat for AutoFormat {
+    type Record = AutoRecord;
+
+    fn empty_record(&self) -> AutoRecord {
+        AutoRecord {
+            detected: None,
+            nginx: self.nginx.empty_record(),
+            json: self.json.empty_record(),
+            syslog: self.syslog.empty_record(),
+            alb: self.alb.empty_record(),
+            sources: self.sources.clone(),
+        }
+    }
+
+    // Records are made for each file read, so the format a record detected is that of its file
+    fn read_record(&self, line: &[u8], record: &mut AutoRecord) -> bool {
+        if let Some(detected) = record.detected {
+            return self.read_as(detected, line, record);
+        }
+        record.detected = DETECTED_FORMATS.iter().cloned().find(|&f| self.read_as(f, line, record));
+        record.detected.is_some()
+    }
+
+    fn table_definition(&self) -> TableDefinition<AutoRecord> {
+        let mut column_map = HashMap::new();
+        let mut ordered_columns = vec![FORMAT_COLUMN.to_owned()];
+        column_map.insert(FORMAT_COLUMN.to_owned(),
+                          ColumnDefinition::Text { name: FORMAT_COLUMN.to_owned(), size: 6, field: 0, binary_extractor: format_bytes, extractor: format_text });
+        for (idx, &(ref name, size)) in self.sources.columns.iter().enumerate() {
+            let name = name.to_owned();
+            let definition = match self.sources.kinds[idx] {
+                "date" =>
+                    ColumnDefinition::Date { name: name, size: size, field: idx, binary_extractor: unified_bytes, extractor: unified_date },
+                "ip" =>
+                    ColumnDefinition::Ip { name: name, size: size, field: idx, binary_extractor: unified_bytes, extractor: unified_text, address_extractor: unified_address },
+                "integer" =>
+                    ColumnDefinition::Integer { name: name, size: size, field: idx, binary_extractor: unified_bytes, extractor: unified_integer },
+                "double" =>
+                    ColumnDefinition::Double { name: name, size: size, field: idx, binary_extractor: unified_bytes, extractor: unified_double },
+                "boolean" =>
+                    ColumnDefinition::Boolean { name: name, size: size, field: idx, binary_extractor: unified_bytes, extractor: unified_boolean },
+                _ =>
+                    ColumnDefinition::Text { name: name, size: size, field: idx, binary_extractor: unified_bytes, extractor: unified_text },
+            };
+            ordered_columns.push(definition.name().to_owned());
+            column_map.insert(definition.name().to_owned(), definition);
+        }
+        TableDefinition { column_map: column_map, ordered_columns: ordered_columns }
+    }
+
+    // Every file found is sniffed, files of none of the formats having all their lines skipped
+    fn is_log_file(_: &Path) -> bool {
+        true
+    }
+}
+
+fn format_bytes(r: &AutoRecord, _: usize) -> Option<&[u8]> {
+    r.detected.map(|d| d.name().as_bytes())
+}
+
+fn format_text(r: &mut AutoRecord, _: usize) -> Option<&str> {
+    r.detected.map(|d| d.name())
+}
+
+// The first of the column's json keys present in the line
+fn json_field(r: &AutoRecord, idx: usize) -> Option<usize> {
+    r.sources.json[idx].iter().cloned().find(|&field| r.json.value_bytes(field).is_some())
+}
+
+fn unified_bytes(r: &AutoRecord, idx: usize) -> Option<&[u8]> {
+    match r.detected {
+        Some(DetectedFormat::Nginx) => r.sources.nginx[idx].as_ref().and_then(|c| c.extract_binary(&r.nginx)),
+        Some(DetectedFormat::Syslog) => r.sources.syslog[idx].as_ref().and_then(|c| c.extract_binary(&r.syslog)),
+        Some(DetectedFormat::Alb) => r.sources.alb[idx].as_ref().and_then(|c| c.extract_binary(&r.alb)),
+        Some(DetectedFormat::Json) => json_field(r, idx).and_then(|field| r.json.value_bytes(field)),
+        None => None,
+    }
+}
+
+fn column_text<'r, T>(column: &Option<ColumnDefinition<T>>, record: &'r mut T) -> Option<&'r str> {
+    match *column {
+        Some(ColumnDefinition::Text { extractor, field, .. }) | Some(ColumnDefinition::Ip { extractor, field, .. }) => extractor(record, field),
+        _ => None,
+    }
+}
+
+fn column_integer<T>(column: &Option<ColumnDefinition<T>>, record: &mut T) -> Option<u64> {
+    match *column {
+        Some(ColumnDefinition::Integer { extractor, field, .. }) => extractor(record, field),
+        _ => None,
+    }
+}
+
+fn column_double<T>(column: &Option<ColumnDefinition<T>>, record: &mut T) -> Option<f64> {
+    match *column {
+        Some(ColumnDefinition::Double { extractor, field, .. }) => extractor(record, field),
+        _ => None,
+    }
+}
+
+fn column_boolean<T>(column: &Option<ColumnDefinition<T>>, record: &mut T) -> Option<bool> {
+    match *column {
+        Some(ColumnDefinition::Boolean { extractor, field, .. }) => extractor(record, field),
+        _ => None,
+    }
+}
+
+fn column_date<'r, T>(column: &Option<ColumnDefinition<T>>, record: &'r mut T) -> Option<&'r DateTime<Local>> {
+    match *column {
+        Some(ColumnDefinition::Date { extractor, field, .. }) => extractor(record, field),
+        _ => None,
+    }
+}
+
+fn column_address<T>(column: &Option<ColumnDefinition<T>>, record: &mut T) -> Option<IpAddr> {
+    match *column {
+        Some(ColumnDefinition::Ip { address_extractor, field, .. }) => address_extractor(record, field),
+        _ => None,
+    }
+}
+
+fn unified_text(r: &mut AutoRecord, idx: usize) -> Option<&str> {
+    match r.detected {
+        Some(DetectedFormat::Nginx) => column_text(&r.sources.nginx[idx], &mut r.nginx),
+        Some(DetectedFormat::Syslog) => column_text(&r.sources.syslog[idx], &mut r.syslog),
+        Some(DetectedFormat::Alb) => column_text(&r.sources.alb[idx], &mut r.alb),
+        Some(DetectedFormat::Json) => json_field(r, idx).and_then(move |field| r.json.parsed_text(field)),
+        None => None,
+    }
+}
+
+fn unified_integer(r: &mut AutoRecord, idx: usize) -> Option<u64> {
+    match r.detected {
+        Some(DetectedFormat::Nginx) => column_integer(&r.sources.nginx[idx], &mut r.nginx),
+        Some(DetectedFormat::Syslog) => column_integer(&r.sources.syslog[idx], &mut r.syslog),
+        Some(DetectedFormat::Alb) => column_integer(&r.sources.alb[idx], &mut r.alb),
+        Some(DetectedFormat::Json) => json_field(r, idx).and_then(|field| r.json.parsed_integer(field)),
+        None => None,
+    }
+}
+
+fn unified_double(r: &mut AutoRecord, idx: usize) -> Option<f64> {
+    match r.detected {
+        Some(DetectedFormat::Nginx) => column_double(&r.sources.nginx[idx], &mut r.nginx),
+        Some(DetectedFormat::Syslog) => column_double(&r.sources.syslog[idx], &mut r.syslog),
+        Some(DetectedFormat::Alb) => column_double(&r.sources.alb[idx], &mut r.alb),
+        Some(DetectedFormat::Json) => json_field(r, idx).and_then(|field| r.json.parsed_double(field)),
+        None => None,
+    }
+}
+
+fn unified_boolean(r: &mut AutoRecord, idx: usize) -> Option<bool> {
+    match r.detected {
+        Some(DetectedFormat::Nginx) => column_boolean(&r.sources.nginx[idx], &mut r.nginx),
+        Some(DetectedFormat::Syslog) => column_boolean(&r.sources.syslog[idx], &mut r.syslog),
+        Some(DetectedFormat::Alb) => column_boolean(&r.sources.alb[idx], &mut r.alb),
+        Some(DetectedFormat::Json) => json_field(r, idx).and_then(|field| r.json.parsed_boolean(field)),
+        None => None,
+    }
+}
+
+fn unified_date(r: &mut AutoRecord, idx: usize) -> Option<&DateTime<Local>> {
+    match r.detected {
+        Some(DetectedFormat::Nginx) => column_date(&r.sources.nginx[idx], &mut r.nginx),
+        Some(DetectedFormat::Syslog) => column_date(&r.sources.syslog[idx], &mut r.syslog),
+        Some(DetectedFormat::Alb) => column_date(&r.sources.alb[idx], &mut r.alb),
+        Some(DetectedFormat::Json) => json_field(r, idx).and_then(move |field| r.json.parsed_date_or_epoch(field)),
+        None => None,
+    }
+}
+
+fn unified_address(r: &mut AutoRecord, idx: usize) -> Option<IpAddr> {
+    match r.detected {
+        Some(DetectedFormat::Nginx) => column_address(&r.sources.nginx[idx], &mut r.nginx),
+        Some(DetectedFormat::Alb) => column_address(&r.sources.alb[idx], &mut r.alb),
+        _ => unified_text(r, idx).and_then(|text| text.parse::<IpAddr>().ok()),
+    }
+}
@@ -0,0 +1,372 @@
+use std::rc::Rc;
+use std::path::Path;
+use std::net::IpAddr;
+use std::collections::HashMap;
+
+use chrono::prelude::*;
+use table::{TableDefinition, ColumnDefinition};
+use format::LogFormat;
+use nginx::{self, index_of, or_empty, empty_opt};
+use cidr;
+
+// AWS access logs: Application Load Balancer logs and S3 server access logs. Both are space
+// delimited lines whose compound fields are quoted (or bracketed for S3 times), so one tokenizer
+// serves both and each log type describes which token feeds which column.
+pub struct AwsFormat {
+    log: AwsLog,
+    columns: Rc<Vec<AwsColumn>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AwsLog {
+    Alb,
+    S3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AwsColumnKind {
+    Integer,
+    Double,
+    Text,
+    Ip,
+    IsoDate,
+    LocalDate,
+}
+
+// Where a column's value comes from within a token.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AwsSource {
+    Token,
+    // ip:port pairs
+    Host,
+    Port,
+    // "METHOD url PROTOCOL" requests
+    Method,
+    Path,
+    Query,
+    // ALB processing times, -1 when the request never reached that stage
+    Elapsed,
+}
+
+#[derive(Debug, Clone)]
+struct AwsColumn {
+    name: &'static str,
+    kind: AwsColumnKind,
+    token: usize,
+    source: AwsSource,
+}
+
+const ALB_COLUMNS: &[(&str, AwsColumnKind, usize, AwsSource)] = &[
+    ("type", AwsColumnKind::Text, 0, AwsSource::Token),
+    ("time", AwsColumnKind::IsoDate, 1, AwsSource::Token),
+    ("elb", AwsColumnKind::Text, 2, AwsSource::Token),
+    ("client_ip", AwsColumnKind::Ip, 3, AwsSource::Host),
+    ("client_port", AwsColumnKind::Integer, 3, AwsSource::Port),
+    ("target_ip", AwsColumnKind::Ip, 4, AwsSource::Host),
+    ("target_port", AwsColumnKind::Integer, 4, AwsSource::Port),
+    ("request_processing_time", AwsColumnKind::Double, 5, AwsSource::Elapsed),
+    ("target_processing_time", AwsColumnKind::Double, 6, AwsSource::Elapsed),
+    ("response_processing_time", AwsColumnKind::Double, 7, AwsSource::Elapsed),
+    ("elb_status_code", AwsColumnKind::Integer, 8, AwsSource::Token),
+    ("target_status_code", AwsColumnKind::Integer, 9, AwsSource::Token),
+    ("received_bytes", AwsColumnKind::Integer, 10, AwsSource::Token),
+    ("sent_bytes", AwsColumnKind::Integer, 11, AwsSource::Token),
+    ("method", AwsColumnKind::Text, 12, AwsSource::Method),
+    ("path", AwsColumnKind::Text, 12, AwsSource::Path),
+    ("query", AwsColumnKind::Text, 12, AwsSource::Query),
+    ("user_agent", AwsColumnKind::Text, 13, AwsSource::Token),
+    ("ssl_cipher", AwsColumnKind::Text, 14, AwsSource::Token),
+    ("ssl_protocol", AwsColumnKind::Text, 15, AwsSource::Token),
+    ("target_group_arn", AwsColumnKind::Text, 16, AwsSource::Token),
+    ("trace_id", AwsColumnKind::Text, 17, AwsSource::Token),
+    ("domain_name", AwsColumnKind::Text, 18, AwsSource::Token),
+    ("matched_rule_priority", AwsColumnKind::Integer, 20, AwsSource::Token),
+    ("actions_executed", AwsColumnKind::Text, 22, AwsSource::Token),
+    ("error_reason", AwsColumnKind::Text, 24, AwsSource::Token),
+];
+
+const S3_COLUMNS: &[(&str, AwsColumnKind, usize, AwsSource)] = &[
+    ("bucket_owner", AwsColumnKind::Text, 0, AwsSource::Token),
+    ("bucket", AwsColumnKind::Text, 1, AwsSource::Token),
+    ("time", AwsColumnKind::LocalDate, 2, AwsSource::Token),
+    ("remote_ip", AwsColumnKind::Ip, 3, AwsSource::Token),
+    ("requester", AwsColumnKind::Text, 4, AwsSource::Token),
+    ("request_id", AwsColumnKind::Text, 5, AwsSource::Token),
+    ("operation", AwsColumnKind::Text, 6, AwsSource::Token),
+    ("key", AwsColumnKind::Text, 7, AwsSource::Token),
+    ("method", AwsColumnKind::Text, 8, AwsSource::Method),
+    ("path", AwsColumnKind::Text, 8, AwsSource::Path),
+    ("query", AwsColumnKind::Text, 8, AwsSource::Query),
+    ("http_status", AwsColumnKind::Integer, 9, AwsSource::Token),
+    ("error_code", AwsColumnKind::Text, 10, AwsSource::Token),
+    ("bytes_sent", AwsColumnKind::Integer, 11, AwsSource::Token),
+    ("object_size", AwsColumnKind::Integer, 12, AwsSource::Token),
+    ("total_time", AwsColumnKind::Integer, 13, AwsSource::Token),
+    ("turn_around_time", AwsColumnKind::Integer, 14, AwsSource::Token),
+    ("referrer", AwsColumnKind::Text, 15, AwsSource::Token),
+    ("user_agent", AwsColumnKind::Text, 16, AwsSource::Token),
+    ("version_id", AwsColumnKind::Text, 17, AwsSource::Token),
+    ("host_id", AwsColumnKind::Text, 18, AwsSource::Token),
+    ("signature_version", AwsColumnKind::Text, 19, AwsSource::Token),
+    ("cipher_suite", AwsColumnKind::Text, 20, AwsSource::Token),
+    ("authentication_type", AwsColumnKind::Text, 21, AwsSource::Token),
+    ("host_header", AwsColumnKind::Text, 22, AwsSource::Token),
+    ("tls_version", AwsColumnKind::Text, 23, AwsSource::Token),
+];
+
+impl AwsFormat {
+    pub fn new(log: AwsLog) -> AwsFormat {
+        let definitions = match log { AwsLog::Alb => ALB_COLUMNS, AwsLog::S3 => S3_COLUMNS };
+        let columns = definitions.iter()
+            .map(|&(name, kind, token, source)| AwsColumn { name: name, kind: kind, token: token, source: source })
+            .collect();
+        AwsFormat { log: log, columns: Rc::new(columns) }
+    }
+
+    // Lines with fewer tokens are truncated or of another log type. Fields appended to the formats
+    // over the years are optional.
+    fn required_tokens(&self) -> usize {
+        match self.log {
+            AwsLog::Alb => 14,
+            AwsLog::S3 => 18,
+        }
+    }
+}
+
+impl LogFormat for AwsFormat {
+    type Record = BinaryAwsLogRecord;
+
+    fn empty_record(&self) -> BinaryAwsLogRecord {
+        BinaryAwsLogRecord::new(self.columns.clone())
+    }
+
+    fn read_record(&self, line: &[u8], record: &mut BinaryAwsLogRecord) -> bool {
+        read_log_record_binary(line, self.required_tokens(), record)
+    }
+
+    fn table_definition(&self) -> TableDefinition<BinaryAwsLogRecord> {
+        create_aws_log_record_table_definition(&self.columns)
+    }
+
+    fn is_log_file(file: &Path) -> bool {
+        is_alb_log(file) || is_s3_access_log(file)
+    }
+}
+
+// ALB logs are delivered as <account>_elasticloadbalancing_<region>_<lb>_<time>_<ip>_<id>.log.gz
+pub fn is_alb_log(file: &Path) -> bool {
+    file.file_name().map(|n| n.to_string_lossy().contains("_elasticloadbalancing_")).unwrap_or(false)
+}
+
+// S3 access logs are delivered as YYYY-mm-DD-HH-MM-SS-<unique id>, without an extension.
+pub fn is_s3_access_log(file: &Path) -> bool {
+    let name = file.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or(String::new());
+    let bytes = name.as_bytes();
+    bytes.len() > 20 && bytes[0..19].iter().enumerate().all(|(idx, b)| {
+        match idx {
+            4 | 7 | 10 | 13 | 16 => *b == b'-',
+            _ => b.is_ascii_digit(),
+        }
+    }) && bytes[19] == b'-'
+}
+
+pub fn read_log_record_binary(line: &[u8], required_tokens: usize, record: &mut BinaryAwsLogRecord) -> bool {
+    let line = trim_line_ending(line);
+    tokenize(line, &mut record.tokens);
+    if record.tokens.len() < required_tokens {
+        return false;
+    }
+
+    let columns = record.columns.clone();
+    for (idx, column) in columns.iter().enumerate() {
+        let token = match record.tokens.get(column.token) {
+            Some(&(start, end)) => or_empty(&line[start..end]),
+            None => &[],
+        };
+        let value =
+            match column.source {
+                AwsSource::Token => token,
+                AwsSource::Host => split_host_port(token).0,
+                AwsSource::Port => split_host_port(token).1,
+                AwsSource::Method => nginx::split_request(token).0,
+                AwsSource::Path => strip_origin(nginx::split_request(token).1),
+                AwsSource::Query => nginx::split_request(token).2,
+                AwsSource::Elapsed => if token == b"-1" { &[] } else { token },
+            };
+        record.values[idx].clear();
+        record.values[idx].extend_from_slice(value);
+        record.parsed_text[idx] = None;
+        record.parsed_date[idx] = None;
+        record.parsed_address[idx] = None;
+    }
+    true
+}
+
+// Splits a line into the spans of its tokens, with the quotes or brackets of compound tokens
+// removed.
+fn tokenize(line: &[u8], tokens: &mut Vec<(usize, usize)>) {
+    tokens.clear();
+    let mut idx = 0;
+    while idx < line.len() {
+        if line[idx] == b' ' {
+            idx += 1;
+            continue;
+        }
+        let close = match line[idx] { b'"' => Some(b'"'), b'[' => Some(b']'), _ => None };
+        match close {
+            Some(close) => {
+                let start = idx + 1;
+                let end = index_of(&line[start..], close).map(|end| start + end).unwrap_or(line.len());
+                tokens.push((start, end));
+                idx = end + 1;
+            },
+            None => {
+                let start = idx;
+                let end = index_of(&line[start..], b' ').map(|end| start + end).unwrap_or(line.len());
+                tokens.push((start, end));
+                idx = end;
+            },
+        }
+    }
+}
+
+fn split_host_port(token: &[u8]) -> (&[u8], &[u8]) {
+    match token.iter().rposition(|b| *b == b':') {
+        Some(idx) => (&token[0..idx], &token[idx+1..]),
+        None => (token, &[]),
+    }
+}
+
+// ALB requests hold absolute urls, so the scheme and authority are dropped to leave the path.
+fn strip_origin(path: &[u8]) -> &[u8] {
+    let start = if path.starts_with(b"https://") { 8 } else if path.starts_with(b"http://") { 7 } else { return path };
+    match index_of(&path[start..], b'/') {
+        Some(idx) => &path[start+idx..],
+        None => b"/",
+    }
+}
+
+fn trim_line_ending(working: &[u8]) -> &[u8] {
+    let mut len = working.len();
+    while len > 0 && (working[len-1] == b'\n' || working[len-1] == b'\r') {
+        len -= 1;
+    }
+    &working[0..len]
+}
+
+#[derive(Debug, Clone)]
+pub struct BinaryAwsLogRecord {
+    columns: Rc<Vec<AwsColumn>>,
+    tokens: Vec<(usize, usize)>,
+    values: Vec<Vec<u8>>,
+    parsed_text: Vec<Option<String>>,
+    parsed_date: Vec<Option<Option<DateTime<Local>>>>,
+    parsed_address: Vec<Option<Option<IpAddr>>>,
+}
+
+impl BinaryAwsLogRecord {
+    fn new(columns: Rc<Vec<AwsColumn>>) -> BinaryAwsLogRecord {
+        let len = columns.len();
+        BinaryAwsLogRecord {
+            columns: columns,
+            tokens: Vec::new(),
+            values: vec![Vec::new(); len],
+            parsed_text: vec![None; len],
+            parsed_date: vec![None; len],
+            parsed_address: vec![None; len],
+        }
+    }
+
+    pub fn value_bytes(&self, idx: usize) -> Option<&[u8]> {
+        empty_opt(&self.values[idx])
+    }
+
+    pub fn parsed_text(&mut self, idx: usize) -> Option<&str> {
+        if self.values[idx].is_empty() {
+            return None;
+        }
+        if self.parsed_text[idx].is_none() {
+            self.parsed_text[idx] = Some(String::from_utf8_lossy(&self.values[idx]).to_string());
+        }
+        self.parsed_text[idx].as_ref().map(|s| s.as_str())
+    }
+
+    pub fn parsed_integer(&mut self, idx: usize) -> Option<u64> {
+        self.parsed_text(idx).and_then(|v| v.parse::<u64>().ok())
+    }
+
+    pub fn parsed_double(&mut self, idx: usize) -> Option<f64> {
+        self.parsed_text(idx).and_then(|v| v.parse::<f64>().ok())
+    }
+
+    pub fn parsed_address(&mut self, idx: usize) -> Option<IpAddr> {
+        if self.parsed_address[idx].is_none() {
+            self.parsed_address[idx] = Some(cidr::parse_address(&self.values[idx]));
+        }
+        self.parsed_address[idx].unwrap()
+    }
+
+    pub fn parsed_date(&mut self, idx: usize) -> Option<&DateTime<Local>> {
+        if self.parsed_date[idx].is_none() {
+            let iso = self.columns[idx].kind == AwsColumnKind::IsoDate;
+            let date = self.parsed_text(idx).and_then(|v| {
+                if iso {
+                    DateTime::parse_from_rfc3339(v).ok()
+                } else {
+                    DateTime::parse_from_str(v, "%d/%b/%Y:%H:%M:%S %z").ok()
+                }
+            });
+            self.parsed_date[idx] = Some(date.map(|d| d.with_timezone(&Local)));
+        }
+        self.parsed_date[idx].as_ref().unwrap().as_ref()
+    }
+}
+
+fn create_aws_log_record_table_definition(columns: &Vec<AwsColumn>) -> TableDefinition<BinaryAwsLogRecord> {
+    let mut column_map = HashMap::new();
+    let mut ordering = Vec::new();
+
+    for (idx, column) in columns.iter().enumerate() {
+        let name = column.name.to_owned();
+        let definition = match column.kind {
+            AwsColumnKind::Integer =>
+                ColumnDefinition::Integer { name: name,
+                                            size: 10,
+                                            field: idx,
+                                            binary_extractor: |r: &BinaryAwsLogRecord, idx| r.value_bytes(idx),
+                                            extractor: |r: &mut BinaryAwsLogRecord, idx| r.parsed_integer(idx) },
+            AwsColumnKind::Double =>
+                ColumnDefinition::Double { name: name,
+                                           size: 10,
+                                           field: idx,
+                                           binary_extractor: |r: &BinaryAwsLogRecord, idx| r.value_bytes(idx),
+                                           extractor: |r: &mut BinaryAwsLogRecord, idx| r.parsed_double(idx) },
+            AwsColumnKind::IsoDate | AwsColumnKind::LocalDate =>
+                ColumnDefinition::Date { name: name,
+                                         size: 26,
+                                         field: idx,
+                                         binary_extractor: |r: &BinaryAwsLogRecord, idx| r.value_bytes(idx),
+                                         extractor: |r: &mut BinaryAwsLogRecord, idx| r.parsed_date(idx) },
+            AwsColumnKind::Text =>
+                ColumnDefinition::Text { name: name,
+                                         size: 20,
+                                         field: idx,
+                                         binary_extractor: |r: &BinaryAwsLogRecord, idx| r.value_bytes(idx),
+                                         extractor: |r: &mut BinaryAwsLogRecord, idx| r.parsed_text(idx) },
+            AwsColumnKind::Ip =>
+                ColumnDefinition::Ip { name: name,
+                                       size: 15,
+                                       field: idx,
+                                       binary_extractor: |r: &BinaryAwsLogRecord, idx| r.value_bytes(idx),
+                                       extractor: |r: &mut BinaryAwsLogRecord, idx| r.parsed_text(idx),
+                                       address_extractor: |r: &mut BinaryAwsLogRecord, idx| r.parsed_address(idx) },
+        };
+        ordering.push(definition.name().to_owned());
+        column_map.insert(definition.name().to_owned(), definition);
+    }
+
+    TableDefinition {
+        column_map: column_map,
+        ordered_columns: ordering,
+    }
+}
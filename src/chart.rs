@@ -0,0 +1,87 @@
+use std::io::{self, Write};
+
+use chrono::Duration;
+use output::{OutputColumn, ResultRenderer};
+use value::Value;
+
+const BAR_WIDTH: usize = 60;
+// Gaps are only filled in when it doesn't turn a chart into pages of empty intervals
+const MAX_FILLED_BUCKETS: i64 = 10000;
+
+// Renders the rows of a time bucketed query, the bucket followed by a value, as a horizontal bar
+// chart. Intervals without any matching line are shown as empty bars.
+pub struct ChartRenderer {
+    out: Box<Write>,
+    interval: i64,
+    name: String,
+    rows: Vec<(Value, f64)>,
+}
+
+impl ChartRenderer {
+    pub fn new(out: Box<Write>, interval: i64) -> ChartRenderer {
+        ChartRenderer { out: out, interval: interval, name: String::new(), rows: Vec::new() }
+    }
+
+    fn fill_gaps(&self) -> Vec<(Value, f64)> {
+        let mut filled: Vec<(Value, f64)> = Vec::with_capacity(self.rows.len());
+        for &(ref bucket, value) in &self.rows {
+            if let (Some(&(Value::Date(previous), _)), &Value::Date(current)) = (filled.last(), bucket) {
+                let missing = (current - previous).num_seconds() / self.interval - 1;
+                if missing > 0 && missing <= MAX_FILLED_BUCKETS {
+                    for idx in 1..(missing + 1) {
+                        filled.push((Value::Date(previous + Duration::seconds(idx * self.interval)), 0.0));
+                    }
+                }
+            }
+            filled.push((bucket.clone(), value));
+        }
+        filled
+    }
+}
+
+impl ResultRenderer for ChartRenderer {
+    fn render_header(&mut self, columns: &Vec<OutputColumn>) -> io::Result<()> {
+        self.name = columns.last().map(|c| c.name.to_owned()).unwrap_or(String::new());
+        self.rows.clear();
+        Ok(())
+    }
+
+    fn render_row(&mut self, values: &Vec<Value>) -> io::Result<()> {
+        let value =
+            match values.last() {
+                Some(Value::Int(i)) => *i as f64,
+                Some(Value::Float(d)) => *d,
+                _ => 0.0,
+            };
+        self.rows.push((values.first().cloned().unwrap_or(Value::Null), value));
+        Ok(())
+    }
+
+    fn render_footer(&mut self) -> io::Result<()> {
+        let rows = if self.interval > 0 { self.fill_gaps() } else { self.rows.clone() };
+        let labels: Vec<String> = rows.iter().map(|r| r.0.to_display_string()).collect();
+        let label_width = labels.iter().map(|l| l.len()).max().unwrap_or(0);
+        let max = rows.iter().map(|r| r.1).fold(0.0, f64::max);
+
+        writeln!(self.out, "{:width$} | {}", "", self.name, width = label_width)?;
+        for (label, &(_, value)) in labels.iter().zip(&rows) {
+            let mut length = if max > 0.0 { (value / max * BAR_WIDTH as f64).round() as usize } else { 0 };
+            // Every non zero value gets a visible bar
+            if length == 0 && value > 0.0 {
+                length = 1;
+            }
+            let bar = (0..length).map(|_| "#").collect::<String>();
+            writeln!(self.out, "{:width$} | {:bar_width$} {}", label, bar, Value::Float(value).to_display_string(),
+                     width = label_width, bar_width = BAR_WIDTH)?;
+        }
+        self.out.flush()
+    }
+
+    fn render_label(&mut self, label: &str) -> io::Result<()> {
+        writeln!(self.out, "{}", label)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
@@ -0,0 +1,126 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::{self, Map};
+use riplog::input;
+
+const VERSION: u64 = 1;
+
+// Where the runs of a query with --checkpoint left off: the bytes read of each file, and the aggregates
+// of every line read so far. A later run reads each file from where the last one stopped and adds what
+// it reads to those aggregates, so only lines appended since are read.
+pub struct Checkpoint {
+    path: PathBuf,
+    query: String,
+    format: String,
+    files: Vec<FileMark>,
+    // Aggregates of the runs so far, null for queries that aren't aggregated
+    pub snapshot: serde_json::Value,
+}
+
+// A file is recognized by its identity before its path, so that a log renamed by rotation is still read
+// from where it was left. Files that are compressed again once rotated are read as new files.
+#[derive(Debug, Clone)]
+struct FileMark {
+    path: PathBuf,
+    identity: u64,
+    offset: u64,
+}
+
+impl Checkpoint {
+    // A checkpoint that doesn't exist yet is started empty, while one written for another query or
+    // format is an error rather than being overwritten.
+    pub fn open(path: &Path, query: &str, format: &str) -> Result<Checkpoint, String> {
+        let mut checkpoint = Checkpoint { path: path.to_owned(), query: query.to_owned(), format: format.to_owned(), files: Vec::new(), snapshot: serde_json::Value::Null };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(checkpoint),
+            Err(e) => return Err(format!("Unable to read checkpoint {}: {}", path.display(), e)),
+        };
+        let invalid = || format!("{} is not a riplog checkpoint", path.display());
+        let saved: serde_json::Value = serde_json::from_reader(io::BufReader::new(file)).map_err(|_| invalid())?;
+        if saved.get("version").and_then(|v| v.as_u64()) != Some(VERSION) {
+            return Err(invalid());
+        }
+        if saved.get("query").and_then(|q| q.as_str()) != Some(query) || saved.get("format").and_then(|f| f.as_str()) != Some(format) {
+            return Err(format!("Checkpoint {} was written by another query or log format, remove it to start over", path.display()));
+        }
+        for file in saved.get("files").and_then(|f| f.as_array()).ok_or_else(invalid)? {
+            let mark = (|| Some(FileMark {
+                path: PathBuf::from(file.get("path")?.as_str()?),
+                identity: file.get("identity")?.as_u64()?,
+                offset: file.get("offset")?.as_u64()?,
+            }))();
+            checkpoint.files.push(mark.ok_or_else(invalid)?);
+        }
+        checkpoint.snapshot = saved.get("aggregates").cloned().unwrap_or(serde_json::Value::Null);
+        Ok(checkpoint)
+    }
+
+    // The position to read a file from, None when nothing was appended to it since it was last read.
+    // Files shorter than what was read of them were truncated, and are read from the start. A file
+    // found under another path, having been renamed, is kept under its new one.
+    pub fn start(&mut self, file: &Path) -> io::Result<Option<u64>> {
+        let metadata = fs::metadata(file)?;
+        let path = absolute_path(file);
+        let offset =
+            match self.find(&path, input::file_identity(&metadata)) {
+                Some(mark) => {
+                    mark.path = path;
+                    Some(mark.offset).filter(|o| *o <= metadata.len()).unwrap_or(0)
+                },
+                None => 0,
+            };
+        Ok(if offset == metadata.len() && offset > 0 { None } else { Some(offset) })
+    }
+
+    // Records that a file was read up to the position given.
+    pub fn finish_file(&mut self, file: &Path, offset: u64) -> io::Result<()> {
+        let identity = input::file_identity(&fs::metadata(file)?);
+        let path = absolute_path(file);
+        self.files.retain(|m| m.path != path && !(identity != 0 && m.identity == identity));
+        self.files.push(FileMark { path: path, identity: identity, offset: offset });
+        Ok(())
+    }
+
+    // Writes the checkpoint to a temporary file first, so an interrupted run leaves the last one whole.
+    // Files no longer found at their path are forgotten.
+    pub fn save(&mut self, snapshot: serde_json::Value) -> io::Result<()> {
+        self.files.retain(|m| fs::metadata(&m.path).map(|meta| input::file_identity(&meta) == m.identity).unwrap_or(false));
+        let files: Vec<serde_json::Value> = self.files.iter().map(|m| {
+            let mut file = Map::new();
+            file.insert("path".to_owned(), serde_json::Value::from(m.path.to_string_lossy().to_string()));
+            file.insert("identity".to_owned(), serde_json::Value::from(m.identity));
+            file.insert("offset".to_owned(), serde_json::Value::from(m.offset));
+            serde_json::Value::Object(file)
+        }).collect();
+        let mut saved = Map::new();
+        saved.insert("version".to_owned(), serde_json::Value::from(VERSION));
+        saved.insert("query".to_owned(), serde_json::Value::from(self.query.to_owned()));
+        saved.insert("format".to_owned(), serde_json::Value::from(self.format.to_owned()));
+        saved.insert("files".to_owned(), serde_json::Value::from(files));
+        saved.insert("aggregates".to_owned(), snapshot);
+
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let mut out = File::create(&temporary)?;
+        serde_json::to_writer(&mut out, &serde_json::Value::Object(saved))?;
+        out.write_all(b"\n")?;
+        out.sync_all()?;
+        fs::rename(&temporary, &self.path)
+    }
+
+    fn find(&mut self, path: &Path, identity: u64) -> Option<&mut FileMark> {
+        if identity != 0 {
+            self.files.iter_mut().find(|m| m.identity == identity)
+        } else {
+            self.files.iter_mut().find(|m| m.path == path)
+        }
+    }
+}
+
+// Files are kept by absolute path, so runs from other directories find them.
+fn absolute_path(file: &Path) -> PathBuf {
+    fs::canonicalize(file).unwrap_or_else(|_| file.to_owned())
+}
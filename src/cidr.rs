@@ -0,0 +1,167 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str;
+
+// Address ranges a filter matches with `in_cidr`, e.g. 10.0.0.0/8 or fd00::/8. Ranges are kept as
+// sorted, merged intervals of each address family, so an address is looked up with a binary search
+// however many ranges are given.
+#[derive(Debug, Clone)]
+pub struct CidrSet {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl CidrSet {
+    // None when any of the ranges isn't an address optionally followed by a prefix length.
+    pub fn parse(ranges: &Vec<String>) -> Option<CidrSet> {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        for range in ranges {
+            let (address, prefix) =
+                match range.find('/') {
+                    Some(idx) => (&range[0..idx], Some(range[idx+1..].parse::<u32>().ok()?)),
+                    None => (&range[..], None),
+                };
+            match address.parse::<IpAddr>().ok()? {
+                IpAddr::V4(address) => v4.push(interval(u32::from(address) as u128, prefix.unwrap_or(32), 32)?),
+                IpAddr::V6(address) => v6.push(interval(u128::from(address), prefix.unwrap_or(128), 128)?),
+            }
+        }
+        Some(CidrSet {
+            v4: merge(v4).into_iter().map(|(first, last)| (first as u32, last as u32)).collect(),
+            v6: merge(v6),
+        })
+    }
+
+    // IPv4 addresses written as IPv6 (::ffff:10.0.0.1) match the IPv4 ranges.
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match address {
+            IpAddr::V4(address) => contains(&self.v4, u32::from(*address)),
+            IpAddr::V6(address) =>
+                match ipv4_mapped(address) {
+                    Some(address) => contains(&self.v4, u32::from(address)),
+                    None => contains(&self.v6, u128::from(*address)),
+                },
+        }
+    }
+}
+
+// Parses the raw bytes of a column holding an address, None when they don't hold one.
+pub fn parse_address(bytes: &[u8]) -> Option<IpAddr> {
+    str::from_utf8(bytes).ok().and_then(|a| a.parse::<IpAddr>().ok())
+}
+
+// First and last address of a range, the bits after the prefix being ignored as with 10.1.2.3/8.
+fn interval(address: u128, prefix: u32, bits: u32) -> Option<(u128, u128)> {
+    if prefix > bits {
+        return None;
+    }
+    let host_bits = bits - prefix;
+    let host_mask = if host_bits == 128 { u128::max_value() } else { (1u128 << host_bits) - 1 };
+    Some((address & !host_mask, address | host_mask))
+}
+
+fn merge(mut intervals: Vec<(u128, u128)>) -> Vec<(u128, u128)> {
+    intervals.sort();
+    let mut merged: Vec<(u128, u128)> = Vec::new();
+    for (first, last) in intervals {
+        match merged.last_mut() {
+            Some(previous) if first <= previous.1.saturating_add(1) => previous.1 = previous.1.max(last),
+            _ => merged.push((first, last)),
+        }
+    }
+    merged
+}
+
+fn contains<N: Ord + Copy>(intervals: &Vec<(N, N)>, address: N) -> bool {
+    match intervals.binary_search_by(|&(first, _)| first.cmp(&address)) {
+        Ok(_) => true,
+        Err(0) => false,
+        Err(idx) => address <= intervals[idx - 1].1,
+    }
+}
+
+fn ipv4_mapped(address: &Ipv6Addr) -> Option<Ipv4Addr> {
+    match address.segments() {
+        [0, 0, 0, 0, 0, 0xffff, high, low] => Some(Ipv4Addr::new((high >> 8) as u8, high as u8, (low >> 8) as u8, low as u8)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(ranges: &[&str]) -> Option<CidrSet> {
+        CidrSet::parse(&ranges.iter().map(|r| r.to_string()).collect())
+    }
+
+    fn address(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn ranges_are_addresses_with_an_optional_prefix_length() {
+        let ranges = set(&["10.0.0.0/8", "192.168.1.20"]).unwrap();
+        assert!(ranges.contains(&address("10.255.0.1")));
+        assert!(!ranges.contains(&address("11.0.0.0")));
+        assert!(ranges.contains(&address("192.168.1.20")));
+        assert!(!ranges.contains(&address("192.168.1.21")));
+        assert!(set(&[]).unwrap().v4.is_empty());
+        for invalid in &["10.0.0/8", "10.0.0.0/", "10.0.0.0/x", "10.0.0.0/-1", "example.com", "10.0.0.0/8/8"] {
+            assert!(set(&["10.0.0.0/8", invalid]).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn prefix_lengths_are_bounded_by_the_address_family() {
+        assert!(set(&["10.0.0.1/32"]).unwrap().contains(&address("10.0.0.1")));
+        assert!(set(&["10.0.0.1/33"]).is_none());
+        assert!(set(&["0.0.0.0/0"]).unwrap().contains(&address("255.255.255.255")));
+        assert!(set(&["::1/128"]).unwrap().contains(&address("::1")));
+        assert!(set(&["::1/129"]).is_none());
+        assert!(set(&["::/0"]).unwrap().contains(&address("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")));
+    }
+
+    #[test]
+    fn bits_after_the_prefix_are_ignored() {
+        let ranges = set(&["10.1.2.3/8"]).unwrap();
+        assert_eq!(ranges.v4, vec![(0x0a00_0000, 0x0aff_ffff)]);
+        assert!(ranges.contains(&address("10.200.0.0")));
+    }
+
+    #[test]
+    fn ipv6_ranges_match_ipv6_addresses_only() {
+        let ranges = set(&["fd00::/8", "2001:db8::/32"]).unwrap();
+        assert!(ranges.contains(&address("fd12:3456::1")));
+        assert!(ranges.contains(&address("2001:db8:ffff::1")));
+        assert!(!ranges.contains(&address("2001:db9::1")));
+        assert!(!ranges.contains(&address("10.0.0.1")));
+        assert!(!set(&["10.0.0.0/8"]).unwrap().contains(&address("fd00::1")));
+    }
+
+    #[test]
+    fn ipv4_addresses_written_as_ipv6_match_ipv4_ranges() {
+        let ranges = set(&["10.0.0.0/8"]).unwrap();
+        assert!(ranges.contains(&address("::ffff:10.0.0.1")));
+        assert!(!ranges.contains(&address("::ffff:11.0.0.1")));
+        assert!(!ranges.contains(&address("::10.0.0.1")));
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_ranges_are_merged() {
+        let ranges = set(&["10.0.1.0/24", "10.0.0.0/16", "10.1.0.0/16", "10.3.0.0/16", "::/1", "8000::/1"]).unwrap();
+        assert_eq!(ranges.v4, vec![(0x0a00_0000, 0x0a01_ffff), (0x0a03_0000, 0x0a03_ffff)]);
+        assert_eq!(ranges.v6, vec![(0, u128::max_value())]);
+        assert!(!ranges.contains(&address("10.2.0.0")));
+        assert!(ranges.contains(&address("10.3.255.255")));
+    }
+
+    #[test]
+    fn addresses_are_parsed_from_column_bytes() {
+        assert_eq!(parse_address(b"192.168.1.20"), Some(address("192.168.1.20")));
+        assert_eq!(parse_address(b"fd00::1"), Some(address("fd00::1")));
+        assert_eq!(parse_address(b"-"), None);
+        assert_eq!(parse_address(b"10.0.0.256"), None);
+        assert_eq!(parse_address(&[0xff, 0xfe]), None);
+    }
+}
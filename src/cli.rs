@@ -0,0 +1,825 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+
+use clap::{App, Arg, ArgMatches, AppSettings, SubCommand};
+use riplog::output::{OutputFormat, OutputOptions};
+use riplog::parser;
+use riplog::sqlite;
+use riplog::storage;
+use riplog::discovery::FileOrder;
+use riplog::sample::Sampling;
+use riplog::input::FileSpan;
+use config::{self, Config};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Query,
+    Index,
+    Hist,
+    Schema,
+    Merge,
+}
+
+// Exit codes, as grep's: whether any line matched, for --quiet, or whether an error occurred
+pub const EXIT_MATCHED: i32 = 0;
+pub const EXIT_NO_MATCH: i32 = 1;
+pub const EXIT_ERROR: i32 = 2;
+// Exit code of a followed query stopped by an alert, with --alert-exit
+pub const EXIT_ALERT: i32 = 3;
+
+const FORMATS: &[&str] = &["nginx", "nginx-error", "apache", "json", "syslog", "csv", "tsv", "elb", "s3", "custom", "auto"];
+
+// Log format of the input, one of FORMATS
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Nginx,
+    NginxError,
+    Apache,
+    Json,
+    Syslog,
+    Csv,
+    Tsv,
+    Elb,
+    S3,
+    Custom,
+    Auto,
+}
+
+impl InputFormat {
+    pub fn from_string(format: &str) -> Option<InputFormat> {
+        match format {
+            "nginx" => Some(InputFormat::Nginx),
+            "nginx-error" => Some(InputFormat::NginxError),
+            "apache" => Some(InputFormat::Apache),
+            "json" => Some(InputFormat::Json),
+            "syslog" => Some(InputFormat::Syslog),
+            "csv" => Some(InputFormat::Csv),
+            "tsv" => Some(InputFormat::Tsv),
+            "elb" => Some(InputFormat::Elb),
+            "s3" => Some(InputFormat::S3),
+            "custom" => Some(InputFormat::Custom),
+            "auto" => Some(InputFormat::Auto),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            InputFormat::Nginx => "nginx",
+            InputFormat::NginxError => "nginx-error",
+            InputFormat::Apache => "apache",
+            InputFormat::Json => "json",
+            InputFormat::Syslog => "syslog",
+            InputFormat::Csv => "csv",
+            InputFormat::Tsv => "tsv",
+            InputFormat::Elb => "elb",
+            InputFormat::S3 => "s3",
+            InputFormat::Custom => "custom",
+            InputFormat::Auto => "auto",
+        }
+    }
+
+    // Whether the format's columns are read from the local files being queried
+    pub fn has_file_columns(&self) -> bool {
+        match self {
+            InputFormat::Json | InputFormat::Csv | InputFormat::Tsv => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+pub struct Options {
+    pub command: Command,
+    pub query: String,
+    pub paths: Vec<PathBuf>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub format: InputFormat,
+    pub log_format: Option<String>,
+    pub geoip: Vec<String>,
+    pub route_patterns: Option<String>,
+    pub columns: Option<String>,
+    pub column_types: Option<String>,
+    pub output: OutputOptions,
+    pub allow_missing_columns: bool,
+    pub estimate: bool,
+    pub follow: bool,
+    pub progress: bool,
+    pub stats: bool,
+    // Print nothing, the exit code telling whether any line matched
+    pub quiet: bool,
+    // Print how long the query took to stderr
+    pub timing: bool,
+    // Write what was scanned, and how long it took, as a json object to stderr
+    pub meta: bool,
+    pub interval: u64,
+    // Threads used to decompress files in the background while the main thread evaluates them
+    pub threads: usize,
+    // Whether files outside the date range of the query are skipped without being read
+    pub prune: bool,
+    pub order: FileOrder,
+    // Inactivity gap in seconds splitting records into sessions, which buffers every record
+    pub sessionize: Option<i64>,
+    // Seconds of the sliding window the summaries of a followed log are computed over
+    pub window: Option<i64>,
+    pub alert: Option<AlertOptions>,
+    // Lines are wrapped by a container runtime, the format parsing the line each wraps
+    pub container: bool,
+    // Text and ip columns whose values are masked, as name[:hash|truncate],...
+    pub mask: Option<String>,
+    // Secret keying the hashes of masked values, a random key being used when missing
+    pub mask_key: Option<String>,
+    // Evaluates only a sample of the lines read
+    pub sample: Option<Sampling>,
+    // Part of each file whose lines are evaluated
+    pub span: FileSpan,
+    // File recording what was read of each file and the aggregates so far, for later runs to carry on from
+    pub checkpoint: Option<PathBuf>,
+    // Hosts whose logs are read over ssh, the paths being theirs
+    pub ssh: Vec<String>,
+    // File the aggregates are written to instead of the results, for `riplog merge` to merge
+    pub emit_partial: Option<PathBuf>,
+}
+
+// Options with every feature off, which the subcommands override with the few options they take.
+// Queries set each of them from the command line.
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            command: Command::Query,
+            query: String::new(),
+            paths: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            format: InputFormat::Nginx,
+            log_format: None,
+            geoip: Vec::new(),
+            route_patterns: None,
+            columns: None,
+            column_types: None,
+            output: OutputOptions::default(),
+            allow_missing_columns: false,
+            estimate: false,
+            follow: false,
+            progress: false,
+            stats: false,
+            quiet: false,
+            timing: false,
+            meta: false,
+            interval: 10,
+            threads: 1,
+            prune: false,
+            order: FileOrder::Oldest,
+            sessionize: None,
+            window: None,
+            alert: None,
+            container: false,
+            mask: None,
+            mask_key: None,
+            sample: None,
+            span: FileSpan::default(),
+            checkpoint: None,
+            ssh: Vec::new(),
+            emit_partial: None,
+        }
+    }
+}
+
+// What to do when result rows of a followed query meet a condition, besides printing an alert line
+pub struct AlertOptions {
+    pub condition: String,
+    // Shell command run for each breach
+    pub command: Option<String>,
+    // Stop following and exit with EXIT_ALERT at the first breach
+    pub exit: bool,
+}
+
+pub fn parse_options() -> Options {
+    let matches = App::new("riplog")
+        .version(crate_version!())
+        .about("Evaluates queries against log files")
+        .setting(AppSettings::ColoredHelp)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(SubCommand::with_name("index")
+                    .about("Writes a columnar index of nginx logs, the only format that can be indexed, that later queries use to skip parsing and files outside their date range")
+                    .arg(Arg::with_name("path")
+                         .help("Log files, directories or glob patterns to index")
+                         .required(true)
+                         .multiple(true))
+                    .arg(Arg::with_name("include")
+                         .long("include")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("pattern")
+                         .help("Only index files whose name matches the glob"))
+                    .arg(Arg::with_name("exclude")
+                         .long("exclude")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("pattern")
+                         .help("Skip files whose name matches the glob")))
+        .subcommand(SubCommand::with_name("hist")
+                    .about("Prints a bar chart over time of the number of matching lines, or of a reducer")
+                    .arg(Arg::with_name("query")
+                         .help("Filter selecting the lines to chart, e.g. 'status >= 500', or '' for every line")
+                         .required(true))
+                    .arg(Arg::with_name("path")
+                         .help("Log files, directories or glob patterns to chart, the config's log_dir by default")
+                         .multiple(true))
+                    .arg(Arg::with_name("interval")
+                         .long("interval")
+                         .takes_value(true)
+                         .default_value("5m")
+                         .validator(|v| parser::parse_duration_seconds(&v).map(|_| ()).ok_or("interval must be a duration such as 30s, 5m or 1h".to_owned()))
+                         .help("Width of each bar's time interval"))
+                    .arg(Arg::with_name("show")
+                         .long("show")
+                         .takes_value(true)
+                         .default_value("count(*)")
+                         .value_name("reducer")
+                         .help("Reducer charted for each interval, e.g. 'sum(bytes)'"))
+                    .arg(Arg::with_name("format")
+                         .long("format")
+                         .takes_value(true)
+                         .possible_values(FORMATS)
+                         .default_value("nginx")
+                         .help("Log format of the input"))
+                    .arg(Arg::with_name("log-format")
+                         .long("log-format")
+                         .takes_value(true)
+                         .help("Parse lines using an nginx log_format directive instead of the combined format"))
+                    .arg(Arg::with_name("include")
+                         .long("include")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("pattern")
+                         .help("Only scan files whose name matches the glob, instead of the format's default"))
+                    .arg(Arg::with_name("exclude")
+                         .long("exclude")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("pattern")
+                         .help("Skip files whose name matches the glob")))
+        .subcommand(SubCommand::with_name("merge")
+                    .about("Merges the partial results of a query written by --emit-partial, e.g. on several hosts, and prints its results")
+                    .arg(Arg::with_name("partial")
+                         .help("Files written by --emit-partial")
+                         .required(true)
+                         .multiple(true))
+                    .arg(Arg::with_name("output")
+                         .long("output")
+                         .takes_value(true)
+                         .default_value("table")
+                         .validator(|o| OutputFormat::from_string(&o).map(|_| ()).ok_or("expected table, json, ndjson or sqlite:<file>".to_owned()))
+                         .help("Output format of the results: table, json, ndjson or sqlite:<file> to write them to a table of a sqlite database"))
+                    .arg(Arg::with_name("precision")
+                         .long("precision")
+                         .takes_value(true)
+                         .value_name("n")
+                         .validator(validate_precision)
+                         .help("Show floats, e.g. averages, with n decimals in tables, and round them to n decimals in other outputs"))
+                    .arg(Arg::with_name("human")
+                         .long("human")
+                         .help("Show sizes in bytes, e.g. sum(bytes), as KB, MB or GB in tables"))
+                    .arg(Arg::with_name("stats")
+                         .long("stats")
+                         .help("Print the lines read, matched and skipped as unparseable, and files scanned and skipped, of every run merged")))
+        .arg(Arg::with_name("query")
+             .help("Query to evaluate, e.g. 'status = \"500\" | group ip | show count(*)'")
+             .required_unless_one(&["query-file", "saved", "schema"]))
+        .arg(Arg::with_name("path")
+             .help("Log files, directories or glob patterns (e.g. '/var/log/nginx/access.log*') to query, the config's log_dir by default")
+             .multiple(true))
+        .arg(Arg::with_name("config")
+             .long("config")
+             .takes_value(true)
+             .value_name("file")
+             .global(true)
+             .help("Read default options from this file instead of ~/.config/riplog/config.toml"))
+        .arg(Arg::with_name("query-file")
+             .long("query-file")
+             .takes_value(true)
+             .value_name("file")
+             .conflicts_with("saved")
+             .help("Read the query from a file instead, which may span several lines and contain '#' comments"))
+        .arg(Arg::with_name("saved")
+             .long("saved")
+             .takes_value(true)
+             .value_name("name")
+             .help("Run a query saved by name in ~/.config/riplog/queries.toml"))
+        .arg(Arg::with_name("schema")
+             .long("schema")
+             .conflicts_with_all(&["query-file", "saved", "estimate", "follow"])
+             .help("Print the columns of the log format with their types and sizes instead of querying"))
+        .arg(Arg::with_name("include")
+             .long("include")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("pattern")
+             .help("Only scan files whose name matches the glob, instead of the format's default"))
+        .arg(Arg::with_name("exclude")
+             .long("exclude")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("pattern")
+             .help("Skip files whose name matches the glob"))
+        .arg(Arg::with_name("format")
+             .long("format")
+             .takes_value(true)
+             .possible_values(FORMATS)
+             .default_value("nginx")
+             .help("Log format of the input"))
+        .arg(Arg::with_name("log-format")
+             .long("log-format")
+             .takes_value(true)
+             .help("Parse lines using an nginx log_format directive instead of the combined format"))
+        .arg(Arg::with_name("geoip")
+             .long("geoip")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("mmdb")
+             .help("MaxMind database used to add country, city and asn columns derived from the ip (nginx only)"))
+        .arg(Arg::with_name("route-patterns")
+             .long("route-patterns")
+             .takes_value(true)
+             .value_name("file")
+             .help("File of '<regex> <replacement>' lines normalizing path segments into the route column (nginx and apache only)"))
+        .arg(Arg::with_name("columns")
+             .long("columns")
+             .takes_value(true)
+             .value_name("names")
+             .help("Comma separated column names of csv/tsv files without a header row"))
+        .arg(Arg::with_name("types")
+             .long("types")
+             .takes_value(true)
+             .value_name("name:type,...")
+             .help("Types of csv/tsv columns (int, double, text, date, bool or ip) instead of inferring them from the data"))
+        .arg(Arg::with_name("output")
+             .long("output")
+             .takes_value(true)
+             .default_value("table")
+             .validator(|o| OutputFormat::from_string(&o).map(|_| ()).ok_or("expected table, json, ndjson or sqlite:<file>".to_owned()))
+             .help("Output format of the results: table, json, ndjson or sqlite:<file> to write them to a table of a sqlite database"))
+        .arg(Arg::with_name("precision")
+             .long("precision")
+             .takes_value(true)
+             .value_name("n")
+             .validator(validate_precision)
+             .help("Show floats, e.g. averages, with n decimals in tables, and round them to n decimals in other outputs"))
+        .arg(Arg::with_name("human")
+             .long("human")
+             .help("Show sizes in bytes, e.g. sum(bytes), as KB, MB or GB in tables"))
+        .arg(Arg::with_name("no-pager")
+             .long("no-pager")
+             .help("Write results to the terminal directly instead of through $PAGER (less -S by default)"))
+        .arg(Arg::with_name("nest")
+             .long("nest")
+             .help("Write grouped json results as objects nested by group key, e.g. {\"GET\": {\"/api\": 120}}, instead of an array of rows"))
+        .arg(Arg::with_name("out")
+             .long("out")
+             .takes_value(true)
+             .value_name("file")
+             .help("Write results to a file instead of stdout, gzipped if the name ends in .gz"))
+        .arg(Arg::with_name("stream")
+             .long("stream")
+             .help("Print table rows as they are found instead of aligning aggregate and limited results once complete"))
+        .arg(Arg::with_name("unbuffered")
+             .long("unbuffered")
+             .help("Write each row as soon as it is found instead of buffering output, e.g. when piping to other commands"))
+        .arg(Arg::with_name("null")
+             .long("null")
+             .takes_value(true)
+             .value_name("text")
+             .default_value("<null>")
+             .help("Text shown in tables for missing values, to tell them apart from text such as \"null\""))
+        .arg(Arg::with_name("container")
+             .long("container")
+             .help("Read logs written by docker's json-file driver or kubernetes (cri), parsing the line each wraps with the log format and adding stream and container_time columns"))
+        .arg(Arg::with_name("mask")
+             .long("mask")
+             .takes_value(true)
+             .value_name("column[:mode],...")
+             .help("Mask the values of text and ip columns, e.g. ip,user_agent:truncate, as hashes (default) or truncated (addresses to their /24 or /48 network), in results, group keys and filters alike"))
+        .arg(Arg::with_name("mask-key")
+             .long("mask-key")
+             .takes_value(true)
+             .value_name("secret")
+             .env("RIPLOG_MASK_KEY")
+             .hide_env_values(true)
+             .help("Secret keying the hashes of --mask, so that runs given the same secret hash values alike; without it each run hashes with a random key"))
+        .arg(Arg::with_name("allow-missing-columns")
+             .long("allow-missing-columns")
+             .help("Treat columns missing from the log format as null instead of failing"))
+        .arg(Arg::with_name("estimate")
+             .long("estimate")
+             .help("Print the number and size of files that would be scanned and an estimated scan time"))
+        .arg(Arg::with_name("progress")
+             .long("progress")
+             .help("Print files processed, throughput and estimated time remaining to stderr while scanning"))
+        .arg(Arg::with_name("stats")
+             .long("stats")
+             .help("Print lines read, matched and skipped as unparseable, and files scanned and skipped after the results"))
+        .arg(Arg::with_name("quiet")
+             .long("quiet")
+             .short("q")
+             .conflicts_with_all(&["follow", "estimate", "schema"])
+             .help("Print nothing, stopping at the first matching line, and exit with 0 if a line matched, 1 if none did and 2 on errors"))
+        .arg(Arg::with_name("timing")
+             .long("timing")
+             .help("Print how long the query took to stderr after the results"))
+        .arg(Arg::with_name("meta")
+             .long("meta")
+             .takes_value(true)
+             .possible_values(&["json"])
+             .value_name("format")
+             .help("Write the duration, files scanned and lines read, parsed and matched as a json object to stderr after the results"))
+        .arg(Arg::with_name("order")
+             .long("order")
+             .takes_value(true)
+             .possible_values(&["oldest", "newest"])
+             .default_value("oldest")
+             .help("Scan files from the oldest or the newest, by modification time and rotation index"))
+        .arg(Arg::with_name("no-prune")
+             .long("no-prune")
+             .help("Read every file, even those whose modification time or first and last lines fall outside the dates filtered on"))
+        .arg(Arg::with_name("follow")
+             .long("follow")
+             .short("f")
+             .conflicts_with("estimate")
+             .help("Keep the file open and evaluate lines as they are appended"))
+        .arg(Arg::with_name("window")
+             .long("window")
+             .takes_value(true)
+             .value_name("duration")
+             .requires("follow")
+             .validator(|v| parser::parse_duration_seconds(&v).map(|_| ()).ok_or("window must be a duration such as 30s, 5m or 1h".to_owned()))
+             .help("Compute the rolling summaries of an aggregate query in follow mode over the lines of this last duration only, e.g. 5m"))
+        .arg(Arg::with_name("alert")
+             .long("alert")
+             .takes_value(true)
+             .value_name("condition")
+             .requires("follow")
+             .help("Print an alert line to stderr when a result row of an aggregate query meets a condition, e.g. 'count(*) > 100', checked at each summary or window in follow mode"))
+        .arg(Arg::with_name("alert-cmd")
+             .long("alert-cmd")
+             .takes_value(true)
+             .value_name("command")
+             .requires("alert")
+             .help("Shell command run when an alert is raised, with the condition in RIPLOG_ALERT and the rows meeting it in RIPLOG_ALERT_ROWS"))
+        .arg(Arg::with_name("alert-exit")
+             .long("alert-exit")
+             .requires("alert")
+             .help("Stop following and exit with code 3 when an alert is raised"))
+        .arg(Arg::with_name("since-line")
+             .long("since-line")
+             .takes_value(true)
+             .value_name("n")
+             .conflicts_with_all(&["follow", "tail-bytes"])
+             .validator(|v| v.parse::<u64>().ok().filter(|n| *n > 0).map(|_| ()).ok_or("since-line must be a line number, the first line being 1".to_owned()))
+             .help("Skip the lines of each file before this one, the first line being 1"))
+        .arg(Arg::with_name("max-lines-per-file")
+             .long("max-lines-per-file")
+             .takes_value(true)
+             .value_name("n")
+             .conflicts_with("follow")
+             .validator(|v| v.parse::<u64>().map(|_| ()).map_err(|_| "max-lines-per-file must be a number of lines".to_owned()))
+             .help("Evaluate at most this many lines of each file"))
+        .arg(Arg::with_name("tail-bytes")
+             .long("tail-bytes")
+             .takes_value(true)
+             .value_name("size")
+             .conflicts_with("follow")
+             .validator(|v| parse_size(&v).map(|_| ()).ok_or("tail-bytes must be a size such as 500000, 200K, 50M or 2G".to_owned()))
+             .help("Only evaluate the lines of the last bytes of each file, e.g. 50M, without reading it from the start (compressed files are read whole)"))
+        .arg(Arg::with_name("sample")
+             .long("sample")
+             .takes_value(true)
+             .value_name("fraction")
+             .conflicts_with_all(&["sample-every", "sessionize"])
+             .validator(|v| v.parse::<f64>().ok().filter(|r| *r > 0.0 && *r <= 1.0).map(|_| ()).ok_or("sample must be a fraction of lines between 0 and 1, e.g. 0.01".to_owned()))
+             .help("Evaluate a random sample of this fraction of the lines read, e.g. 0.01, with --stats estimating the lines matched from it"))
+        .arg(Arg::with_name("sample-every")
+             .long("sample-every")
+             .takes_value(true)
+             .value_name("n")
+             .conflicts_with("sessionize")
+             .validator(|v| v.parse::<u64>().ok().filter(|n| *n > 0).map(|_| ()).ok_or("sample-every must be a positive number of lines".to_owned()))
+             .help("Evaluate every nth line read only, with --stats estimating the lines matched from them"))
+        .arg(Arg::with_name("checkpoint")
+             .long("checkpoint")
+             .takes_value(true)
+             .value_name("file")
+             .conflicts_with_all(&["follow", "sessionize", "sample", "sample-every", "since-line", "max-lines-per-file", "tail-bytes", "quiet"])
+             .help("Record how far each file was read, and the aggregates of the query, in this file; later runs with it only read lines appended since, adding them to the saved aggregates"))
+        .arg(Arg::with_name("emit-partial")
+             .long("emit-partial")
+             .takes_value(true)
+             .value_name("file")
+             .conflicts_with_all(&["follow", "estimate", "quiet", "schema"])
+             .help("Write the aggregates of the query to this file instead of printing its results, for `riplog merge` to merge with those of other runs"))
+        .arg(Arg::with_name("ssh")
+             .long("ssh")
+             .takes_value(true)
+             .value_name("hosts")
+             .use_delimiter(true)
+             .conflicts_with_all(&["follow", "checkpoint", "progress", "sessionize", "tail-bytes"])
+             .help("Read the logs at the paths on these hosts over ssh, e.g. web1,web2, with a host column telling the results of each apart"))
+        .arg(Arg::with_name("sessionize")
+             .long("sessionize")
+             .takes_value(true)
+             .value_name("gap")
+             .conflicts_with("follow")
+             .validator(|v| parser::parse_duration_seconds(&v).map(|_| ()).ok_or("gap must be a duration such as 30s, 5m or 1h".to_owned()))
+             .help("Add session_id, session_start, session_duration and session_requests columns, a client's (ip and user agent) session ending after this long without requests. Every line is read before any is evaluated"))
+        .arg(Arg::with_name("threads")
+             .long("threads")
+             .takes_value(true)
+             .value_name("n")
+             .validator(|v| v.parse::<usize>().ok().filter(|t| *t > 0).map(|_| ()).ok_or("threads must be a positive number".to_owned()))
+             .help("Threads to use, decompressing files in the background while they are evaluated (number of cpus by default)"))
+        .arg(Arg::with_name("interval")
+             .long("interval")
+             .takes_value(true)
+             .default_value("10")
+             .validator(|v| v.parse::<u64>().map(|_| ()).map_err(|_| "interval must be a number of seconds".to_owned()))
+             .help("Seconds between rolling summaries of aggregate queries in follow mode, those with every printing each window as it ends"))
+        .get_matches_safe()
+        .unwrap_or_else(|e| exit_with_usage_error(e));
+
+    if let Some(index) = matches.subcommand_matches("index") {
+        return Options {
+            command: Command::Index,
+            paths: path_values(index, "path"),
+            include: values(index, "include"),
+            exclude: values(index, "exclude"),
+            ..Options::default()
+        };
+    }
+
+    if let Some(merge) = matches.subcommand_matches("merge") {
+        let output = OutputFormat::from_string(merge.value_of("output").unwrap()).unwrap();
+        check_human(merge, &output);
+        if let OutputFormat::Sqlite(ref path) = output {
+            if let Err(e) = sqlite::open_database(path) {
+                exit_with_usage_error(clap::Error::with_description(&e, clap::ErrorKind::InvalidValue));
+            }
+        }
+        return Options {
+            command: Command::Merge,
+            paths: path_values(merge, "partial"),
+            output: OutputOptions { format: output, precision: precision_value(merge), human: merge.is_present("human"), ..OutputOptions::default() },
+            stats: merge.is_present("stats"),
+            ..Options::default()
+        };
+    }
+
+    if let Some(hist) = matches.subcommand_matches("hist") {
+        let config = load_config(hist);
+        let interval = hist.value_of("interval").unwrap();
+        let log_format = hist.value_of("log-format").map(|f| f.to_owned());
+        let format = if log_format.is_some() { InputFormat::Custom } else { input_format(hist, &config) };
+        if format == InputFormat::Custom && log_format.is_none() {
+            exit_with_usage_error(clap::Error::with_description("--format custom requires --log-format", clap::ErrorKind::MissingRequiredArgument));
+        }
+        return Options {
+            command: Command::Hist,
+            query: histogram_query(hist.value_of("query").unwrap(), interval, hist.value_of("show").unwrap()),
+            paths: paths_or_log_dir(path_values(hist, "path"), &config),
+            include: values_or_config(hist, "include", &config.include),
+            exclude: values_or_config(hist, "exclude", &config.exclude),
+            format: format,
+            log_format: log_format,
+            output: OutputOptions { format: OutputFormat::Chart(parser::parse_duration_seconds(interval).unwrap()), ..OutputOptions::default() },
+            threads: config.threads.unwrap_or_else(default_threads),
+            prune: true,
+            ..Options::default()
+        };
+    }
+
+    let config = load_config(&matches);
+    let log_format = matches.value_of("log-format").map(|f| f.to_owned());
+    let format = if log_format.is_some() { InputFormat::Custom } else { input_format(&matches, &config) };
+    if format == InputFormat::Custom && log_format.is_none() {
+        exit_with_usage_error(clap::Error::with_description("--format custom requires --log-format", clap::ErrorKind::MissingRequiredArgument));
+    }
+    if matches.is_present("geoip") && format != InputFormat::Nginx {
+        exit_with_usage_error(clap::Error::with_description("--geoip is only supported by the nginx format", clap::ErrorKind::ArgumentConflict));
+    }
+    if matches.is_present("route-patterns") && format != InputFormat::Nginx && format != InputFormat::Apache {
+        exit_with_usage_error(clap::Error::with_description("--route-patterns is only supported by the nginx and apache formats", clap::ErrorKind::ArgumentConflict));
+    }
+    if (matches.is_present("columns") || matches.is_present("types")) && format != InputFormat::Csv && format != InputFormat::Tsv {
+        exit_with_usage_error(clap::Error::with_description("--columns and --types are only supported by the csv and tsv formats", clap::ErrorKind::ArgumentConflict));
+    }
+    // Quiet queries discard their results, so they never write a database or file
+    let quiet = matches.is_present("quiet");
+    let output = if quiet { OutputFormat::Table } else { OutputFormat::from_string(&option_or_config(&matches, "output", &config.output)).unwrap() };
+    if matches.is_present("ssh") && format.has_file_columns() {
+        exit_with_usage_error(clap::Error::with_description("--ssh isn't supported by the json, csv and tsv formats, whose columns are read from local files", clap::ErrorKind::ArgumentConflict));
+    }
+    if matches.is_present("nest") && output != OutputFormat::Json {
+        exit_with_usage_error(clap::Error::with_description("--nest is only supported with json output", clap::ErrorKind::ArgumentConflict));
+    }
+    check_human(&matches, &output);
+    if let OutputFormat::Sqlite(ref path) = output {
+        if let Err(e) = sqlite::open_database(path) {
+            exit_with_usage_error(clap::Error::with_description(&e, clap::ErrorKind::InvalidValue));
+        }
+    }
+    // Without a query argument, every positional argument is a path
+    let (query, paths) =
+        match (matches.value_of("query-file"), matches.value_of("saved")) {
+            _ if matches.is_present("schema") => (String::new(), path_values(&matches, "query").into_iter().chain(path_values(&matches, "path")).collect()),
+            (None, None) => (matches.value_of("query").unwrap().to_owned(), path_values(&matches, "path")),
+            (query_file, saved) => {
+                let query = query_file.map(config::read_query_file).unwrap_or_else(|| config::saved_query(saved.unwrap()));
+                let query = query.unwrap_or_else(|e| exit_with_usage_error(clap::Error::with_description(&e, clap::ErrorKind::InvalidValue)));
+                (query, path_values(&matches, "query").into_iter().chain(path_values(&matches, "path")).collect())
+            },
+        };
+    // Only formats whose columns are discovered from the files need paths to print a schema
+    let schema = matches.is_present("schema");
+    let paths = if schema && !format.has_file_columns() { paths } else { paths_or_log_dir(paths, &config) };
+    let objects = paths.iter().filter(|p| storage::is_object_url(p)).count();
+    if objects > 0 {
+        if format.has_file_columns() {
+            exit_with_usage_error(clap::Error::with_description("s3:// and gs:// urls aren't supported by the json, csv and tsv formats, whose columns are read from local files", clap::ErrorKind::ArgumentConflict));
+        }
+        let unsupported = ["follow", "checkpoint", "progress", "sessionize", "tail-bytes", "ssh"].iter().find(|o| matches.is_present(o));
+        if let Some(option) = unsupported {
+            exit_with_usage_error(clap::Error::with_description(&format!("--{} can't be used with s3:// and gs:// urls, whose objects are streamed", option), clap::ErrorKind::ArgumentConflict));
+        }
+    }
+    if matches.is_present("follow") && paths.len() > 1 {
+        exit_with_usage_error(clap::Error::with_description("--follow accepts a single file", clap::ErrorKind::TooManyValues));
+    }
+
+    Options {
+        command: if schema { Command::Schema } else { Command::Query },
+        query: query,
+        paths: paths,
+        include: values_or_config(&matches, "include", &config.include),
+        exclude: values_or_config(&matches, "exclude", &config.exclude),
+        format: format,
+        log_format: log_format,
+        geoip: values(&matches, "geoip"),
+        route_patterns: matches.value_of("route-patterns").map(|p| p.to_owned()),
+        columns: matches.value_of("columns").map(|c| c.to_owned()),
+        column_types: matches.value_of("types").map(|t| t.to_owned()),
+        output: OutputOptions { format: output,
+                                out: if quiet { None } else { matches.value_of("out").map(|o| o.to_owned()) },
+                                stream: matches.is_present("stream"),
+                                unbuffered: matches.is_present("unbuffered"),
+                                discard: quiet,
+                                null_text: matches.value_of("null").unwrap().to_owned(),
+                                pager: !matches.is_present("no-pager") && !matches.is_present("follow") && !matches.is_present("emit-partial"),
+                                nest: matches.is_present("nest"),
+                                precision: precision_value(&matches),
+                                human: matches.is_present("human") },
+        allow_missing_columns: matches.is_present("allow-missing-columns"),
+        estimate: matches.is_present("estimate"),
+        follow: matches.is_present("follow"),
+        progress: matches.is_present("progress"),
+        stats: matches.is_present("stats"),
+        quiet: quiet,
+        timing: matches.is_present("timing"),
+        meta: matches.is_present("meta"),
+        interval: matches.value_of("interval").unwrap().parse::<u64>().unwrap(),
+        threads: matches.value_of("threads").map(|t| t.parse::<usize>().unwrap()).or(config.threads).unwrap_or_else(default_threads),
+        prune: !matches.is_present("no-prune"),
+        order: FileOrder::from_string(matches.value_of("order").unwrap()).unwrap(),
+        sessionize: matches.value_of("sessionize").map(|g| parser::parse_duration_seconds(g).unwrap()),
+        window: matches.value_of("window").map(|w| parser::parse_duration_seconds(w).unwrap()),
+        alert: matches.value_of("alert").map(|condition| AlertOptions { condition: condition.to_owned(),
+                                                                        command: matches.value_of("alert-cmd").map(|c| c.to_owned()),
+                                                                        exit: matches.is_present("alert-exit") }),
+        container: matches.is_present("container"),
+        mask: matches.value_of("mask").map(|m| m.to_owned()),
+        mask_key: matches.value_of("mask-key").map(|k| k.to_owned()),
+        span: FileSpan { since_line: matches.value_of("since-line").map(|l| l.parse::<u64>().unwrap()),
+                         max_lines: matches.value_of("max-lines-per-file").map(|l| l.parse::<u64>().unwrap()),
+                         tail_bytes: matches.value_of("tail-bytes").map(|b| parse_size(b).unwrap()) },
+        sample: matches.value_of("sample").map(|r| Sampling::Rate(r.parse::<f64>().unwrap()))
+            .or_else(|| matches.value_of("sample-every").map(|n| Sampling::Every(n.parse::<u64>().unwrap()))),
+        checkpoint: matches.value_of_os("checkpoint").map(PathBuf::from),
+        ssh: values(&matches, "ssh").into_iter().map(|h| h.trim().to_owned()).filter(|h| !h.is_empty()).collect(),
+        emit_partial: matches.value_of_os("emit-partial").map(PathBuf::from),
+    }
+}
+
+// The chart is the filter grouped into time buckets of the interval, reduced to a single value.
+fn histogram_query(filter: &str, interval: &str, show: &str) -> String {
+    let grouping = format!("group time({}) | show {}", interval, show);
+    if filter.trim().is_empty() {
+        grouping
+    } else {
+        format!("{} | {}", filter.trim(), grouping)
+    }
+}
+
+fn load_config(matches: &ArgMatches) -> Config {
+    let config = config::load_config(matches.value_of("config"))
+        .unwrap_or_else(|e| exit_with_usage_error(clap::Error::with_description(&e, clap::ErrorKind::InvalidValue)));
+    if let Some(ref format) = config.format {
+        if InputFormat::from_string(format).is_none() {
+            exit_with_usage_error(clap::Error::with_description(&format!("Invalid format '{}' in config file, expected one of {}", format, FORMATS.join(", ")), clap::ErrorKind::InvalidValue));
+        }
+    }
+    if let Some(ref output) = config.output {
+        if OutputFormat::from_string(output).is_none() {
+            exit_with_usage_error(clap::Error::with_description(&format!("Invalid output '{}' in config file, expected table, json, ndjson or sqlite:<file>", output), clap::ErrorKind::InvalidValue));
+        }
+    }
+    config
+}
+
+fn input_format(matches: &ArgMatches, config: &Config) -> InputFormat {
+    InputFormat::from_string(&option_or_config(matches, "format", &config.format)).unwrap()
+}
+
+// Value of an option with a default, the config's value taking precedence over the default when the
+// option isn't given.
+fn option_or_config(matches: &ArgMatches, name: &str, config: &Option<String>) -> String {
+    match *config {
+        Some(ref value) if matches.occurrences_of(name) == 0 => value.to_owned(),
+        _ => matches.value_of(name).unwrap().to_owned(),
+    }
+}
+
+fn values_or_config(matches: &ArgMatches, name: &str, config: &Vec<String>) -> Vec<String> {
+    if matches.is_present(name) { values(matches, name) } else { config.clone() }
+}
+
+fn paths_or_log_dir(paths: Vec<PathBuf>, config: &Config) -> Vec<PathBuf> {
+    match config.log_dir {
+        Some(ref log_dir) if paths.is_empty() => vec![PathBuf::from(log_dir)],
+        _ if paths.is_empty() =>
+            exit_with_usage_error(clap::Error::with_description("At least one path is required, or a log_dir in the config file", clap::ErrorKind::MissingRequiredArgument)),
+        _ => paths,
+    }
+}
+
+// Usage errors exit with the same code as other errors, while help and version still exit with 0.
+fn exit_with_usage_error(error: clap::Error) -> ! {
+    if error.use_stderr() {
+        eprintln!("{}", error.message);
+        process::exit(EXIT_ERROR);
+    }
+    error.exit()
+}
+
+fn default_threads() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+fn values(matches: &ArgMatches, name: &str) -> Vec<String> {
+    matches.values_of(name).map(|v| v.map(|s| s.to_owned()).collect()).unwrap_or(Vec::new())
+}
+
+// Other outputs are read by programs, which are given numbers rather than text
+fn check_human(matches: &ArgMatches, output: &OutputFormat) {
+    if matches.is_present("human") && *output != OutputFormat::Table {
+        exit_with_usage_error(clap::Error::with_description("--human is only supported with table output", clap::ErrorKind::ArgumentConflict));
+    }
+}
+
+fn validate_precision(precision: String) -> Result<(), String> {
+    precision.parse::<usize>().ok().filter(|p| *p <= 17).map(|_| ()).ok_or("precision must be a number of decimals from 0 to 17".to_owned())
+}
+
+fn precision_value(matches: &ArgMatches) -> Option<usize> {
+    matches.value_of("precision").map(|p| p.parse::<usize>().unwrap())
+}
+
+// Paths are kept as given, as file names need not be valid UTF-8.
+fn path_values(matches: &ArgMatches, name: &str) -> Vec<PathBuf> {
+    matches.values_of_os(name).map(|v| v.map(PathBuf::from).collect()).unwrap_or(Vec::new())
+}
+
+// A number of bytes, optionally in K, M or G (powers of 1024), e.g. 50M.
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (number, unit) =
+        match size.chars().last().map(|c| c.to_ascii_uppercase()) {
+            Some('K') => (&size[0..size.len() - 1], 1 << 10),
+            Some('M') => (&size[0..size.len() - 1], 1 << 20),
+            Some('G') => (&size[0..size.len() - 1], 1 << 30),
+            _ => (size, 1),
+        };
+    number.parse::<u64>().ok().and_then(|n| n.checked_mul(unit))
+}
@@ -0,0 +1,148 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use toml::Value;
+
+const SETTINGS: &[&str] = &["format", "log_dir", "output", "include", "exclude", "threads"];
+
+// Defaults read from config.toml, used for options not given on the command line:
+//
+//     format = "nginx"
+//     log_dir = "/var/log/nginx"
+//     output = "table"
+//     include = ["*access*"]
+//     exclude = ["*debug*"]
+//     threads = 4
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub format: Option<String>,
+    // Scanned when a query is given no paths
+    pub log_dir: Option<String>,
+    pub output: Option<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub threads: Option<usize>,
+}
+
+// Reads the config file given with --config, or config.toml in the configuration directory when it
+// exists.
+pub fn load_config(path: Option<&str>) -> Result<Config, String> {
+    let path =
+        match path {
+            Some(path) => PathBuf::from(path),
+            None =>
+                match config_dir().map(|dir| dir.join("config.toml")) {
+                    Some(ref path) if path.exists() => path.to_owned(),
+                    _ => return Ok(Config::default()),
+                },
+        };
+    let contents = fs::read_to_string(&path).map_err(|e| format!("Unable to read config file {}: {}", path.display(), e))?;
+    parse_config(&contents).map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+}
+
+fn parse_config(contents: &str) -> Result<Config, String> {
+    let value = contents.parse::<Value>().map_err(|e| e.to_string())?;
+    let table = value.as_table().ok_or("expected a table of settings".to_owned())?;
+    if let Some(key) = table.keys().find(|k| !SETTINGS.contains(&k.as_str())) {
+        return Err(format!("unknown setting '{}', expected one of {}", key, SETTINGS.join(", ")));
+    }
+    Ok(Config {
+        format: string_setting(table.get("format"), "format")?,
+        log_dir: string_setting(table.get("log_dir"), "log_dir")?,
+        output: string_setting(table.get("output"), "output")?,
+        include: list_setting(table.get("include"), "include")?,
+        exclude: list_setting(table.get("exclude"), "exclude")?,
+        threads: count_setting(table.get("threads"), "threads")?,
+    })
+}
+
+fn string_setting(value: Option<&Value>, name: &str) -> Result<Option<String>, String> {
+    match value {
+        Some(Value::String(string)) => Ok(Some(string.to_owned())),
+        Some(_) => Err(format!("'{}' must be a string", name)),
+        None => Ok(None),
+    }
+}
+
+fn count_setting(value: Option<&Value>, name: &str) -> Result<Option<usize>, String> {
+    match value {
+        Some(Value::Integer(count)) if *count > 0 => Ok(Some(*count as usize)),
+        Some(_) => Err(format!("'{}' must be a positive number", name)),
+        None => Ok(None),
+    }
+}
+
+// Patterns are given as a list of strings, or a single string
+fn list_setting(value: Option<&Value>, name: &str) -> Result<Vec<String>, String> {
+    match value {
+        Some(Value::String(string)) => Ok(vec![string.to_owned()]),
+        Some(Value::Array(values)) =>
+            values.iter().map(|v| v.as_str().map(|s| s.to_owned()).ok_or(format!("'{}' must be a list of strings", name))).collect(),
+        Some(_) => Err(format!("'{}' must be a list of strings", name)),
+        None => Ok(Vec::new()),
+    }
+}
+
+// Directory of riplog's configuration files, ~/.config/riplog unless XDG_CONFIG_HOME is set.
+pub fn config_dir() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("riplog")),
+        _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config").join("riplog")),
+    }
+}
+
+// Reads a query from a file, which may span several lines and contain '#' comments.
+pub fn read_query_file(path: &str) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("Unable to read query file {}: {}", path, e))
+}
+
+// Saved queries that are available without a queries.toml, unless it has a query of the same name.
+const BUILTIN_QUERIES: &[(&str, &str)] = &[
+    // Routes whose upstreams are slowest for most requests, with the time nginx added on top
+    ("slow-upstreams", "upstream_time is not null | group route \
+                        | show count(*), p(upstream_time, 95) as upstream_p95, p(request_time, 95) as request_p95, avg(nginx_time) \
+                        | sort upstream_p95 desc | limit 10"),
+];
+
+// Looks up a query by name in queries.toml, whose entries are either a query string or a table
+// with a query key:
+//
+//     top-errors = "status >= 500 | group path | show count(*) | sort count(*) desc | limit 10"
+//
+//     [slow-routes]
+//     query = '''
+//     request_time > 1.0    # seconds
+//     | group route | show count(*), avg(request_time)
+//     '''
+//
+// Names queries.toml doesn't have, or all of them without one, are looked up in the built-in queries.
+pub fn saved_query(name: &str) -> Result<String, String> {
+    let builtin = BUILTIN_QUERIES.iter().find(|q| q.0 == name).map(|q| q.1.to_owned());
+    let path =
+        match config_dir() {
+            Some(dir) => dir.join("queries.toml"),
+            None => return builtin.ok_or("Unable to locate the configuration directory".to_owned()),
+        };
+    let contents =
+        match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) => return builtin.ok_or(format!("Unable to read saved queries from {}: {}", path.display(), e)),
+        };
+    let queries = contents.parse::<Value>().map_err(|e| format!("Invalid saved queries in {}: {}", path.display(), e))?;
+    match queries.get(name) {
+        Some(Value::String(query)) => Ok(query.to_owned()),
+        Some(Value::Table(table)) =>
+            match table.get("query") {
+                Some(Value::String(query)) => Ok(query.to_owned()),
+                _ => Err(format!("Saved query '{}' in {} has no query", name, path.display())),
+            },
+        Some(_) => Err(format!("Saved query '{}' in {} must be a string or a table with a query", name, path.display())),
+        None if builtin.is_some() => Ok(builtin.unwrap()),
+        None => {
+            let mut names: Vec<&str> = queries.as_table().map(|t| t.keys().map(|k| k.as_str()).collect()).unwrap_or(Vec::new());
+            names.extend(BUILTIN_QUERIES.iter().map(|q| q.0));
+            Err(format!("No saved query named '{}' in {} (available: {})", name, path.display(), names.join(", ")))
+        },
+    }
+}
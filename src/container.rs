@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+
+use chrono::prelude::*;
+use serde_json::{self, Value};
+
+use format::LogFormat;
+use table::{self, ColumnDefinition, TableDefinition, WrappedRecord};
+
+// Lines written by container runtimes wrap the line logged by the container, e.g. docker's json-file
+// driver writes {"log":"...\n","stream":"stdout","time":"2019-03-01T10:00:00.123456789Z"} and
+// kubernetes' cri runtimes `2019-03-01T10:00:00.123456789Z stdout F ...`. With --container, the line
+// wrapped is parsed by the log format, and the stream and time of the wrapper are added as columns.
+
+const STREAM_COLUMN: &str = "stream";
+const TIME_COLUMN: &str = "container_time";
+
+// A line unwrapped from its container runtime's wrapper.
+pub struct UnwrappedLine {
+    pub log: Vec<u8>,
+    pub stream: Option<String>,
+    // The time as written, and parsed
+    pub time: Option<(String, DateTime<Local>)>,
+    // Lines split by the runtime are written in parts, all but the last being partial
+    pub partial: bool,
+}
+
+// Unwraps a docker json line or a cri line, None for a line of neither.
+pub fn unwrap_line(line: &[u8]) -> Option<UnwrappedLine> {
+    let line = trim_line_end(line);
+    if line.first() == Some(&b'{') {
+        let wrapper: serde_json::Map<String, Value> = serde_json::from_slice(line).ok()?;
+        let log = wrapper.get("log")?.as_str()?;
+        let time = wrapper.get("time").and_then(|t| t.as_str()).and_then(parse_time);
+        let stream = wrapper.get("stream").and_then(|s| s.as_str()).map(|s| s.to_owned());
+        // Docker splits lines longer than 16k, all but the last part lacking the newline
+        let partial = !log.ends_with('\n');
+        Some(UnwrappedLine { log: trim_line_end(log.as_bytes()).to_vec(), stream: stream, time: time, partial: partial })
+    } else {
+        let mut fields = line.splitn(4, |b| *b == b' ');
+        let time = parse_time(::std::str::from_utf8(fields.next()?).ok()?)?;
+        let stream = String::from_utf8_lossy(fields.next()?).to_string();
+        let partial = match fields.next()? { b"P" => true, b"F" => false, _ => return None };
+        Some(UnwrappedLine { log: fields.next().unwrap_or(b"").to_vec(), stream: Some(stream), time: Some(time), partial: partial })
+    }
+}
+
+fn parse_time(time: &str) -> Option<(String, DateTime<Local>)> {
+    DateTime::parse_from_rfc3339(time).ok().map(|t| (time.to_owned(), t.with_timezone(&Local)))
+}
+
+fn trim_line_end(line: &[u8]) -> &[u8] {
+    let line = if line.last() == Some(&b'\n') { &line[..line.len() - 1] } else { line };
+    if line.last() == Some(&b'\r') { &line[..line.len() - 1] } else { line }
+}
+
+// Parses the lines wrapped by container runtimes with the format. Lines the runtime split in parts
+// are joined back before being parsed, the record holding the parts read so far.
+pub struct ContainerFormat<F: LogFormat> {
+    format: F,
+    columns: Rc<Vec<ColumnDefinition<F::Record>>>,
+    names: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct ContainerRecord<R> {
+    record: R,
+    columns: Rc<Vec<ColumnDefinition<R>>>,
+    stream: Option<String>,
+    time: Option<(String, DateTime<Local>)>,
+    parts: Vec<u8>,
+}
+
+impl<F: LogFormat> ContainerFormat<F> {
+    pub fn new(format: F) -> ContainerFormat<F> {
+        let mut definition = format.table_definition();
+        let names = definition.ordered_columns.clone();
+        let columns = names.iter().map(|n| definition.column_map.remove(n).unwrap()).collect();
+        ContainerFormat { format: format, columns: Rc::new(columns), names: names }
+    }
+}
+
+impl<R> WrappedRecord for ContainerRecord<R> {
+    type Inner = R;
+
+    fn inner(&self) -> &R {
+        &self.record
+    }
+
+    fn inner_mut(&mut self) -> &mut R {
+        &mut self.record
+    }
+
+    fn inner_columns(&self) -> &Vec<ColumnDefinition<R>> {
+        &self.columns
+    }
+}
+
+impl<F: LogFormat> LogFormat for ContainerFormat<F> {
+    type Record = ContainerRecord<F::Record>;
+
+    fn empty_record(&self) -> ContainerRecord<F::Record> {
+        ContainerRecord { record: self.format.empty_record(), columns: self.columns.clone(), stream: None, time: None, parts: Vec::new() }
+    }
+
+    // The leading parts of a split line aren't records, and are skipped as unparsed
+    fn read_record(&self, line: &[u8], record: &mut ContainerRecord<F::Record>) -> bool {
+        let unwrapped = match unwrap_line(line) {
+            Some(unwrapped) => unwrapped,
+            None => return false,
+        };
+        if unwrapped.partial {
+            record.parts.extend_from_slice(&unwrapped.log);
+            return false;
+        }
+        record.stream = unwrapped.stream;
+        record.time = unwrapped.time;
+        if record.parts.is_empty() {
+            self.format.read_record(&unwrapped.log, &mut record.record)
+        } else {
+            record.parts.extend_from_slice(&unwrapped.log);
+            let read = self.format.read_record(&record.parts, &mut record.record);
+            record.parts.clear();
+            read
+        }
+    }
+
+    fn project_record(&self, columns: &Vec<String>, record: &mut ContainerRecord<F::Record>) {
+        self.format.project_record(columns, &mut record.record);
+    }
+
+    fn table_definition(&self) -> TableDefinition<ContainerRecord<F::Record>> {
+        let mut column_map = HashMap::new();
+        let mut ordered_columns = Vec::new();
+        for (idx, column) in self.columns.iter().enumerate() {
+            column_map.insert(self.names[idx].to_owned(), table::wrap_column(column, idx));
+            ordered_columns.push(self.names[idx].to_owned());
+        }
+        for name in &[STREAM_COLUMN, TIME_COLUMN] {
+            if !column_map.contains_key(*name) {
+                ordered_columns.push(name.to_string());
+            }
+        }
+        column_map.entry(STREAM_COLUMN.to_owned())
+            .or_insert(ColumnDefinition::Text { name: STREAM_COLUMN.to_owned(), size: 6, field: 0, binary_extractor: stream_bytes, extractor: stream_text });
+        column_map.entry(TIME_COLUMN.to_owned())
+            .or_insert(ColumnDefinition::Date { name: TIME_COLUMN.to_owned(), size: 26, field: 0, binary_extractor: time_bytes, extractor: container_time });
+        TableDefinition { column_map: column_map, ordered_columns: ordered_columns }
+    }
+
+    fn has_header(&self) -> bool {
+        self.format.has_header()
+    }
+
+    // Wrapped lines are escaped as json, so their values can't be looked for in the lines as they are
+    fn verbatim_columns(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+
+    fn is_log_file(file: &Path) -> bool {
+        is_container_log_file(file)
+    }
+}
+
+// Logs of docker (e.g. <id>-json.log) and kubernetes (e.g. /var/log/pods/<pod>/<container>/0.log),
+// along with those they were rotated to.
+pub fn is_container_log_file(file: &Path) -> bool {
+    file.file_name().map(|n| n.to_string_lossy().contains(".log")).unwrap_or(false)
+}
+
+fn stream_bytes<R>(r: &ContainerRecord<R>, _: usize) -> Option<&[u8]> {
+    r.stream.as_ref().map(|s| s.as_bytes())
+}
+
+fn stream_text<R>(r: &mut ContainerRecord<R>, _: usize) -> Option<&str> {
+    r.stream.as_ref().map(|s| s.as_str())
+}
+
+fn time_bytes<R>(r: &ContainerRecord<R>, _: usize) -> Option<&[u8]> {
+    r.time.as_ref().map(|&(ref text, _)| text.as_bytes())
+}
+
+fn container_time<R>(r: &mut ContainerRecord<R>, _: usize) -> Option<&DateTime<Local>> {
+    r.time.as_ref().map(|&(_, ref time)| time)
+}
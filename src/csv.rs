@@ -0,0 +1,368 @@
+use std::rc::Rc;
+use std::path::Path;
+use std::net::IpAddr;
+use std::collections::HashMap;
+
+use chrono::prelude::*;
+use table::{TableDefinition, ColumnDefinition};
+use format::LogFormat;
+use input;
+use json;
+use nginx::empty_opt;
+use cidr;
+
+// Delimited text (csv or tsv) whose columns are named by a header row, or given explicitly when the
+// files have none. Column types are declared or inferred from a sample of rows.
+#[derive(Debug, Clone)]
+pub struct CsvFormat {
+    pub delimiter: u8,
+    pub header: bool,
+    pub columns: Vec<CsvColumn>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CsvColumn {
+    pub name: String,
+    pub kind: CsvColumnKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CsvColumnKind {
+    Integer,
+    Double,
+    Text,
+    Date,
+    Boolean,
+    // Never inferred, only given with --types
+    Ip,
+}
+
+impl CsvColumnKind {
+    pub fn from_string(kind: &str) -> Option<CsvColumnKind> {
+        match kind {
+            "int" | "integer" => Some(CsvColumnKind::Integer),
+            "double" | "float" => Some(CsvColumnKind::Double),
+            "text" | "string" => Some(CsvColumnKind::Text),
+            "date" => Some(CsvColumnKind::Date),
+            "bool" | "boolean" => Some(CsvColumnKind::Boolean),
+            "ip" => Some(CsvColumnKind::Ip),
+            _ => None,
+        }
+    }
+}
+
+impl CsvFormat {
+    // Builds the format from sample lines of the first file. Without explicit column names the first
+    // sample line is the header. Types given as `name:type,...` override the inferred ones.
+    pub fn new(delimiter: u8, columns: &Option<String>, types: &Option<String>, sample: &Vec<Vec<u8>>) -> Result<CsvFormat, String> {
+        let mut rows = Vec::new();
+        for line in sample {
+            let mut values = Vec::new();
+            split_fields(line, delimiter, &mut values);
+            rows.push(values);
+        }
+        let header = columns.is_none();
+        let names: Vec<String> =
+            match columns {
+                Some(columns) => columns.split(',').map(|c| c.trim().to_owned()).collect(),
+                None if rows.is_empty() => Vec::new(),
+                None => rows.remove(0).iter().map(|v| String::from_utf8_lossy(v).to_string()).collect(),
+            };
+
+        // Malformed rows are skipped when querying, so they don't count towards inference either
+        rows.retain(|r| r.len() == names.len());
+
+        let mut format = CsvFormat { delimiter: delimiter, header: header, columns: Vec::new() };
+        for (idx, name) in names.iter().enumerate() {
+            let name = json::column_name(name.trim());
+            if name.is_empty() {
+                return Err(format!("Column {} has no name", idx + 1));
+            }
+            if format.columns.iter().any(|c| c.name == name) {
+                return Err(format!("Column '{}' appears more than once", name));
+            }
+            let kind = infer_column_kind(rows.iter().filter_map(|r| r.get(idx)).filter(|v| !v.is_empty()));
+            format.columns.push(CsvColumn { name: name, kind: kind });
+        }
+
+        if let Some(types) = types {
+            for declaration in types.split(',') {
+                let mut parts = declaration.splitn(2, ':');
+                let name = parts.next().unwrap().trim();
+                let kind = parts.next().map(|k| k.trim()).unwrap_or("");
+                let kind = CsvColumnKind::from_string(kind)
+                    .ok_or(format!("Invalid type '{}' for column '{}', expected int, double, text, date or bool", kind, name))?;
+                match format.columns.iter_mut().find(|c| c.name == name) {
+                    Some(column) => column.kind = kind,
+                    None => return Err(format!("Type declared for unknown column '{}'", name)),
+                }
+            }
+        }
+        Ok(format)
+    }
+}
+
+impl LogFormat for CsvFormat {
+    type Record = BinaryCsvRecord;
+
+    fn empty_record(&self) -> BinaryCsvRecord {
+        BinaryCsvRecord::new(self)
+    }
+
+    fn read_record(&self, line: &[u8], record: &mut BinaryCsvRecord) -> bool {
+        read_log_record_binary(line, record)
+    }
+
+    fn table_definition(&self) -> TableDefinition<BinaryCsvRecord> {
+        create_csv_record_table_definition(&self.columns)
+    }
+
+    fn has_header(&self) -> bool {
+        self.header
+    }
+
+    fn is_log_file(file: &Path) -> bool {
+        is_csv_file(file) || is_tsv_file(file)
+    }
+}
+
+pub fn is_csv_file(file: &Path) -> bool {
+    let name = uncompressed_name(file);
+    name.ends_with(".csv")
+}
+
+pub fn is_tsv_file(file: &Path) -> bool {
+    let name = uncompressed_name(file);
+    name.ends_with(".tsv") || name.ends_with(".tab")
+}
+
+fn uncompressed_name(file: &Path) -> String {
+    let name = file.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or(String::new());
+    if input::is_compressed_name(file) { name.rsplitn(2, '.').last().unwrap_or("").to_owned() } else { name }
+}
+
+// The narrowest type every sampled value parses as, text when nothing was sampled.
+fn infer_column_kind<'a, I: Iterator<Item=&'a Vec<u8>>>(values: I) -> CsvColumnKind {
+    let mut kinds: Option<Vec<CsvColumnKind>> = None;
+    for value in values {
+        let text = String::from_utf8_lossy(value);
+        let candidates = kinds.unwrap_or(vec![CsvColumnKind::Integer, CsvColumnKind::Double, CsvColumnKind::Boolean, CsvColumnKind::Date]);
+        kinds = Some(candidates.into_iter().filter(|k| parses_as(*k, &text)).collect());
+    }
+    kinds.and_then(|k| k.first().cloned()).unwrap_or(CsvColumnKind::Text)
+}
+
+fn parses_as(kind: CsvColumnKind, value: &str) -> bool {
+    match kind {
+        CsvColumnKind::Integer => value.parse::<u64>().is_ok(),
+        CsvColumnKind::Double => value.parse::<f64>().is_ok(),
+        CsvColumnKind::Boolean => value.parse::<bool>().is_ok(),
+        CsvColumnKind::Date => parse_csv_date(value).is_some(),
+        CsvColumnKind::Ip => cidr::parse_address(value.as_bytes()).is_some(),
+        CsvColumnKind::Text => true,
+    }
+}
+
+fn parse_csv_date(value: &str) -> Option<DateTime<Local>> {
+    DateTime::parse_from_rfc3339(value)
+        .or_else(|_| DateTime::parse_from_str(value, "%d/%b/%Y:%H:%M:%S %z"))
+        .map(|d| d.with_timezone(&Local))
+        .ok()
+        .or_else(|| {
+            NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").ok()
+                .and_then(|d| Local.from_local_datetime(&d).earliest())
+        })
+}
+
+// Rows with a different number of fields than there are columns are skipped.
+pub fn read_log_record_binary(line: &[u8], record: &mut BinaryCsvRecord) -> bool {
+    let format = record.format.clone();
+    let count = split_fields(line, format.delimiter, &mut record.values);
+    for value in &mut record.parsed_text {
+        *value = None;
+    }
+    for value in &mut record.parsed_date {
+        *value = None;
+    }
+    for value in &mut record.parsed_address {
+        *value = None;
+    }
+    count == format.columns.len()
+}
+
+// Splits a line into the values of its fields, reusing the value buffers, and returns the number
+// of fields. Comma separated values may be quoted with doubled quotes escaping a quote, tab
+// separated values are taken as is.
+fn split_fields(line: &[u8], delimiter: u8, values: &mut Vec<Vec<u8>>) -> usize {
+    let line = trim_line_ending(line);
+    let quoting = delimiter != b'\t';
+    let mut count = 0;
+    let mut idx = 0;
+
+    if line.is_empty() {
+        return 0;
+    }
+    loop {
+        if values.len() <= count {
+            values.push(Vec::new());
+        }
+        let value = &mut values[count];
+        value.clear();
+        count += 1;
+
+        if quoting && line.get(idx) == Some(&b'"') {
+            idx += 1;
+            while idx < line.len() {
+                if line[idx] == b'"' {
+                    if line.get(idx + 1) == Some(&b'"') {
+                        value.push(b'"');
+                        idx += 2;
+                        continue;
+                    }
+                    idx += 1;
+                    break;
+                }
+                value.push(line[idx]);
+                idx += 1;
+            }
+        }
+        while idx < line.len() && line[idx] != delimiter {
+            value.push(line[idx]);
+            idx += 1;
+        }
+        if idx >= line.len() {
+            break;
+        }
+        idx += 1;
+    }
+    for value in values.iter_mut().skip(count) {
+        value.clear();
+    }
+    count
+}
+
+fn trim_line_ending(working: &[u8]) -> &[u8] {
+    let mut len = working.len();
+    while len > 0 && (working[len-1] == b'\n' || working[len-1] == b'\r') {
+        len -= 1;
+    }
+    &working[0..len]
+}
+
+#[derive(Debug, Clone)]
+pub struct BinaryCsvRecord {
+    format: Rc<CsvFormat>,
+    values: Vec<Vec<u8>>,
+    parsed_text: Vec<Option<String>>,
+    parsed_date: Vec<Option<Option<DateTime<Local>>>>,
+    parsed_address: Vec<Option<Option<IpAddr>>>,
+}
+
+impl BinaryCsvRecord {
+    pub fn new(format: &CsvFormat) -> BinaryCsvRecord {
+        let len = format.columns.len();
+        BinaryCsvRecord {
+            format: Rc::new(format.clone()),
+            values: vec![Vec::new(); len],
+            parsed_text: vec![None; len],
+            parsed_date: vec![None; len],
+            parsed_address: vec![None; len],
+        }
+    }
+
+    pub fn value_bytes(&self, idx: usize) -> Option<&[u8]> {
+        empty_opt(&self.values[idx])
+    }
+
+    pub fn parsed_text(&mut self, idx: usize) -> Option<&str> {
+        if self.values[idx].is_empty() {
+            return None;
+        }
+        if self.parsed_text[idx].is_none() {
+            self.parsed_text[idx] = Some(String::from_utf8_lossy(&self.values[idx]).to_string());
+        }
+        self.parsed_text[idx].as_ref().map(|s| s.as_str())
+    }
+
+    pub fn parsed_integer(&mut self, idx: usize) -> Option<u64> {
+        self.parsed_text(idx).and_then(|v| v.parse::<u64>().ok())
+    }
+
+    pub fn parsed_double(&mut self, idx: usize) -> Option<f64> {
+        self.parsed_text(idx).and_then(|v| v.parse::<f64>().ok())
+    }
+
+    pub fn parsed_boolean(&mut self, idx: usize) -> Option<bool> {
+        self.parsed_text(idx).and_then(|v| v.parse::<bool>().ok())
+    }
+
+    pub fn parsed_date(&mut self, idx: usize) -> Option<&DateTime<Local>> {
+        if self.parsed_date[idx].is_none() {
+            let date = self.parsed_text(idx).and_then(|v| parse_csv_date(v));
+            self.parsed_date[idx] = Some(date);
+        }
+        self.parsed_date[idx].as_ref().unwrap().as_ref()
+    }
+
+    pub fn parsed_address(&mut self, idx: usize) -> Option<IpAddr> {
+        if self.parsed_address[idx].is_none() {
+            self.parsed_address[idx] = Some(cidr::parse_address(&self.values[idx]));
+        }
+        self.parsed_address[idx].unwrap()
+    }
+}
+
+pub fn create_csv_record_table_definition(columns: &Vec<CsvColumn>) -> TableDefinition<BinaryCsvRecord> {
+    let mut column_map = HashMap::new();
+    let mut ordering = Vec::new();
+
+    for (idx, column) in columns.iter().enumerate() {
+        let name = column.name.to_owned();
+        let definition = match column.kind {
+            CsvColumnKind::Integer =>
+                ColumnDefinition::Integer { name: name,
+                                            size: 10,
+                                            field: idx,
+                                            binary_extractor: |r: &BinaryCsvRecord, idx| r.value_bytes(idx),
+                                            extractor: |r: &mut BinaryCsvRecord, idx| r.parsed_integer(idx) },
+            CsvColumnKind::Double =>
+                ColumnDefinition::Double { name: name,
+                                           size: 10,
+                                           field: idx,
+                                           binary_extractor: |r: &BinaryCsvRecord, idx| r.value_bytes(idx),
+                                           extractor: |r: &mut BinaryCsvRecord, idx| r.parsed_double(idx) },
+            CsvColumnKind::Boolean =>
+                ColumnDefinition::Boolean { name: name,
+                                            size: 5,
+                                            field: idx,
+                                            binary_extractor: |r: &BinaryCsvRecord, idx| r.value_bytes(idx),
+                                            extractor: |r: &mut BinaryCsvRecord, idx| r.parsed_boolean(idx) },
+            CsvColumnKind::Date =>
+                ColumnDefinition::Date { name: name,
+                                         size: 26,
+                                         field: idx,
+                                         binary_extractor: |r: &BinaryCsvRecord, idx| r.value_bytes(idx),
+                                         extractor: |r: &mut BinaryCsvRecord, idx| r.parsed_date(idx) },
+            CsvColumnKind::Text =>
+                ColumnDefinition::Text { name: name,
+                                         size: 20,
+                                         field: idx,
+                                         binary_extractor: |r: &BinaryCsvRecord, idx| r.value_bytes(idx),
+                                         extractor: |r: &mut BinaryCsvRecord, idx| r.parsed_text(idx) },
+            CsvColumnKind::Ip =>
+                ColumnDefinition::Ip { name: name,
+                                       size: 15,
+                                       field: idx,
+                                       binary_extractor: |r: &BinaryCsvRecord, idx| r.value_bytes(idx),
+                                       extractor: |r: &mut BinaryCsvRecord, idx| r.parsed_text(idx),
+                                       address_extractor: |r: &mut BinaryCsvRecord, idx| r.parsed_address(idx) },
+        };
+        ordering.push(definition.name().to_owned());
+        column_map.insert(definition.name().to_owned(), definition);
+    }
+
+    TableDefinition {
+        column_map: column_map,
+        ordered_columns: ordering,
+    }
+}
@@ -0,0 +1,381 @@
+use std::rc::Rc;
+use std::path::Path;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::prelude::*;
+use nginx::{self, index_of, empty_opt, DerivedTime};
+use route::RouteNormalizer;
+use table::{TableDefinition, ColumnDefinition};
+use format::LogFormat;
+use discovery;
+
+pub fn read_log_record_binary(line: &[u8], record: &mut BinaryCustomLogRecord) -> bool {
+    let format = record.format.clone();
+    let mut working = trim_line_ending(line);
+
+    for value in &mut record.values {
+        value.clear();
+    }
+    for value in &mut record.parsed_text {
+        *value = None;
+    }
+    for value in &mut record.parsed_date {
+        *value = None;
+    }
+    record.route = None;
+    record.nginx_time.clear();
+
+    for (idx, token) in format.tokens.iter().enumerate() {
+        match token {
+            FormatToken::Literal(literal) => {
+                if !working.starts_with(literal) {
+                    // Malformed line, the record is skipped
+                    return false;
+                }
+                working = &working[literal.len()..];
+            },
+            FormatToken::Variable(variable, column) => {
+                let end =
+                    match format.tokens.get(idx+1) {
+                        Some(FormatToken::Literal(literal)) => find_subsequence(working, literal).unwrap_or(working.len()),
+                        _ => working.len(),
+                    };
+                let value = &working[0..end];
+                working = &working[end..];
+                if value == b"-" {
+                    continue;
+                }
+                if variable == "request" {
+                    let (method, path, query) = nginx::split_request(value);
+                    record.values[*column] = method.to_vec();
+                    record.values[*column+1] = path.to_vec();
+                    record.values[*column+2] = query.to_vec();
+                } else {
+                    record.values[*column] = value.to_vec();
+                }
+            },
+        }
+    }
+    true
+}
+
+fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    let mut offset = 0;
+    while offset < haystack.len() {
+        let idx = index_of(&haystack[offset..], needle[0])? + offset;
+        if haystack[idx..].starts_with(needle) {
+            return Some(idx);
+        }
+        offset = idx + 1;
+    }
+    None
+}
+
+fn trim_line_ending(working: &[u8]) -> &[u8] {
+    let mut len = working.len();
+    while len > 0 && (working[len-1] == b'\n' || working[len-1] == b'\r') {
+        len -= 1;
+    }
+    &working[0..len]
+}
+
+#[derive(Debug, Clone)]
+enum FormatToken {
+    Literal(Vec<u8>),
+    // Variable name and the index of the first column it populates
+    Variable(String, usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct CustomColumn {
+    pub name: String,
+    pub kind: CustomColumnKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CustomColumnKind {
+    Integer,
+    Double,
+    // Upstream times, summed over the upstreams tried, e.g. "0.050, 0.070"
+    UpstreamTime,
+    Text,
+    LocalDate,
+    IsoDate,
+    // Computed from the path, as the nginx format's route
+    Route,
+    // Computed from request_time and upstream_time, as the nginx format's nginx_time
+    NginxTime,
+}
+
+// A compiled nginx log_format directive, e.g.
+// '$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent'
+#[derive(Debug, Clone)]
+pub struct CustomFormat {
+    tokens: Vec<FormatToken>,
+    pub columns: Vec<CustomColumn>,
+    pub routes: Arc<RouteNormalizer>,
+    // Columns of the path, request time and upstream time that computed columns are read from
+    path: Option<usize>,
+    request_time: Option<usize>,
+    upstream_time: Option<usize>,
+}
+
+impl CustomFormat {
+    pub fn parse(format: &str) -> Result<CustomFormat, String> {
+        let mut tokens = Vec::new();
+        let mut columns = Vec::new();
+        let mut literal = String::new();
+        let mut chars = format.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            if c != '$' {
+                literal.push(c);
+                continue;
+            }
+            let braced = chars.peek() == Some(&'{');
+            if braced {
+                chars.next();
+            }
+            let mut variable = String::new();
+            while chars.peek().map(|c| c.is_alphanumeric() || *c == '_').unwrap_or(false) {
+                variable.push(chars.next().unwrap());
+            }
+            if braced && chars.next() != Some('}') {
+                return Err(format!("Unterminated variable '${{{}' in log format", variable));
+            }
+            if variable.is_empty() {
+                literal.push('$');
+                continue;
+            }
+            if !literal.is_empty() {
+                tokens.push(FormatToken::Literal(literal.into_bytes()));
+                literal = String::new();
+            } else if let Some(FormatToken::Variable(previous, _)) = tokens.last() {
+                return Err(format!("Variables '${}' and '${}' must be separated in log format", previous, variable));
+            }
+            tokens.push(FormatToken::Variable(variable.clone(), columns.len()));
+            for column in variable_columns(&variable) {
+                if columns.iter().any(|c: &CustomColumn| c.name == column.name) {
+                    return Err(format!("Column '{}' appears more than once in log format", column.name));
+                }
+                columns.push(column);
+            }
+        }
+        if !literal.is_empty() {
+            tokens.push(FormatToken::Literal(literal.into_bytes()));
+        }
+
+        // Computed columns follow those of the variables, as they do in the nginx format
+        let position = |columns: &Vec<CustomColumn>, name: &str| columns.iter().position(|c| c.name == name);
+        let (path, request_time, upstream_time) = (position(&columns, "path"), position(&columns, "request_time"), position(&columns, "upstream_time"));
+        if path.is_some() && position(&columns, "route").is_none() {
+            columns.push(CustomColumn { name: "route".to_owned(), kind: CustomColumnKind::Route });
+        }
+        if request_time.is_some() && upstream_time.is_some() && position(&columns, "nginx_time").is_none() {
+            columns.push(CustomColumn { name: "nginx_time".to_owned(), kind: CustomColumnKind::NginxTime });
+        }
+        Ok(CustomFormat { tokens, columns, routes: Arc::new(RouteNormalizer::default()), path, request_time, upstream_time })
+    }
+}
+
+impl LogFormat for CustomFormat {
+    type Record = BinaryCustomLogRecord;
+
+    fn empty_record(&self) -> BinaryCustomLogRecord {
+        BinaryCustomLogRecord::new(self)
+    }
+
+    fn read_record(&self, line: &[u8], record: &mut BinaryCustomLogRecord) -> bool {
+        read_log_record_binary(line, record)
+    }
+
+    fn table_definition(&self) -> TableDefinition<BinaryCustomLogRecord> {
+        create_custom_log_record_table_definition(self)
+    }
+
+    fn is_log_file(file: &Path) -> bool {
+        discovery::is_access_log(file)
+    }
+}
+
+// Standard variables are exposed under the same column names as the combined nginx format so that
+// queries work unchanged against custom formats.
+fn variable_columns(variable: &str) -> Vec<CustomColumn> {
+    let column = |name: &str, kind: CustomColumnKind| CustomColumn { name: name.to_owned(), kind: kind };
+    match variable {
+        "remote_addr" => vec![column("ip", CustomColumnKind::Text)],
+        "remote_user" => vec![column("username", CustomColumnKind::Text)],
+        "time_local" => vec![column("date", CustomColumnKind::LocalDate)],
+        "time_iso8601" => vec![column("date", CustomColumnKind::IsoDate)],
+        "request" => vec![column("method", CustomColumnKind::Text),
+                          column("path", CustomColumnKind::Text),
+                          column("query", CustomColumnKind::Text)],
+        "body_bytes_sent" => vec![column("bytes", CustomColumnKind::Integer)],
+        "upstream_response_time" => vec![column("upstream_time", CustomColumnKind::UpstreamTime)],
+        "upstream_connect_time" | "upstream_header_time" => vec![column(variable, CustomColumnKind::UpstreamTime)],
+        "http_referer" => vec![column("referrer", CustomColumnKind::Text)],
+        "http_user_agent" => vec![column("user_agent", CustomColumnKind::Text)],
+        "status" | "bytes_sent" | "request_length" | "connection" | "connection_requests" | "upstream_status" | "server_port" =>
+            vec![column(variable, CustomColumnKind::Integer)],
+        "request_time" | "msec" =>
+            vec![column(variable, CustomColumnKind::Double)],
+        _ => vec![column(variable, CustomColumnKind::Text)],
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BinaryCustomLogRecord {
+    format: Rc<CustomFormat>,
+    values: Vec<Vec<u8>>,
+    parsed_text: Vec<Option<String>>,
+    parsed_date: Vec<Option<Option<DateTime<Local>>>>,
+    route: Option<String>,
+    nginx_time: DerivedTime,
+}
+
+impl BinaryCustomLogRecord {
+    pub fn new(format: &CustomFormat) -> BinaryCustomLogRecord {
+        let len = format.columns.len();
+        BinaryCustomLogRecord {
+            format: Rc::new(format.clone()),
+            values: vec![Vec::new(); len],
+            parsed_text: vec![None; len],
+            parsed_date: vec![None; len],
+            route: None,
+            nginx_time: DerivedTime::default(),
+        }
+    }
+
+    pub fn value_bytes(&self, idx: usize) -> Option<&[u8]> {
+        empty_opt(&self.values[idx])
+    }
+
+    pub fn parsed_text(&mut self, idx: usize) -> Option<&str> {
+        if self.values[idx].is_empty() {
+            return None;
+        }
+        if self.parsed_text[idx].is_none() {
+            self.parsed_text[idx] = Some(String::from_utf8_lossy(&self.values[idx]).to_string());
+        }
+        self.parsed_text[idx].as_ref().map(|s| s.as_str())
+    }
+
+    pub fn parsed_integer(&mut self, idx: usize) -> Option<u64> {
+        self.parsed_text(idx).and_then(|v| v.parse::<u64>().ok())
+    }
+
+    pub fn parsed_double(&mut self, idx: usize) -> Option<f64> {
+        self.parsed_text(idx).and_then(|v| v.parse::<f64>().ok())
+    }
+
+    pub fn parsed_upstream_time(&mut self, idx: usize) -> Option<f64> {
+        self.parsed_text(idx).and_then(nginx::parse_upstream_time)
+    }
+
+    pub fn parsed_route(&mut self) -> Option<&str> {
+        if self.route.is_none() {
+            let format = self.format.clone();
+            let path = format.path.and_then(|idx| self.parsed_text(idx))?;
+            self.route = Some(format.routes.normalize(path));
+        }
+        self.route.as_ref().map(|r| r.as_str())
+    }
+
+    // Raw bytes of the route, only available once it has been computed from the path.
+    pub fn route_bytes(&self) -> Option<&[u8]> {
+        self.route.as_ref().map(|r| r.as_bytes())
+    }
+
+    pub fn parsed_nginx_time(&mut self) -> Option<f64> {
+        if !self.nginx_time.is_computed() {
+            let format = self.format.clone();
+            let request_time = format.request_time.and_then(|idx| self.parsed_double(idx));
+            let upstream_time = format.upstream_time.and_then(|idx| self.parsed_upstream_time(idx));
+            self.nginx_time.set(nginx::nginx_time(request_time, upstream_time));
+        }
+        self.nginx_time.value()
+    }
+
+    pub fn nginx_time_bytes(&self) -> Option<&[u8]> {
+        self.nginx_time.bytes()
+    }
+
+    pub fn parsed_date(&mut self, idx: usize) -> Option<&DateTime<Local>> {
+        if self.parsed_date[idx].is_none() {
+            let iso = self.format.columns[idx].kind == CustomColumnKind::IsoDate;
+            let date = self.parsed_text(idx).and_then(|v| {
+                if iso {
+                    DateTime::parse_from_rfc3339(v).ok()
+                } else {
+                    DateTime::parse_from_str(v, "%d/%b/%Y:%H:%M:%S %z").ok()
+                }
+            });
+            self.parsed_date[idx] = Some(date.map(|d| d.with_timezone(&Local)));
+        }
+        self.parsed_date[idx].as_ref().unwrap().as_ref()
+    }
+}
+
+pub fn create_custom_log_record_table_definition(format: &CustomFormat) -> TableDefinition<BinaryCustomLogRecord> {
+    let mut column_map = HashMap::new();
+    let mut ordering = Vec::new();
+
+    for (idx, column) in format.columns.iter().enumerate() {
+        let name = column.name.to_owned();
+        let definition = match column.kind {
+            CustomColumnKind::Integer =>
+                ColumnDefinition::Integer { name: name,
+                                            size: 10,
+                                            field: idx,
+                                            binary_extractor: |r: &BinaryCustomLogRecord, idx| r.value_bytes(idx),
+                                            extractor: |r: &mut BinaryCustomLogRecord, idx| r.parsed_integer(idx) },
+            CustomColumnKind::Double =>
+                ColumnDefinition::Double { name: name,
+                                           size: 10,
+                                           field: idx,
+                                           binary_extractor: |r: &BinaryCustomLogRecord, idx| r.value_bytes(idx),
+                                           extractor: |r: &mut BinaryCustomLogRecord, idx| r.parsed_double(idx) },
+            CustomColumnKind::UpstreamTime =>
+                ColumnDefinition::Double { name: name,
+                                           size: 10,
+                                           field: idx,
+                                           binary_extractor: |r: &BinaryCustomLogRecord, idx| r.value_bytes(idx),
+                                           extractor: |r: &mut BinaryCustomLogRecord, idx| r.parsed_upstream_time(idx) },
+            CustomColumnKind::NginxTime =>
+                ColumnDefinition::Double { name: name,
+                                           size: 10,
+                                           field: idx,
+                                           binary_extractor: |r: &BinaryCustomLogRecord, _| r.nginx_time_bytes(),
+                                           extractor: |r: &mut BinaryCustomLogRecord, _| r.parsed_nginx_time() },
+            CustomColumnKind::Route =>
+                ColumnDefinition::Text { name: name,
+                                         size: 20,
+                                         field: idx,
+                                         binary_extractor: |r: &BinaryCustomLogRecord, _| r.route_bytes(),
+                                         extractor: |r: &mut BinaryCustomLogRecord, _| r.parsed_route() },
+            CustomColumnKind::LocalDate | CustomColumnKind::IsoDate =>
+                ColumnDefinition::Date { name: name,
+                                         size: 26,
+                                         field: idx,
+                                         binary_extractor: |r: &BinaryCustomLogRecord, idx| r.value_bytes(idx),
+                                         extractor: |r: &mut BinaryCustomLogRecord, idx| r.parsed_date(idx) },
+            CustomColumnKind::Text =>
+                ColumnDefinition::Text { name: name,
+                                         size: 20,
+                                         field: idx,
+                                         binary_extractor: |r: &BinaryCustomLogRecord, idx| r.value_bytes(idx),
+                                         extractor: |r: &mut BinaryCustomLogRecord, idx| r.parsed_text(idx) },
+        };
+        ordering.push(definition.name().to_owned());
+        column_map.insert(definition.name().to_owned(), definition);
+    }
+
+    TableDefinition {
+        column_map: column_map,
+        ordered_columns: ordering,
+    }
+}
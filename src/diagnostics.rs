@@ -0,0 +1,30 @@
+// Non-fatal issues of a run, e.g. parts of a query that have no effect, aggregates that aren't exact or
+// files that weren't read. They are collected as the query is parsed, validated and evaluated, and
+// reported once results are written, rather than being dropped silently.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    warnings: Vec<String>,
+    // Warnings already taken to be reported
+    taken: usize,
+}
+
+impl Diagnostics {
+    pub fn new() -> Diagnostics {
+        Diagnostics::default()
+    }
+
+    // Each warning is kept once, however often it's raised, e.g. by every summary of a followed log.
+    pub fn warn<S: Into<String>>(&mut self, warning: S) {
+        let warning = warning.into();
+        if !self.warnings.contains(&warning) {
+            self.warnings.push(warning);
+        }
+    }
+
+    // Warnings raised since warnings were last taken.
+    pub fn take(&mut self) -> Vec<String> {
+        let warnings = self.warnings[self.taken..].to_vec();
+        self.taken = self.warnings.len();
+        warnings
+    }
+}
@@ -0,0 +1,340 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::cmp::Reverse;
+use std::time::SystemTime;
+
+use glob::{self, Pattern};
+use input;
+use index::INDEX_DIR;
+
+// Decides which files found while walking directories are scanned. Include and exclude patterns are
+// globs matched against the file name; without any include patterns the format's default filter
+// (e.g. access.log naming for nginx) is used instead.
+pub struct FileSelector {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    default_filter: fn(&Path) -> bool,
+}
+
+impl FileSelector {
+    pub fn new(include: &Vec<String>, exclude: &Vec<String>, default_filter: fn(&Path) -> bool) -> Result<FileSelector, DiscoveryError> {
+        Ok(FileSelector {
+            include: compile_patterns(include)?,
+            exclude: compile_patterns(exclude)?,
+            default_filter: default_filter,
+        })
+    }
+
+    pub fn is_excluded(&self, file: &Path) -> bool {
+        let name = file_name(file);
+        self.exclude.iter().any(|p| p.matches(&name))
+    }
+
+    pub fn is_selected(&self, file: &Path) -> bool {
+        if self.is_excluded(file) {
+            false
+        } else if !self.include.is_empty() {
+            let name = file_name(file);
+            self.include.iter().any(|p| p.matches(&name))
+        } else {
+            (self.default_filter)(file)
+        }
+    }
+}
+
+fn compile_patterns(patterns: &Vec<String>) -> Result<Vec<Pattern>, DiscoveryError> {
+    patterns.iter()
+        .map(|p| Pattern::new(p).map_err(|e| DiscoveryError { msg: format!("Invalid pattern '{}': {}", p, e) }))
+        .collect()
+}
+
+// Names that aren't valid UTF-8 are matched with the invalid bytes replaced, so that e.g. `*.log`
+// still selects them.
+fn file_name(file: &Path) -> String {
+    file.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or(String::new())
+}
+
+// Default selection for formats with nginx style log names: access logs and rotated compressed
+// logs, leaving out those the nginx-error format reads.
+pub fn is_access_log(file: &Path) -> bool {
+    let name = file_name(file);
+    name.contains("access.log") || name.contains("access_log") || (input::is_compressed_name(file) && !is_error_log(file))
+}
+
+// Default selection for the nginx-error format: error.log and its rotations.
+pub fn is_error_log(file: &Path) -> bool {
+    let name = file_name(file);
+    name.contains("error.log") || name.contains("error_log")
+}
+
+// Default selection for the syslog format: the files syslog daemons conventionally write, including
+// their rotations.
+pub fn is_syslog(file: &Path) -> bool {
+    let name = file_name(file);
+    ["syslog", "messages", "auth.log", "kern.log", "daemon.log", "user.log", "mail.log", "maillog", "secure", "cron"]
+        .iter().any(|prefix| name.starts_with(prefix))
+}
+
+// Order in which files are scanned, so that a limit without a sort keeps the earliest or latest
+// lines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileOrder {
+    Oldest,
+    Newest,
+}
+
+impl FileOrder {
+    pub fn from_string(order: &str) -> Option<FileOrder> {
+        match order {
+            "oldest" => Some(FileOrder::Oldest),
+            "newest" => Some(FileOrder::Newest),
+            _ => None,
+        }
+    }
+}
+
+// Files are ordered by modification time, and files modified at the same time by their rotation
+// index, access.log.2.gz being older than access.log.1 and access.log.
+pub fn sort_files(files: &mut Vec<PathBuf>, order: FileOrder) {
+    sort_files_by(files, order, |f| fs::metadata(f).and_then(|m| m.modified()).ok());
+}
+
+// Files not found on disk, e.g. objects of a bucket, are ordered by the modification times they were
+// listed with, and those whose time isn't known, e.g. files of other hosts, by rotation index alone.
+pub fn sort_files_by<M: Fn(&Path) -> Option<SystemTime>>(files: &mut Vec<PathBuf>, order: FileOrder, modified: M) {
+    let mut keyed: Vec<(Option<SystemTime>, Reverse<u64>, PathBuf)> = files.drain(..)
+        .map(|f| (modified(&f), Reverse(rotation_index(&f)), f))
+        .collect();
+    keyed.sort();
+    if order == FileOrder::Newest {
+        keyed.reverse();
+    }
+    files.extend(keyed.into_iter().map(|(_, _, f)| f));
+}
+
+fn rotation_index(file: &Path) -> u64 {
+    let name = file_name(file);
+    let name = if input::is_compressed_name(file) { name.rsplitn(2, '.').last().unwrap_or("").to_owned() } else { name };
+    name.rsplit('.').next().and_then(|suffix| suffix.parse::<u64>().ok()).unwrap_or(0)
+}
+
+#[derive(Debug, Default)]
+pub struct DiscoveredFiles {
+    pub files: Vec<PathBuf>,
+    pub skipped: usize,
+}
+
+// Expands the paths given on the command line into the list of files to scan. Files named
+// directly or matched by a glob are always scanned unless excluded, while files found by walking
+// directories must also be selected.
+pub fn discover_files(paths: &Vec<PathBuf>, selector: &FileSelector) -> Result<DiscoveredFiles, DiscoveryError> {
+    let mut discovered = DiscoveredFiles::default();
+    for path in paths {
+        if let Some(pattern) = glob_pattern(path) {
+            let matches = glob::glob(&pattern).map_err(|e| DiscoveryError { msg: format!("Invalid pattern '{}': {}", pattern, e) })?;
+            let mut found = false;
+            for entry in matches {
+                let entry = entry.map_err(|e| read_error(e.path(), e.error()))?;
+                add_path(&entry, selector, &mut discovered)?;
+                found = true;
+            }
+            if !found {
+                return Err(DiscoveryError { msg: format!("No files match '{}'", pattern) });
+            }
+        } else {
+            if !path.exists() {
+                return Err(DiscoveryError { msg: format!("No such file or directory '{}'", path.display()) });
+            }
+            add_path(path, selector, &mut discovered)?;
+        }
+    }
+    Ok(discovered)
+}
+
+// Paths that aren't valid UTF-8 can't be patterns, so they are always taken as they are. Patterns
+// may use either separator on Windows.
+fn glob_pattern(path: &Path) -> Option<String> {
+    path.to_str().filter(|p| p.contains(|c| c == '*' || c == '?' || c == '[')).map(|p| normalize_separators(p, MAIN_SEPARATOR))
+}
+
+// Separators other than slashes, i.e. backslashes on Windows, become slashes, which glob takes on
+// every platform. Backslashes are left alone elsewhere, as they may be part of names.
+fn normalize_separators(pattern: &str, separator: char) -> String {
+    if separator == '/' { pattern.to_owned() } else { pattern.replace(separator, "/") }
+}
+
+fn add_path(path: &Path, selector: &FileSelector, discovered: &mut DiscoveredFiles) -> Result<(), DiscoveryError> {
+    if path.is_dir() {
+        add_dir(path, selector, discovered)?;
+    } else if selector.is_excluded(path) {
+        discovered.skipped += 1;
+    } else {
+        discovered.files.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+fn add_dir(dir: &Path, selector: &FileSelector, discovered: &mut DiscoveredFiles) -> Result<(), DiscoveryError> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|e| read_error(dir, &e))? {
+        entries.push(entry.map_err(|e| read_error(dir, &e))?.path());
+    }
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            if path.file_name().map(|n| n != INDEX_DIR).unwrap_or(true) {
+                add_dir(&path, selector, discovered)?;
+            }
+        } else if selector.is_selected(&path) {
+            discovered.files.push(path);
+        } else {
+            discovered.skipped += 1;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct DiscoveryError { msg: String }
+
+fn read_error(path: &Path, error: &io::Error) -> DiscoveryError {
+    DiscoveryError { msg: format!("Unable to read '{}': {}", path.display(), error) }
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::File;
+
+    fn create_dir(name: &str, files: &[&str]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("riplog-discovery-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for file in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path).unwrap();
+        }
+        dir
+    }
+
+    fn access_log_filter(file: &Path) -> bool {
+        file_name(file).contains("access.log")
+    }
+
+    fn names(discovered: &DiscoveredFiles, dir: &Path) -> Vec<String> {
+        discovered.files.iter().map(|f| f.strip_prefix(dir).unwrap().to_string_lossy().to_string()).collect()
+    }
+
+    fn selector(include: &[&str], exclude: &[&str]) -> FileSelector {
+        let include = include.iter().map(|s| s.to_string()).collect();
+        let exclude = exclude.iter().map(|s| s.to_string()).collect();
+        FileSelector::new(&include, &exclude, access_log_filter).unwrap()
+    }
+
+    #[test]
+    fn directories_use_default_filter() {
+        let dir = create_dir("default", &["access.log", "access.log.1.gz", "error.log", "nested/access.log"]);
+        let discovered = discover_files(&vec![dir.clone()], &selector(&[], &[])).unwrap();
+        assert_eq!(names(&discovered, &dir), vec!["access.log", "access.log.1.gz", "nested/access.log"]);
+        assert_eq!(discovered.skipped, 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn error_logs_are_left_to_the_error_format() {
+        assert!(!is_access_log(Path::new("error.log.1.gz")));
+        assert!(is_access_log(Path::new("access.log.1.gz")));
+        assert!(is_access_log(Path::new("site.log.gz")));
+        assert!(is_error_log(Path::new("error.log.1.gz")));
+        assert!(!is_error_log(Path::new("access.log")));
+    }
+
+    #[test]
+    fn rotated_files_are_ordered_by_age() {
+        let dir = create_dir("order", &["access.log.10", "access.log.2.gz", "access.log.1", "access.log"]);
+        let mut files = discover_files(&vec![dir.clone()], &selector(&[], &[])).unwrap().files;
+        sort_files(&mut files, FileOrder::Oldest);
+        assert_eq!(files.iter().map(|f| file_name(f)).collect::<Vec<String>>(), vec!["access.log.10", "access.log.2.gz", "access.log.1", "access.log"]);
+        sort_files(&mut files, FileOrder::Newest);
+        assert_eq!(files.iter().map(|f| file_name(f)).collect::<Vec<String>>(), vec!["access.log", "access.log.1", "access.log.2.gz", "access.log.10"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn include_replaces_default_filter() {
+        let dir = create_dir("include", &["access.log", "app.log", "app.log.1"]);
+        let discovered = discover_files(&vec![dir.clone()], &selector(&["app.log*"], &[])).unwrap();
+        assert_eq!(names(&discovered, &dir), vec!["app.log", "app.log.1"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn exclude_applies_to_all_paths() {
+        let dir = create_dir("exclude", &["access.log", "access.log.1", "access.log.2.gz"]);
+        let paths = vec![dir.clone(), dir.join("access.log.2.gz")];
+        let discovered = discover_files(&paths, &selector(&[], &["*.gz"])).unwrap();
+        assert_eq!(names(&discovered, &dir), vec!["access.log", "access.log.1"]);
+        assert_eq!(discovered.skipped, 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn globs_select_matching_files() {
+        let dir = create_dir("glob", &["access.log", "access.log.1", "other.log"]);
+        let pattern = dir.join("access.log*");
+        let discovered = discover_files(&vec![pattern], &selector(&[], &[])).unwrap();
+        assert_eq!(names(&discovered, &dir), vec!["access.log", "access.log.1"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn patterns_may_use_backslashes_on_windows() {
+        let dir = create_dir("separators", &["logs/access.log", "logs/access.log.1", "logs/other.log"]);
+        let pattern = normalize_separators(&format!("{}\\logs\\access.log*", dir.display()), '\\');
+        let discovered = discover_files(&vec![PathBuf::from(pattern)], &selector(&[], &[])).unwrap();
+        assert_eq!(names(&discovered, &dir), vec!["logs/access.log", "logs/access.log.1"]);
+        assert_eq!(normalize_separators("logs\\*.log", '/'), "logs\\*.log");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn explicit_files_bypass_default_filter() {
+        let dir = create_dir("explicit", &["custom.log"]);
+        let discovered = discover_files(&vec![dir.join("custom.log")], &selector(&[], &[])).unwrap();
+        assert_eq!(names(&discovered, &dir), vec!["custom.log"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_paths_are_errors() {
+        let dir = create_dir("missing", &[]);
+        assert!(discover_files(&vec![dir.join("nope.log")], &selector(&[], &[])).is_err());
+        assert!(discover_files(&vec![dir.join("nope*")], &selector(&[], &[])).is_err());
+        assert!(FileSelector::new(&vec!["[".to_owned()], &vec![], access_log_filter).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn names_need_not_be_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let dir = create_dir("utf8", &[]);
+        fs::create_dir_all(dir.join(OsStr::from_bytes(b"logs-\xff"))).unwrap();
+        File::create(dir.join(OsStr::from_bytes(b"logs-\xff/access.log.\xfe"))).unwrap();
+        File::create(dir.join(OsStr::from_bytes(b"logs-\xff/other-\xfe.log"))).unwrap();
+        let discovered = discover_files(&vec![dir.clone()], &selector(&[], &[])).unwrap();
+        assert_eq!(names(&discovered, &dir), vec!["logs-\u{fffd}/access.log.\u{fffd}"]);
+        let discovered = discover_files(&vec![dir.join(OsStr::from_bytes(b"logs-\xff"))], &selector(&["*.log"], &[])).unwrap();
+        assert_eq!(names(&discovered, &dir), vec!["logs-\u{fffd}/other-\u{fffd}.log"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::io::{self, BufRead, Seek, SeekFrom};
+use std::time::{Duration, Instant};
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use riplog::format::LogFormat;
+use riplog::input::{self, Compression};
+
+const CALIBRATION_BYTES: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug, Default)]
+struct ScanEstimate {
+    files: u64,
+    skipped_files: u64,
+    compressed_bytes: u64,
+    uncompressed_bytes: u64,
+}
+
+// Estimates the scan of the files left once pruned, the others being counted as skipped, timing the
+// format's own parser over the start of the first file. Streamed files, of other hosts or object
+// storage, are scanned too but left out of the sizes and time, which only cover local files.
+pub fn print_estimate<F: LogFormat>(files: &Vec<PathBuf>, streamed: usize, skipped: usize, format: &F) -> io::Result<()> {
+    let mut estimate = ScanEstimate::default();
+    estimate.skipped_files = skipped as u64;
+    for file in files {
+        estimate_file(file, &mut estimate)?;
+    }
+
+    println!("Files to scan:      {}", estimate.files + streamed as u64);
+    if streamed > 0 {
+        println!("Streamed files:     {} (not included in the sizes and time below)", streamed);
+    }
+    println!("Files skipped:      {}", estimate.skipped_files);
+    println!("Compressed size:    {}", estimate.compressed_bytes);
+    println!("Uncompressed size:  {}", estimate.uncompressed_bytes);
+
+    if let Some(file) = files.first() {
+        let (bytes, elapsed) = calibrate(file, format)?;
+        let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+        if bytes > 0 && seconds > 0.0 {
+            let rate = bytes as f64 / seconds;
+            println!("Throughput:         {:.0} bytes/sec (sampled {} bytes)", rate, bytes);
+            println!("Estimated time:     {:.1}s", estimate.uncompressed_bytes as f64 / rate);
+        }
+    }
+    Ok(())
+}
+
+fn estimate_file(path: &Path, estimate: &mut ScanEstimate) -> io::Result<()> {
+    let compressed = fs::metadata(path)?.len();
+    estimate.files += 1;
+    estimate.compressed_bytes += compressed;
+    // Only gzip records its uncompressed size, other formats are counted as is
+    estimate.uncompressed_bytes +=
+        match input::detect_compression(path)? {
+            Compression::Gzip => read_gzip_isize(path).unwrap_or(compressed),
+            _ => compressed,
+        };
+    Ok(())
+}
+
+// The gzip trailer stores the uncompressed size modulo 2^32 in its last four bytes (ISIZE).
+fn read_gzip_isize(path: &Path) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::End(-4))?;
+    Ok(file.read_u32::<LittleEndian>()? as u64)
+}
+
+fn calibrate<F: LogFormat>(path: &Path, format: &F) -> io::Result<(u64, Duration)> {
+    let mut reader = input::open_input(path)?;
+    let mut buf = vec![];
+    let mut record = format.empty_record();
+    let mut bytes = 0;
+    let start = Instant::now();
+
+    while bytes < CALIBRATION_BYTES {
+        buf.clear();
+        let size = reader.read_until(b'\n', &mut buf)?;
+        if size <= 0 {
+            break;
+        }
+        format.read_record(&buf[0..size], &mut record);
+        bytes += size as u64;
+    }
+    Ok((bytes, Instant::now() - start))
+}
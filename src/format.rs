@@ -0,0 +1,50 @@
+use std::path::Path;
+
+use table::TableDefinition;
+
+// A source of log records: how lines are parsed into records and which columns those records
+// expose to queries. The evaluation loop is written once against this trait.
+pub trait LogFormat {
+    type Record: Clone;
+
+    fn empty_record(&self) -> Self::Record;
+
+    // Parses a line into the record, returning false when the line is malformed and is skipped.
+    fn read_record(&self, line: &[u8], record: &mut Self::Record) -> bool;
+
+    fn table_definition(&self) -> TableDefinition<Self::Record>;
+
+    // Whether the first line of every file is a header rather than a record.
+    fn has_header(&self) -> bool {
+        false
+    }
+
+    // Formats supporting the columnar index expose the raw bytes of each field of a record, and
+    // rebuild records from those fields instead of parsing lines when an index is present.
+    fn index_fields<'a>(&self, record: &'a Self::Record) -> Option<Vec<&'a [u8]>> {
+        let _ = record;
+        None
+    }
+
+    fn read_indexed_record(&self, fields: &Vec<&[u8]>, record: &mut Self::Record) {
+        let _ = (fields, record);
+    }
+
+    // Columns whose values are copied from the line as is, so that lines not containing a value
+    // compared with can be skipped before they are parsed.
+    fn verbatim_columns(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+
+    // Formats able to skip fields leave those no column given depends on out of the lines later read
+    // into the record, the columns being those the query reads.
+    fn project_record(&self, columns: &Vec<String>, record: &mut Self::Record) {
+        let _ = (columns, record);
+    }
+
+    // Whether a file found while walking a directory holds logs of this format.
+    fn is_log_file(file: &Path) -> bool where Self: Sized {
+        let _ = file;
+        true
+    }
+}
@@ -0,0 +1,67 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+
+use maxminddb::{geoip2, Reader};
+
+// Resolves ip addresses against one or more MaxMind databases. City (or Country) and ASN data
+// are distributed as separate databases, so each lookup consults every database given.
+pub struct GeoIp {
+    readers: Vec<Reader<Vec<u8>>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GeoInfo {
+    pub country: Option<String>,
+    pub city: Option<String>,
+    pub asn: Option<String>,
+}
+
+impl GeoIp {
+    pub fn open(paths: &Vec<String>) -> Result<GeoIp, GeoIpError> {
+        let mut readers = Vec::new();
+        for path in paths {
+            let reader = Reader::open_readfile(Path::new(path))
+                .map_err(|e| GeoIpError { msg: format!("Unable to open GeoIP database '{}': {}", path, e) })?;
+            readers.push(reader);
+        }
+        Ok(GeoIp { readers: readers })
+    }
+
+    pub fn lookup(&self, ip: &str) -> GeoInfo {
+        let mut info = GeoInfo::default();
+        let address = match ip.parse::<IpAddr>() {
+            Ok(address) => address,
+            Err(_) => return info,
+        };
+        for reader in &self.readers {
+            if reader.metadata.database_type.contains("ASN") {
+                if let Ok(asn) = reader.lookup::<geoip2::Asn>(address) {
+                    info.asn = info.asn.or(asn.autonomous_system_number.map(|n| n.to_string()));
+                }
+            } else if let Ok(city) = reader.lookup::<geoip2::City>(address) {
+                let country = city.country.and_then(|c| c.iso_code).map(|c| c.to_owned());
+                let name = city.city.and_then(|c| c.names).and_then(|n| n.get("en").map(|n| n.to_string()));
+                info.country = info.country.or(country);
+                info.city = info.city.or(name);
+            }
+        }
+        info
+    }
+}
+
+impl fmt::Debug for GeoIp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let types: Vec<&str> = self.readers.iter().map(|r| r.metadata.database_type.as_str()).collect();
+        write!(f, "GeoIp {:?}", types)
+    }
+}
+
+#[derive(Debug)]
+pub struct GeoIpError { msg: String }
+
+impl fmt::Display for GeoIpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
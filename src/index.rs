@@ -0,0 +1,221 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use chrono::prelude::*;
+
+use format::LogFormat;
+use input;
+use query::{self, DateRange};
+use table::ColumnDefinition;
+
+// Indexes are kept next to the logs they describe, in a directory skipped by file discovery.
+pub const INDEX_DIR: &str = ".riplog";
+
+const MAGIC: &[u8] = b"RLIX";
+const VERSION: u32 = 1;
+
+// Layout of an index file (little endian):
+//
+//   magic, version (u32), source length (u64), source mtime (i64), records (u64),
+//   has dates (u8), min date (i64), max date (i64), fields (u32)
+//
+// followed for each field by records + 1 offsets (u32) and the length (u64) and bytes of the
+// concatenated values of that field.
+pub struct FileIndex {
+    path: PathBuf,
+    records: usize,
+    fields: usize,
+    dates: Option<(DateTime<Local>, DateTime<Local>)>,
+}
+
+pub struct IndexedRecords {
+    data: Vec<u8>,
+    records: usize,
+    columns: Vec<IndexColumn>,
+}
+
+struct IndexColumn {
+    offsets: Vec<u32>,
+    start: usize,
+}
+
+pub fn index_path(file: &Path) -> PathBuf {
+    let mut name = file.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(".idx");
+    file.parent().unwrap_or(Path::new(".")).join(INDEX_DIR).join(name)
+}
+
+// Identifies the contents of a log file, so indexes of logs that have since changed are ignored.
+fn source_stamp(file: &Path) -> io::Result<(u64, i64)> {
+    let metadata = fs::metadata(file)?;
+    let mtime = metadata.modified()?.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    Ok((metadata.len(), mtime))
+}
+
+impl FileIndex {
+    // Reads the header of a log file's index, None when there is no index or it is out of date.
+    pub fn open(file: &Path) -> io::Result<Option<FileIndex>> {
+        let path = index_path(file);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let mut reader = BufReader::new(File::open(&path)?);
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC || reader.read_u32::<LittleEndian>()? != VERSION {
+            return Ok(None);
+        }
+        let stamp = (reader.read_u64::<LittleEndian>()?, reader.read_i64::<LittleEndian>()?);
+        if stamp != source_stamp(file)? {
+            return Ok(None);
+        }
+        let records = reader.read_u64::<LittleEndian>()? as usize;
+        let has_dates = reader.read_u8()? == 1;
+        let min = reader.read_i64::<LittleEndian>()?;
+        let max = reader.read_i64::<LittleEndian>()?;
+        let fields = reader.read_u32::<LittleEndian>()? as usize;
+        Ok(Some(FileIndex {
+            path: path,
+            records: records,
+            fields: fields,
+            dates: if has_dates { Some((Local.timestamp(min, 0), Local.timestamp(max, 0))) } else { None },
+        }))
+    }
+
+    pub fn fields(&self) -> usize {
+        self.fields
+    }
+
+    // Whether any record of the file can fall within the date range of a query.
+    pub fn may_match(&self, range: &DateRange) -> bool {
+        match self.dates {
+            Some((ref min, ref max)) => range.overlaps(min, max),
+            None => true,
+        }
+    }
+
+    pub fn load(&self) -> io::Result<IndexedRecords> {
+        let mut data = Vec::new();
+        File::open(&self.path)?.read_to_end(&mut data)?;
+        let mut columns = Vec::with_capacity(self.fields);
+        let mut position = MAGIC.len() + 4 + 8 + 8 + 8 + 1 + 8 + 8 + 4;
+        for _ in 0..self.fields {
+            let mut reader = &data[position..];
+            let mut offsets = Vec::with_capacity(self.records + 1);
+            for _ in 0..self.records + 1 {
+                offsets.push(reader.read_u32::<LittleEndian>()?);
+            }
+            let len = reader.read_u64::<LittleEndian>()? as usize;
+            let start = position + (self.records + 1) * 4 + 8;
+            if start + len > data.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Truncated index {}", self.path.display())));
+            }
+            columns.push(IndexColumn { offsets: offsets, start: start });
+            position = start + len;
+        }
+        Ok(IndexedRecords { data: data, records: self.records, columns: columns })
+    }
+}
+
+impl IndexedRecords {
+    pub fn records(&self) -> usize {
+        self.records
+    }
+
+    pub fn read_fields<'a>(&'a self, record: usize, fields: &mut Vec<&'a [u8]>) {
+        fields.clear();
+        for column in &self.columns {
+            let start = column.start + column.offsets[record] as usize;
+            let end = column.start + column.offsets[record + 1] as usize;
+            fields.push(&self.data[start..end]);
+        }
+    }
+}
+
+#[derive(Default)]
+struct ColumnBuilder {
+    offsets: Vec<u32>,
+    data: Vec<u8>,
+}
+
+impl ColumnBuilder {
+    fn push(&mut self, value: &[u8]) -> io::Result<()> {
+        if self.offsets.is_empty() {
+            self.offsets.push(0);
+        }
+        if self.data.len() + value.len() > u32::max_value() as usize {
+            return Err(io::Error::new(io::ErrorKind::Other, "Log file too large to index"));
+        }
+        self.data.extend_from_slice(value);
+        self.offsets.push(self.data.len() as u32);
+        Ok(())
+    }
+}
+
+// Scans a log file and writes its index, returning the number of records indexed.
+pub fn build_index<F: LogFormat>(file: &Path, format: &F) -> io::Result<u64> {
+    let definition = format.table_definition();
+    let date_column = query::find_date_column(&definition).and_then(|c| definition.column_map.get(&c));
+    let stamp = source_stamp(file)?;
+    let mut reader = input::open_input(file)?;
+    let mut buf = vec![];
+    let mut record = format.empty_record();
+    let mut columns: Vec<ColumnBuilder> = Vec::new();
+    let mut records = 0;
+    let mut dates: Option<(i64, i64)> = None;
+
+    loop {
+        buf.clear();
+        let size = reader.read_until(b'\n', &mut buf)?;
+        if size == 0 {
+            break;
+        }
+        if !format.read_record(&buf[0..size], &mut record) {
+            continue;
+        }
+        if let Some(fields) = format.index_fields(&record) {
+            if columns.is_empty() {
+                columns = fields.iter().map(|_| ColumnBuilder::default()).collect();
+            }
+            for (column, field) in columns.iter_mut().zip(fields) {
+                column.push(field)?;
+            }
+        }
+        if let Some(ColumnDefinition::Date { extractor, field, .. }) = date_column {
+            if let Some(date) = extractor(&mut record, *field) {
+                let timestamp = date.timestamp();
+                dates = Some(dates.map(|(min, max)| (min.min(timestamp), max.max(timestamp))).unwrap_or((timestamp, timestamp)));
+            }
+        }
+        records += 1;
+    }
+
+    let path = index_path(file);
+    fs::create_dir_all(path.parent().unwrap())?;
+    let tmp_path = path.with_extension("idx.tmp");
+    {
+        let mut out = BufWriter::new(File::create(&tmp_path)?);
+        out.write_all(MAGIC)?;
+        out.write_u32::<LittleEndian>(VERSION)?;
+        out.write_u64::<LittleEndian>(stamp.0)?;
+        out.write_i64::<LittleEndian>(stamp.1)?;
+        out.write_u64::<LittleEndian>(records)?;
+        out.write_u8(if dates.is_some() { 1 } else { 0 })?;
+        out.write_i64::<LittleEndian>(dates.map(|d| d.0).unwrap_or(0))?;
+        out.write_i64::<LittleEndian>(dates.map(|d| d.1).unwrap_or(0))?;
+        out.write_u32::<LittleEndian>(columns.len() as u32)?;
+        for column in &columns {
+            for offset in &column.offsets {
+                out.write_u32::<LittleEndian>(*offset)?;
+            }
+            out.write_u64::<LittleEndian>(column.data.len() as u64)?;
+            out.write_all(&column.data)?;
+        }
+        out.flush()?;
+    }
+    fs::rename(&tmp_path, &path)?;
+    Ok(records)
+}
@@ -0,0 +1,392 @@
+use std::cell::Cell;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use bzip2::read::MultiBzDecoder;
+use memchr::{memchr, memchr_iter};
+use memmap2::Mmap;
+use flate2::read::MultiGzDecoder;
+use xz2::read::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+const CHUNK_SIZE: usize = 1 << 20;
+const CHUNKS_IN_FLIGHT: usize = 4;
+// Logs modified more recently than this may still be written to, and truncated while they're read
+const ACTIVE_PERIOD: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Plain,
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+}
+
+const MAGIC_BYTES: &[(&[u8], Compression)] = &[
+    (&[0x1f, 0x8b], Compression::Gzip),
+    (&[0x28, 0xb5, 0x2f, 0xfd], Compression::Zstd),
+    (b"BZh", Compression::Bzip2),
+    (&[0xfd, b'7', b'z', b'X', b'Z', 0x00], Compression::Xz),
+];
+
+pub fn compression_from_name(file: &Path) -> Option<Compression> {
+    match file.extension().and_then(|e| e.to_str()) {
+        Some("gz") => Some(Compression::Gzip),
+        Some("zst") => Some(Compression::Zstd),
+        Some("bz2") => Some(Compression::Bzip2),
+        Some("xz") => Some(Compression::Xz),
+        _ => None,
+    }
+}
+
+pub fn is_compressed_name(file: &Path) -> bool {
+    compression_from_name(file).is_some()
+}
+
+// Files are identified by extension, falling back to their leading magic bytes so rotated logs
+// without a conventional suffix are still decompressed.
+pub fn detect_compression(file: &Path) -> io::Result<Compression> {
+    if let Some(compression) = compression_from_name(file) {
+        return Ok(compression);
+    }
+    let mut header = [0; 6];
+    let mut len = 0;
+    let mut reader = File::open(file)?;
+    while len < header.len() {
+        let read = reader.read(&mut header[len..])?;
+        if read == 0 {
+            break;
+        }
+        len += read;
+    }
+    Ok(magic_compression(&header[0..len]))
+}
+
+fn magic_compression(header: &[u8]) -> Compression {
+    MAGIC_BYTES.iter()
+        .find(|(magic, _)| header.starts_with(magic))
+        .map(|(_, compression)| *compression)
+        .unwrap_or(Compression::Plain)
+}
+
+pub fn open_input(file: &Path) -> io::Result<Box<BufRead>> {
+    open_counted_input(file).map(|(reader, _)| reader)
+}
+
+// Counts the bytes read from a file before they are decompressed, giving the position reached in
+// the file on disk.
+struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count.set(self.count.get() + read as u64);
+        Ok(read)
+    }
+}
+
+// Ends the stream of a compressed file that is truncated or corrupt, e.g. one whose last member a
+// log shipper cut short, instead of failing, so that the lines decompressed before are still read.
+// Errors reading the file itself, which come from the OS, are still returned.
+struct TolerantReader<R> {
+    inner: R,
+    file: PathBuf,
+    ended: bool,
+}
+
+impl<R: Read> Read for TolerantReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.ended {
+            return Ok(0);
+        }
+        match self.inner.read(buf) {
+            Err(ref e) if e.raw_os_error().is_none() && e.kind() != io::ErrorKind::Interrupted => {
+                eprintln!("Warning: {} is truncated or corrupt ({}), only the lines before are read", self.file.display(), e);
+                self.ended = true;
+                Ok(0)
+            },
+            result => result,
+        }
+    }
+}
+
+// Opens a log for reading along with a counter of the bytes consumed from the file so far, used to
+// report progress through compressed files. Compressed files made of several members or streams,
+// e.g. rotated logs concatenated with cat, are read through to the end.
+pub fn open_counted_input(file: &Path) -> io::Result<(Box<BufRead>, Rc<Cell<u64>>)> {
+    open_counted_input_from(file, 0)
+}
+
+// Opens a log for reading from a position in the file on disk, which for compressed files has to be
+// the start of a member or stream, e.g. one appended since the file was last read up to there.
+pub fn open_counted_input_from(file: &Path, position: u64) -> io::Result<(Box<BufRead>, Rc<Cell<u64>>)> {
+    let compression = detect_compression(file)?;
+    let count = Rc::new(Cell::new(position));
+    let mut inner = File::open(file)?;
+    inner.seek(SeekFrom::Start(position))?;
+    let reader = CountingReader { inner: inner, count: count.clone() };
+    Ok((decompress(reader, compression, file)?, count))
+}
+
+// Reads a log from a stream, e.g. the output of a command, decompressed as the name of the file it
+// holds tells, or else as its leading magic bytes do. The counter is of the bytes of the stream.
+pub fn open_stream_input<R: Read + 'static>(name: &Path, stream: R) -> io::Result<(Box<BufRead>, Rc<Cell<u64>>)> {
+    let count = Rc::new(Cell::new(0));
+    let mut reader = BufReader::new(CountingReader { inner: stream, count: count.clone() });
+    let compression =
+        match compression_from_name(name) {
+            Some(compression) => compression,
+            None => magic_compression(reader.fill_buf()?),
+        };
+    Ok((decompress(reader, compression, name)?, count))
+}
+
+// Reads a log from the output of a command, e.g. one streaming a file of another host. The program is
+// named as such in errors, e.g. "ssh to web1".
+pub fn open_command_input(mut command: Command, program: &str, name: &Path) -> io::Result<(Box<BufRead>, Rc<Cell<u64>>)> {
+    let mut child = command.stdin(Stdio::null()).stdout(Stdio::piped()).spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("unable to run {}: {}", program, e)))?;
+    let stdout = child.stdout.take().unwrap();
+    open_stream_input(name, CommandStream { child: child, stdout: stdout, program: program.to_owned() })
+}
+
+// The output of a command, whose exit status is checked once it ends, so that a file that couldn't be
+// read is an error rather than an empty file. The process is stopped if the stream is dropped before
+// then, e.g. when a limit is reached.
+struct CommandStream {
+    child: Child,
+    stdout: ChildStdout,
+    program: String,
+}
+
+impl Read for CommandStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stdout.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::new(io::ErrorKind::Other, format!("{} exited with {}", self.program, status)));
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl Drop for CommandStream {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+fn decompress<R: Read + 'static>(reader: R, compression: Compression, file: &Path) -> io::Result<Box<BufRead>> {
+    let tolerant = |inner: Box<Read>| BufReader::new(TolerantReader { inner: inner, file: file.to_owned(), ended: false });
+    Ok(match compression {
+        Compression::Plain => Box::new(BufReader::new(reader)),
+        Compression::Gzip => Box::new(tolerant(Box::new(MultiGzDecoder::new(reader)))),
+        Compression::Zstd => Box::new(tolerant(Box::new(ZstdDecoder::new(reader)?))),
+        Compression::Bzip2 => Box::new(tolerant(Box::new(MultiBzDecoder::new(reader)))),
+        Compression::Xz => Box::new(tolerant(Box::new(XzDecoder::new_multi_decoder(reader)))),
+    })
+}
+
+// Maps uncompressed logs into memory so lines can be parsed in place without being copied into a
+// read buffer. Compressed and empty files return None and are read as a stream instead, as are logs
+// still being written to: reading a map of a file truncated in place, as logrotate's copytruncate
+// does to the live log, kills the process (SIGBUS), where a stream just ends early.
+pub fn map_input(file: &Path) -> io::Result<Option<Mmap>> {
+    if detect_compression(file)? != Compression::Plain {
+        return Ok(None);
+    }
+    let reader = File::open(file)?;
+    let metadata = reader.metadata()?;
+    if metadata.len() == 0 || is_active(&metadata) {
+        return Ok(None);
+    }
+    unsafe { Mmap::map(&reader).map(Some) }
+}
+
+// Whether a log was modified recently enough to still be written to, which is assumed of logs whose
+// modification time is unknown or ahead of the clock.
+pub fn is_active(metadata: &fs::Metadata) -> bool {
+    metadata.modified().ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .map(|age| age < ACTIVE_PERIOD)
+        .unwrap_or(true)
+}
+
+// Whole lines of a file decompressed ahead of the parser, along with the position reached in the file
+// on disk.
+pub struct InputChunk {
+    pub data: Vec<u8>,
+    pub position: u64,
+}
+
+// Decompresses a file on a thread of its own, handing lines over in chunks of about 1MB so that
+// decompressing and parsing use separate cores. At most a few chunks are held ahead of the parser.
+pub struct BackgroundInput {
+    chunks: Receiver<io::Result<InputChunk>>,
+}
+
+pub fn open_background_input(file: &Path, position: u64) -> BackgroundInput {
+    let file = file.to_owned();
+    open_background(move || open_counted_input_from(&file, position))
+}
+
+// Reads an input, opened on the thread it's read on, e.g. a log streamed from another host.
+pub fn open_background<O>(open: O) -> BackgroundInput where O: FnOnce() -> io::Result<(Box<BufRead>, Rc<Cell<u64>>)> + Send + 'static {
+    let (sender, receiver) = mpsc::sync_channel(CHUNKS_IN_FLIGHT);
+    thread::spawn(move || {
+        if let Err(e) = open().and_then(|(reader, count)| send_chunks(reader, count, &sender)) {
+            let _ = sender.send(Err(e));
+        }
+    });
+    BackgroundInput { chunks: receiver }
+}
+
+// Stops early without an error once the receiving end is dropped, e.g. when a limit is reached.
+fn send_chunks(mut reader: Box<BufRead>, count: Rc<Cell<u64>>, sender: &SyncSender<io::Result<InputChunk>>) -> io::Result<()> {
+    loop {
+        let mut data = Vec::with_capacity(CHUNK_SIZE + 4096);
+        read_chunk(&mut reader, &mut data)?;
+        if data.is_empty() || sender.send(Ok(InputChunk { data: data, position: count.get() })).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+// Appends about 1MB of whole lines from the reader to data, leaving it empty at the end of the input.
+pub fn read_chunk(reader: &mut BufRead, data: &mut Vec<u8>) -> io::Result<()> {
+    while data.len() < CHUNK_SIZE {
+        let read = {
+            let buf = reader.fill_buf()?;
+            let read = buf.len().min(CHUNK_SIZE - data.len());
+            data.extend_from_slice(&buf[0..read]);
+            read
+        };
+        if read == 0 {
+            break;
+        }
+        reader.consume(read);
+    }
+    // Chunks end on a line boundary
+    if data.len() >= CHUNK_SIZE {
+        reader.read_until(b'\n', data)?;
+    }
+    Ok(())
+}
+
+// The part of each file whose lines are evaluated: the lines from since_line on (the first being 1,
+// after any header), at most max_lines of them, or the lines of the last tail_bytes of the file.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FileSpan {
+    pub since_line: Option<u64>,
+    pub max_lines: Option<u64>,
+    pub tail_bytes: Option<u64>,
+}
+
+impl FileSpan {
+    pub fn is_whole(&self) -> bool {
+        *self == FileSpan::default()
+    }
+
+    // Picks the lines of one file, chunk after chunk.
+    pub fn lines(&self) -> LineSpan {
+        LineSpan { skip: self.since_line.map(|l| l.saturating_sub(1)).unwrap_or(0), remaining: self.max_lines }
+    }
+
+    // Start of the first whole line of the last tail_bytes of a mapped file, lines from start on.
+    pub fn tail_start(&self, data: &[u8], start: usize) -> usize {
+        match self.tail_bytes {
+            Some(bytes) if (data.len() - start) as u64 > bytes => {
+                let tail = data.len() - bytes as usize;
+                if data[tail - 1] == b'\n' { tail } else { memchr(b'\n', &data[tail..]).map(|idx| tail + idx + 1).unwrap_or(data.len()) }
+            },
+            _ => start,
+        }
+    }
+}
+
+pub struct LineSpan {
+    // Lines still to skip before the span, and to evaluate within it when it's limited
+    skip: u64,
+    remaining: Option<u64>,
+}
+
+impl LineSpan {
+    // The lines of a chunk of whole lines within the span, None once every line of it was picked.
+    pub fn select<'a>(&mut self, data: &'a [u8]) -> Option<&'a [u8]> {
+        if self.remaining == Some(0) {
+            return None;
+        }
+        if data.is_empty() {
+            return Some(data);
+        }
+        let mut ends = memchr_iter(b'\n', data).map(|idx| idx + 1).chain(if data.last() == Some(&b'\n') { None } else { Some(data.len()) });
+        let mut start = 0;
+        while self.skip > 0 {
+            match ends.next() {
+                Some(end) => start = end,
+                None => return Some(&data[data.len()..]),
+            }
+            self.skip -= 1;
+        }
+        let end =
+            match self.remaining {
+                Some(remaining) => {
+                    let mut end = start;
+                    let mut picked = 0;
+                    while picked < remaining {
+                        match ends.next() {
+                            Some(line_end) => end = line_end,
+                            None => break,
+                        }
+                        picked += 1;
+                    }
+                    self.remaining = Some(remaining - picked);
+                    end
+                },
+                None => data.len(),
+            };
+        Some(&data[start..end])
+    }
+}
+
+// The end of a chunk of about 1MB of whole lines of a mapped file starting at start.
+pub fn chunk_end(data: &[u8], start: usize) -> usize {
+    let end = (start + CHUNK_SIZE).min(data.len());
+    memchr(b'\n', &data[end - 1..]).map(|idx| end + idx).unwrap_or(data.len())
+}
+
+impl Iterator for BackgroundInput {
+    type Item = io::Result<InputChunk>;
+
+    fn next(&mut self) -> Option<io::Result<InputChunk>> {
+        self.chunks.recv().ok()
+    }
+}
+
+// Tells files apart by their inode, which stays the same when a log is renamed by rotation. Always 0
+// where there are no inodes.
+#[cfg(unix)]
+pub fn file_identity(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+pub fn file_identity(_metadata: &fs::Metadata) -> u64 {
+    0
+}
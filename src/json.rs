@@ -0,0 +1,246 @@
+use std::rc::Rc;
+use std::collections::HashMap;
+
+use chrono::prelude::*;
+use serde_json::{self, Value};
+use table::{TableDefinition, ColumnDefinition};
+use format::LogFormat;
+
+// Json lines whose columns were discovered from a sample line.
+pub struct JsonFormat {
+    pub columns: Vec<JsonColumn>,
+}
+
+impl LogFormat for JsonFormat {
+    type Record = BinaryJsonLogRecord;
+
+    fn empty_record(&self) -> BinaryJsonLogRecord {
+        BinaryJsonLogRecord::new(&self.columns)
+    }
+
+    fn read_record(&self, line: &[u8], record: &mut BinaryJsonLogRecord) -> bool {
+        read_log_record_binary(line, record)
+    }
+
+    fn table_definition(&self) -> TableDefinition<BinaryJsonLogRecord> {
+        create_json_log_record_table_definition(&self.columns)
+    }
+}
+
+pub fn read_log_record_binary(line: &[u8], record: &mut BinaryJsonLogRecord) -> bool {
+    let parsed = serde_json::from_slice::<Value>(line).ok();
+    let object = parsed.as_ref().and_then(|v| v.as_object());
+
+    for (idx, key) in record.keys.iter().enumerate() {
+        record.values[idx] = object.and_then(|o| o.get(key)).and_then(|v| json_value_text(v));
+        record.dates[idx] = None;
+    }
+    object.is_some()
+}
+
+// Derives the columns of a json log from the keys of sample lines, each key found in any of them being
+// a column. A column's type is inferred from its sampled values, columns holding both integers and
+// doubles being doubles and those holding values of other different types text. Keys named alike once
+// normalized, e.g. a-b and a_b, are told apart by a suffix.
+pub fn discover_json_columns(lines: &[Vec<u8>]) -> Vec<JsonColumn> {
+    let mut columns: Vec<JsonColumn> = Vec::new();
+    let mut kinds: Vec<Option<JsonColumnKind>> = Vec::new();
+    for line in lines {
+        let object = match serde_json::from_slice::<Value>(line) {
+            Ok(Value::Object(object)) => object,
+            _ => continue,
+        };
+        for (key, value) in object.iter() {
+            let idx = match columns.iter().position(|c| &c.key == key) {
+                Some(idx) => idx,
+                None => {
+                    let name = unique_column_name(&columns, column_name(key));
+                    columns.push(JsonColumn { name: name, key: key.to_owned(), kind: JsonColumnKind::Text });
+                    kinds.push(None);
+                    columns.len() - 1
+                },
+            };
+            if !value.is_null() {
+                let kind = json_column_kind(value);
+                kinds[idx] = Some(match kinds[idx].take() {
+                    None => kind,
+                    Some(previous) => merge_column_kinds(previous, kind),
+                });
+            }
+        }
+    }
+    for (column, kind) in columns.iter_mut().zip(kinds) {
+        column.kind = kind.unwrap_or(JsonColumnKind::Text);
+    }
+    columns
+}
+
+fn unique_column_name(columns: &Vec<JsonColumn>, name: String) -> String {
+    let mut unique = name.clone();
+    let mut suffix = 2;
+    while columns.iter().any(|c| c.name == unique) {
+        unique = format!("{}_{}", name, suffix);
+        suffix += 1;
+    }
+    unique
+}
+
+fn merge_column_kinds(previous: JsonColumnKind, kind: JsonColumnKind) -> JsonColumnKind {
+    match (previous, kind) {
+        (ref a, ref b) if a == b => b.clone(),
+        (JsonColumnKind::Integer, JsonColumnKind::Double) | (JsonColumnKind::Double, JsonColumnKind::Integer) => JsonColumnKind::Double,
+        _ => JsonColumnKind::Text,
+    }
+}
+
+pub fn column_name(key: &str) -> String {
+    key.chars().map(|c| if c.is_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect()
+}
+
+fn json_column_kind(value: &Value) -> JsonColumnKind {
+    match value {
+        Value::Number(n) if n.is_u64() => JsonColumnKind::Integer,
+        Value::Number(_) => JsonColumnKind::Double,
+        Value::Bool(_) => JsonColumnKind::Boolean,
+        Value::String(s) if parse_json_date(s).is_some() => JsonColumnKind::Date,
+        _ => JsonColumnKind::Text,
+    }
+}
+
+fn json_value_text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => Some(s.to_owned()),
+        other => Some(other.to_string()),
+    }
+}
+
+fn parse_json_date(value: &str) -> Option<DateTime<Local>> {
+    DateTime::parse_from_rfc3339(value)
+        .or_else(|_| DateTime::parse_from_str(value, "%d/%b/%Y:%H:%M:%S %z"))
+        .ok()
+        .map(|d| d.with_timezone(&Local))
+}
+
+// Seconds since the epoch, or milliseconds when too many to be seconds of a date before the year 5000
+fn parse_epoch_date(value: &str) -> Option<DateTime<Local>> {
+    let epoch = value.parse::<f64>().ok().filter(|e| e.is_finite() && *e >= 0.0)?;
+    let millis = (if epoch >= 1e11 { epoch } else { epoch * 1000.0 }).round() as i64;
+    Local.timestamp_opt(millis / 1000, (millis % 1000) as u32 * 1_000_000).single()
+}
+
+#[derive(Debug, Clone)]
+pub struct JsonColumn {
+    pub name: String,
+    pub key: String,
+    pub kind: JsonColumnKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonColumnKind {
+    Integer,
+    Double,
+    Text,
+    Date,
+    Boolean,
+}
+
+#[derive(Debug, Clone)]
+pub struct BinaryJsonLogRecord {
+    keys: Rc<Vec<String>>,
+    values: Vec<Option<String>>,
+    dates: Vec<Option<Option<DateTime<Local>>>>,
+}
+
+impl BinaryJsonLogRecord {
+    pub fn new(columns: &Vec<JsonColumn>) -> BinaryJsonLogRecord {
+        BinaryJsonLogRecord {
+            keys: Rc::new(columns.iter().map(|c| c.key.to_owned()).collect()),
+            values: vec![None; columns.len()],
+            dates: vec![None; columns.len()],
+        }
+    }
+
+    pub fn value_bytes(&self, idx: usize) -> Option<&[u8]> {
+        self.values[idx].as_ref().map(|v| v.as_bytes())
+    }
+
+    pub fn parsed_text(&mut self, idx: usize) -> Option<&str> {
+        self.values[idx].as_ref().map(|v| v.as_str())
+    }
+
+    pub fn parsed_integer(&mut self, idx: usize) -> Option<u64> {
+        self.values[idx].as_ref().and_then(|v| v.parse::<u64>().ok())
+    }
+
+    pub fn parsed_double(&mut self, idx: usize) -> Option<f64> {
+        self.values[idx].as_ref().and_then(|v| v.parse::<f64>().ok())
+    }
+
+    pub fn parsed_boolean(&mut self, idx: usize) -> Option<bool> {
+        self.values[idx].as_ref().and_then(|v| v.parse::<bool>().ok())
+    }
+
+    pub fn parsed_date(&mut self, idx: usize) -> Option<&DateTime<Local>> {
+        if self.dates[idx].is_none() {
+            self.dates[idx] = Some(self.values[idx].as_ref().and_then(|v| parse_json_date(v)));
+        }
+        self.dates[idx].as_ref().unwrap().as_ref()
+    }
+
+    // As parsed_date, numbers being read as epoch times, as the ts and time keys of many loggers are
+    pub fn parsed_date_or_epoch(&mut self, idx: usize) -> Option<&DateTime<Local>> {
+        if self.dates[idx].is_none() {
+            self.dates[idx] = Some(self.values[idx].as_ref().and_then(|v| parse_json_date(v).or_else(|| parse_epoch_date(v))));
+        }
+        self.dates[idx].as_ref().unwrap().as_ref()
+    }
+}
+
+pub fn create_json_log_record_table_definition(columns: &Vec<JsonColumn>) -> TableDefinition<BinaryJsonLogRecord> {
+    let mut column_map = HashMap::new();
+    let mut ordering = Vec::new();
+
+    for (idx, column) in columns.iter().enumerate() {
+        let name = column.name.to_owned();
+        let definition = match column.kind {
+            JsonColumnKind::Integer =>
+                ColumnDefinition::Integer { name: name,
+                                            size: 10,
+                                            field: idx,
+                                            binary_extractor: |r: &BinaryJsonLogRecord, idx| r.value_bytes(idx),
+                                            extractor: |r: &mut BinaryJsonLogRecord, idx| r.parsed_integer(idx) },
+            JsonColumnKind::Double =>
+                ColumnDefinition::Double { name: name,
+                                           size: 10,
+                                           field: idx,
+                                           binary_extractor: |r: &BinaryJsonLogRecord, idx| r.value_bytes(idx),
+                                           extractor: |r: &mut BinaryJsonLogRecord, idx| r.parsed_double(idx) },
+            JsonColumnKind::Boolean =>
+                ColumnDefinition::Boolean { name: name,
+                                            size: 5,
+                                            field: idx,
+                                            binary_extractor: |r: &BinaryJsonLogRecord, idx| r.value_bytes(idx),
+                                            extractor: |r: &mut BinaryJsonLogRecord, idx| r.parsed_boolean(idx) },
+            JsonColumnKind::Date =>
+                ColumnDefinition::Date { name: name,
+                                         size: 26,
+                                         field: idx,
+                                         binary_extractor: |r: &BinaryJsonLogRecord, idx| r.value_bytes(idx),
+                                         extractor: |r: &mut BinaryJsonLogRecord, idx| r.parsed_date(idx) },
+            JsonColumnKind::Text =>
+                ColumnDefinition::Text { name: name,
+                                         size: 20,
+                                         field: idx,
+                                         binary_extractor: |r: &BinaryJsonLogRecord, idx| r.value_bytes(idx),
+                                         extractor: |r: &mut BinaryJsonLogRecord, idx| r.parsed_text(idx) },
+        };
+        ordering.push(definition.name().to_owned());
+        column_map.insert(definition.name().to_owned(), definition);
+    }
+
+    TableDefinition {
+        column_map: column_map,
+        ordered_columns: ordering,
+    }
+}
@@ -0,0 +1,55 @@
+#[macro_use]
+extern crate nom;
+extern crate regex;
+extern crate regex_syntax;
+extern crate chrono;
+extern crate byteorder;
+extern crate flate2;
+extern crate serde_json;
+extern crate zstd;
+extern crate bzip2;
+extern crate xz2;
+extern crate glob;
+extern crate maxminddb;
+extern crate memmap2;
+extern crate rusqlite;
+extern crate memchr;
+
+pub mod query;
+pub mod nginx;
+pub mod nginx_error;
+pub mod parser;
+pub mod table;
+pub mod json;
+pub mod apache;
+pub mod custom;
+pub mod output;
+pub mod input;
+pub mod discovery;
+pub mod format;
+pub mod geoip;
+pub mod route;
+pub mod index;
+pub mod syslog;
+pub mod csv;
+pub mod aws;
+pub mod chart;
+pub mod sqlite;
+pub mod prefilter;
+pub mod value;
+pub mod stage;
+pub mod cidr;
+pub mod session;
+pub mod mask;
+pub mod container;
+pub mod sample;
+pub mod remote;
+pub mod storage;
+pub mod diagnostics;
+pub mod auto;
+
+pub use parser::{parse_query, RipLogQuery, QueryParseError};
+pub use query::{QueryEvaluator, QueryValidationError, UnknownColumn, validate_riplog_query};
+pub use table::{TableDefinition, ColumnDefinition};
+pub use format::LogFormat;
+pub use output::{OutputFormat, OutputOptions};
@@ -1,108 +1,973 @@
-#[macro_use]
-extern crate nom;
-extern crate regex;
-extern crate chrono;
+extern crate riplog;
 extern crate byteorder;
+extern crate ctrlc;
+extern crate toml;
+extern crate chrono;
+extern crate serde_json;
 extern crate flate2;
+#[macro_use]
+extern crate clap;
 
+use std::fmt;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::rc::Rc;
 use std::fs::{self, File};
-use std::path::Path;
-use std::env;
-use std::io::{self, BufRead, BufReader};
-use std::time::Instant;
-use flate2::read::GzDecoder;
+use std::process;
+use std::path::{Path, PathBuf};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
-mod query;
-mod nginx;
-mod parser;
-mod table;
+use serde_json::Map;
 
-use nginx::BinaryNginxLogRecord;
-use query::QueryEvaluator;
+mod estimate;
+mod checkpoint;
+mod partial;
+mod cli;
+mod config;
+mod progress;
+mod prune;
+
+// Rows read from the first csv file to infer column types
+const CSV_SAMPLE_LINES: usize = 100;
+const JSON_SAMPLE_LINES: usize = 100;
+
+use riplog::{json, csv, query, parser, output, input, discovery, remote, stage, storage};
+use riplog::format::LogFormat;
+use riplog::nginx::NginxFormat;
+use riplog::nginx_error::NginxErrorFormat;
+use riplog::json::JsonFormat;
+use riplog::apache::ApacheFormat;
+use riplog::custom::CustomFormat;
+use riplog::syslog::SyslogFormat;
+use riplog::csv::CsvFormat;
+use riplog::aws::{self, AwsFormat, AwsLog};
+use riplog::geoip::GeoIp;
+use riplog::route::RouteNormalizer;
+use riplog::query::{QueryEvaluator, ScanStats};
+use riplog::prefilter::LinePrefilter;
+use riplog::input::{BackgroundInput, Compression, FileSpan};
+use riplog::table::TableDefinition;
+use riplog::output::OutputOptions;
+use riplog::discovery::{DiscoveredFiles, FileSelector};
+use riplog::index::{self, FileIndex};
+use riplog::session::{SessionFormat, SessionRecord};
+use riplog::mask::{MaskFormat, MaskKey};
+use riplog::container::{self, ContainerFormat};
+use riplog::sample::Sampler;
+use riplog::remote::HostFormat;
+use riplog::auto::AutoFormat;
+use riplog::parser::RipLogQuery;
+use checkpoint::Checkpoint;
+use partial::Partial;
+use cli::{AlertOptions, Command, InputFormat, Options, EXIT_ALERT, EXIT_MATCHED, EXIT_NO_MATCH, EXIT_ERROR};
+use progress::Progress;
+use prune::FilePruner;
 
 fn main() { 
-    let args: Vec<String> = env::args().collect();
-    let start = Instant::now();
-    run_query(args[2].to_string(), args[1].to_string());
-    let end = Instant::now();
-    println!("Duration: {:?}", end - start);
+    let options = cli::parse_options();
+    if options.command == Command::Index {
+        build_indexes(&options, NginxFormat { geoip: None, routes: Arc::new(RouteNormalizer::default()) });
+        return;
+    }
+    if options.command == Command::Merge {
+        merge_partials(&options);
+        return;
+    }
+    match options.format {
+        InputFormat::Nginx => {
+            let geoip = open_geoip(&options.geoip).map(Arc::new);
+            execute(&options, NginxFormat { geoip: geoip, routes: Arc::new(open_routes(&options.route_patterns)) });
+        },
+        InputFormat::NginxError => execute(&options, NginxErrorFormat),
+        InputFormat::Apache => execute(&options, ApacheFormat { routes: Arc::new(open_routes(&options.route_patterns)) }),
+        InputFormat::Syslog => execute(&options, SyslogFormat::new()),
+        InputFormat::Auto => {
+            let geoip = open_geoip(&options.geoip).map(Arc::new);
+            execute(&options, AutoFormat::new(NginxFormat { geoip: geoip, routes: Arc::new(open_routes(&options.route_patterns)) }));
+        },
+        InputFormat::Elb => run(&options, &discover_files(&options, aws::is_alb_log), AwsFormat::new(AwsLog::Alb)),
+        InputFormat::S3 => run(&options, &discover_files(&options, aws::is_s3_access_log), AwsFormat::new(AwsLog::S3)),
+        InputFormat::Custom => {
+            let mut format = CustomFormat::parse(options.log_format.as_ref().unwrap()).unwrap_or_else(|e| exit_with_error(&e));
+            format.routes = Arc::new(open_routes(&options.route_patterns));
+            execute(&options, format);
+        },
+        InputFormat::Json => {
+            let files = discover_files(&options, JsonFormat::is_log_file);
+            let columns = match read_sample_lines(&files, JSON_SAMPLE_LINES, options.container).unwrap_or_else(|e| exit_with_error(&e)) {
+                Some((file, sample)) => {
+                    let columns = json::discover_json_columns(&sample);
+                    if columns.is_empty() {
+                        exit_with_error::<_, ()>(&format!("No json object with keys found in the first {} lines of {} to take columns from", JSON_SAMPLE_LINES, file.display()));
+                    }
+                    columns
+                },
+                None => Vec::new(),
+            };
+            run(&options, &files, JsonFormat { columns: columns });
+        },
+        InputFormat::Csv | InputFormat::Tsv => {
+            let tsv = options.format == InputFormat::Tsv;
+            let files = discover_files(&options, if tsv { csv::is_tsv_file } else { csv::is_csv_file });
+            let sample = read_sample_lines(&files, CSV_SAMPLE_LINES, options.container).unwrap_or_else(|e| exit_with_error(&e)).map(|(_, lines)| lines).unwrap_or(Vec::new());
+            let delimiter = if tsv { b'\t' } else { b',' };
+            let format = CsvFormat::new(delimiter, &options.columns, &options.column_types, &sample).unwrap_or_else(|e| exit_with_error(&e));
+            run(&options, &files, format);
+        },
+    }
 }
 
-fn run_query(query: String, path: String) {
-    let definition = nginx::create_nginx_log_record_table_definition();
-    let query = parser::parse_query(query);
-    let result = query::validate_riplog_query(&query, &definition);
-    result.unwrap();
-    let mut evaluator = QueryEvaluator::<BinaryNginxLogRecord>::new(query, definition);
+fn execute<F: LogFormat>(options: &Options, format: F) {
+    let files = discover_files(options, F::is_log_file);
+    run(options, &files, format);
+}
 
-    let path = Path::new(&path);
-    evaluate_query_log_file_or_dir(path, &mut evaluator).unwrap();
-    evaluator.finalize();
+fn open_geoip(paths: &Vec<String>) -> Option<GeoIp> {
+    if paths.is_empty() {
+        None
+    } else {
+        Some(GeoIp::open(paths).unwrap_or_else(|e| exit_with_error(&e)))
+    }
 }
 
-fn evaluate_query_log_file_or_dir(path: &Path, evaluator: &mut QueryEvaluator<BinaryNginxLogRecord>) -> io::Result<()> {
-    if path.is_dir() {
-        evaluate_query_log_dir(&path, evaluator)?;
+fn open_routes(path: &Option<String>) -> RouteNormalizer {
+    match path {
+        Some(path) => RouteNormalizer::from_file(path).unwrap_or_else(|e| exit_with_error(&e)),
+        None => RouteNormalizer::default(),
+    }
+}
+
+fn discover_files(options: &Options, default_filter: fn(&Path) -> bool) -> DiscoveredFiles {
+    // Files of other hosts, and objects, are found once the format is known
+    let paths: Vec<PathBuf> = options.paths.iter().filter(|p| !storage::is_object_url(p)).cloned().collect();
+    if !options.ssh.is_empty() || paths.is_empty() {
+        return DiscoveredFiles::default();
+    }
+    if options.follow {
+        return DiscoveredFiles { files: vec![options.paths[0].clone()], skipped: 0 };
+    }
+    let default_filter = if options.container { container::is_container_log_file } else { default_filter };
+    let selector = FileSelector::new(&options.include, &options.exclude, default_filter).unwrap_or_else(|e| exit_with_error(&e));
+    let mut files = discovery::discover_files(&paths, &selector).unwrap_or_else(|e| exit_with_error(&e));
+    discovery::sort_files(&mut files.files, options.order);
+    files
+}
+
+fn run<F: LogFormat>(options: &Options, files: &DiscoveredFiles, format: F) {
+    if options.container {
+        run_masked(options, files, ContainerFormat::new(format));
     } else {
-        evaluate_query_log_file(&path, evaluator)?;
+        run_masked(options, files, format);
+    }
+}
+
+fn run_masked<F: LogFormat>(options: &Options, files: &DiscoveredFiles, format: F) {
+    match options.mask {
+        Some(ref columns) => {
+            let key = options.mask_key.as_ref().map(|secret| MaskKey::from_secret(secret)).unwrap_or_else(MaskKey::random);
+            run_format(options, files, MaskFormat::new(format, columns, key).unwrap_or_else(|e| exit_with_error(&e)))
+        },
+        None => run_format(options, files, format),
     }
-    Ok(())
 }
 
-fn evaluate_query_log_dir(dir: &Path, evaluator: &mut QueryEvaluator<BinaryNginxLogRecord>) -> io::Result<()> {
-    for entry in fs::read_dir(dir)? {
+fn run_format<F: LogFormat>(options: &Options, files: &DiscoveredFiles, format: F) {
+    if options.estimate {
+        run_estimate(options, files, &format);
+        return;
+    }
+    if let Some(gap) = options.sessionize {
+        run_sessionized(options, files, SessionFormat::new(format, gap).unwrap_or_else(|e| exit_with_error(&e)));
+        return;
+    }
+    if !options.ssh.is_empty() {
+        run_remote(options, HostFormat::new(format));
+        return;
+    }
+    if options.command == Command::Schema {
+        print_schema(&format.table_definition());
+        return;
+    }
+    let mut evaluator = create_evaluator(parse_query(&options.query), format.table_definition(), options.allow_missing_columns, &options.output);
+    check_emit_partial(options, &evaluator);
+    if let Some(sampling) = options.sample {
+        evaluator.sample(Sampler::new(sampling));
+    }
+    let start = Instant::now();
+    if options.follow {
+        if let Some(window) = options.window {
+            if !evaluator.is_aggregate() || evaluator.is_windowed() {
+                exit_with_error::<_, ()>(&"--window requires an aggregate query without 'every', e.g. 'status >= 500 | show count(*)'");
+            }
+            if !evaluator.has_date_column() {
+                exit_with_error::<_, ()>(&"--window requires a log format with a date column, which the window is computed over");
+            }
+            evaluator.slide_window(window);
+        }
+        if let Some(ref alert) = options.alert {
+            // The query was parsed when the evaluator was created, the alert's aliases resolving to its columns
+            let query = parse_query(&options.query);
+            let condition = parser::parse_alert(&alert.condition, &query).unwrap_or_else(|e| exit_with_error(&e));
+            query::validate_riplog_alert(&condition, &query).unwrap_or_else(|e| exit_with_error(&e));
+            evaluator.set_alert(condition);
+        }
+        follow_query(&options.paths[0], &mut evaluator, &format, Duration::from_secs(options.interval), options.alert.as_ref())
+            .unwrap_or_else(|e| exit_with_error(&format!("Unable to read {}: {}", options.paths[0].display(), e)));
+        close_output();
+        let mut stats = evaluator.stats();
+        stats.files = 1;
+        report_run(options, &stats, Instant::now() - start);
+        return;
+    }
+    let mut checkpoint = options.checkpoint.as_ref().map(|path| open_checkpoint(path, options, &mut evaluator));
+    // Indexes hold every line of a file, so they are only used when files are read whole
+    let indexed_fields = format.index_fields(&format.empty_record()).map(|f| f.len()).filter(|_| options.span.is_whole() && checkpoint.is_none());
+    let date_range = evaluator.date_range();
+    let prefilter = evaluator.line_prefilter(&format.verbatim_columns());
+    let candidates = prune_files(&files.files, &format, &date_range, options.prune);
+    let (candidates, starts) = resume_files(candidates, checkpoint.as_mut());
+    let mut progress = Progress::new(&candidates, options.progress);
+    let mut scanned = 0;
+    let mut pruned = files.files.len() - candidates.len();
+    let mut decompressing = HashMap::new();
+    for (idx, file) in candidates.iter().enumerate() {
         if evaluator.should_stop() {
             break;
         }
-        let entry = entry?;
-        let path = entry.path();
+        start_decompressing(&candidates[idx..], indexed_fields.is_some(), options.threads, &starts, &mut decompressing);
+        progress.start_file(file);
+        let file_index = if indexed_fields.is_some() { FileIndex::open(file).unwrap_or(None) } else { None };
+        match file_index {
+            Some(ref file_index) if Some(file_index.fields()) == indexed_fields => {
+                if file_index.may_match(&date_range) {
+                    evaluate_indexed_file(file_index, &mut evaluator, &format, &mut progress)
+                        .unwrap_or_else(|e| exit_with_error(&format!("Unable to read the index of {}: {}", file.display(), e)));
+                    scanned += 1;
+                } else {
+                    pruned += 1;
+                }
+            },
+            _ => {
+                let position = evaluate_query_log_file(file, decompressing.remove(file), &mut evaluator, &format, &prefilter, &options.span, starts.get(file).cloned(), &mut progress)
+                    .unwrap_or_else(|e| exit_with_error(&format!("Unable to read {}: {}", file.display(), e)));
+                if let Some(ref mut checkpoint) = checkpoint {
+                    checkpoint.finish_file(file, position).unwrap_or_else(|e| exit_with_error(&format!("Unable to read {}: {}", file.display(), e)));
+                }
+                scanned += 1;
+            },
+        }
+        progress.finish_file();
+    }
+    progress.finish();
+    if is_object_storage(options) {
+        let (streamed, skipped) = evaluate_objects(options, &mut evaluator, &format, &prefilter);
+        scanned += streamed;
+        pruned += skipped;
+    }
+    if let Some(ref mut checkpoint) = checkpoint {
+        save_checkpoint(checkpoint, &evaluator, options.checkpoint.as_ref().unwrap());
+    }
+    finish_run(options, &mut evaluator, scanned, files.skipped + pruned, start);
+}
 
-        if path.is_dir() {
-            evaluate_query_log_dir(&path, evaluator)?;
-        } else {
-            evaluate_query_log_file(&path, evaluator);
+// Aggregates saved by earlier runs with the checkpoint are merged before any line is read. Windows of
+// 'every' are printed as they end, and a stage that isn't aggregated has nothing to save for the stages
+// after it, so those queries can't carry on from a checkpoint.
+fn open_checkpoint<T>(path: &Path, options: &Options, evaluator: &mut QueryEvaluator<T>) -> Checkpoint {
+    if evaluator.is_windowed() {
+        exit_with_error::<_, ()>(&"--checkpoint can't be used with 'every', whose windows are printed as they end");
+    }
+    if evaluator.is_chained() && !evaluator.is_aggregate() {
+        exit_with_error::<_, ()>(&"--checkpoint requires the first stage of a chained query to be aggregated");
+    }
+    let checkpoint = Checkpoint::open(path, &options.query, options.format.name()).unwrap_or_else(|e| exit_with_error(&e));
+    evaluator.merge_snapshot(&checkpoint.snapshot)
+        .unwrap_or_else(|e| exit_with_error(&format!("Unable to resume from checkpoint {}: {}", path.display(), e)));
+    checkpoint
+}
+
+// The files with lines to read and where to start reading them, files read up to their end by an
+// earlier run with the checkpoint being left out. Files are read from their start without one.
+fn resume_files(files: Vec<PathBuf>, checkpoint: Option<&mut Checkpoint>) -> (Vec<PathBuf>, HashMap<PathBuf, u64>) {
+    let checkpoint = match checkpoint {
+        Some(checkpoint) => checkpoint,
+        None => return (files, HashMap::new()),
+    };
+    let mut starts = HashMap::new();
+    let mut resumed = Vec::new();
+    for file in files {
+        let start = checkpoint.start(&file).unwrap_or_else(|e| exit_with_error(&format!("Unable to read {}: {}", file.display(), e)));
+        if let Some(start) = start {
+            starts.insert(file.clone(), start);
+            resumed.push(file);
         }
     }
-    Ok(())
+    (resumed, starts)
 }
 
-fn evaluate_query_log_file(file: &Path, evaluator: &mut QueryEvaluator<BinaryNginxLogRecord>) -> io::Result<()> {
-    if !file.file_name().unwrap().to_str().unwrap().contains("error") && file.file_name().unwrap().to_str().unwrap().ends_with(".gz") {
-        let file = File::open(file)?;
-        let mut reader = BufReader::new(GzDecoder::new(file));
-        let mut buf = vec![];
-        let mut record = BinaryNginxLogRecord::empty();
+// A query stopped early, e.g. by its limit, didn't read every line, so the checkpoint is left as it was.
+fn save_checkpoint<T>(checkpoint: &mut Checkpoint, evaluator: &QueryEvaluator<T>, path: &Path) {
+    if evaluator.should_stop() {
+        eprintln!("Warning: not every line was read, so checkpoint {} is not updated", path.display());
+        return;
+    }
+    checkpoint.save(evaluator.snapshot())
+        .unwrap_or_else(|e| exit_with_error(&format!("Unable to write checkpoint {}: {}", path.display(), e)));
+}
 
-        loop {
+// The logs of each host are read in turn over ssh, results being told apart by host. Files of other
+// hosts are streamed whole, as they can't be mapped, pruned or read from their index.
+fn run_remote<F: LogFormat>(options: &Options, format: HostFormat<F>) {
+    if options.command == Command::Schema {
+        print_schema(&format.table_definition());
+        return;
+    }
+    let mut query = parse_query(&options.query);
+    remote::add_host_column(&mut query, format.host_column());
+    let mut evaluator = create_evaluator(query, format.table_definition(), options.allow_missing_columns, &options.output);
+    check_emit_partial(options, &evaluator);
+    if let Some(sampling) = options.sample {
+        evaluator.sample(Sampler::new(sampling));
+    }
+    let selector = FileSelector::new(&options.include, &options.exclude, F::is_log_file).unwrap_or_else(|e| exit_with_error(&e));
+    let prefilter = evaluator.line_prefilter(&format.verbatim_columns());
+    let start = Instant::now();
+    let mut scanned = 0;
+    let mut skipped = 0;
+    for host in &options.ssh {
+        let mut files = remote::discover_files(host, &options.paths, &selector).unwrap_or_else(|e| exit_with_error(&e));
+        discovery::sort_files_by(&mut files.files, options.order, |_| None);
+        skipped += files.skipped;
+        format.read_host(host);
+        let mut progress = Progress::new(&files.files, false);
+        for file in &files.files {
             if evaluator.should_stop() {
                 break;
             }
-            buf.clear();
-            let size = reader.read_until(b'\n', &mut buf).unwrap();
-            if size <= 0 {
-                break;
-            }
-            nginx::read_log_record_binary(&buf, size, &mut record);
-            evaluator.evaluate(&mut record);
-        }
-    } else if file.file_name().unwrap().to_str().unwrap().contains("access.log") {
-        let file = File::open(file)?;
-        let mut reader = BufReader::new(file);
-        let mut buf = vec![];
-        let mut record = BinaryNginxLogRecord::empty();
-        
-        loop {
-            if evaluator.should_stop() {
-                break;
+            let (remote_host, remote_file) = (host.to_owned(), file.to_owned());
+            evaluate_streamed_file(move || remote::open_remote_input(&remote_host, &remote_file), options.threads, &mut evaluator, &format, &prefilter, &options.span, &mut progress)
+                .unwrap_or_else(|e| exit_with_error(&format!("Unable to read {}:{}: {}", host, file.display(), e)));
+            scanned += 1;
+        }
+    }
+    finish_run(options, &mut evaluator, scanned, skipped, start);
+}
+
+fn is_object_storage(options: &Options) -> bool {
+    options.paths.iter().any(|p| storage::is_object_url(p))
+}
+
+// Objects of s3:// and gs:// urls are streamed whole, in the order they were last modified, after the
+// local files of the run, as they can't be mapped, pruned or read from their index. Returns the number
+// of objects read and of those skipped.
+fn evaluate_objects<F: LogFormat>(options: &Options, evaluator: &mut QueryEvaluator<F::Record>, format: &F, prefilter: &LinePrefilter) -> (usize, usize) {
+    let selector = FileSelector::new(&options.include, &options.exclude, F::is_log_file).unwrap_or_else(|e| exit_with_error(&e));
+    let mut listed = storage::ListedObjects::default();
+    for url in options.paths.iter().filter(|p| storage::is_object_url(p)) {
+        storage::list_objects(url, &selector, &mut listed).unwrap_or_else(|e| exit_with_error(&e));
+    }
+    let modified = listed.modified;
+    let mut objects = listed.objects;
+    discovery::sort_files_by(&mut objects.files, options.order, |o| modified.get(o).cloned());
+    let mut progress = Progress::new(&objects.files, false);
+    let mut scanned = 0;
+    for object in &objects.files {
+        if evaluator.should_stop() {
+            break;
+        }
+        let url = object.to_owned();
+        evaluate_streamed_file(move || storage::open_object_input(&url), options.threads, evaluator, format, prefilter, &options.span, &mut progress)
+            .unwrap_or_else(|e| exit_with_error(&format!("Unable to read {}: {}", object.display(), e)));
+        scanned += 1;
+    }
+    (scanned, objects.skipped)
+}
+
+// Sessions span files and depend on every record of a client, so all files are read, without
+// skipping lines or files the query can't match, before the records are evaluated in time order.
+fn run_sessionized<F: LogFormat>(options: &Options, files: &DiscoveredFiles, format: SessionFormat<F>) {
+    if options.command == Command::Schema {
+        print_schema(&format.table_definition());
+        return;
+    }
+    let mut evaluator = create_evaluator(parse_query(&options.query), format.table_definition(), options.allow_missing_columns, &options.output);
+    check_emit_partial(options, &evaluator);
+    let start = Instant::now();
+    let mut progress = Progress::new(&files.files, options.progress);
+    let mut records = Vec::new();
+    for file in &files.files {
+        progress.start_file(file);
+        read_session_records(file, &format, &mut evaluator, &mut records, &mut progress)
+            .unwrap_or_else(|e| exit_with_error(&format!("Unable to read {}: {}", file.display(), e)));
+        progress.finish_file();
+    }
+    progress.finish();
+    for mut record in format.sessionize(records) {
+        if evaluator.should_stop() {
+            break;
+        }
+        evaluator.evaluate(&mut record);
+    }
+    finish_run(options, &mut evaluator, files.files.len(), files.skipped, start);
+}
+
+fn read_session_records<F: LogFormat>(file: &Path, format: &SessionFormat<F>, evaluator: &mut QueryEvaluator<SessionRecord<F::Record>>,
+                                      records: &mut Vec<SessionRecord<F::Record>>, progress: &mut Progress) -> io::Result<()> {
+    let (mut reader, position) = input::open_counted_input(file)?;
+    let mut line = Vec::new();
+    let mut record = format.empty_record();
+    let mut header = format.has_header();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        if header {
+            header = false;
+        } else if format.read_record(&line, &mut record) {
+            records.push(record.clone());
+        } else {
+            evaluator.skip_unparsed();
+        }
+        progress.lines_read(1, position.get());
+    }
+    Ok(())
+}
+
+fn finish_run<T>(options: &Options, evaluator: &mut QueryEvaluator<T>, scanned: usize, skipped: usize, start: Instant) {
+    let mut stats = evaluator.stats();
+    stats.files = scanned as u64;
+    stats.skipped_files = skipped as u64;
+    match options.emit_partial {
+        Some(ref path) => emit_partial(path, options, evaluator, stats),
+        None => evaluator.finalize(),
+    }
+    close_output();
+    if !options.quiet {
+        diagnose_run(options, evaluator, &stats);
+        report_warnings(evaluator);
+    }
+    report_run(options, &stats, Instant::now() - start);
+    if options.quiet {
+        process::exit(if evaluator.stats().matched > 0 { EXIT_MATCHED } else { EXIT_NO_MATCH });
+    }
+}
+
+// Partial results are only of aggregates, which can be merged: windows of 'every' are printed as they
+// end, and a first stage that isn't aggregated has no aggregates for the stages after it.
+fn check_emit_partial<T>(options: &Options, evaluator: &QueryEvaluator<T>) {
+    if options.emit_partial.is_none() {
+        return;
+    }
+    if !evaluator.is_aggregate() {
+        exit_with_error::<_, ()>(&"--emit-partial requires an aggregate query, e.g. 'group status | show count(*)'");
+    }
+    if evaluator.is_windowed() {
+        exit_with_error::<_, ()>(&"--emit-partial can't be used with 'every', whose windows are printed as they end");
+    }
+}
+
+// The query is saved as given, along with the host column --ssh added to it. A query stopped early,
+// e.g. by its limit, didn't reduce every line, so its aggregates would be merged short.
+fn emit_partial<T>(path: &Path, options: &Options, evaluator: &QueryEvaluator<T>, stats: ScanStats) {
+    if evaluator.should_stop() {
+        eprintln!("Warning: not every line was read, so the partial results in {} are incomplete", path.display());
+    }
+    let definition = evaluator.table_definition();
+    let host_column = if options.ssh.is_empty() { None } else { definition.ordered_columns.first().cloned() };
+    Partial::new(&options.query, definition, host_column, evaluator.snapshot(), stats).write(path)
+        .unwrap_or_else(|e| exit_with_error(&format!("Unable to write {}: {}", path.display(), e)));
+}
+
+// Merges the partial results of a query written by --emit-partial, e.g. by runs on several hosts or
+// over the logs of different days, into the results of the query over every line they read. The query
+// is evaluated over a table of the columns saved with them, as the log format's files aren't read.
+fn merge_partials(options: &Options) {
+    let start = Instant::now();
+    let partials: Vec<Partial> = options.paths.iter().map(|p| Partial::read(p).unwrap_or_else(|e| exit_with_error(&e))).collect();
+    let first = &partials[0];
+    for (idx, partial) in partials.iter().enumerate().skip(1) {
+        if partial.query != first.query || partial.columns != first.columns || partial.host_column != first.host_column {
+            exit_with_error::<_, ()>(&format!("{} was written by another query or log format than {}", options.paths[idx].display(), options.paths[0].display()));
+        }
+    }
+    let definition = stage::described_table(&first.columns)
+        .unwrap_or_else(|| exit_with_error(&format!("{} has columns of an unknown type", options.paths[0].display())));
+    let mut query = parse_query(&first.query);
+    if let Some(ref column) = first.host_column {
+        remote::add_host_column(&mut query, column);
+    }
+    let mut evaluator = create_evaluator(query, definition, false, &options.output);
+    let mut stats = ScanStats::default();
+    for (idx, partial) in partials.iter().enumerate() {
+        evaluator.merge_snapshot(&partial.aggregates)
+            .unwrap_or_else(|e| exit_with_error(&format!("Unable to merge {}: {}", options.paths[idx].display(), e)));
+        stats.add(&partial.stats);
+    }
+    evaluator.finalize();
+    close_output();
+    report_warnings(&mut evaluator);
+    report_run(options, &stats, Instant::now() - start);
+}
+
+// Runs that matched nothing may have read the wrong files, or read them with the wrong format.
+fn diagnose_run<T>(options: &Options, evaluator: &mut QueryEvaluator<T>, stats: &ScanStats) {
+    if stats.matched > 0 {
+        return;
+    }
+    if stats.files == 0 && stats.skipped_files > 0 {
+        evaluator.warn(format!("No files were read, the {} found being skipped by their names or as they can't hold the dates filtered on (--include selects files by name)",
+                               stats.skipped_files));
+    } else if stats.unparsed > 0 {
+        evaluator.warn(format!("{} of the {} lines read couldn't be parsed as {} logs (--format sets the log format)", stats.unparsed, stats.lines, options.format));
+    }
+}
+
+fn report_warnings<T>(evaluator: &mut QueryEvaluator<T>) {
+    for warning in evaluator.take_warnings() {
+        eprintln!("Warning: {}", warning);
+    }
+}
+
+// Prints what was scanned and how long it took, as asked for, once results are complete. Only stats
+// of table results (not followed) go to stdout, keeping anything else written there free of all but
+// results.
+fn report_run(options: &Options, stats: &ScanStats, duration: Duration) {
+    if options.timing {
+        eprintln!("Duration: {:?}", duration);
+    }
+    if options.stats {
+        if options.output.format.is_text() && !options.quiet && !options.follow {
+            println!("{}", stats);
+        } else {
+            eprintln!("{}", stats);
+        }
+    }
+    if options.meta {
+        let mut meta = Map::new();
+        meta.insert("duration_seconds".to_owned(), serde_json::Value::from(duration.as_secs_f64()));
+        meta.insert("files_scanned".to_owned(), serde_json::Value::from(stats.files));
+        meta.insert("files_skipped".to_owned(), serde_json::Value::from(stats.skipped_files));
+        meta.insert("lines_read".to_owned(), serde_json::Value::from(stats.lines));
+        meta.insert("lines_matched".to_owned(), serde_json::Value::from(stats.matched));
+        if let Some(sampled) = stats.sampled {
+            meta.insert("lines_sampled".to_owned(), serde_json::Value::from(sampled));
+            meta.insert("lines_matched_estimate".to_owned(), serde_json::Value::from(stats.estimated_matches()));
+        }
+        meta.insert("lines_unparsed".to_owned(), serde_json::Value::from(stats.unparsed));
+        eprintln!("{}", serde_json::Value::Object(meta));
+    }
+}
+
+// Files pruned by the dates the query's filter can match, by their names or their indexes, are left out
+// of the estimate, as they are of a scan. Sessions depend on every line, so sessionized runs read every
+// file. Files of other hosts and objects are only counted, their sizes being unknown until streamed.
+fn run_estimate<F: LogFormat>(options: &Options, files: &DiscoveredFiles, format: &F) {
+    let date_range = query::query_date_range(&parse_query(&options.query), &format.table_definition());
+    let pruned = options.sessionize.is_none();
+    let indexed_fields = format.index_fields(&format.empty_record()).map(|f| f.len()).filter(|_| pruned && options.span.is_whole() && options.checkpoint.is_none());
+    let candidates: Vec<PathBuf> = prune_files(&files.files, format, &date_range, options.prune && pruned).into_iter()
+        .filter(|file| !is_pruned_by_index(file, indexed_fields, &date_range))
+        .collect();
+    let streamed = discover_streamed_files::<F>(options);
+    let skipped = files.skipped + streamed.skipped + files.files.len() - candidates.len();
+    estimate::print_estimate(&candidates, streamed.files.len(), skipped, format).unwrap_or_else(|e| exit_with_error(&e));
+}
+
+// Files of the --ssh hosts, or objects of s3:// and gs:// urls, selected as they are when queried.
+fn discover_streamed_files<F: LogFormat>(options: &Options) -> DiscoveredFiles {
+    let selector = FileSelector::new(&options.include, &options.exclude, F::is_log_file).unwrap_or_else(|e| exit_with_error(&e));
+    let mut discovered = DiscoveredFiles::default();
+    for host in &options.ssh {
+        let files = remote::discover_files(host, &options.paths, &selector).unwrap_or_else(|e| exit_with_error(&e));
+        discovered.files.extend(files.files);
+        discovered.skipped += files.skipped;
+    }
+    if is_object_storage(options) {
+        let mut listed = storage::ListedObjects::default();
+        for url in options.paths.iter().filter(|p| storage::is_object_url(p)) {
+            storage::list_objects(url, &selector, &mut listed).unwrap_or_else(|e| exit_with_error(&e));
+        }
+        discovered.files.extend(listed.objects.files);
+        discovered.skipped += listed.objects.skipped;
+    }
+    discovered
+}
+
+// Whether the index of a file, of the format's fields, shows that none of its lines is within the dates.
+fn is_pruned_by_index(file: &Path, indexed_fields: Option<usize>, date_range: &query::DateRange) -> bool {
+    match indexed_fields.and_then(|_| FileIndex::open(file).unwrap_or(None)) {
+        Some(ref file_index) => Some(file_index.fields()) == indexed_fields && !file_index.may_match(date_range),
+        None => false,
+    }
+}
+
+// Files that may hold records within the date range filtered on, all of them when pruning is
+// disabled or the query has no date range.
+fn prune_files<F: LogFormat>(files: &Vec<PathBuf>, format: &F, date_range: &query::DateRange, prune: bool) -> Vec<PathBuf> {
+    match FilePruner::new(format, date_range) {
+        Some(ref pruner) if prune => files.iter().filter(|f| pruner.may_match(f)).cloned().collect(),
+        _ => files.clone(),
+    }
+}
+
+// Prints each column of the format with its type and size, in the order the format defines them.
+fn print_schema<T>(definition: &TableDefinition<T>) {
+    let width = definition.ordered_columns.iter().map(|c| c.len()).max().unwrap_or(0).max("Column".len());
+    println!("{:<width$}  {:<7}  {}", "Column", "Type", "Size", width = width);
+    for name in &definition.ordered_columns {
+        let column = &definition.column_map[name];
+        println!("{:<width$}  {:<7}  {}", name, column.kind_name(), column.get_size(), width = width);
+    }
+}
+
+// With more than one thread, compressed files are decompressed on background threads, up to one
+// file per spare thread ahead of the file being evaluated, from where they are resumed. Files that
+// may be read from their index are left alone.
+fn start_decompressing(files: &[PathBuf], indexable: bool, threads: usize, starts: &HashMap<PathBuf, u64>, decompressing: &mut HashMap<PathBuf, BackgroundInput>) {
+    for file in files.iter().take(threads.saturating_sub(1)) {
+        let compressed = input::detect_compression(file).map(|c| c != Compression::Plain).unwrap_or(false);
+        let indexed = indexable && index::index_path(file).exists();
+        if compressed && !indexed && !decompressing.contains_key(file) {
+            decompressing.insert(file.to_owned(), input::open_background_input(file, starts.get(file).cloned().unwrap_or(0)));
+        }
+    }
+}
+
+// Indexes every discovered log file, skipping files whose index is already up to date.
+// Indexes are only written for nginx logs, the one format with index fields.
+fn build_indexes<F: LogFormat>(options: &Options, format: F) {
+    let files = discover_files(options, F::is_log_file);
+    let mut records = 0;
+    let mut indexed = 0;
+    for file in &files.files {
+        if FileIndex::open(file).unwrap_or(None).is_some() {
+            println!("Up to date: {}", file.display());
+            continue;
+        }
+        let count = index::build_index(file, &format).unwrap_or_else(|e| exit_with_error(&format!("Unable to index {}: {}", file.display(), e)));
+        println!("Indexed:    {} ({} records)", file.display(), count);
+        records += count;
+        indexed += 1;
+    }
+    println!("Indexed {} records from {} files ({} up to date)", records, indexed, files.files.len() - indexed);
+}
+
+fn parse_query(query: &str) -> RipLogQuery {
+    parser::parse_query(query.to_owned()).unwrap_or_else(|e| exit_with_error(&e))
+}
+
+fn create_evaluator<T>(query: RipLogQuery, definition: TableDefinition<T>, allow_missing_columns: bool, output: &OutputOptions) -> QueryEvaluator<T> {
+    // Results are nested by the group keys of the last stage, the one whose rows are written
+    let mut last = &query;
+    while let Some(ref next) = last.next {
+        last = next;
+    }
+    if output.nest && last.grouping.is_none() {
+        exit_with_error::<_, ()>(&"--nest requires a grouped query, e.g. 'group method, path | show count(*)'");
+    }
+    let mut missing = Vec::new();
+    if allow_missing_columns {
+        missing = query::validate_riplog_query_allow_missing(&query, &definition);
+    } else {
+        query::validate_riplog_query(&query, &definition).unwrap_or_else(|e| exit_with_error(&e));
+    }
+    let out = output::open_output(output).unwrap_or_else(|e| exit_with_error(&format!("Unable to open output file: {}", e)));
+    let mut evaluator = QueryEvaluator::<T>::new(query, definition, output, out);
+    for warning in missing {
+        evaluator.warn(warning);
+    }
+    evaluator
+}
+
+// Results whose reader went away, e.g. `head`, were cut short as asked rather than by an error.
+fn close_output() {
+    output::close_pager();
+    if let Some(error) = output::write_error() {
+        exit_with_error::<_, ()>(&format!("Unable to write the results: {}", error));
+    }
+}
+
+fn exit_with_error<E: fmt::Display, R>(error: &E) -> R {
+    output::close_pager();
+    eprintln!("Error: {}", error);
+    process::exit(EXIT_ERROR);
+}
+
+// Tails a single log file, feeding appended lines to the evaluator until interrupted. Aggregate
+// queries print a rolling summary every interval and a final summary on Ctrl-C, and those with
+// `every` print each window once it has ended.
+fn follow_query<F: LogFormat>(path: &Path, evaluator: &mut QueryEvaluator<F::Record>, format: &F, interval: Duration, alert: Option<&AlertOptions>) -> io::Result<()> {
+    let running = Arc::new(AtomicBool::new(true));
+    let handler_running = running.clone();
+    ctrlc::set_handler(move || handler_running.store(false, Ordering::SeqCst)).unwrap();
+
+    let mut file = File::open(path)?;
+    let mut file_id = input::file_identity(&file.metadata()?);
+    let mut position = file.seek(SeekFrom::End(0))?;
+    let mut reader = BufReader::new(file);
+    let mut buf = vec![];
+    let mut record = format.empty_record();
+    format.project_record(evaluator.required_columns(), &mut record);
+    let mut header = false;
+    let mut last_summary = Instant::now();
+
+    while running.load(Ordering::SeqCst) && !evaluator.should_stop() {
+        let size = reader.read_until(b'\n', &mut buf)?;
+        if size > 0 {
+            position += size as u64;
+            // Wait for the rest of a partially written line
+            if buf.last() == Some(&b'\n') {
+                if header {
+                    header = false;
+                } else if format.read_record(&buf, &mut record) {
+                    evaluator.evaluate(&mut record);
+                } else {
+                    evaluator.skip_unparsed();
+                }
+                buf.clear();
             }
+            continue;
+        }
+
+        if was_rotated(path, file_id, position) {
+            file = File::open(path)?;
+            file_id = input::file_identity(&file.metadata()?);
+            position = 0;
+            reader = BufReader::new(file);
+            header = format.has_header();
             buf.clear();
-            let size = reader.read_until(b'\n', &mut buf).unwrap();
-            if size <= 0 {
+        }
+
+        // Tumbling windows are printed as each ends, rather than partially every interval
+        if evaluator.is_windowed() {
+            evaluator.close_elapsed_window();
+        } else if evaluator.is_aggregate() && Instant::now() - last_summary >= interval {
+            evaluator.print_summary();
+            last_summary = Instant::now();
+        }
+        evaluator.flush();
+        raise_alerts(evaluator, alert);
+        report_warnings(evaluator);
+        thread::sleep(Duration::from_millis(250));
+    }
+    evaluator.finalize();
+    raise_alerts(evaluator, alert);
+    report_warnings(evaluator);
+    Ok(())
+}
+
+// Prints a line to stderr for each result row that met the alert condition since the last check,
+// then runs the alert command, or stops following, as asked.
+fn raise_alerts<T>(evaluator: &mut QueryEvaluator<T>, alert: Option<&AlertOptions>) {
+    let alert = match alert {
+        Some(alert) => alert,
+        None => return,
+    };
+    let rows = evaluator.take_alerts();
+    if rows.is_empty() {
+        return;
+    }
+    let now = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+    for row in &rows {
+        eprintln!("Alert at {}: {} ({})", now, alert.condition, row);
+    }
+    if let Some(ref command) = alert.command {
+        let status = process::Command::new("sh").arg("-c").arg(command)
+            .env("RIPLOG_ALERT", &alert.condition)
+            .env("RIPLOG_ALERT_ROWS", rows.join("\n"))
+            .status();
+        match status {
+            Ok(status) if !status.success() => eprintln!("Warning: alert command exited with {}", status),
+            Err(e) => eprintln!("Warning: unable to run alert command: {}", e),
+            _ => (),
+        }
+    }
+    if alert.exit {
+        evaluator.flush();
+        process::exit(EXIT_ALERT);
+    }
+}
+
+// A log is considered rotated when the path now points at a different file or at a file shorter
+// than what has already been read (truncated in place).
+fn was_rotated(path: &Path, file_id: u64, position: u64) -> bool {
+    fs::metadata(path).map(|m| input::file_identity(&m) != file_id || m.len() < position).unwrap_or(false)
+}
+
+// Reads up to count lines from the start of the first non-empty log file, used to sample formats
+// whose columns are derived from the log itself. Returns the file sampled with its lines.
+// Lines of container logs are sampled as the lines they wrap.
+fn read_sample_lines(files: &DiscoveredFiles, count: usize, container: bool) -> io::Result<Option<(PathBuf, Vec<Vec<u8>>)>> {
+    for file in &files.files {
+        let mut reader = input::open_input(file)?;
+        let mut lines = Vec::new();
+        while lines.len() < count {
+            let mut buf = vec![];
+            if reader.read_until(b'\n', &mut buf)? == 0 {
                 break;
             }
-            nginx::read_log_record_binary(&buf, size, &mut record);
-            evaluator.evaluate(&mut record);
+            if !container {
+                lines.push(buf);
+            } else if let Some(unwrapped) = container::unwrap_line(&buf).filter(|u| !u.partial) {
+                lines.push(unwrapped.log);
+            }
+        }
+        if !lines.is_empty() {
+            return Ok(Some((file.clone(), lines)));
         }
     }
+    Ok(None)
+}
+
+fn evaluate_indexed_file<F: LogFormat>(file_index: &FileIndex, evaluator: &mut QueryEvaluator<F::Record>, format: &F, progress: &mut Progress) -> io::Result<()> {
+    let indexed = file_index.load()?;
+    let mut record = format.empty_record();
+    format.project_record(evaluator.required_columns(), &mut record);
+    let mut fields = Vec::new();
+
+    for idx in 0..indexed.records() {
+        if evaluator.should_stop() {
+            break;
+        }
+        indexed.read_fields(idx, &mut fields);
+        format.read_indexed_record(&fields, &mut record);
+        evaluator.evaluate(&mut record);
+        progress.lines_read(1, 0);
+    }
     Ok(())
 }
+
+// Only the lines of the file within the span are evaluated. Compressed files can't be read from their
+// end, so they are read whole when only their last bytes are asked for. Files resumed from a checkpoint
+// are read from the position given, and only up to their last whole line when uncompressed, the line
+// still being written being left for the next run. Returns the position reached in the file.
+fn evaluate_query_log_file<F: LogFormat>(file: &Path, background: Option<BackgroundInput>, evaluator: &mut QueryEvaluator<F::Record>, format: &F, prefilter: &LinePrefilter,
+                                         span: &FileSpan, resume: Option<u64>, progress: &mut Progress) -> io::Result<u64> {
+    if span.tail_bytes.is_some() && input::detect_compression(file)? != Compression::Plain {
+        eprintln!("Warning: {} is compressed, so it is read whole rather than from its last bytes", file.display());
+    }
+    let whole_lines = resume.is_some() && input::detect_compression(file)? == Compression::Plain;
+    if let Some(background) = background {
+        return evaluate_background_input(background, evaluator, format, prefilter, span, resume.unwrap_or(0), whole_lines, progress);
+    }
+    if let Some(mapped) = input::map_input(file)? {
+        return Ok(evaluate_mapped_log_file(&mapped, evaluator, format, prefilter, span, resume, progress));
+    }
+    let (reader, position) = input::open_counted_input_from(file, resume.unwrap_or(0))?;
+    let header = format.has_header() && position.get() == 0;
+    evaluate_stream(reader, position, evaluator, format, prefilter, span, header, whole_lines, progress)
+}
+
+// Files that aren't local, e.g. of another host, are streamed by a command, on a thread of their own
+// with more than one thread.
+fn evaluate_streamed_file<F: LogFormat, O>(open: O, threads: usize, evaluator: &mut QueryEvaluator<F::Record>, format: &F, prefilter: &LinePrefilter,
+                                           span: &FileSpan, progress: &mut Progress) -> io::Result<()>
+    where O: FnOnce() -> io::Result<(Box<BufRead>, Rc<Cell<u64>>)> + Send + 'static {
+    if threads > 1 {
+        return evaluate_background_input(input::open_background(open), evaluator, format, prefilter, span, 0, false, progress).map(|_| ());
+    }
+    let (reader, position) = open()?;
+    evaluate_stream(reader, position, evaluator, format, prefilter, span, format.has_header(), false, progress).map(|_| ())
+}
+
+// Evaluates the lines of a stream chunk by chunk, returning the position reached in the file. With
+// whole_lines, a last line without its newline yet is left out, along with the position past it.
+fn evaluate_stream<F: LogFormat>(mut reader: Box<BufRead>, position: Rc<Cell<u64>>, evaluator: &mut QueryEvaluator<F::Record>, format: &F, prefilter: &LinePrefilter,
+                                 span: &FileSpan, mut header: bool, whole_lines: bool, progress: &mut Progress) -> io::Result<u64> {
+    let mut data = Vec::new();
+    let mut record = format.empty_record();
+    format.project_record(evaluator.required_columns(), &mut record);
+    let mut lines = span.lines();
+    let mut partial = 0;
+
+    while !evaluator.should_stop() {
+        data.clear();
+        input::read_chunk(&mut reader, &mut data)?;
+        if data.is_empty() {
+            break;
+        }
+        let whole = if whole_lines { whole_lines_end(&data) } else { data.len() };
+        partial = (data.len() - whole) as u64;
+        let start = if header { line_end(&data[..whole], 0) } else { 0 };
+        header = false;
+        let selected = match lines.select(&data[start..whole]) {
+            Some(selected) => selected,
+            None => break,
+        };
+        let read = evaluator.evaluate_batch(selected, format, prefilter, &mut record);
+        progress.lines_read(read as u64, position.get());
+    }
+    Ok(position.get() - partial)
+}
+
+// Mapped files are evaluated in chunks of lines the same size as those read from a stream.
+fn evaluate_mapped_log_file<F: LogFormat>(data: &[u8], evaluator: &mut QueryEvaluator<F::Record>, format: &F, prefilter: &LinePrefilter, span: &FileSpan,
+                                          resume: Option<u64>, progress: &mut Progress) -> u64 {
+    let (data, first) =
+        match resume {
+            Some(position) => {
+                let whole = whole_lines_end(data);
+                (&data[..whole], (position as usize).min(whole))
+            },
+            None => (data, 0),
+        };
+    let mut record = format.empty_record();
+    format.project_record(evaluator.required_columns(), &mut record);
+    let mut start = span.tail_start(data, if first == 0 && format.has_header() { line_end(data, 0) } else { first });
+    let mut lines = span.lines();
+
+    while start < data.len() && !evaluator.should_stop() {
+        let end = input::chunk_end(data, start);
+        let selected = match lines.select(&data[start..end]) {
+            Some(selected) => selected,
+            None => break,
+        };
+        let read = evaluator.evaluate_batch(selected, format, prefilter, &mut record);
+        progress.lines_read(read as u64, end as u64);
+        start = end;
+    }
+    start as u64
+}
+
+// Evaluates the chunks of lines of a file decompressed on another thread, from the position given,
+// leaving out a last line still being written with whole_lines as evaluate_stream does.
+fn evaluate_background_input<F: LogFormat>(input: BackgroundInput, evaluator: &mut QueryEvaluator<F::Record>, format: &F, prefilter: &LinePrefilter, span: &FileSpan,
+                                           start: u64, whole_lines: bool, progress: &mut Progress) -> io::Result<u64> {
+    let mut record = format.empty_record();
+    format.project_record(evaluator.required_columns(), &mut record);
+    let mut header = format.has_header() && start == 0;
+    let mut position = start;
+    let mut lines = span.lines();
+    for chunk in input {
+        if evaluator.should_stop() {
+            break;
+        }
+        let chunk = chunk?;
+        let whole = if whole_lines { whole_lines_end(&chunk.data) } else { chunk.data.len() };
+        let start = if header { line_end(&chunk.data[..whole], 0) } else { 0 };
+        header = false;
+        let selected = match lines.select(&chunk.data[start..whole]) {
+            Some(selected) => selected,
+            None => break,
+        };
+        let read = evaluator.evaluate_batch(selected, format, prefilter, &mut record);
+        progress.lines_read(read as u64, chunk.position);
+        position = chunk.position - (chunk.data.len() - whole) as u64;
+    }
+    Ok(position)
+}
+
+// The position just past the line starting at start, including its newline.
+fn line_end(data: &[u8], start: usize) -> usize {
+    data[start..].iter().position(|b| *b == b'\n').map(|idx| start + idx + 1).unwrap_or(data.len())
+}
+
+// The position just past the last newline, leaving out a line still being written.
+fn whole_lines_end(data: &[u8]) -> usize {
+    data.iter().rposition(|b| *b == b'\n').map(|idx| idx + 1).unwrap_or(0)
+}
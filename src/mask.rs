@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::IpAddr;
+use std::path::Path;
+use std::rc::Rc;
+
+use byteorder::{ByteOrder, LittleEndian};
+use format::LogFormat;
+use table::{self, ColumnDefinition, TableDefinition, WrappedRecord};
+
+// How the values of a masked column are shown: as a hash, the same for equal values so that they
+// still group together, or truncated, addresses to their network (/24 or /48) and other text to its
+// first characters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaskMode {
+    Hash,
+    Truncate,
+}
+
+const TRUNCATED_CHARS: usize = 8;
+
+// Key of the hashes of masked values. Without it, hashes can't be found back by hashing every value
+// they may be of, e.g. every IPv4 address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MaskKey(u64, u64);
+
+impl MaskKey {
+    // A key of this run only, whose hashes can't be compared with those of other runs
+    pub fn random() -> MaskKey {
+        let state = RandomState::new();
+        let mut first = state.build_hasher();
+        first.write_u8(0);
+        let mut second = state.build_hasher();
+        second.write_u8(1);
+        MaskKey(first.finish(), second.finish())
+    }
+
+    // A key derived from a secret, so that runs given the same secret hash values alike
+    pub fn from_secret(secret: &str) -> MaskKey {
+        MaskKey(siphash(&MaskKey(0, 0), secret.as_bytes()), siphash(&MaskKey(0, 1), secret.as_bytes()))
+    }
+}
+
+// SipHash-2-4, written out rather than taken from std, whose hashers may change from one release of
+// Rust to the next.
+fn siphash(key: &MaskKey, data: &[u8]) -> u64 {
+    let mut v = [key.0 ^ 0x736f_6d65_7073_6575, key.1 ^ 0x646f_7261_6e64_6f6d, key.0 ^ 0x6c79_6765_6e65_7261, key.1 ^ 0x7465_6462_7974_6573];
+    let whole = data.len() - data.len() % 8;
+    for chunk in data[..whole].chunks(8) {
+        let word = LittleEndian::read_u64(chunk);
+        v[3] ^= word;
+        sip_round(&mut v);
+        sip_round(&mut v);
+        v[0] ^= word;
+    }
+    let mut last = (data.len() as u64) << 56;
+    for (idx, byte) in data[whole..].iter().enumerate() {
+        last |= (*byte as u64) << (8 * idx);
+    }
+    v[3] ^= last;
+    sip_round(&mut v);
+    sip_round(&mut v);
+    v[0] ^= last;
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+impl MaskMode {
+    fn from_string(mode: &str) -> Option<MaskMode> {
+        match mode {
+            "hash" => Some(MaskMode::Hash),
+            "truncate" => Some(MaskMode::Truncate),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, value: &str, key: &MaskKey) -> String {
+        match self {
+            MaskMode::Hash => format!("{:012x}", siphash(key, value.as_bytes()) >> 16),
+            MaskMode::Truncate =>
+                match value.parse::<IpAddr>() {
+                    Ok(IpAddr::V4(address)) => {
+                        let octets = address.octets();
+                        format!("{}.{}.{}.0", octets[0], octets[1], octets[2])
+                    },
+                    Ok(IpAddr::V6(address)) => {
+                        let segments = address.segments();
+                        format!("{:x}:{:x}:{:x}::", segments[0], segments[1], segments[2])
+                    },
+                    Err(_) => value.chars().take(TRUNCATED_CHARS).collect(),
+                },
+        }
+    }
+}
+
+// Masks the values of text and ip columns, e.g. `--mask ip,user_agent:truncate`, so that results can
+// be shared without personal data. A masked column only ever holds its masked values, in results,
+// group keys, reducers and filters alike, so they are masked as each record is read.
+pub struct MaskFormat<F: LogFormat> {
+    format: F,
+    columns: Rc<Vec<ColumnDefinition<F::Record>>>,
+    names: Vec<String>,
+    masks: Rc<Vec<Option<MaskMode>>>,
+    key: MaskKey,
+}
+
+#[derive(Clone)]
+pub struct MaskedRecord<R> {
+    record: R,
+    columns: Rc<Vec<ColumnDefinition<R>>>,
+    masks: Rc<Vec<Option<MaskMode>>>,
+    key: MaskKey,
+    // Masked values of the masked columns of the record
+    masked: Vec<Option<String>>,
+}
+
+impl<F: LogFormat> MaskFormat<F> {
+    // The columns are given as name[:mode], hashed unless truncate is given as the mode.
+    pub fn new(format: F, columns: &str, key: MaskKey) -> Result<MaskFormat<F>, String> {
+        let mut definition = format.table_definition();
+        let mut masks = vec![None; definition.ordered_columns.len()];
+        for column in columns.split(',').map(|c| c.trim()).filter(|c| !c.is_empty()) {
+            let (name, mode) =
+                match column.find(':') {
+                    Some(idx) => (&column[0..idx], MaskMode::from_string(&column[idx+1..])
+                                  .ok_or(format!("Invalid mask mode in '{}', expected hash or truncate", column))?),
+                    None => (column, MaskMode::Hash),
+                };
+            let idx = definition.ordered_columns.iter().position(|c| c == name).ok_or(format!("Unable to mask unknown column '{}'", name))?;
+            match definition.column_map.get(name) {
+                Some(ColumnDefinition::Text { .. }) | Some(ColumnDefinition::Ip { .. }) => masks[idx] = Some(mode),
+                Some(other) => return Err(format!("Only text and ip columns can be masked, '{}' is {}", name, other.kind_name())),
+                None => (),
+            }
+        }
+        let names = definition.ordered_columns.clone();
+        let columns = names.iter().map(|n| definition.column_map.remove(n).unwrap()).collect();
+        Ok(MaskFormat { format: format, columns: Rc::new(columns), names: names, masks: Rc::new(masks), key: key })
+    }
+}
+
+impl<R> WrappedRecord for MaskedRecord<R> {
+    type Inner = R;
+
+    fn inner(&self) -> &R {
+        &self.record
+    }
+
+    fn inner_mut(&mut self) -> &mut R {
+        &mut self.record
+    }
+
+    fn inner_columns(&self) -> &Vec<ColumnDefinition<R>> {
+        &self.columns
+    }
+}
+
+impl<F: LogFormat> LogFormat for MaskFormat<F> {
+    type Record = MaskedRecord<F::Record>;
+
+    fn empty_record(&self) -> MaskedRecord<F::Record> {
+        MaskedRecord { record: self.format.empty_record(), columns: self.columns.clone(), masks: self.masks.clone(), key: self.key, masked: vec![None; self.columns.len()] }
+    }
+
+    fn read_record(&self, line: &[u8], record: &mut MaskedRecord<F::Record>) -> bool {
+        let read = self.format.read_record(line, &mut record.record);
+        if read {
+            mask_record(record);
+        }
+        read
+    }
+
+    fn project_record(&self, columns: &Vec<String>, record: &mut MaskedRecord<F::Record>) {
+        self.format.project_record(columns, &mut record.record);
+    }
+
+    fn table_definition(&self) -> TableDefinition<MaskedRecord<F::Record>> {
+        let mut column_map = HashMap::new();
+        for (idx, column) in self.columns.iter().enumerate() {
+            let column =
+                match (column, self.masks[idx]) {
+                    (ColumnDefinition::Text { name, size, .. }, Some(_)) =>
+                        ColumnDefinition::Text { name: name.to_owned(), size: *size, field: idx, binary_extractor: masked_bytes, extractor: masked_text },
+                    (ColumnDefinition::Ip { name, size, .. }, Some(_)) =>
+                        ColumnDefinition::Ip { name: name.to_owned(), size: *size, field: idx, binary_extractor: masked_bytes, extractor: masked_text,
+                                               address_extractor: masked_address },
+                    _ => table::wrap_column(column, idx),
+                };
+            column_map.insert(self.names[idx].to_owned(), column);
+        }
+        TableDefinition { column_map: column_map, ordered_columns: self.names.clone() }
+    }
+
+    fn has_header(&self) -> bool {
+        self.format.has_header()
+    }
+
+    fn index_fields<'a>(&self, record: &'a MaskedRecord<F::Record>) -> Option<Vec<&'a [u8]>> {
+        self.format.index_fields(&record.record)
+    }
+
+    fn read_indexed_record(&self, fields: &Vec<&[u8]>, record: &mut MaskedRecord<F::Record>) {
+        self.format.read_indexed_record(fields, &mut record.record);
+        mask_record(record);
+    }
+
+    // Lines hold the original values of masked columns, so they can only be skipped on other columns
+    fn verbatim_columns(&self) -> Vec<&'static str> {
+        let masked: Vec<&String> = self.names.iter().enumerate().filter(|&(idx, _)| self.masks[idx].is_some()).map(|(_, n)| n).collect();
+        self.format.verbatim_columns().into_iter().filter(|c| !masked.iter().any(|m| m == c)).collect()
+    }
+
+    fn is_log_file(file: &Path) -> bool {
+        F::is_log_file(file)
+    }
+}
+
+fn mask_record<R>(record: &mut MaskedRecord<R>) {
+    for idx in 0..record.masked.len() {
+        record.masked[idx] =
+            match record.masks[idx] {
+                Some(mode) => {
+                    let key = record.key;
+                    table::wrapped_text(record, idx).map(|value| mode.apply(value, &key))
+                },
+                None => None,
+            };
+    }
+}
+
+fn masked_bytes<R>(r: &MaskedRecord<R>, idx: usize) -> Option<&[u8]> {
+    r.masked[idx].as_ref().map(|m| m.as_bytes())
+}
+
+fn masked_text<R>(r: &mut MaskedRecord<R>, idx: usize) -> Option<&str> {
+    r.masked[idx].as_ref().map(|m| m.as_str())
+}
+
+// Truncated addresses are still addresses, of their network, while hashes aren't
+fn masked_address<R>(r: &mut MaskedRecord<R>, idx: usize) -> Option<IpAddr> {
+    r.masked[idx].as_ref().and_then(|m| m.parse::<IpAddr>().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vectors of the SipHash paper, keyed with the bytes 0 to 15, of the messages of the bytes 0
+    // to n - 1
+    #[test]
+    fn siphash_matches_the_reference_vectors() {
+        let key = MaskKey(LittleEndian::read_u64(&[0, 1, 2, 3, 4, 5, 6, 7]), LittleEndian::read_u64(&[8, 9, 10, 11, 12, 13, 14, 15]));
+        let message: Vec<u8> = (0..15).collect();
+        assert_eq!(siphash(&key, &message[..0]), 0x726f_db47_dd0e_0e31);
+        assert_eq!(siphash(&key, &message[..8]), 0x93f5_f579_9a93_2462);
+        assert_eq!(siphash(&key, &message[..15]), 0xa129_ca61_49be_45e5);
+    }
+
+    #[test]
+    fn hashes_depend_on_the_key() {
+        let first = MaskMode::Hash.apply("10.0.0.1", &MaskKey::from_secret("first"));
+        assert_eq!(first, MaskMode::Hash.apply("10.0.0.1", &MaskKey::from_secret("first")));
+        assert!(first != MaskMode::Hash.apply("10.0.0.1", &MaskKey::from_secret("second")));
+        assert!(MaskKey::random() != MaskKey::random());
+    }
+}
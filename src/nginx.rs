@@ -1,126 +1,247 @@
 use std::str;
+use std::path::Path;
+use std::net::IpAddr;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::cell::OnceCell;
 
 use chrono::prelude::*;
 use table::{TableDefinition, ColumnDefinition};
+use format::LogFormat;
+use discovery;
+use geoip::{GeoIp, GeoInfo};
+use route::RouteNormalizer;
 use byteorder::{BigEndian, ReadBytesExt};
+use cidr;
+use memchr::{memchr, memrchr};
 
-pub fn read_log_record_binary(buf: &Vec<u8>, len: usize, record: &mut BinaryNginxLogRecord) {
-    let empty: &[u8] = &[];
-    let working = &buf[0..len];
+pub struct NginxFormat {
+    pub geoip: Option<Arc<GeoIp>>,
+    pub routes: Arc<RouteNormalizer>,
+}
+
+impl LogFormat for NginxFormat {
+    type Record = BinaryNginxLogRecord;
+
+    fn empty_record(&self) -> BinaryNginxLogRecord {
+        let mut record = BinaryNginxLogRecord::empty();
+        record.geoip = self.geoip.clone();
+        record.routes = self.routes.clone();
+        record
+    }
+
+    fn read_record(&self, line: &[u8], record: &mut BinaryNginxLogRecord) -> bool {
+        read_log_record_binary(line, record)
+    }
+
+    fn table_definition(&self) -> TableDefinition<BinaryNginxLogRecord> {
+        let mut definition = create_nginx_log_record_table_definition();
+        if self.geoip.is_some() {
+            add_geoip_columns(&mut definition);
+        }
+        definition
+    }
 
-    let space_idx = index_of(working, b' ').unwrap();
+    fn verbatim_columns(&self) -> Vec<&'static str> {
+        vec!["ip", "username", "method", "path", "query", "status", "bytes", "referrer", "user_agent"]
+    }
+
+    fn project_record(&self, columns: &Vec<String>, record: &mut BinaryNginxLogRecord) {
+        record.project(columns);
+    }
+
+    fn index_fields<'a>(&self, record: &'a BinaryNginxLogRecord) -> Option<Vec<&'a [u8]>> {
+        Some(record.fields())
+    }
+
+    fn read_indexed_record(&self, fields: &Vec<&[u8]>, record: &mut BinaryNginxLogRecord) {
+        record.set_fields(fields);
+    }
+
+    fn is_log_file(file: &Path) -> bool {
+        discovery::is_access_log(file)
+    }
+}
+
+// Returns false, leaving the record untouched, when the line is not in the combined log format.
+pub fn read_log_record_binary(line: &[u8], record: &mut BinaryNginxLogRecord) -> bool {
+    match split_log_line(line) {
+        Some(fields) => {
+            record.set_fields(&fields);
+            true
+        },
+        None => false,
+    }
+}
+
+fn split_log_line(line: &[u8]) -> Option<[&[u8]; FIELD_COUNT]> {
+    let working = line;
+
+    let space_idx = index_of(working, b' ')?;
     let ip = &working[0..space_idx];
     let working = &working[space_idx+1..working.len()];
 
-    let space_idx = index_of(working, b' ').unwrap();
+    let space_idx = index_of(working, b' ')?;
     let working = &working[space_idx+1..working.len()];
-    let space_idx = index_of(working, b' ').unwrap();
+    let space_idx = index_of(working, b' ')?;
     let username = &working[0..space_idx];
     let working = &working[space_idx+1..working.len()];
 
-    let brace_idx = index_of(working, b']').unwrap();
-    let date = &working[1..brace_idx];
-    let working = &working[brace_idx+3..working.len()];
+    let brace_idx = index_of(working, b']')?;
+    let date = working.get(1..brace_idx)?;
+    let working = working.get(brace_idx+3..working.len())?;
 
-    let quote_idx = index_of(working, b'"').unwrap();
+    let quote_idx = index_of(working, b'"')?;
     let request = &working[0..quote_idx];
-    let working = &working[quote_idx+2..working.len()];
+    let working = working.get(quote_idx+2..working.len())?;
 
-    let req_space_idx = index_of(request, b' ');
-    let (method, path, query) =
-        if req_space_idx.is_some() {
-            let method = &request[0..req_space_idx.unwrap()];
-            let req_working = &request[req_space_idx.unwrap()+1..request.len()];
-            let req_space_idx = index_of(req_working, b' ');
-            let req_question_idx = index_of(req_working, b'?');
-            let path =
-                if req_question_idx.is_some() {
-                    &req_working[0..req_question_idx.unwrap()]
-                } else if req_space_idx.is_some() {
-                    &req_working[0..req_space_idx.unwrap()]
-                } else {
-                    req_working
-                };
-            let query =
-                if req_question_idx.is_some() {
-                    if req_space_idx.is_some() {
-                        &req_working[req_question_idx.unwrap()..req_space_idx.unwrap()]
-                    } else {
-                        &req_working[req_question_idx.unwrap()..]
-                    }
-                } else {
-                    empty
-                };
-            (method, path, query)
-        } else {
-            (empty, request, empty)
-        };
+    let (method, path, query) = split_request(request);
     
-    let space_idx = index_of(working, b' ').unwrap();
+    let space_idx = index_of(working, b' ')?;
     let status = &working[0..space_idx];
     let working = &working[space_idx+1..working.len()];
 
-    let space_idx = index_of(working, b' ').unwrap();
+    let space_idx = index_of(working, b' ')?;
     let bytes = &working[0..space_idx];
     let working = &working[space_idx+1..working.len()];
 
-    let space_idx = index_of(working, b' ').unwrap();
+    let space_idx = index_of(working, b' ')?;
 
-    let referrer = &working[1..space_idx-1];
+    let referrer = working.get(1..space_idx.checked_sub(1)?)?;
     let working = &working[space_idx+1..working.len()];
 
-    let user_agent = &working[1..working.len()-1];
-
-    record.ip = ip.to_vec();
-    record.username = or_empty(username.to_vec(), empty);
-    record.date = date.to_vec();
-    record.method = method.to_vec();
-    record.path = path.to_vec();
-    record.query = query.to_vec();
-    record.status = status.to_vec();
-    record.bytes = bytes.to_vec();
-    record.referrer = referrer.to_vec();
-    record.user_agent = user_agent.to_vec();
-
-    record.parsed_record.ip = None;
-    record.parsed_record.username = None;
-    record.parsed_record.date = None;
-    record.parsed_record.method = None;
-    record.parsed_record.path = None;
-    record.parsed_record.query = None;
-    record.parsed_record.status = None;
-    record.parsed_record.bytes = None;
-    record.parsed_record.referrer = None;
-    record.parsed_record.user_agent = None;
+    // Extended formats append unquoted fields after the user agent, usually $request_time and
+    // $upstream_response_time.
+    let quote_idx = memrchr(b'"', working).filter(|idx| *idx > 0)?;
+    if working.first() != Some(&b'"') {
+        return None;
+    }
+    let user_agent = &working[1..quote_idx];
+    let trailing = trim_spaces(&working[quote_idx+1..working.len()]);
+    let (request_time, upstream_time) =
+        match index_of(trailing, b' ') {
+            Some(space_idx) => (&trailing[0..space_idx], trim_spaces(&trailing[space_idx+1..trailing.len()])),
+            None => (trailing, &trailing[trailing.len()..]),
+        };
+
+    Some([ip, or_empty(username), date, method, path, query, status, bytes, referrer, user_agent,
+          or_empty(request_time), or_empty(upstream_time)])
 }
 
-fn or_empty(vec: Vec<u8>, empty: &[u8]) -> Vec<u8> {
-    if vec.len() == 1 && vec[0] == b'-' {
-        empty.to_vec()
+// Trims spaces, and the line ending of the last field.
+fn trim_spaces(working: &[u8]) -> &[u8] {
+    let start = working.iter().position(|b| *b != b' ').unwrap_or(working.len());
+    let end = working.iter().rposition(|b| *b != b' ' && *b != b'\n' && *b != b'\r').map(|idx| idx + 1).unwrap_or(start);
+    &working[start..end]
+}
+
+// Sum of the times nginx lists when a request was passed to several upstreams, e.g. "0.050, 0.070"
+// or "0.050 : 0.070".
+pub fn parse_upstream_time(value: &str) -> Option<f64> {
+    let times: Vec<f64> = value.split(|c| c == ',' || c == ':')
+        .filter_map(|t| t.trim().parse::<f64>().ok())
+        .collect();
+    if times.is_empty() {
+        None
     } else {
-        vec
+        Some(times.iter().sum())
     }
 }
 
-fn index_of(vec: &[u8], char: u8) -> Option<usize> {
-    let mut idx = 0;
-    let mut found_idx = None;
-    while idx < vec.len() {
-        if vec[idx] == char {
-            found_idx = Some(idx);
-            break;
-        }
-        idx += 1;
+// Time of a request spent outside its upstreams, to the millisecond as nginx logs times. Null for
+// requests that weren't passed to an upstream.
+pub fn nginx_time(request_time: Option<f64>, upstream_time: Option<f64>) -> Option<f64> {
+    match (request_time, upstream_time) {
+        (Some(request), Some(upstream)) if request > upstream => Some(((request - upstream) * 1000.0).round() / 1000.0),
+        (Some(_), Some(_)) => Some(0.0),
+        _ => None,
+    }
+}
+
+// A time computed from other columns of a record, e.g. nginx_time. Its text is only rendered when
+// its bytes are read, which most queries, comparing or reducing the number, never do.
+#[derive(Debug, Clone, Default)]
+pub struct DerivedTime {
+    value: Option<Option<f64>>,
+    text: OnceCell<String>,
+}
+
+impl DerivedTime {
+    pub fn clear(&mut self) {
+        self.value = None;
+        self.text.take();
+    }
+
+    pub fn is_computed(&self) -> bool {
+        self.value.is_some()
+    }
+
+    pub fn set(&mut self, value: Option<f64>) {
+        self.value = Some(value);
+        self.text.take();
+    }
+
+    pub fn value(&self) -> Option<f64> {
+        self.value.and_then(|v| v)
+    }
+
+    // Bytes of the time, only available once it has been computed.
+    pub fn bytes(&self) -> Option<&[u8]> {
+        let value = self.value()?;
+        Some(self.text.get_or_init(|| value.to_string()).as_bytes())
     }
-    found_idx
+}
+
+pub fn split_request(request: &[u8]) -> (&[u8], &[u8], &[u8]) {
+    let empty: &[u8] = &[];
+    let req_space_idx = index_of(request, b' ');
+    if req_space_idx.is_some() {
+        let method = &request[0..req_space_idx.unwrap()];
+        let req_working = &request[req_space_idx.unwrap()+1..request.len()];
+        let req_space_idx = index_of(req_working, b' ');
+        let req_question_idx = index_of(req_working, b'?');
+        let path =
+            if req_question_idx.is_some() {
+                &req_working[0..req_question_idx.unwrap()]
+            } else if req_space_idx.is_some() {
+                &req_working[0..req_space_idx.unwrap()]
+            } else {
+                req_working
+            };
+        let query =
+            if req_question_idx.is_some() {
+                if req_space_idx.is_some() {
+                    &req_working[req_question_idx.unwrap()..req_space_idx.unwrap()]
+                } else {
+                    &req_working[req_question_idx.unwrap()..]
+                }
+            } else {
+                empty
+            };
+        (method, path, query)
+    } else {
+        (empty, request, empty)
+    }
+}
+
+pub fn or_empty(value: &[u8]) -> &[u8] {
+    if value == b"-" {
+        &[]
+    } else {
+        value
+    }
+}
+
+// Splitting lines into fields is the hottest loop of a scan, memchr searches several bytes at a time.
+pub fn index_of(vec: &[u8], char: u8) -> Option<usize> {
+    memchr(char, vec)
 }
 
 fn is_empty(value: &str) -> bool {
     value == "-" || value == "\"-\""
 }
 
-fn empty_opt(bytes: &[u8]) -> Option<&[u8]> {
+pub fn empty_opt(bytes: &[u8]) -> Option<&[u8]> {
     if bytes.len() < 1 {
         None
     } else {
@@ -128,72 +249,193 @@ fn empty_opt(bytes: &[u8]) -> Option<&[u8]> {
     }
 }
 
+const FIELD_COUNT: usize = 12;
+
+// Fields a column is read from, derived columns being read from the fields they're derived from.
+fn column_fields(column: &str) -> &'static [usize] {
+    match column {
+        "ip" | "country" | "city" | "asn" => &[0],
+        "username" => &[1],
+        "date" => &[2],
+        "method" => &[3],
+        "path" | "route" => &[4],
+        "query" => &[5],
+        "status" | "status_class" => &[6],
+        "bytes" => &[7],
+        "referrer" => &[8],
+        "user_agent" => &[9],
+        "request_time" => &[10],
+        "upstream_time" => &[11],
+        "nginx_time" => &[10, 11],
+        _ => &[],
+    }
+}
+
+// The raw fields of a line are stored back to back in a single buffer that is reused from line to
+// line, with each field a span of it, so reading a record doesn't allocate once the buffer has grown.
 #[derive(Debug, Clone)]
 pub struct BinaryNginxLogRecord {
-    pub ip: Vec<u8>,
-    pub username: Vec<u8>,
-    pub date: Vec<u8>,
-    pub method: Vec<u8>,
-    pub path: Vec<u8>,
-    pub query: Vec<u8>,
-    pub status: Vec<u8>,
-    pub bytes: Vec<u8>,
-    pub referrer: Vec<u8>,
-    pub user_agent: Vec<u8>,
+    buffer: Vec<u8>,
+    spans: [(usize, usize); FIELD_COUNT],
+    pub geoip: Option<Arc<GeoIp>>,
+    pub routes: Arc<RouteNormalizer>,
     parsed_record: ParsedNginxLogRecord,
+    // Fields copied into the buffer, the others being left empty as no column the query reads needs them
+    projected: [bool; FIELD_COUNT],
 }
 
 // TODO: Parse query string separate from path (put in parameters in map -- lazy?)
 impl BinaryNginxLogRecord {
     pub fn empty() -> BinaryNginxLogRecord {
         BinaryNginxLogRecord {
-            ip: Vec::new(),
-            username: Vec::new(),
-            date: Vec::new(),
-            method: Vec::new(),
-            path: Vec::new(),
-            query: Vec::new(),
-            status: Vec::new(),
-            bytes: Vec::new(),
-            referrer: Vec::new(),
-            user_agent: Vec::new(),
+            buffer: Vec::new(),
+            spans: [(0, 0); FIELD_COUNT],
+            geoip: None,
+            routes: Arc::new(RouteNormalizer::default()),
             parsed_record: ParsedNginxLogRecord::empty(),
+            projected: [true; FIELD_COUNT],
         }
     }
 
+    // Replaces the fields of the record, in the order ip, username, date, method, path, query,
+    // status, bytes, referrer, user agent, request time and upstream time. Missing trailing fields
+    // are left empty.
+    pub fn set_fields(&mut self, fields: &[&[u8]]) {
+        self.buffer.clear();
+        let projected = self.projected;
+        for (idx, span) in self.spans.iter_mut().enumerate() {
+            let start = self.buffer.len();
+            if let Some(field) = fields.get(idx).filter(|_| projected[idx]) {
+                self.buffer.extend_from_slice(field);
+            }
+            *span = (start, self.buffer.len());
+        }
+        self.reset_parsed();
+    }
+
+    // Keeps only the fields the columns given are read from, columns of other formats being ignored.
+    pub fn project(&mut self, columns: &Vec<String>) {
+        self.projected = [false; FIELD_COUNT];
+        for field in columns.iter().flat_map(|c| column_fields(c)) {
+            self.projected[*field] = true;
+        }
+    }
+
+    pub fn fields(&self) -> Vec<&[u8]> {
+        self.spans.iter().map(|&(start, end)| &self.buffer[start..end]).collect()
+    }
+
+    fn field(&self, idx: usize) -> &[u8] {
+        let (start, end) = self.spans[idx];
+        &self.buffer[start..end]
+    }
+
+    pub fn ip(&self) -> &[u8] {
+        self.field(0)
+    }
+
+    pub fn username(&self) -> &[u8] {
+        self.field(1)
+    }
+
+    pub fn date(&self) -> &[u8] {
+        self.field(2)
+    }
+
+    pub fn method(&self) -> &[u8] {
+        self.field(3)
+    }
+
+    pub fn path(&self) -> &[u8] {
+        self.field(4)
+    }
+
+    pub fn query(&self) -> &[u8] {
+        self.field(5)
+    }
+
+    pub fn status(&self) -> &[u8] {
+        self.field(6)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        self.field(7)
+    }
+
+    pub fn referrer(&self) -> &[u8] {
+        self.field(8)
+    }
+
+    pub fn user_agent(&self) -> &[u8] {
+        self.field(9)
+    }
+
+    pub fn request_time(&self) -> &[u8] {
+        self.field(10)
+    }
+
+    pub fn upstream_time(&self) -> &[u8] {
+        self.field(11)
+    }
+
+    pub fn reset_parsed(&mut self) {
+        self.parsed_record.ip = None;
+        self.parsed_record.address = None;
+        self.parsed_record.username = None;
+        self.parsed_record.date = None;
+        self.parsed_record.method = None;
+        self.parsed_record.path = None;
+        self.parsed_record.query = None;
+        self.parsed_record.status = None;
+        self.parsed_record.bytes = None;
+        self.parsed_record.referrer = None;
+        self.parsed_record.user_agent = None;
+        self.parsed_record.request_time = None;
+        self.parsed_record.upstream_time = None;
+        self.parsed_record.nginx_time.clear();
+        self.parsed_record.route = None;
+        self.parsed_record.geo = None;
+    }
+
     pub fn parsed_ip(&mut self) -> &str {
         unsafe {
             if self.parsed_record.ip.is_some() {
                 &self.parsed_record.ip.as_ref().unwrap()
             } else {
-                self.parsed_record.ip = Some(String::from_utf8_unchecked(self.ip.clone()));
+                self.parsed_record.ip = Some(String::from_utf8_unchecked(self.ip().to_vec()));
                 &self.parsed_record.ip.as_ref().unwrap()
             }
         }
     }
 
+    pub fn parsed_address(&mut self) -> Option<IpAddr> {
+        if self.parsed_record.address.is_none() {
+            self.parsed_record.address = Some(cidr::parse_address(self.ip()));
+        }
+        self.parsed_record.address.unwrap()
+    }
+
     pub fn parsed_username(&mut self) -> Option<&str> {
         unsafe {
             if self.parsed_record.username.is_some() {
                 self.parsed_record.username.as_ref().unwrap().as_ref().map(|s| s.as_str())
             } else {
                 self.parsed_record.username =
-                    if self.username.len() < 1 { Some(None) }
-                    else { Some(Some(String::from_utf8_unchecked(self.username.clone()))) };
+                    if self.username().len() < 1 { Some(None) }
+                    else { Some(Some(String::from_utf8_unchecked(self.username().to_vec()))) };
                 self.parsed_record.username.as_ref().unwrap().as_ref().map(|s| s.as_str())
             }
         }
     }
 
-    pub fn parsed_date(&mut self) -> &DateTime<Local> {
-        unsafe {
-            if self.parsed_record.date.is_some() {
-                self.parsed_record.date.as_ref().unwrap()
-            } else {
-                self.parsed_record.date = DateTime::parse_from_str(&String::from_utf8_unchecked(self.date.clone()), "%d/%b/%Y:%H:%M:%S %z").ok().map(|d| d.with_timezone(&Local));
-                self.parsed_record.date.as_ref().unwrap()
-            }
+    // Dates that don't parse are null, as the rest of the line may still be of use
+    pub fn parsed_date(&mut self) -> Option<&DateTime<Local>> {
+        if self.parsed_record.date.is_none() {
+            self.parsed_record.date = Some(str::from_utf8(self.date()).ok()
+                .and_then(|d| DateTime::parse_from_str(d, "%d/%b/%Y:%H:%M:%S %z").ok())
+                .map(|d| d.with_timezone(&Local)));
         }
+        self.parsed_record.date.as_ref().unwrap().as_ref()
     }
 
     pub fn parsed_method(&mut self) -> Option<&str> {
@@ -202,8 +444,8 @@ impl BinaryNginxLogRecord {
                 self.parsed_record.method.as_ref().unwrap().as_ref().map(|s| s.as_str())
             } else {
                 self.parsed_record.method =
-                    if self.method.len() < 1 { Some(None) }
-                else { Some(Some(String::from_utf8_unchecked(self.method.clone()))) };
+                    if self.method().len() < 1 { Some(None) }
+                else { Some(Some(String::from_utf8_unchecked(self.method().to_vec()))) };
                 self.parsed_record.method.as_ref().unwrap().as_ref().map(|s| s.as_str())
             }
         }
@@ -214,7 +456,7 @@ impl BinaryNginxLogRecord {
             if self.parsed_record.path.is_some() {
                 &self.parsed_record.path.as_ref().unwrap()
             } else {
-                self.parsed_record.path = Some(String::from_utf8_unchecked(self.path.clone()));
+                self.parsed_record.path = Some(String::from_utf8_unchecked(self.path().to_vec()));
                 &self.parsed_record.path.as_ref().unwrap()
             }
         }
@@ -226,8 +468,8 @@ impl BinaryNginxLogRecord {
                 self.parsed_record.query.as_ref().unwrap().as_ref().map(|s| s.as_str())
             } else {
                 self.parsed_record.query =
-                    if self.query.len() < 1 { Some(None) }
-                else { Some(Some(String::from_utf8_unchecked(self.query.clone()))) };
+                    if self.query().len() < 1 { Some(None) }
+                else { Some(Some(String::from_utf8_unchecked(self.query().to_vec()))) };
                 self.parsed_record.query.as_ref().unwrap().as_ref().map(|s| s.as_str())
             }
         }
@@ -239,8 +481,8 @@ impl BinaryNginxLogRecord {
                 self.parsed_record.status.unwrap()
             } else {
                 self.parsed_record.status =
-                    if self.status.len() < 1 { Some(None) }
-                else { Some(String::from_utf8_unchecked(self.status.clone()).parse::<u64>().ok()) };
+                    if self.status().len() < 1 { Some(None) }
+                else { Some(String::from_utf8_unchecked(self.status().to_vec()).parse::<u64>().ok()) };
                 self.parsed_record.status.unwrap()
             }
         }
@@ -252,8 +494,8 @@ impl BinaryNginxLogRecord {
                 self.parsed_record.bytes.unwrap()
             } else {
                 self.parsed_record.bytes =
-                    if self.bytes.len() < 1 { Some(None) }
-                else { Some(String::from_utf8_unchecked(self.bytes.clone()).parse::<u64>().ok()) };
+                    if self.bytes().len() < 1 { Some(None) }
+                else { Some(String::from_utf8_unchecked(self.bytes().to_vec()).parse::<u64>().ok()) };
                 self.parsed_record.bytes.unwrap()
             }
         }
@@ -265,8 +507,8 @@ impl BinaryNginxLogRecord {
                 self.parsed_record.referrer.as_ref().unwrap().as_ref().map(|s| s.as_str())
             } else {
                 self.parsed_record.referrer =
-                    if self.referrer.len() < 1 { Some(None) }
-                else { Some(Some(String::from_utf8_unchecked(self.referrer.clone()))) };
+                    if self.referrer().len() < 1 { Some(None) }
+                else { Some(Some(String::from_utf8_unchecked(self.referrer().to_vec()))) };
                 self.parsed_record.referrer.as_ref().unwrap().as_ref().map(|s| s.as_str())
             }
         }
@@ -278,19 +520,98 @@ impl BinaryNginxLogRecord {
                 self.parsed_record.user_agent.as_ref().unwrap().as_ref().map(|s| s.as_str())
             } else {
                 self.parsed_record.user_agent =
-                    if self.user_agent.len() < 1 { Some(None) }
-                else { Some(Some(String::from_utf8_unchecked(self.user_agent.clone()))) };
+                    if self.user_agent().len() < 1 { Some(None) }
+                else { Some(Some(String::from_utf8_unchecked(self.user_agent().to_vec()))) };
                 self.parsed_record.user_agent.as_ref().unwrap().as_ref().map(|s| s.as_str())
             }
         }
     }
+
+    pub fn parsed_request_time(&mut self) -> Option<f64> {
+        if self.parsed_record.request_time.is_none() {
+            self.parsed_record.request_time =
+                Some(str::from_utf8(self.request_time()).ok().and_then(|t| t.parse::<f64>().ok()));
+        }
+        self.parsed_record.request_time.unwrap()
+    }
+
+    pub fn parsed_upstream_time(&mut self) -> Option<f64> {
+        if self.parsed_record.upstream_time.is_none() {
+            self.parsed_record.upstream_time =
+                Some(str::from_utf8(self.upstream_time()).ok().and_then(parse_upstream_time));
+        }
+        self.parsed_record.upstream_time.unwrap()
+    }
+
+    // Time nginx spent on the request outside its upstreams, reading the request and sending the
+    // response to the client.
+    pub fn parsed_nginx_time(&mut self) -> Option<f64> {
+        if !self.parsed_record.nginx_time.is_computed() {
+            let time = nginx_time(self.parsed_request_time(), self.parsed_upstream_time());
+            self.parsed_record.nginx_time.set(time);
+        }
+        self.parsed_record.nginx_time.value()
+    }
+
+    pub fn nginx_time_bytes(&self) -> Option<&[u8]> {
+        self.parsed_record.nginx_time.bytes()
+    }
+
+    pub fn parsed_route(&mut self) -> &str {
+        if self.parsed_record.route.is_none() {
+            let routes = self.routes.clone();
+            self.parsed_record.route = Some(routes.normalize(self.parsed_path()));
+        }
+        self.parsed_record.route.as_ref().unwrap()
+    }
+
+    // Class of the status, e.g. 5xx for 503, read straight from its digits.
+    pub fn status_class(&self) -> Option<&'static str> {
+        status_class(self.status())
+    }
+
+    // Raw bytes of the route, only available once it has been computed from the path.
+    pub fn route_bytes(&self) -> Option<&[u8]> {
+        self.parsed_record.route.as_ref().map(|r| r.as_bytes())
+    }
+
+    // GeoIP data is looked up the first time any of its columns is used and cached for the record.
+    pub fn parsed_geo(&mut self) -> &GeoInfo {
+        if self.parsed_record.geo.is_none() {
+            let info =
+                match self.geoip.clone() {
+                    Some(geoip) => geoip.lookup(self.parsed_ip()),
+                    None => GeoInfo::default(),
+                };
+            self.parsed_record.geo = Some(info);
+        }
+        self.parsed_record.geo.as_ref().unwrap()
+    }
+
+    pub fn parsed_country(&mut self) -> Option<&str> {
+        self.parsed_geo().country.as_ref().map(|s| s.as_str())
+    }
+
+    pub fn parsed_city(&mut self) -> Option<&str> {
+        self.parsed_geo().city.as_ref().map(|s| s.as_str())
+    }
+
+    pub fn parsed_asn(&mut self) -> Option<u64> {
+        self.parsed_geo().asn.as_ref().and_then(|s| s.parse::<u64>().ok())
+    }
+
+    // Raw bytes of a GeoIP field, only available once the lookup has been made.
+    pub fn geo_bytes<F>(&self, field: F) -> Option<&[u8]> where F: Fn(&GeoInfo) -> &Option<String> {
+        self.parsed_record.geo.as_ref().and_then(|g| field(g).as_ref()).map(|s| s.as_bytes())
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ParsedNginxLogRecord {
     ip: Option<String>,
+    address: Option<Option<IpAddr>>,
     username: Option<Option<String>>,
-    date: Option<DateTime<Local>>,
+    date: Option<Option<DateTime<Local>>>,
     method: Option<Option<String>>,
     path: Option<String>,
     query: Option<Option<String>>,
@@ -298,12 +619,18 @@ pub struct ParsedNginxLogRecord {
     bytes: Option<Option<u64>>,
     referrer: Option<Option<String>>,
     user_agent: Option<Option<String>>,
+    request_time: Option<Option<f64>>,
+    upstream_time: Option<Option<f64>>,
+    nginx_time: DerivedTime,
+    route: Option<String>,
+    geo: Option<GeoInfo>,
 }
 
 impl ParsedNginxLogRecord {
     pub fn empty() -> ParsedNginxLogRecord {
         ParsedNginxLogRecord {
             ip: None,
+            address: None,
             username: None,
             date: None,
             method: None,
@@ -313,52 +640,93 @@ impl ParsedNginxLogRecord {
             bytes: None,
             referrer: None,
             user_agent: None,
+            request_time: None,
+            upstream_time: None,
+            nginx_time: DerivedTime::default(),
+            route: None,
+            geo: None,
         }
     }
 }
 
 pub fn create_nginx_log_record_table_definition<'a>() -> TableDefinition<BinaryNginxLogRecord> {
     let columns = vec![
-            ColumnDefinition::Text { name: "ip",
-                                     size: 15,
-                                     binary_extractor: Box::new(|r: &BinaryNginxLogRecord| empty_opt(&r.ip)),
-                                     extractor: Box::new(|r: &mut BinaryNginxLogRecord| Some(r.parsed_ip())) },
-            ColumnDefinition::Text { name: "username",
+            ColumnDefinition::Ip { name: "ip".to_owned(),
+                                   size: 15,
+                                   field: 0,
+                                   binary_extractor: |r: &BinaryNginxLogRecord, _| empty_opt(r.ip()),
+                                   extractor: |r: &mut BinaryNginxLogRecord, _| Some(r.parsed_ip()),
+                                   address_extractor: |r: &mut BinaryNginxLogRecord, _| r.parsed_address() },
+            ColumnDefinition::Text { name: "username".to_owned(),
                                      size: 5,
-                                     binary_extractor: Box::new(|r: &BinaryNginxLogRecord| empty_opt(&r.username)),
-                                     extractor: Box::new(|r: &mut BinaryNginxLogRecord| r.parsed_username()) },
-            ColumnDefinition::Date { name: "date",
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryNginxLogRecord, _| empty_opt(r.username()),
+                                     extractor: |r: &mut BinaryNginxLogRecord, _| r.parsed_username() },
+            ColumnDefinition::Date { name: "date".to_owned(),
                                      size: 26,
-                                     binary_extractor: Box::new(|r: &BinaryNginxLogRecord| empty_opt(&r.date)),
-                                     extractor: Box::new(|r: &mut BinaryNginxLogRecord| Some(r.parsed_date())) },
-            ColumnDefinition::Text { name: "method",
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryNginxLogRecord, _| empty_opt(r.date()),
+                                     extractor: |r: &mut BinaryNginxLogRecord, _| r.parsed_date() },
+            ColumnDefinition::Text { name: "method".to_owned(),
                                      size: 5,
-                                     binary_extractor: Box::new(|r: &BinaryNginxLogRecord| empty_opt(&r.method)),
-                                     extractor: Box::new(|r: &mut BinaryNginxLogRecord| r.parsed_method()) },
-            ColumnDefinition::Text { name: "path",
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryNginxLogRecord, _| empty_opt(r.method()),
+                                     extractor: |r: &mut BinaryNginxLogRecord, _| r.parsed_method() },
+            ColumnDefinition::Text { name: "path".to_owned(),
                                      size: 20,
-                                     binary_extractor: Box::new(|r: &BinaryNginxLogRecord| empty_opt(&r.path)),
-                                     extractor: Box::new(|r: &mut BinaryNginxLogRecord| Some(r.parsed_path())) },
-            ColumnDefinition::Text { name: "query",
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryNginxLogRecord, _| empty_opt(r.path()),
+                                     extractor: |r: &mut BinaryNginxLogRecord, _| Some(r.parsed_path()) },
+            ColumnDefinition::Text { name: "query".to_owned(),
                                      size: 50,
-                                     binary_extractor: Box::new(|r: &BinaryNginxLogRecord| empty_opt(&r.query)),
-                                     extractor: Box::new(|r: &mut BinaryNginxLogRecord| r.parsed_query()) },
-            ColumnDefinition::Integer { name: "status",
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryNginxLogRecord, _| empty_opt(r.query()),
+                                     extractor: |r: &mut BinaryNginxLogRecord, _| r.parsed_query() },
+            ColumnDefinition::Integer { name: "status".to_owned(),
                                         size: 3,
-                                        binary_extractor: Box::new(|r: &BinaryNginxLogRecord| empty_opt(&r.status)),
-                                        extractor: Box::new({ |r: &mut BinaryNginxLogRecord| r.parsed_status() }) },
-            ColumnDefinition::Integer { name: "bytes",
+                                        field: 0,
+                                        binary_extractor: |r: &BinaryNginxLogRecord, _| empty_opt(r.status()),
+                                        extractor: |r: &mut BinaryNginxLogRecord, _| r.parsed_status() },
+            ColumnDefinition::Integer { name: "bytes".to_owned(),
                                         size: 10,
-                                        binary_extractor: Box::new(|r: &BinaryNginxLogRecord| empty_opt(&r.bytes)),
-                                        extractor: Box::new({ |r: &mut BinaryNginxLogRecord| r.parsed_bytes() }) },
-            ColumnDefinition::Text { name: "referrer",
+                                        field: 0,
+                                        binary_extractor: |r: &BinaryNginxLogRecord, _| empty_opt(r.bytes()),
+                                        extractor: |r: &mut BinaryNginxLogRecord, _| r.parsed_bytes() },
+            ColumnDefinition::Text { name: "referrer".to_owned(),
                                      size: 50,
-                                     binary_extractor: Box::new(|r: &BinaryNginxLogRecord| empty_opt(&r.referrer)),
-                                     extractor: Box::new(|r: &mut BinaryNginxLogRecord| r.parsed_referrer()) },
-            ColumnDefinition::Text { name: "user_agent",
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryNginxLogRecord, _| empty_opt(r.referrer()),
+                                     extractor: |r: &mut BinaryNginxLogRecord, _| r.parsed_referrer() },
+            ColumnDefinition::Text { name: "user_agent".to_owned(),
                                      size: 50,
-                                     binary_extractor: Box::new(|r: &BinaryNginxLogRecord| empty_opt(&r.user_agent)),
-                                     extractor: Box::new(|r: &mut BinaryNginxLogRecord| r.parsed_user_agent()) },
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryNginxLogRecord, _| empty_opt(r.user_agent()),
+                                     extractor: |r: &mut BinaryNginxLogRecord, _| r.parsed_user_agent() },
+            ColumnDefinition::Double { name: "request_time".to_owned(),
+                                       size: 7,
+                                       field: 0,
+                                       binary_extractor: |r: &BinaryNginxLogRecord, _| empty_opt(r.request_time()),
+                                       extractor: |r: &mut BinaryNginxLogRecord, _| r.parsed_request_time() },
+            ColumnDefinition::Double { name: "upstream_time".to_owned(),
+                                       size: 7,
+                                       field: 0,
+                                       binary_extractor: |r: &BinaryNginxLogRecord, _| empty_opt(r.upstream_time()),
+                                       extractor: |r: &mut BinaryNginxLogRecord, _| r.parsed_upstream_time() },
+            ColumnDefinition::Double { name: "nginx_time".to_owned(),
+                                       size: 7,
+                                       field: 0,
+                                       binary_extractor: |r: &BinaryNginxLogRecord, _| r.nginx_time_bytes(),
+                                       extractor: |r: &mut BinaryNginxLogRecord, _| r.parsed_nginx_time() },
+            ColumnDefinition::Text { name: "route".to_owned(),
+                                     size: 20,
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryNginxLogRecord, _| r.route_bytes(),
+                                     extractor: |r: &mut BinaryNginxLogRecord, _| Some(r.parsed_route()) },
+            ColumnDefinition::Text { name: "status_class".to_owned(),
+                                     size: 3,
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryNginxLogRecord, _| r.status_class().map(|c| c.as_bytes()),
+                                     extractor: |r: &mut BinaryNginxLogRecord, _| r.status_class() },
         ];
 
     let mut column_map = HashMap::new();
@@ -374,3 +742,44 @@ pub fn create_nginx_log_record_table_definition<'a>() -> TableDefinition<BinaryN
         ordered_columns: ordering,
     }
 }
+
+// 1xx to 5xx for three digit statuses, None for anything else, e.g. a status missing from the line.
+pub fn status_class(status: &[u8]) -> Option<&'static str> {
+    if status.len() != 3 || !status.iter().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    match status[0] {
+        b'1' => Some("1xx"),
+        b'2' => Some("2xx"),
+        b'3' => Some("3xx"),
+        b'4' => Some("4xx"),
+        b'5' => Some("5xx"),
+        _ => None,
+    }
+}
+
+// Virtual columns resolved from the ip column with the configured GeoIP databases.
+fn add_geoip_columns(definition: &mut TableDefinition<BinaryNginxLogRecord>) {
+    let columns = vec![
+            ColumnDefinition::Text { name: "country".to_owned(),
+                                     size: 7,
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryNginxLogRecord, _| r.geo_bytes(|g| &g.country),
+                                     extractor: |r: &mut BinaryNginxLogRecord, _| r.parsed_country() },
+            ColumnDefinition::Text { name: "city".to_owned(),
+                                     size: 20,
+                                     field: 0,
+                                     binary_extractor: |r: &BinaryNginxLogRecord, _| r.geo_bytes(|g| &g.city),
+                                     extractor: |r: &mut BinaryNginxLogRecord, _| r.parsed_city() },
+            ColumnDefinition::Integer { name: "asn".to_owned(),
+                                        size: 10,
+                                        field: 0,
+                                        binary_extractor: |r: &BinaryNginxLogRecord, _| r.geo_bytes(|g| &g.asn),
+                                        extractor: |r: &mut BinaryNginxLogRecord, _| r.parsed_asn() },
+        ];
+
+    for c in columns {
+        definition.ordered_columns.push(c.name().to_owned());
+        definition.column_map.insert(c.name().to_string(), c);
+    }
+}
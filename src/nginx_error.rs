@@ -0,0 +1,265 @@
+use std::str;
+use std::path::Path;
+use std::net::IpAddr;
+use std::collections::HashMap;
+
+use chrono::prelude::*;
+use memchr::memmem;
+use table::{TableDefinition, ColumnDefinition};
+use format::LogFormat;
+use discovery;
+use nginx::{index_of, empty_opt};
+use cidr;
+
+// Fields following the message, written by nginx as `, key: value` with request, upstream, host and
+// referrer quoted.
+const TRAILING_FIELDS: [&str; 6] = ["client", "server", "request", "upstream", "host", "referrer"];
+
+const TIMESTAMP: usize = 0;
+const LEVEL: usize = 1;
+const PID: usize = 2;
+const TID: usize = 3;
+const CONNECTION: usize = 4;
+const MESSAGE: usize = 5;
+const CLIENT: usize = 6;
+const FIELD_COUNT: usize = CLIENT + TRAILING_FIELDS.len();
+
+// nginx error.log lines, e.g.
+//
+//     2026/10/16 10:00:00 [error] 1234#0: *42 open() "/srv/favicon.ico" failed (2: No such file or
+//     directory), client: 10.0.0.1, server: example.com, request: "GET /favicon.ico HTTP/1.1", host: "example.com"
+pub struct NginxErrorFormat;
+
+impl LogFormat for NginxErrorFormat {
+    type Record = BinaryNginxErrorRecord;
+
+    fn empty_record(&self) -> BinaryNginxErrorRecord {
+        BinaryNginxErrorRecord::empty()
+    }
+
+    fn read_record(&self, line: &[u8], record: &mut BinaryNginxErrorRecord) -> bool {
+        read_log_record_binary(line, record)
+    }
+
+    fn table_definition(&self) -> TableDefinition<BinaryNginxErrorRecord> {
+        create_nginx_error_record_table_definition()
+    }
+
+    fn verbatim_columns(&self) -> Vec<&'static str> {
+        vec!["level", "pid", "tid", "connection", "message", "client", "server", "request", "upstream", "host", "referrer"]
+    }
+
+    fn is_log_file(file: &Path) -> bool {
+        discovery::is_error_log(file)
+    }
+}
+
+// Returns false, leaving the record untouched, when the line is not an error log entry. Continuation
+// lines of multi-line messages (e.g. upstream responses) are skipped this way.
+pub fn read_log_record_binary(line: &[u8], record: &mut BinaryNginxErrorRecord) -> bool {
+    let working = trim_line_ending(line);
+    if !is_timestamp(working) || working.get(20) != Some(&b'[') {
+        return false;
+    }
+    let mut fields: [&[u8]; FIELD_COUNT] = [&[]; FIELD_COUNT];
+    fields[TIMESTAMP] = &working[0..19];
+
+    let working = &working[21..];
+    let end = match index_of(working, b']') {
+        Some(end) => end,
+        None => return false,
+    };
+    fields[LEVEL] = &working[0..end];
+
+    let working = working.get(end+2..).unwrap_or(&[]);
+    let end = match index_of(working, b':') {
+        Some(end) => end,
+        None => return false,
+    };
+    let process = &working[0..end];
+    match index_of(process, b'#') {
+        Some(idx) => {
+            fields[PID] = &process[0..idx];
+            fields[TID] = &process[idx+1..];
+        },
+        None => fields[PID] = process,
+    }
+
+    let mut working = working.get(end+2..).unwrap_or(&[]);
+    if working.first() == Some(&b'*') {
+        let end = index_of(working, b' ').unwrap_or(working.len());
+        fields[CONNECTION] = &working[1..end];
+        working = working.get(end+1..).unwrap_or(&[]);
+    }
+
+    fields[MESSAGE] =
+        match memmem::find(working, b", client: ") {
+            Some(idx) => {
+                split_trailing_fields(&working[idx+2..], &mut fields);
+                &working[0..idx]
+            },
+            None => working,
+        };
+    record.set_fields(&fields);
+    true
+}
+
+// Reads `key: value` pairs separated by ", ", stopping at the first unknown key.
+fn split_trailing_fields<'a>(mut working: &'a [u8], fields: &mut [&'a [u8]; FIELD_COUNT]) {
+    while !working.is_empty() {
+        let end = match memmem::find(working, b": ") {
+            Some(end) => end,
+            None => return,
+        };
+        let idx = match TRAILING_FIELDS.iter().position(|f| f.as_bytes() == &working[0..end]) {
+            Some(idx) => idx,
+            None => return,
+        };
+        let rest = &working[end+2..];
+        let (value, rest) =
+            if rest.first() == Some(&b'"') {
+                let last = if rest.len() > 1 && rest.ends_with(b"\"") { rest.len() - 1 } else { rest.len() };
+                let close = memmem::find(&rest[1..], b"\", ").map(|i| i + 1).unwrap_or(last);
+                (&rest[1..close], rest.get(close+3..).unwrap_or(&[]))
+            } else {
+                match memmem::find(rest, b", ") {
+                    Some(close) => (&rest[0..close], &rest[close+2..]),
+                    None => (rest, &[][..]),
+                }
+            };
+        fields[CLIENT + idx] = value;
+        working = rest;
+    }
+}
+
+// YYYY/MM/DD HH:MM:SS
+fn is_timestamp(working: &[u8]) -> bool {
+    working.len() > 20 && working[4] == b'/' && working[7] == b'/' && working[10] == b' '
+        && working[13] == b':' && working[16] == b':' && working[19] == b' '
+        && working[0..4].iter().all(|b| b.is_ascii_digit())
+}
+
+fn trim_line_ending(working: &[u8]) -> &[u8] {
+    let mut len = working.len();
+    while len > 0 && (working[len-1] == b'\n' || working[len-1] == b'\r') {
+        len -= 1;
+    }
+    &working[0..len]
+}
+
+#[derive(Debug, Clone)]
+pub struct BinaryNginxErrorRecord {
+    buffer: Vec<u8>,
+    spans: [(usize, usize); FIELD_COUNT],
+    parsed_timestamp: Option<Option<DateTime<Local>>>,
+    parsed_text: [Option<String>; FIELD_COUNT],
+    parsed_client: Option<Option<IpAddr>>,
+}
+
+impl BinaryNginxErrorRecord {
+    pub fn empty() -> BinaryNginxErrorRecord {
+        BinaryNginxErrorRecord {
+            buffer: Vec::new(),
+            spans: [(0, 0); FIELD_COUNT],
+            parsed_timestamp: None,
+            parsed_text: Default::default(),
+            parsed_client: None,
+        }
+    }
+
+    fn set_fields(&mut self, fields: &[&[u8]]) {
+        self.buffer.clear();
+        for (span, field) in self.spans.iter_mut().zip(fields) {
+            let start = self.buffer.len();
+            self.buffer.extend_from_slice(field);
+            *span = (start, self.buffer.len());
+        }
+        self.parsed_timestamp = None;
+        self.parsed_client = None;
+        for text in self.parsed_text.iter_mut() {
+            *text = None;
+        }
+    }
+
+    pub fn field(&self, idx: usize) -> &[u8] {
+        let (start, end) = self.spans[idx];
+        &self.buffer[start..end]
+    }
+
+    pub fn parsed_timestamp(&mut self) -> Option<&DateTime<Local>> {
+        if self.parsed_timestamp.is_none() {
+            let timestamp = str::from_utf8(self.field(TIMESTAMP)).ok()
+                .and_then(|t| NaiveDateTime::parse_from_str(t, "%Y/%m/%d %H:%M:%S").ok())
+                .and_then(|d| Local.from_local_datetime(&d).earliest());
+            self.parsed_timestamp = Some(timestamp);
+        }
+        self.parsed_timestamp.as_ref().unwrap().as_ref()
+    }
+
+    pub fn parsed_text(&mut self, idx: usize) -> Option<&str> {
+        if self.parsed_text[idx].is_none() {
+            self.parsed_text[idx] = Some(String::from_utf8_lossy(self.field(idx)).to_string());
+        }
+        self.parsed_text[idx].as_ref().map(|s| s.as_str()).filter(|s| !s.is_empty())
+    }
+
+    pub fn parsed_integer(&mut self, idx: usize) -> Option<u64> {
+        str::from_utf8(self.field(idx)).ok().and_then(|v| v.parse::<u64>().ok())
+    }
+
+    pub fn parsed_client(&mut self) -> Option<IpAddr> {
+        if self.parsed_client.is_none() {
+            self.parsed_client = Some(cidr::parse_address(self.field(CLIENT)));
+        }
+        self.parsed_client.unwrap()
+    }
+}
+
+pub fn create_nginx_error_record_table_definition() -> TableDefinition<BinaryNginxErrorRecord> {
+    let text = |name: &str, size: usize, idx: usize|
+        ColumnDefinition::Text { name: name.to_owned(),
+                                 size: size,
+                                 field: idx,
+                                 binary_extractor: |r: &BinaryNginxErrorRecord, idx| empty_opt(r.field(idx)),
+                                 extractor: |r: &mut BinaryNginxErrorRecord, idx| r.parsed_text(idx) };
+    let integer = |name: &str, size: usize, idx: usize|
+        ColumnDefinition::Integer { name: name.to_owned(),
+                                    size: size,
+                                    field: idx,
+                                    binary_extractor: |r: &BinaryNginxErrorRecord, idx| empty_opt(r.field(idx)),
+                                    extractor: |r: &mut BinaryNginxErrorRecord, idx| r.parsed_integer(idx) };
+    let mut columns = vec![
+            ColumnDefinition::Date { name: "timestamp".to_owned(),
+                                     size: 26,
+                                     field: TIMESTAMP,
+                                     binary_extractor: |r: &BinaryNginxErrorRecord, idx| empty_opt(r.field(idx)),
+                                     extractor: |r: &mut BinaryNginxErrorRecord, _| r.parsed_timestamp() },
+            text("level", 6, LEVEL),
+            integer("pid", 7, PID),
+            integer("tid", 5, TID),
+            integer("connection", 10, CONNECTION),
+            text("message", 50, MESSAGE),
+        ];
+    columns.push(ColumnDefinition::Ip { name: "client".to_owned(),
+                                        size: 15,
+                                        field: CLIENT,
+                                        binary_extractor: |r: &BinaryNginxErrorRecord, idx| empty_opt(r.field(idx)),
+                                        extractor: |r: &mut BinaryNginxErrorRecord, idx| r.parsed_text(idx),
+                                        address_extractor: |r: &mut BinaryNginxErrorRecord, _| r.parsed_client() });
+    for (idx, name) in TRAILING_FIELDS.iter().enumerate().skip(1) {
+        columns.push(text(name, 20, CLIENT + idx));
+    }
+
+    let mut column_map = HashMap::new();
+    let mut ordering = Vec::new();
+
+    for c in columns {
+        ordering.push(c.name().to_owned());
+        column_map.insert(c.name().to_string(), c);
+    }
+
+    TableDefinition {
+        column_map: column_map,
+        ordered_columns: ordering,
+    }
+}
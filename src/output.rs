@@ -0,0 +1,528 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::mem;
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use serde_json::{self, Map};
+use value::Value;
+use chart::ChartRenderer;
+use sqlite::SqliteRenderer;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Ndjson,
+    // Bar chart of time buckets of the given number of seconds
+    Chart(i64),
+    // Table of a sqlite database at the given path
+    Sqlite(String),
+}
+
+impl OutputFormat {
+    pub fn from_string(format: &str) -> Option<OutputFormat> {
+        match format.to_lowercase().as_ref() {
+            "table" => Some(OutputFormat::Table),
+            "json" => Some(OutputFormat::Json),
+            "ndjson" => Some(OutputFormat::Ndjson),
+            _ if format.starts_with("sqlite:") && format.len() > 7 => Some(OutputFormat::Sqlite(format[7..].to_owned())),
+            _ => None,
+        }
+    }
+
+    // Whether the output is meant to be read in a terminal, rather than by other programs.
+    pub fn is_text(&self) -> bool {
+        match self {
+            OutputFormat::Table | OutputFormat::Chart(_) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OutputOptions {
+    pub format: OutputFormat,
+    pub out: Option<String>,
+    // Print table rows as they are produced instead of aligning them once the table is complete
+    pub stream: bool,
+    // Write rows as soon as they are rendered instead of through a buffer flushed once results are complete
+    pub unbuffered: bool,
+    // Results are thrown away, e.g. when only the exit code matters
+    pub discard: bool,
+    // Shown in tables for missing values, which json and sqlite output as null
+    pub null_text: String,
+    // Page results written to a terminal through $PAGER
+    pub pager: bool,
+    // Write grouped json results as objects nested by group key instead of an array of rows
+    pub nest: bool,
+    // Decimals floats are shown with, in tables, or rounded to otherwise
+    pub precision: Option<usize>,
+    // Show numbers of bytes in tables as KB, MB or GB
+    pub human: bool,
+}
+
+impl Default for OutputOptions {
+    fn default() -> OutputOptions {
+        OutputOptions { format: OutputFormat::Table, out: None, stream: false, unbuffered: false, discard: false, null_text: "<null>".to_owned(), pager: false, nest: false, precision: None, human: false }
+    }
+}
+
+// Opens the sink results are written to: stdout, a pager when stdout is a terminal and paging is
+// enabled, or a file that is gzipped when its name ends in .gz. Output is buffered and flushed once
+// results are complete unless unbuffered output was asked for.
+pub fn open_output(options: &OutputOptions) -> io::Result<Box<Write>> {
+    let pager = if options.pager && options.out.is_none() && !options.discard && io::stdout().is_terminal() { start_pager() } else { None };
+    match options.out {
+        _ if options.discard => Ok(Box::new(io::sink())),
+        None if pager.is_some() && options.unbuffered => Ok(Box::new(pager.unwrap())),
+        None if pager.is_some() => Ok(Box::new(BufWriter::new(pager.unwrap()))),
+        Some(ref path) if path.ends_with(".gz") => {
+            let file = BufWriter::new(File::create(Path::new(path))?);
+            Ok(Box::new(GzEncoder::new(file, Compression::default())))
+        },
+        Some(ref path) if options.unbuffered => Ok(Box::new(File::create(Path::new(path))?)),
+        Some(ref path) => Ok(Box::new(BufWriter::new(File::create(Path::new(path))?))),
+        None if options.unbuffered => Ok(Box::new(io::stdout())),
+        None => Ok(Box::new(BufWriter::new(io::stdout()))),
+    }
+}
+
+// The pager results are written to, as with git: $PAGER, or less -S by default, quitting on its own
+// when the results fit on one screen. It is waited for by close_pager once results are complete.
+struct Pager {
+    child: Child,
+    stdin: Arc<Mutex<Option<ChildStdin>>>,
+}
+
+static PAGER: Mutex<Option<Pager>> = Mutex::new(None);
+static OUTPUT_CLOSED: AtomicBool = AtomicBool::new(false);
+static WRITE_ERROR: Mutex<Option<String>> = Mutex::new(None);
+
+// Writes to the pager's input. Results written once the pager is closed, or after it was quit, are
+// dropped, and queries stop once it was quit.
+struct PagerOutput {
+    stdin: Arc<Mutex<Option<ChildStdin>>>,
+}
+
+impl Write for PagerOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.stdin.lock().unwrap().as_mut().map(|stdin| stdin.write(buf)) {
+            Some(Err(ref e)) if e.kind() == io::ErrorKind::BrokenPipe => {
+                OUTPUT_CLOSED.store(true, Ordering::SeqCst);
+                Ok(buf.len())
+            },
+            Some(result) => result,
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.stdin.lock().unwrap().as_mut().map(|stdin| stdin.flush()) {
+            Some(Err(ref e)) if e.kind() == io::ErrorKind::BrokenPipe => {
+                OUTPUT_CLOSED.store(true, Ordering::SeqCst);
+                Ok(())
+            },
+            Some(result) => result,
+            None => Ok(()),
+        }
+    }
+}
+
+// None when no pager is set, e.g. PAGER=cat or an empty PAGER, or when it can't be started, results
+// then being written to stdout.
+fn start_pager() -> Option<PagerOutput> {
+    let command = env::var("PAGER").unwrap_or_else(|_| "less -S".to_owned());
+    if command.trim().is_empty() || command.trim() == "cat" {
+        return None;
+    }
+    let mut pager = Command::new("sh");
+    pager.arg("-c").arg(&command).stdin(Stdio::piped());
+    if env::var_os("LESS").is_none() {
+        pager.env("LESS", "FRX");
+    }
+    let mut child = pager.spawn().ok()?;
+    let stdin = Arc::new(Mutex::new(child.stdin.take()));
+    *PAGER.lock().unwrap() = Some(Pager { child: child, stdin: stdin.clone() });
+    Some(PagerOutput { stdin: stdin })
+}
+
+// Whether the reader of the results went away before they were complete: a pager that was quit, or
+// a command they were piped to that exited, e.g. `head`.
+pub fn output_closed() -> bool {
+    OUTPUT_CLOSED.load(Ordering::SeqCst)
+}
+
+// Results written once their reader went away are dropped, and queries stop as when a pager is quit.
+// Queries also stop at other errors, e.g. a full disk, the first being kept for write_error.
+pub fn check_written(result: io::Result<()>) {
+    if let Err(e) = result {
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            WRITE_ERROR.lock().unwrap().get_or_insert(e.to_string());
+        }
+        OUTPUT_CLOSED.store(true, Ordering::SeqCst);
+    }
+}
+
+// Why results couldn't be written, other than their reader having gone away.
+pub fn write_error() -> Option<String> {
+    WRITE_ERROR.lock().unwrap().clone()
+}
+
+// Ends the pager's input and waits for it to be quit, so that nothing else is written to the
+// terminal while it is showing results.
+pub fn close_pager() {
+    if let Some(mut pager) = PAGER.lock().unwrap().take() {
+        pager.stdin.lock().unwrap().take();
+        let _ = pager.child.wait();
+    }
+}
+
+pub struct OutputColumn {
+    pub name: String,
+    pub size: usize,
+    // Whether the column holds a group key
+    pub group: bool,
+    // Whether the column holds numbers of bytes, e.g. the sum of a column of response sizes
+    pub bytes: bool,
+}
+
+// Renderers return the errors of writing results, which check_written handles.
+pub trait ResultRenderer {
+    fn render_header(&mut self, columns: &Vec<OutputColumn>) -> io::Result<()>;
+    fn render_row(&mut self, values: &Vec<Value>) -> io::Result<()>;
+    fn render_footer(&mut self) -> io::Result<()>;
+    fn render_label(&mut self, label: &str) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+// Bounded results, e.g. aggregates or limited queries, are buffered by the table renderer unless
+// streaming was asked for, so that column widths fit every row.
+pub fn create_renderer(options: &OutputOptions, out: Box<Write>, bounded: bool) -> Box<ResultRenderer> {
+    let renderer: Box<ResultRenderer> =
+        match options.format {
+            OutputFormat::Table => Box::new(TableRenderer { out: out,
+                                                            widths: Vec::new(),
+                                                            buffered: bounded && !options.stream,
+                                                            columns: Vec::new(),
+                                                            rows: Vec::new(),
+                                                            null_text: options.null_text.clone(),
+                                                            precision: options.precision,
+                                                            human: if options.human { Some(Vec::new()) } else { None } }),
+            OutputFormat::Json if options.nest => Box::new(NestedJsonRenderer { out: out, names: Vec::new(), keys: Vec::new(), root: Map::new() }),
+            OutputFormat::Json => Box::new(JsonRenderer { out: out, names: Vec::new(), rows: 0 }),
+            OutputFormat::Ndjson => Box::new(NdjsonRenderer { out: out, names: Vec::new() }),
+            OutputFormat::Chart(interval) => Box::new(ChartRenderer::new(out, interval)),
+            OutputFormat::Sqlite(ref path) => Box::new(SqliteRenderer::new(path)),
+        };
+    match options.precision {
+        Some(precision) if options.format != OutputFormat::Table => Box::new(RoundingRenderer { renderer: renderer, precision: precision }),
+        _ => renderer,
+    }
+}
+
+// Rounds the floats of rows written as values rather than text, e.g. as json numbers.
+struct RoundingRenderer {
+    renderer: Box<ResultRenderer>,
+    precision: usize,
+}
+
+impl ResultRenderer for RoundingRenderer {
+    fn render_header(&mut self, columns: &Vec<OutputColumn>) -> io::Result<()> {
+        self.renderer.render_header(columns)
+    }
+
+    fn render_row(&mut self, values: &Vec<Value>) -> io::Result<()> {
+        let values = values.iter().map(|v| v.round(self.precision)).collect();
+        self.renderer.render_row(&values)
+    }
+
+    fn render_footer(&mut self) -> io::Result<()> {
+        self.renderer.render_footer()
+    }
+
+    fn render_label(&mut self, label: &str) -> io::Result<()> {
+        self.renderer.render_label(label)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.renderer.flush()
+    }
+}
+
+const MAX_COLUMN_WIDTH: usize = 50;
+
+const BYTE_UNITS: [&str; 6] = ["B", "KB", "MB", "GB", "TB", "PB"];
+
+// e.g. 1.5 MB, in powers of 1024 with a decimal unless another precision is given. Bytes are whole.
+fn human_bytes(bytes: f64, precision: Option<usize>) -> String {
+    let mut size = bytes;
+    let mut unit = 0;
+    while size.abs() >= 1024.0 && unit < BYTE_UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    let precision = if unit == 0 { 0 } else { precision.unwrap_or(1) };
+    format!("{} {}", Value::Float(size).to_precision_string(Some(precision)), BYTE_UNITS[unit])
+}
+
+struct TableRenderer {
+    out: Box<Write>,
+    widths: Vec<usize>,
+    buffered: bool,
+    columns: Vec<String>,
+    rows: Vec<Vec<String>>,
+    null_text: String,
+    precision: Option<usize>,
+    // With --human, whether each column holds numbers of bytes
+    human: Option<Vec<bool>>,
+}
+
+impl TableRenderer {
+    fn write_header(&mut self) -> io::Result<()> {
+        let mut header_row = "|".to_owned();
+        for (name, width) in self.columns.iter().zip(&self.widths) {
+            header_row += &format!(" {:width$} |", name, width = width);
+        }
+        let pad = (0..header_row.len().saturating_sub(2)).map(|_| "-").collect::<String>();
+        writeln!(self.out, "+{}+", pad)?;
+        writeln!(self.out, "{}", header_row)?;
+        writeln!(self.out, "|{}|", pad)
+    }
+
+    fn write_row(&mut self, values: &Vec<String>) -> io::Result<()> {
+        write!(self.out, "|")?;
+        for (idx, value) in values.iter().enumerate() {
+            write!(self.out, " {:width$} |", value, width = self.widths[idx])?;
+        }
+        writeln!(self.out, "")
+    }
+
+    // Grows the column widths to fit a row, values longer than the maximum width overflowing their
+    // column instead.
+    fn fit_row(&mut self, values: &Vec<String>) {
+        for (width, value) in self.widths.iter_mut().zip(values) {
+            if *width < value.len() && *width < MAX_COLUMN_WIDTH {
+                *width = value.len().min(MAX_COLUMN_WIDTH);
+            }
+        }
+    }
+}
+
+// Values wider than their column are cut short, ending with an ellipsis.
+fn truncate_value(value: &str, width: usize) -> String {
+    if value.chars().count() <= width {
+        return value.to_owned();
+    }
+    let mut truncated: String = value.chars().take(width.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+impl ResultRenderer for TableRenderer {
+    fn render_header(&mut self, columns: &Vec<OutputColumn>) -> io::Result<()> {
+        // Widths grown by earlier rows are kept so that repeated summaries stay aligned
+        self.widths.resize(columns.len(), 0);
+        self.columns = columns.iter().map(|c| c.name.to_owned()).collect();
+        if let Some(ref mut human) = self.human {
+            *human = columns.iter().map(|c| c.bytes).collect();
+        }
+        for (idx, column) in columns.iter().enumerate() {
+            // Buffered tables are sized to their contents rather than the expected column size
+            let size = if self.buffered { 0 } else { column.size };
+            self.widths[idx] = *[self.widths[idx], size, column.name.len()].iter().max().unwrap();
+        }
+        if !self.buffered {
+            self.write_header()?;
+        }
+        Ok(())
+    }
+
+    fn render_row(&mut self, values: &Vec<Value>) -> io::Result<()> {
+        let values = values.iter().enumerate().map(|(idx, v)| match v {
+            Value::Null => self.null_text.clone(),
+            Value::Int(_) | Value::Float(_) if self.human.as_ref().map(|h| h[idx]).unwrap_or(false) =>
+                human_bytes(v.as_f64().unwrap(), self.precision),
+            value => value.to_precision_string(self.precision),
+        }).collect();
+        if self.buffered {
+            self.fit_row(&values);
+            self.rows.push(values);
+            Ok(())
+        } else {
+            // The header is already written, so the columns keep its widths
+            let values = values.iter().zip(&self.widths).map(|(v, w)| truncate_value(v, *w)).collect();
+            self.write_row(&values)
+        }
+    }
+
+    fn render_footer(&mut self) -> io::Result<()> {
+        if self.buffered {
+            self.write_header()?;
+            let rows = mem::replace(&mut self.rows, Vec::new());
+            for row in &rows {
+                self.write_row(row)?;
+            }
+        }
+        let mut len = 1;
+        for width in &self.widths {
+            len += width+3
+        }
+        let pad = (0..len.saturating_sub(2)).map(|_| "-").collect::<String>();
+        writeln!(self.out, "+{}+", pad)?;
+        self.out.flush()
+    }
+
+    fn render_label(&mut self, label: &str) -> io::Result<()> {
+        writeln!(self.out, "{}", label)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn json_object(names: &Vec<String>, values: &Vec<Value>) -> String {
+    let mut object = Map::new();
+    for (name, value) in names.iter().zip(values.iter()) {
+        object.insert(name.to_owned(), value.to_json());
+    }
+    serde_json::to_string(&serde_json::Value::Object(object)).unwrap()
+}
+
+struct JsonRenderer {
+    out: Box<Write>,
+    names: Vec<String>,
+    rows: usize,
+}
+
+impl ResultRenderer for JsonRenderer {
+    fn render_header(&mut self, columns: &Vec<OutputColumn>) -> io::Result<()> {
+        self.names = columns.iter().map(|c| c.name.to_owned()).collect();
+        self.rows = 0;
+        write!(self.out, "[")
+    }
+
+    fn render_row(&mut self, values: &Vec<Value>) -> io::Result<()> {
+        if self.rows > 0 {
+            write!(self.out, ",")?;
+        }
+        write!(self.out, "\n  {}", json_object(&self.names, values))?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    fn render_footer(&mut self) -> io::Result<()> {
+        writeln!(self.out, "\n]")?;
+        self.out.flush()
+    }
+
+    fn render_label(&mut self, _label: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// Nests grouped results by their group keys, in the order the groups are shown, e.g.
+// {"GET": {"/api": 120, "/home": 45}} for `group method, path | show method, path, count(*)`. The
+// leaves are the value of the only other column, or an object of the other columns.
+struct NestedJsonRenderer {
+    out: Box<Write>,
+    names: Vec<String>,
+    keys: Vec<usize>,
+    root: Map<String, serde_json::Value>,
+}
+
+impl NestedJsonRenderer {
+    fn leaf(&self, values: &Vec<Value>) -> serde_json::Value {
+        let mut object = Map::new();
+        for (idx, (name, value)) in self.names.iter().zip(values).enumerate() {
+            if !self.keys.contains(&idx) {
+                object.insert(name.to_owned(), value.to_json());
+            }
+        }
+        if object.len() == 1 {
+            object.into_iter().next().unwrap().1
+        } else {
+            serde_json::Value::Object(object)
+        }
+    }
+}
+
+impl ResultRenderer for NestedJsonRenderer {
+    fn render_header(&mut self, columns: &Vec<OutputColumn>) -> io::Result<()> {
+        self.names = columns.iter().map(|c| c.name.to_owned()).collect();
+        self.keys = columns.iter().enumerate().filter(|&(_, c)| c.group).map(|(idx, _)| idx).collect();
+        self.root = Map::new();
+        Ok(())
+    }
+
+    fn render_row(&mut self, values: &Vec<Value>) -> io::Result<()> {
+        let leaf = self.leaf(values);
+        let keys: Vec<String> = self.keys.iter().map(|&idx| values[idx].to_display_string()).collect();
+        let (last, parents) = match keys.split_last() {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        let mut object = &mut self.root;
+        for key in parents {
+            let child = object.entry(key.to_owned()).or_insert_with(|| serde_json::Value::Object(Map::new()));
+            if !child.is_object() {
+                *child = serde_json::Value::Object(Map::new());
+            }
+            object = child.as_object_mut().unwrap();
+        }
+        object.insert(last.to_owned(), leaf);
+        Ok(())
+    }
+
+    fn render_footer(&mut self) -> io::Result<()> {
+        let root = mem::replace(&mut self.root, Map::new());
+        writeln!(self.out, "{}", serde_json::to_string_pretty(&serde_json::Value::Object(root)).unwrap())?;
+        self.out.flush()
+    }
+
+    fn render_label(&mut self, _label: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+struct NdjsonRenderer {
+    out: Box<Write>,
+    names: Vec<String>,
+}
+
+impl ResultRenderer for NdjsonRenderer {
+    fn render_header(&mut self, columns: &Vec<OutputColumn>) -> io::Result<()> {
+        self.names = columns.iter().map(|c| c.name.to_owned()).collect();
+        Ok(())
+    }
+
+    fn render_row(&mut self, values: &Vec<Value>) -> io::Result<()> {
+        writeln!(self.out, "{}", json_object(&self.names, values))
+    }
+
+    fn render_footer(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn render_label(&mut self, _label: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
@@ -1,9 +1,22 @@
+use std::fmt;
+use std::collections::HashMap;
+
 use table::TableDefinition;
+use cidr::CidrSet;
 
 use nom;
+use nom::{Context, ErrorKind};
 use nom::types::CompleteStr;
 use chrono::prelude::*;
+use chrono::{Duration, ParseError};
 use regex::Regex;
+use regex::bytes::Regex as BytesRegex;
+
+const INVALID_REGEX: u32 = 1;
+const INVALID_DATE: u32 = 2;
+const INVALID_CIDR: u32 = 3;
+
+const CLAUSES: &[&str] = &["group", "show", "having", "sort", "limit", "every"];
 
 
 ////////////
@@ -11,7 +24,9 @@ use regex::Regex;
 ////////////
 
 named!(parse_filter_operator<CompleteStr, QueryFilterBinaryOp>,
-       alt!(map!(tag_s!("<"), |_| QueryFilterBinaryOp::Lt) |
+       alt!(map!(tag_s!("<="), |_| QueryFilterBinaryOp::Le) |
+            map!(tag_s!(">="), |_| QueryFilterBinaryOp::Ge) |
+            map!(tag_s!("<"), |_| QueryFilterBinaryOp::Lt) |
             map!(tag_s!(">"), |_| QueryFilterBinaryOp::Gt) |
             map!(tag_s!("="), |_| QueryFilterBinaryOp::Eq) |
             map!(tag_s!("!="), |_| QueryFilterBinaryOp::Ne) |
@@ -31,31 +46,46 @@ named!(parse_text_operand<CompleteStr, QueryValue>,
             |t| QueryValue::Text(t.to_string(), t.to_string().into_bytes())));
 
 named!(parse_regex_operand<CompleteStr, QueryValue>,
-       map!(tuple!(tag!("r"), delimited!(char!('"'), take_until_s!("\""), char!('"'))),
-            |t| QueryValue::Regex(Regex::new(&t.1.to_string()).unwrap())));
+       preceded!(tag!("r\""),
+                 return_error!(ErrorKind::Custom(INVALID_REGEX),
+                               map_res!(terminated!(take_until_s!("\""), char!('"')),
+                                        |t: CompleteStr| compile_regex(&t)))));
 
 named!(parse_date_operand<CompleteStr, QueryValue>,
-       map!(tuple!(tag!("d"), delimited!(char!('"'), take_until_s!("\""), char!('"'))),
-            |t| QueryValue::Date(create_date_from_string(t.1.to_string()))));
+       preceded!(tag!("d\""),
+                 return_error!(ErrorKind::Custom(INVALID_DATE),
+                               map_opt!(terminated!(take_until_s!("\""), char!('"')),
+                                        |t: CompleteStr| create_date_from_string(&t).map(|d| QueryValue::Date(d))))));
        
 named!(parse_symbol_operand<CompleteStr, QueryValue>,
-       map!(take_while!(is_symbol),
+       map!(verify!(take_while1!(is_symbol), |s: CompleteStr| !s.starts_with(|c: char| c.is_digit(10))),
             |s| QueryValue::Symbol(s.to_string().to_lowercase())));
 
+// Aggregates such as count(*) are referenced by their output column name in having filters
+named!(parse_reducer_operand<CompleteStr, QueryValue>,
+       map!(tuple!(parse_reducer, delimited!(char!('('), take_until_s!(")"), char!(')'))),
+            |r| QueryValue::Symbol(format!("{}({})", r.0.to_string(), r.1.to_string().to_lowercase()))));
+
 named!(parse_int_operand<CompleteStr, QueryValue>,
-       map!(nom::digit,
-            |i| QueryValue::Int(i.parse::<i64>().unwrap(), i.to_string().into_bytes())));
+       map_res!(nom::digit,
+                |i: CompleteStr| i.parse::<i64>().map(|v| QueryValue::Int(v, i.to_string().into_bytes()))));
 
 named!(parse_double_operand<CompleteStr, QueryValue>,
        map!(tuple!(nom::digit, tag_s!("."), nom::digit),
             |d| QueryValue::Double(format!("{}.{}", d.0, d.2).to_string().parse::<f64>().unwrap(), format!("{}.{}", d.0, d.2).to_string().into_bytes())));
 
+// bytes / 1024 or lower(method)
+named!(parse_expression_operand_value<CompleteStr, QueryValue>,
+       map!(parse_computed_expression, QueryValue::Expression));
+
 named!(parse_filter_operand<CompleteStr, QueryValue>,
-       alt!(parse_text_operand |
+       alt!(parse_expression_operand_value |
+            parse_text_operand |
             parse_regex_operand |
             parse_date_operand |
             parse_boolean_operand |
             parse_null_operand |
+            parse_reducer_operand |
             parse_symbol_operand |
             parse_double_operand |
             parse_int_operand));
@@ -66,6 +96,44 @@ named!(parse_binary_op_filter<CompleteStr, QueryFilter>,
                        parse_filter_operand)),
        |t| QueryFilter::BinaryOpFilter(t.0, t.2, t.1)));
 
+// status in (500, 502) / method not in ("GET", "HEAD")
+named!(parse_in_filter<CompleteStr, QueryFilter>,
+       map!(ws!(tuple!(parse_filter_operand,
+                       opt!(tag_no_case_s!("not")),
+                       tag_no_case_s!("in"),
+                       tag_s!("("),
+                       separated_nonempty_list!(ws!(tag_s!(",")), ws!(parse_filter_operand)),
+                       tag_s!(")"))),
+            |f| QueryFilter::InFilter(f.0, f.4, f.1.is_some())));
+
+named!(parse_quoted_text<CompleteStr, String>,
+       map!(delimited!(char!('"'), take_until_s!("\""), char!('"')), |t| t.to_string()));
+
+// "10.0.0.0/8" or ("10.0.0.0/8", "fd00::/8")
+named!(parse_cidr_ranges<CompleteStr, CidrSet>,
+       return_error!(ErrorKind::Custom(INVALID_CIDR),
+                     map_opt!(alt!(delimited!(ws!(tag_s!("(")),
+                                              separated_nonempty_list!(ws!(tag_s!(",")), parse_quoted_text),
+                                              ws!(tag_s!(")"))) |
+                                   map!(parse_quoted_text, |r| vec![r])),
+                              |r: Vec<String>| CidrSet::parse(&r))));
+
+// ip in_cidr "10.0.0.0/8" / ip not in_cidr ("10.0.0.0/8", "192.168.0.0/16")
+named!(parse_cidr_filter<CompleteStr, QueryFilter>,
+       map!(ws!(tuple!(parse_filter_operand,
+                       opt!(tag_no_case_s!("not")),
+                       tag_no_case_s!("in_cidr"),
+                       parse_cidr_ranges)),
+            |f| QueryFilter::CidrFilter(f.0, f.3, f.1.is_some())));
+
+// username is null / route is not null, the same as comparing with null
+named!(parse_null_filter<CompleteStr, QueryFilter>,
+       map!(ws!(tuple!(parse_filter_operand,
+                       tag_no_case_s!("is"),
+                       opt!(tag_no_case_s!("not")),
+                       tag_no_case_s!("null"))),
+            |f| QueryFilter::BinaryOpFilter(f.0, QueryValue::Null, if f.2.is_some() { QueryFilterBinaryOp::Ne } else { QueryFilterBinaryOp::Eq })));
+
 named!(parse_parenthetical_filter<CompleteStr, QueryFilter>,
        map!(ws!(tuple!(tag_s!("("),
                        parse_filter,
@@ -73,7 +141,7 @@ named!(parse_parenthetical_filter<CompleteStr, QueryFilter>,
             |f| f.1));
 
 named!(parse_unit_filter<CompleteStr, QueryFilter>,
-       alt_complete!(parse_parenthetical_filter | parse_binary_op_filter));
+       alt_complete!(parse_parenthetical_filter | parse_cidr_filter | parse_in_filter | parse_null_filter | parse_binary_op_filter));
 
 named!(parse_and_filter<CompleteStr, QueryFilter>,
        map!(ws!(tuple!(parse_unit_filter,
@@ -96,24 +164,110 @@ named!(parse_or_fallback_filter<CompleteStr, QueryFilter>,
 named!(parse_filter<CompleteStr, QueryFilter>,
        ws!(parse_or_fallback_filter));
 
+/////////////////
+// EXPRESSIONS //
+/////////////////
+
+// lower(path), substr(user_agent, 0, 30) or split(path, "/", 1)
+named!(parse_function_expression<CompleteStr, QueryExpression>,
+       map!(tuple!(map_opt!(take_while1!(is_symbol), |f: CompleteStr| QueryFunction::from_string(&f)),
+                   ws!(char!('(')),
+                   separated_list!(ws!(char!(',')), parse_expression),
+                   ws!(char!(')'))),
+            |f| QueryExpression::Function(f.0, f.2)));
+
+// sum(bytes) inside an arithmetic expression, its value being taken after aggregation
+named!(parse_reducer_expression<CompleteStr, QueryExpression>,
+       map!(tuple!(parse_reducer, delimited!(char!('('), take_until_s!(")"), char!(')'))),
+            |r| QueryExpression::Reducer(r.0, r.1.to_string().to_lowercase())));
+
+named!(parse_expression_operand<CompleteStr, QueryExpression>,
+       alt!(parse_function_expression |
+            parse_reducer_expression |
+            map!(delimited!(char!('"'), take_until_s!("\""), char!('"')), |t| QueryExpression::Text(t.to_string())) |
+            map!(recognize!(tuple!(nom::digit, tag_s!("."), nom::digit)), |d| QueryExpression::Double(d.parse::<f64>().unwrap())) |
+            map_res!(nom::digit, |i: CompleteStr| i.parse::<i64>().map(QueryExpression::Int)) |
+            map!(take_while1!(is_symbol), |s| QueryExpression::Symbol(s.to_string().to_lowercase()))));
+
+named!(parse_expression_factor<CompleteStr, QueryExpression>,
+       alt!(delimited!(ws!(char!('(')), parse_expression, ws!(char!(')'))) | parse_expression_operand));
+
+// Multiplication and division bind tighter than addition and subtraction, operators of the same
+// precedence being applied from left to right.
+named!(parse_expression_term<CompleteStr, QueryExpression>,
+       map!(tuple!(parse_expression_factor,
+                   many0!(tuple!(ws!(alt!(map!(char!('*'), |_| QueryArithmeticOp::Mul) | map!(char!('/'), |_| QueryArithmeticOp::Div))),
+                                 parse_expression_factor))),
+            |t| fold_arithmetic(t.0, t.1)));
+
+named!(parse_expression<CompleteStr, QueryExpression>,
+       map!(tuple!(parse_expression_term,
+                   many0!(tuple!(ws!(alt!(map!(char!('+'), |_| QueryArithmeticOp::Add) | map!(char!('-'), |_| QueryArithmeticOp::Sub))),
+                                 parse_expression_term))),
+            |t| fold_arithmetic(t.0, t.1)));
+
+// Expressions computing a value, rather than a plain column, reducer or literal
+named!(parse_computed_expression<CompleteStr, QueryExpression>,
+       map_opt!(parse_expression, |e: QueryExpression| if e.is_computed() { Some(e) } else { None }));
+
+fn fold_arithmetic(first: QueryExpression, rest: Vec<(QueryArithmeticOp, QueryExpression)>) -> QueryExpression {
+    rest.into_iter().fold(first, |left, (op, right)| QueryExpression::Arithmetic(Box::new(left), op, Box::new(right)))
+}
+
 //////////////
 // GROUPING //
 //////////////
 
 named!(parse_grouping<CompleteStr, QueryGrouping>,
-       map!(tuple!(tag_no_case_s!("group"), separated_list!(tag!(","), ws!(map!(take_while!(is_symbol), |s| s.to_string().to_lowercase())))),
+       map!(tuple!(tag_no_case_s!("group"), separated_list!(tag!(","), ws!(parse_grouping_element))),
             |groupings| QueryGrouping { groupings: groupings.1 }));
 
+named!(parse_grouping_element<CompleteStr, QueryGroupingElement>,
+       alt!(parse_bucket_grouping | parse_time_grouping | parse_expression_grouping | parse_symbol_grouping));
+
+named!(parse_expression_grouping<CompleteStr, QueryGroupingElement>,
+       map!(parse_computed_expression, QueryGroupingElement::Expression));
+
+named!(parse_symbol_grouping<CompleteStr, QueryGroupingElement>,
+       map!(take_while!(is_symbol),
+            |s| QueryGroupingElement::Symbol(s.to_string().to_lowercase())));
+
+// bucket(date, 1h) groups records into fixed windows of the given date column
+named!(parse_bucket_grouping<CompleteStr, QueryGroupingElement>,
+       map!(tuple!(tag_no_case_s!("bucket"), ws!(char!('(')), take_while1!(is_symbol), ws!(char!(',')), recognize!(parse_duration), ws!(char!(')'))),
+            |b| QueryGroupingElement::Bucket(QueryBucket::new(Some(b.2.to_string().to_lowercase()), &b.4))));
+
+// time(5m) is a bucket over the first date column of the log format
+named!(parse_time_grouping<CompleteStr, QueryGroupingElement>,
+       map!(tuple!(tag_no_case_s!("time"), ws!(char!('(')), recognize!(parse_duration), ws!(char!(')'))),
+            |t| QueryGroupingElement::Bucket(QueryBucket::new(None, &t.2))));
+
 //////////
 // SHOW //
 //////////
 
 named!(parse_show<CompleteStr, QueryShow>,
-       map!(tuple!(tag_no_case_s!("show"), separated_list!(tag!(","), ws!(parse_show_element))),
-            |elements| QueryShow { elements: elements.1 }));
+       map!(tuple!(tag_no_case_s!("show"), separated_list!(tag!(","), ws!(parse_aliased_show_element))),
+            |elements| {
+                let mut aliases = HashMap::new();
+                for &(ref element, ref alias) in &elements.1 {
+                    if let Some(alias) = alias {
+                        aliases.insert(element.name(), alias.to_owned());
+                    }
+                }
+                QueryShow { elements: elements.1.into_iter().map(|e| e.0).collect(), aliases: aliases }
+            }));
+
+// count(*) as hits
+named!(parse_aliased_show_element<CompleteStr, (QueryShowElement, Option<String>)>,
+       tuple!(parse_show_element,
+              opt!(map!(preceded!(ws!(tag_no_case_s!("as")), take_while1!(is_symbol)), |a| a.to_string().to_lowercase()))));
 
 named!(parse_show_element<CompleteStr, QueryShowElement>,
-       alt!(parse_show_all | parse_show_reducer | parse_show_symbol));
+       alt!(parse_show_all | parse_show_top | parse_show_ratio | parse_show_percentile | parse_show_expression | parse_show_reducer | parse_show_symbol));
+
+named!(parse_show_expression<CompleteStr, QueryShowElement>,
+       map!(parse_computed_expression, QueryShowElement::Expression));
 
 named!(parse_show_all<CompleteStr, QueryShowElement>,
        map!(tag_no_case_s!("*"),
@@ -127,31 +281,96 @@ named!(parse_show_reducer<CompleteStr, QueryShowElement>,
        map!(tuple!(parse_reducer, delimited!(char!('('), take_until_s!(")"), char!(')'))),
             |s| QueryShowElement::Reducer(s.0, s.1.to_string().to_lowercase())));
 
+// top(5, ip) shows the five most frequent values of a column
+named!(parse_show_top<CompleteStr, QueryShowElement>,
+       map_res!(tuple!(tag_no_case_s!("top"), ws!(char!('(')), nom::digit, ws!(char!(',')), take_while1!(is_symbol), ws!(char!(')'))),
+                |t: (CompleteStr, char, CompleteStr, char, CompleteStr, char)|
+                    t.2.parse::<usize>().map(|n| QueryShowElement::Reducer(QueryReducer::Top(n), t.4.to_string().to_lowercase()))));
+
+// p(request_time, 95) shows the value a percent of a column's values are at or below
+named!(parse_show_percentile<CompleteStr, QueryShowElement>,
+       map!(parse_percentile,
+            |p: (QueryReducer, String)| QueryShowElement::Reducer(p.0, p.1.to_lowercase())));
+
+named!(parse_percentile<CompleteStr, (QueryReducer, String)>,
+       map_res!(tuple!(tag_no_case_s!("p"), ws!(char!('(')), take_while1!(is_symbol), ws!(char!(',')), nom::digit, ws!(char!(')'))),
+                |t: (CompleteStr, char, CompleteStr, char, CompleteStr, char)|
+                    t.4.parse::<usize>().map(|n| (QueryReducer::Percentile(n), t.2.to_string()))));
+
+named!(parse_named_reducer<CompleteStr, (QueryReducer, String)>,
+       alt!(parse_percentile |
+            map!(tuple!(parse_reducer, delimited!(char!('('), take_until_s!(")"), char!(')'))),
+                 |r| (r.0, r.1.to_string()))));
+
+// ratio(status >= 500) shows the fraction of records matching a filter, which is kept as written
+named!(parse_show_ratio<CompleteStr, QueryShowElement>,
+       map!(tuple!(tag_no_case_s!("ratio"), ws!(char!('(')), recognize!(parse_filter), ws!(char!(')'))),
+            |t: (CompleteStr, char, CompleteStr, char)| QueryShowElement::Reducer(QueryReducer::Ratio, t.2.trim().to_owned())));
+
 named!(parse_reducer<CompleteStr, QueryReducer>,
-       alt!(map!(tag_s!("count"), |_| QueryReducer::Count) |
+       alt!(map!(tag_s!("count_distinct"), |_| QueryReducer::CountDistinct) |
+            map!(tag_s!("count"), |_| QueryReducer::Count) |
+            map!(tag_s!("unique"), |_| QueryReducer::Unique) |
+            map!(tag_s!("approx_unique"), |_| QueryReducer::ApproxUnique) |
             map!(tag_s!("sum"), |_| QueryReducer::Sum) |
             map!(tag_s!("max"), |_| QueryReducer::Max) |
-            map!(tag_s!("avg"), |_| QueryReducer::Avg)));
+            map!(tag_s!("min"), |_| QueryReducer::Min) |
+            map!(tag_s!("avg"), |_| QueryReducer::Avg) |
+            map!(tag_s!("first"), |_| QueryReducer::First) |
+            map!(tag_s!("last"), |_| QueryReducer::Last) |
+            map!(tag_s!("rate"), |_| QueryReducer::Rate) |
+            map!(tag_s!("stddev"), |_| QueryReducer::Stddev) |
+            map!(tag_s!("var"), |_| QueryReducer::Var)));
+
+////////////
+// HAVING //
+////////////
+
+named!(parse_having<CompleteStr, QueryHaving>,
+       map!(tuple!(tag_no_case_s!("having"), parse_filter),
+            |h| QueryHaving { filter: h.1 }));
 
 //////////
 // SORT //
 //////////
 
+named!(parse_sort_element<CompleteStr, QuerySortElement>,
+       map!(tuple!(parse_sort_field,
+                   take_while!(is_whitespace),
+                   opt!(alt!(tag_no_case_s!("asc") | tag_no_case_s!("desc")))),
+            |s| QuerySortElement::new(s.0.to_lowercase(), s.2.map(|st| st.to_string()))));
+
+// Percentiles are sorted on as they're shown, e.g. p(request_time, 95), and named by their column
+named!(parse_sort_field<CompleteStr, String>,
+       alt!(map!(parse_percentile, |p: (QueryReducer, String)| p.0.column_name(&p.1)) |
+            map!(take_while1!(is_symbol_or_parens), |s| s.to_string())));
+
+// sort count(*) desc, sum(bytes) desc or sort group
 named!(parse_sort<CompleteStr, QuerySort>,
        map!(tuple!(tag_no_case_s!("sort"),
                    take_while!(is_whitespace),
-                   take_while!(is_symbol_or_parens),
-                   take_while!(is_whitespace),
-                   opt!(alt!(tag_no_case_s!("asc") | tag_no_case_s!("desc")))),
-            |s| QuerySort { sortings: vec![QuerySortElement::new(s.2.to_string().to_lowercase(), s.4.map(|st| st.to_string()))] }));
+                   separated_nonempty_list!(ws!(tag_s!(",")), parse_sort_element)),
+            |s| QuerySort { sortings: s.2 }));
 
 ///////////
 // LIMIT //
 ///////////
 
 named!(parse_limit<CompleteStr, QueryLimit>,
-       map!(tuple!(tag_no_case_s!("limit"), take_while!(is_whitespace), nom::digit),
-            |limit| QueryLimit { limit: limit.2.parse::<usize>().unwrap() }));
+       map_res!(tuple!(tag_no_case_s!("limit"), take_while!(is_whitespace), nom::digit),
+                |limit: (CompleteStr, CompleteStr, CompleteStr)| limit.2.parse::<usize>().map(|l| QueryLimit { limit: l })));
+
+///////////
+// EVERY //
+///////////
+
+named!(parse_every<CompleteStr, QueryWindow>,
+       map!(tuple!(tag_no_case_s!("every"), take_while!(is_whitespace), parse_duration),
+            |every| QueryWindow { seconds: every.2 }));
+
+named!(parse_duration<CompleteStr, i64>,
+       map_res!(tuple!(nom::digit, alt!(tag_s!("s") | tag_s!("m") | tag_s!("h") | tag_s!("d"))),
+                |d: (CompleteStr, CompleteStr)| d.0.parse::<i64>().map(|v| v * duration_unit_seconds(&d.1))));
 
 ///////////
 // QUERY //
@@ -164,14 +383,20 @@ named!(parse_riplog_query<CompleteStr, RipLogQuery>,
                    opt!(tag_no_case_s!("|")),
                    opt!(ws!(parse_show)),
                    opt!(tag_no_case_s!("|")),
+                   opt!(ws!(parse_having)),
+                   opt!(tag_no_case_s!("|")),
                    opt!(ws!(parse_sort)),
                    opt!(tag_no_case_s!("|")),
-                   opt!(ws!(parse_limit))),
-            |f| RipLogQuery { filter: f.0, grouping: f.2, show: f.4, sort: f.6, limit: f.8, computed_show: None }));
+                   opt!(ws!(parse_limit)),
+                   opt!(tag_no_case_s!("|")),
+                   opt!(ws!(parse_every)),
+                   opt!(preceded!(ws!(tag_s!("~>")), parse_riplog_query))),
+            |f| RipLogQuery { filter: f.0, grouping: f.2, show: f.4, having: f.6, sort: f.8, limit: f.10, every: f.12, computed_show: None,
+                              next: f.13.map(Box::new) }));
 
 
 fn is_whitespace(chr: char) -> bool {
-    chr == ' '
+    chr == ' ' || chr == '\t' || chr == '\n' || chr == '\r'
 }
 
 fn is_symbol(chr: char) -> bool {
@@ -182,19 +407,285 @@ fn is_symbol_or_parens(chr: char) -> bool {
     chr.is_alphanumeric() || chr == '_' || chr == '(' || chr == ')' || chr == '*'
 }
 
-fn create_date_from_string(date: String) -> DateTime<Local> {
+fn duration_unit_seconds(unit: &str) -> i64 {
+    match unit {
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => 1,
+    }
+}
+
+// Dates are either absolute or relative to the current local time: now, today, yesterday or a
+// duration in the past such as -24h.
+fn create_date_from_string(date: &str) -> Option<DateTime<Local>> {
+    match date.trim().to_lowercase().as_ref() {
+        "now" => Some(Local::now()),
+        "today" => Some(Local::today().and_hms(0, 0, 0)),
+        "yesterday" => Some(Local::today().and_hms(0, 0, 0) - Duration::days(1)),
+        relative if relative.starts_with('-') => create_relative_date(&relative[1..]),
+        _ => create_absolute_date(date.to_owned()).ok(),
+    }
+}
+
+// Number of seconds of a duration such as 30s, 5m or 1h.
+pub fn parse_duration_seconds(duration: &str) -> Option<i64> {
+    match parse_duration(CompleteStr(duration)) {
+        Ok((remaining, seconds)) if remaining.is_empty() && seconds > 0 => Some(seconds),
+        _ => None,
+    }
+}
+
+fn create_relative_date(duration: &str) -> Option<DateTime<Local>> {
+    match parse_duration(CompleteStr(duration)) {
+        Ok((remaining, seconds)) if remaining.is_empty() => Some(Local::now() - Duration::seconds(seconds)),
+        _ => None,
+    }
+}
+
+fn create_absolute_date(date: String) -> Result<DateTime<Local>, ParseError> {
     if date.len() <= 10 {
         let dt = date + " 00:00:00";
-        Local.datetime_from_str(&dt, "%m-%d-%Y %H:%M:%S").unwrap()
+        Local.datetime_from_str(&dt, "%m-%d-%Y %H:%M:%S")
     } else if date.len() <= 20 {
-        Local.datetime_from_str(&date, "%m-%d-%Y %H:%M:%S").unwrap()
+        Local.datetime_from_str(&date, "%m-%d-%Y %H:%M:%S")
     } else {
-        DateTime::parse_from_str(&date, "%m-%d-%Y %H:%M:%S %z").unwrap().with_timezone(&Local)
+        DateTime::parse_from_str(&date, "%m-%d-%Y %H:%M:%S %z").map(|d| d.with_timezone(&Local))
+    }
+}
+
+// Reducer named by a column of a sort, e.g. sum(bytes) in 'sort sum(bytes) desc'.
+pub fn parse_reducer_name(name: &str) -> Option<(QueryReducer, String)> {
+    match parse_named_reducer(CompleteStr(name)) {
+        Ok((remaining, reducer)) if remaining.is_empty() => Some(reducer),
+        _ => None,
+    }
+}
+
+// Filter of a ratio() reducer, which is kept as text in its column name.
+pub fn parse_ratio_filter(filter: &str) -> Option<QueryFilter> {
+    match parse_filter(CompleteStr(filter)) {
+        Ok((remaining, filter)) if remaining.trim().is_empty() => Some(combine_regex_filters(filter)),
+        _ => None,
+    }
+}
+
+pub fn parse_query(query: String) -> Result<RipLogQuery, QueryParseError> {
+    let query = strip_comments(&query);
+    match parse_riplog_query(CompleteStr(&query)) {
+        Ok((remaining, _)) if !remaining.trim().is_empty() => {
+            let unparsed = remaining.trim_start();
+            Err(QueryParseError::new(&query, query.len() - unparsed.len(), describe_unparsed(unparsed)))
+        },
+        Ok((_, mut parsed)) => {
+            prepare_stages(&mut parsed);
+            Ok(parsed)
+        },
+        Err(nom::Err::Error(Context::Code(remaining, kind))) | Err(nom::Err::Failure(Context::Code(remaining, kind))) =>
+            Err(QueryParseError::new(&query, query.len() - remaining.len(), describe_parse_error(&remaining, kind))),
+        Err(nom::Err::Incomplete(_)) =>
+            Err(QueryParseError::new(&query, query.len(), "Unexpected end of query".to_owned())),
+    }
+}
+
+// Parses the condition of an alert, a filter over the result rows of a query like its having clause,
+// e.g. `count(*) > 100`. Aliases the query shows columns under are resolved as in having filters.
+pub fn parse_alert(condition: &str, query: &RipLogQuery) -> Result<QueryFilter, QueryParseError> {
+    match parse_filter(CompleteStr(condition)) {
+        Ok((remaining, _)) if !remaining.trim().is_empty() => {
+            let unparsed = remaining.trim_start();
+            Err(QueryParseError::new(condition, condition.len() - unparsed.len(), describe_unparsed(unparsed)))
+        },
+        Ok((_, mut filter)) => {
+            if let Some(ref show) = query.show {
+                resolve_filter_aliases(&mut filter, show);
+            }
+            Ok(filter)
+        },
+        Err(nom::Err::Error(Context::Code(remaining, kind))) | Err(nom::Err::Failure(Context::Code(remaining, kind))) =>
+            Err(QueryParseError::new(condition, condition.len() - remaining.len(), describe_parse_error(&remaining, kind))),
+        Err(nom::Err::Incomplete(_)) =>
+            Err(QueryParseError::new(condition, condition.len(), "Unexpected end of condition".to_owned())),
+    }
+}
+
+fn prepare_stages(query: &mut RipLogQuery) {
+    resolve_aliases(query);
+    query.filter = query.filter.take().map(combine_regex_filters);
+    if let Some(ref mut next) = query.next {
+        prepare_stages(next);
+    }
+}
+
+// Regex matches of a column joined with ||, e.g. path ~ r"^/api/" || path ~ r"\.php$", are combined
+// into a single regex so that the column is only scanned once.
+fn combine_regex_filters(filter: QueryFilter) -> QueryFilter {
+    match filter {
+        QueryFilter::OrFilter(filter1, filter2) =>
+            match (combine_regex_filters(*filter1), combine_regex_filters(*filter2)) {
+                (QueryFilter::BinaryOpFilter(QueryValue::Symbol(ref symbol1), QueryValue::Regex(ref regex1, _), QueryFilterBinaryOp::Re),
+                 QueryFilter::BinaryOpFilter(QueryValue::Symbol(ref symbol2), QueryValue::Regex(ref regex2, _), QueryFilterBinaryOp::Re))
+                    if symbol1 == symbol2 => {
+                    let pattern = format!("(?:{})|(?:{})", regex1.as_str(), regex2.as_str());
+                    let regex = compile_regex(&pattern).expect("combined regexes are valid");
+                    QueryFilter::BinaryOpFilter(QueryValue::Symbol(symbol1.to_owned()), regex, QueryFilterBinaryOp::Re)
+                },
+                (filter1, filter2) => QueryFilter::OrFilter(Box::new(filter1), Box::new(filter2)),
+            },
+        QueryFilter::AndFilter(filter1, filter2) =>
+            QueryFilter::AndFilter(Box::new(combine_regex_filters(*filter1)), Box::new(combine_regex_filters(*filter2))),
+        filter => filter,
+    }
+}
+
+// Queries, e.g. those read from files, may have comments running from a '#' outside of a string to
+// the end of the line. They are blanked out rather than removed so that error positions still match
+// the original query.
+fn strip_comments(query: &str) -> String {
+    let mut stripped = String::with_capacity(query.len());
+    let mut quoted = false;
+    let mut comment = false;
+    for chr in query.chars() {
+        if chr == '\n' {
+            comment = false;
+        } else if chr == '"' && !comment {
+            quoted = !quoted;
+        } else if chr == '#' && !quoted {
+            comment = true;
+        }
+        if comment {
+            stripped.extend((0..chr.len_utf8()).map(|_| ' '));
+        } else {
+            stripped.push(chr);
+        }
+    }
+    stripped
+}
+
+// Sorts and having filters may refer to shown columns by their alias, which is replaced by the
+// column it names so that later stages only deal with column names.
+fn resolve_aliases(query: &mut RipLogQuery) {
+    let show = match query.show {
+        Some(ref show) if !show.aliases.is_empty() => show.clone(),
+        _ => return,
+    };
+    if let Some(ref mut sort) = query.sort {
+        for sorting in sort.sortings.iter_mut() {
+            sorting.field = show.resolve_alias(&sorting.field);
+        }
+    }
+    if let Some(ref mut having) = query.having {
+        resolve_filter_aliases(&mut having.filter, &show);
+    }
+}
+
+fn resolve_filter_aliases(filter: &mut QueryFilter, show: &QueryShow) {
+    match filter {
+        QueryFilter::BinaryOpFilter(operand1, operand2, _) => {
+            resolve_value_alias(operand1, show);
+            resolve_value_alias(operand2, show);
+        },
+        QueryFilter::InFilter(operand, values, _) => {
+            resolve_value_alias(operand, show);
+            for value in values.iter_mut() {
+                resolve_value_alias(value, show);
+            }
+        },
+        QueryFilter::CidrFilter(operand, _, _) => resolve_value_alias(operand, show),
+        QueryFilter::AndFilter(filter1, filter2) | QueryFilter::OrFilter(filter1, filter2) => {
+            resolve_filter_aliases(filter1, show);
+            resolve_filter_aliases(filter2, show);
+        },
+    }
+}
+
+fn resolve_value_alias(value: &mut QueryValue, show: &QueryShow) {
+    match *value {
+        QueryValue::Symbol(ref mut symbol) => *symbol = show.resolve_alias(symbol),
+        QueryValue::Expression(ref mut expression) => expression.resolve_aliases(show),
+        _ => (),
+    }
+}
+
+fn compile_regex(pattern: &str) -> Result<QueryValue, regex::Error> {
+    Ok(QueryValue::Regex(Regex::new(pattern)?, BytesRegex::new(pattern)?))
+}
+
+fn describe_parse_error(remaining: &str, kind: ErrorKind) -> String {
+    let literal = remaining.split('"').next().unwrap_or("");
+    match kind {
+        ErrorKind::Custom(INVALID_REGEX) =>
+            match Regex::new(literal) {
+                Err(e) => format!("Invalid regular expression r\"{}\": {}", literal, e.to_string().lines().last().unwrap_or("").trim_start_matches("error: ")),
+                Ok(_) => format!("Unterminated regular expression r\"{}", literal),
+            },
+        ErrorKind::Custom(INVALID_DATE) =>
+            format!("Invalid date d\"{}\", expected MM-DD-YYYY, MM-DD-YYYY HH:MM:SS, MM-DD-YYYY HH:MM:SS +ZZZZ, now, today, yesterday or a relative duration like -24h", literal),
+        ErrorKind::Custom(INVALID_CIDR) =>
+            "Invalid address range, expected quoted ranges like \"10.0.0.0/8\" or (\"10.0.0.0/8\", \"fd00::/8\")".to_owned(),
+        _ => "Unable to parse query".to_owned(),
+    }
+}
+
+// Every clause is optional, so text left over after parsing is a misspelled or misplaced clause or
+// a filter that doesn't parse.
+fn describe_unparsed(unparsed: &str) -> String {
+    let word = unparsed.chars().take_while(|c| is_symbol(*c)).collect::<String>().to_lowercase();
+    if CLAUSES.contains(&word.as_str()) {
+        return format!("Misplaced or invalid '{}' clause, clauses must be given in the order {}", word, CLAUSES.join(", "));
+    }
+    match suggest(&word, CLAUSES.iter().cloned()) {
+        Some(clause) if !word.is_empty() => format!("Unknown clause '{}', did you mean '{}'?", word, clause),
+        _ => format!("Unexpected '{}'", unparsed.split(is_whitespace).next().unwrap_or(unparsed)),
+    }
+}
+
+// The candidate closest to a misspelled word, if any is close enough to be what was meant.
+pub fn suggest<'a, I>(word: &str, candidates: I) -> Option<&'a str> where I: Iterator<Item = &'a str> {
+    candidates.map(|c| (edit_distance(word, c), c)).filter(|&(d, _)| d <= word.len() / 3 + 1).min().map(|(_, c)| c)
+}
+
+// Number of single character insertions, deletions and substitutions to turn one word into another.
+fn edit_distance(word1: &str, word2: &str) -> usize {
+    let chars2: Vec<char> = word2.chars().collect();
+    let mut previous: Vec<usize> = (0..chars2.len() + 1).collect();
+    for (i, chr1) in word1.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, chr2) in chars2.iter().enumerate() {
+            let substitution = previous[j] + if chr1 == *chr2 { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
     }
+    previous[chars2.len()]
 }
 
-pub fn parse_query(query: String) -> RipLogQuery {
-    parse_riplog_query(CompleteStr(&query)).unwrap().1
+#[derive(Debug, Clone)]
+pub struct QueryParseError {
+    pub query: String,
+    pub position: usize,
+    pub msg: String,
+}
+
+impl QueryParseError {
+    pub fn new(query: &str, position: usize, msg: String) -> QueryParseError {
+        QueryParseError { query: query.to_owned(), position, msg }
+    }
+}
+
+impl fmt::Display for QueryParseError {
+    // Queries spanning several lines only show the line of the error.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let start = self.query[0..self.position].rfind('\n').map(|idx| idx + 1).unwrap_or(0);
+        let end = self.query[self.position..].find('\n').map(|idx| self.position + idx).unwrap_or(self.query.len());
+        let pad = (0..self.query[start..self.position].chars().count()).map(|_| " ").collect::<String>();
+        if start == 0 && end == self.query.len() {
+            write!(f, "{} (at position {})\n\n    {}\n    {}^", self.msg, self.position, self.query, pad)
+        } else {
+            let line = self.query[0..start].matches('\n').count() + 1;
+            write!(f, "{} (at line {}, column {})\n\n    {}\n    {}^", self.msg, line, self.position - start + 1, &self.query[start..end], pad)
+        }
+    }
 }
 
 
@@ -203,9 +694,13 @@ pub struct RipLogQuery {
     pub filter: Option<QueryFilter>,
     pub grouping: Option<QueryGrouping>,
     pub show: Option<QueryShow>,
+    pub having: Option<QueryHaving>,
     pub sort: Option<QuerySort>,
     pub limit: Option<QueryLimit>,
-    pub computed_show: Option<QueryShow>
+    pub every: Option<QueryWindow>,
+    pub computed_show: Option<QueryShow>,
+    // The stage after `~>`, evaluated over the rows this one results in
+    pub next: Option<Box<RipLogQuery>>,
 }
 
 impl RipLogQuery {
@@ -213,9 +708,9 @@ impl RipLogQuery {
         let mut elements = Vec::new();
         if self.show.is_some() {
             if self.grouping.is_some() {
-                let filtered_shows: Vec<QueryShowElement> = self.show.as_ref().unwrap().elements.iter().filter(|e| e.is_reducer()).map(|e| e.clone()).collect();
+                let filtered_shows: Vec<QueryShowElement> = self.show.as_ref().unwrap().elements.iter().filter(|e| e.is_aggregate()).map(|e| e.clone()).collect();
                 for group in &self.grouping.as_ref().unwrap().groupings {
-                    elements.push(QueryShowElement::Symbol(group.to_owned()));
+                    elements.push(QueryShowElement::Symbol(group.name()));
                 }
                 if filtered_shows.is_empty() {
                     elements.push(QueryShowElement::Reducer(QueryReducer::Count, "*".to_owned()));
@@ -223,8 +718,8 @@ impl RipLogQuery {
                 for show in filtered_shows {
                     elements.push(show.clone());
                 }
-            } else if self.show.as_ref().unwrap().elements.iter().any(|e| e.is_reducer()) {
-                let filtered_shows: Vec<QueryShowElement> = self.show.as_ref().unwrap().elements.iter().filter(|e| e.is_reducer()).map(|e| e.clone()).collect();
+            } else if self.show.as_ref().unwrap().elements.iter().any(|e| e.is_aggregate()) {
+                let filtered_shows: Vec<QueryShowElement> = self.show.as_ref().unwrap().elements.iter().filter(|e| e.is_aggregate()).map(|e| e.clone()).collect();
                 for show in filtered_shows {
                     elements.push(show);
                 }
@@ -241,7 +736,7 @@ impl RipLogQuery {
         } else {
             if self.grouping.is_some() {
                 for group in &self.grouping.as_ref().unwrap().groupings {
-                    elements.push(QueryShowElement::Symbol(group.to_owned()));
+                    elements.push(QueryShowElement::Symbol(group.name()));
                 }
                 elements.push(QueryShowElement::Reducer(QueryReducer::Count, "*".to_owned()));
             } else {
@@ -250,13 +745,29 @@ impl RipLogQuery {
                 }
             }
         }
-        self.computed_show = Some(QueryShow { elements })
+        let aliases = self.show.as_ref().map(|s| s.aliases.clone()).unwrap_or(HashMap::new());
+        self.computed_show = Some(QueryShow { elements, aliases })
+    }
+
+    // Elements of show that compute_show leaves out, as aggregate queries only show their group columns
+    // and reducers, e.g. method in 'group path | show method, count(*)'.
+    pub fn dropped_show_elements(&self) -> Vec<&QueryShowElement> {
+        let show = match self.show {
+            Some(ref show) if self.grouping.is_some() || show.elements.iter().any(|e| e.is_aggregate()) => show,
+            _ => return Vec::new(),
+        };
+        let groups: Vec<String> = self.grouping.iter().flat_map(|g| g.groupings.iter().map(|e| e.name())).collect();
+        show.elements.iter().filter(|e| !e.is_aggregate() && !groups.contains(&e.name())).collect()
     }
 }
 
 #[derive(Debug, Clone)]
 pub enum QueryFilter {
     BinaryOpFilter(QueryValue, QueryValue, QueryFilterBinaryOp),
+    // Operand, candidate values and whether the filter is negated (not in)
+    InFilter(QueryValue, Vec<QueryValue>, bool),
+    // Operand, address ranges and whether the filter is negated (not in_cidr)
+    CidrFilter(QueryValue, CidrSet, bool),
     AndFilter(Box<QueryFilter>, Box<QueryFilter>),
     OrFilter(Box<QueryFilter>, Box<QueryFilter>),
 }
@@ -265,11 +776,13 @@ pub enum QueryFilter {
 pub enum QueryValue {
     Symbol(String),
     Text(String, Vec<u8>),
-    Regex(Regex),
+    // Matched against text, and against the raw bytes of text columns
+    Regex(Regex, BytesRegex),
     Int(i64, Vec<u8>),
     Double(f64, Vec<u8>),
     Boolean(bool),
     Date(DateTime<Local>),
+    Expression(QueryExpression),
     Null,
 }
 
@@ -280,28 +793,335 @@ impl QueryValue {
             _ => false,
         }
     }
+
+    pub fn is_expression(&self) -> bool {
+        match self {
+            QueryValue::Expression(_) => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum QueryFilterBinaryOp {
-    Lt, Gt, Eq, Ne, Re, Nr
+    Lt, Gt, Le, Ge, Eq, Ne, Re, Nr
+}
+
+impl QueryFilterBinaryOp {
+    // The operator comparing the operands in the opposite order, e.g. a < b is b > a
+    pub fn flip(&self) -> QueryFilterBinaryOp {
+        match self {
+            QueryFilterBinaryOp::Lt => QueryFilterBinaryOp::Gt,
+            QueryFilterBinaryOp::Gt => QueryFilterBinaryOp::Lt,
+            QueryFilterBinaryOp::Le => QueryFilterBinaryOp::Ge,
+            QueryFilterBinaryOp::Ge => QueryFilterBinaryOp::Le,
+            op => op.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct QueryGrouping {
-    pub groupings: Vec<String>
+    pub groupings: Vec<QueryGroupingElement>
+}
+
+#[derive(Debug, Clone)]
+pub enum QueryGroupingElement {
+    Symbol(String),
+    Bucket(QueryBucket),
+    Expression(QueryExpression),
+}
+
+impl QueryGroupingElement {
+    pub fn name(&self) -> String {
+        match self {
+            QueryGroupingElement::Symbol(symbol) => symbol.to_owned(),
+            QueryGroupingElement::Bucket(bucket) => bucket.name.to_owned(),
+            QueryGroupingElement::Expression(expression) => expression.name(),
+        }
+    }
+
+    pub fn is_bucket(&self) -> bool {
+        match self {
+            QueryGroupingElement::Bucket(_) => true,
+            _ => false
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QueryBucket {
+    pub name: String,
+    pub column: Option<String>,
+    pub seconds: i64,
+}
+
+impl QueryBucket {
+    fn new(column: Option<String>, duration: &str) -> QueryBucket {
+        let seconds = parse_duration(CompleteStr(duration)).map(|d| d.1).unwrap_or(0);
+        let name =
+            match column {
+                Some(ref column) => format!("bucket({},{})", column, duration),
+                None => format!("time({})", duration),
+            };
+        QueryBucket { name, column, seconds }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct QueryShow {
-    pub elements: Vec<QueryShowElement>
+    pub elements: Vec<QueryShowElement>,
+    // Output names given with 'as', keyed by the column name of the element
+    pub aliases: HashMap<String, String>,
+}
+
+impl QueryShow {
+    // Output name of a column, its alias if it was given one.
+    pub fn display_name(&self, name: &str) -> String {
+        self.aliases.get(name).cloned().unwrap_or(name.to_owned())
+    }
+
+    // Column name an alias refers to, or the name itself when it isn't an alias.
+    pub fn resolve_alias(&self, name: &str) -> String {
+        self.aliases.iter().find(|&(_, alias)| alias == name).map(|(column, _)| column.to_owned()).unwrap_or(name.to_owned())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum QueryExpression {
+    Symbol(String),
+    Text(String),
+    Int(i64),
+    Double(f64),
+    Function(QueryFunction, Vec<QueryExpression>),
+    // A reducer, only valid in show and having expressions of aggregate queries
+    Reducer(QueryReducer, String),
+    Arithmetic(Box<QueryExpression>, QueryArithmeticOp, Box<QueryExpression>),
+}
+
+impl QueryExpression {
+    // Name of the expression as an output column, e.g. split(path,"/",1) or sum(bytes)/1024.
+    pub fn name(&self) -> String {
+        match self {
+            QueryExpression::Symbol(symbol) => symbol.to_owned(),
+            QueryExpression::Text(text) => format!("\"{}\"", text),
+            QueryExpression::Int(int) => int.to_string(),
+            // Whole doubles keep their point, e.g. bytes/1024.0, as they're divided unlike integers
+            QueryExpression::Double(double) if double.fract() == 0.0 => format!("{:.1}", double),
+            QueryExpression::Double(double) => double.to_string(),
+            QueryExpression::Function(function, arguments) =>
+                format!("{}({})", function.to_string(), arguments.iter().map(|a| a.name()).collect::<Vec<String>>().join(",")),
+            QueryExpression::Reducer(reducer, symbol) => reducer.column_name(symbol),
+            QueryExpression::Arithmetic(left, op, right) =>
+                format!("{}{}{}", left.operand_name(op, false), op.to_string(), right.operand_name(op, true)),
+        }
+    }
+
+    // Name of the expression as an operand of an arithmetic operator, parenthesized when it
+    // wouldn't otherwise be evaluated first, e.g. (bytes+1)*2 or bytes-(1+2).
+    fn operand_name(&self, parent: &QueryArithmeticOp, right: bool) -> String {
+        match self {
+            QueryExpression::Arithmetic(_, op, _) if op.precedence() < parent.precedence() || (right && op.precedence() == parent.precedence()) =>
+                format!("({})", self.name()),
+            _ => self.name(),
+        }
+    }
+
+    // Columns read from records to evaluate the expression, including those of its reducers.
+    pub fn symbols(&self, symbols: &mut Vec<String>) {
+        match self {
+            QueryExpression::Symbol(symbol) => symbols.push(symbol.to_owned()),
+            QueryExpression::Reducer(reducer, symbol) if symbol != "*" && reducer.reads_column() => symbols.push(symbol.to_owned()),
+            QueryExpression::Function(_, arguments) => {
+                for argument in arguments {
+                    argument.symbols(symbols);
+                }
+            },
+            QueryExpression::Arithmetic(left, _, right) => {
+                left.symbols(symbols);
+                right.symbols(symbols);
+            },
+            _ => (),
+        }
+    }
+
+    // Output columns the expression refers to: group columns and reducers such as count(*).
+    pub fn columns(&self, columns: &mut Vec<String>) {
+        match self {
+            QueryExpression::Symbol(symbol) => columns.push(symbol.to_owned()),
+            QueryExpression::Reducer(reducer, symbol) => columns.push(reducer.column_name(symbol)),
+            QueryExpression::Function(_, arguments) => {
+                for argument in arguments {
+                    argument.columns(columns);
+                }
+            },
+            QueryExpression::Arithmetic(left, _, right) => {
+                left.columns(columns);
+                right.columns(columns);
+            },
+            _ => (),
+        }
+    }
+
+    pub fn reducers(&self, reducers: &mut Vec<(QueryReducer, String)>) {
+        match self {
+            QueryExpression::Reducer(reducer, symbol) => reducers.push((reducer.clone(), symbol.to_owned())),
+            QueryExpression::Function(_, arguments) => {
+                for argument in arguments {
+                    argument.reducers(reducers);
+                }
+            },
+            QueryExpression::Arithmetic(left, _, right) => {
+                left.reducers(reducers);
+                right.reducers(reducers);
+            },
+            _ => (),
+        }
+    }
+
+    pub fn has_reducer(&self) -> bool {
+        let mut reducers = Vec::new();
+        self.reducers(&mut reducers);
+        !reducers.is_empty()
+    }
+
+    // Whether the expression computes a value, rather than being a plain column, reducer or literal.
+    pub fn is_computed(&self) -> bool {
+        match self {
+            QueryExpression::Function(_, _) | QueryExpression::Arithmetic(_, _, _) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        match self {
+            QueryExpression::Int(_) | QueryExpression::Double(_) | QueryExpression::Arithmetic(_, _, _) => true,
+            QueryExpression::Function(function, _) => function.is_date_part() || *function == QueryFunction::Bucket,
+            _ => false,
+        }
+    }
+
+    // Replaces aliases of shown columns used in the expression by the columns they name.
+    fn resolve_aliases(&mut self, show: &QueryShow) {
+        match self {
+            QueryExpression::Symbol(ref mut symbol) => *symbol = show.resolve_alias(symbol),
+            QueryExpression::Function(_, ref mut arguments) => {
+                for argument in arguments.iter_mut() {
+                    argument.resolve_aliases(show);
+                }
+            },
+            QueryExpression::Arithmetic(ref mut left, _, ref mut right) => {
+                left.resolve_aliases(show);
+                right.resolve_aliases(show);
+            },
+            _ => (),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueryArithmeticOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl QueryArithmeticOp {
+    pub fn to_string(&self) -> &str {
+        match self {
+            QueryArithmeticOp::Add => "+",
+            QueryArithmeticOp::Sub => "-",
+            QueryArithmeticOp::Mul => "*",
+            QueryArithmeticOp::Div => "/",
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            QueryArithmeticOp::Add | QueryArithmeticOp::Sub => 1,
+            QueryArithmeticOp::Mul | QueryArithmeticOp::Div => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueryFunction {
+    Lower,
+    Substr,
+    Split,
+    Hour,
+    DayOfWeek,
+    Day,
+    // Start of the range of fixed width a number falls in
+    Bucket,
+}
+
+impl QueryFunction {
+    pub fn from_string(function: &str) -> Option<QueryFunction> {
+        match function.to_lowercase().as_ref() {
+            "lower" => Some(QueryFunction::Lower),
+            "substr" => Some(QueryFunction::Substr),
+            "split" => Some(QueryFunction::Split),
+            "hour" => Some(QueryFunction::Hour),
+            "dayofweek" => Some(QueryFunction::DayOfWeek),
+            "day" => Some(QueryFunction::Day),
+            "bucket" => Some(QueryFunction::Bucket),
+            _ => None,
+        }
+    }
+
+    pub fn to_string(&self) -> &str {
+        match self {
+            QueryFunction::Lower => "lower",
+            QueryFunction::Substr => "substr",
+            QueryFunction::Split => "split",
+            QueryFunction::Hour => "hour",
+            QueryFunction::DayOfWeek => "dayofweek",
+            QueryFunction::Day => "day",
+            QueryFunction::Bucket => "bucket",
+        }
+    }
+
+    // Usage shown when a function is called with the wrong arguments
+    pub fn signature(&self) -> &str {
+        match self {
+            QueryFunction::Lower => "lower(text)",
+            QueryFunction::Substr => "substr(text, start[, length])",
+            QueryFunction::Split => "split(text, \"separator\", index)",
+            QueryFunction::Hour => "hour(date)",
+            QueryFunction::DayOfWeek => "dayofweek(date)",
+            QueryFunction::Day => "day(date)",
+            QueryFunction::Bucket => "bucket(number, width)",
+        }
+    }
+
+    // Minimum and maximum number of arguments
+    pub fn arity(&self) -> (usize, usize) {
+        match self {
+            QueryFunction::Lower => (1, 1),
+            QueryFunction::Substr => (2, 3),
+            QueryFunction::Split => (3, 3),
+            QueryFunction::Hour | QueryFunction::DayOfWeek | QueryFunction::Day => (1, 1),
+            QueryFunction::Bucket => (2, 2),
+        }
+    }
+
+    // Functions extracting a part of a date, returning numbers
+    pub fn is_date_part(&self) -> bool {
+        match self {
+            QueryFunction::Hour | QueryFunction::DayOfWeek | QueryFunction::Day => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum QueryShowElement {
     All,
     Symbol(String),
-    Reducer(QueryReducer, String)
+    Reducer(QueryReducer, String),
+    Expression(QueryExpression),
 }
 
 impl QueryShowElement {
@@ -312,6 +1132,15 @@ impl QueryShowElement {
         }
     }
 
+    // Reducers and expressions over reducers, whose values are known once records are aggregated.
+    pub fn is_aggregate(&self) -> bool {
+        match self {
+            QueryShowElement::Reducer(_, _) => true,
+            QueryShowElement::Expression(expression) => expression.has_reducer(),
+            _ => false
+        }
+    }
+
     pub fn symbol(&self) -> Option<&str> {
         match self {
             QueryShowElement::Symbol(sym) => Some(sym),
@@ -325,14 +1154,41 @@ impl QueryShowElement {
             _ => false
         }
     }
+
+    // Name of the output column of the element, e.g. path or count(*).
+    pub fn name(&self) -> String {
+        match self {
+            QueryShowElement::All => "*".to_owned(),
+            QueryShowElement::Symbol(symbol) => symbol.to_owned(),
+            QueryShowElement::Reducer(reducer, symbol) => reducer.column_name(symbol),
+            QueryShowElement::Expression(expression) => expression.name(),
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum QueryReducer {
     Count,
     Sum,
     Max,
+    Min,
     Avg,
+    Unique,
+    CountDistinct,
+    ApproxUnique,
+    Top(usize),
+    // Values of the earliest and latest records by the log format's date column
+    First,
+    Last,
+    // Number of records per duration, e.g. rate(1m), the duration taking the place of the column
+    Rate,
+    // Sample standard deviation and variance
+    Stddev,
+    Var,
+    // Fraction of records matching a filter, the filter taking the place of the column
+    Ratio,
+    // Value at a percentile of a column, e.g. p(request_time, 95)
+    Percentile(usize),
 }
 
 impl QueryReducer {
@@ -341,9 +1197,41 @@ impl QueryReducer {
             QueryReducer::Count => "count",
             QueryReducer::Sum => "sum",
             QueryReducer::Max => "max",
+            QueryReducer::Min => "min",
             QueryReducer::Avg => "avg",
+            QueryReducer::Unique => "unique",
+            QueryReducer::CountDistinct => "count_distinct",
+            QueryReducer::ApproxUnique => "approx_unique",
+            QueryReducer::Top(_) => "top",
+            QueryReducer::First => "first",
+            QueryReducer::Last => "last",
+            QueryReducer::Rate => "rate",
+            QueryReducer::Stddev => "stddev",
+            QueryReducer::Var => "var",
+            QueryReducer::Ratio => "ratio",
+            QueryReducer::Percentile(_) => "p",
         }
     }
+
+    pub fn reads_column(&self) -> bool {
+        *self != QueryReducer::Rate && *self != QueryReducer::Ratio
+    }
+
+    // Name of the output column of the reducer applied to a symbol, e.g. count(*), top(5,ip) or
+    // p(request_time,95).
+    pub fn column_name(&self, symbol: &str) -> String {
+        match self {
+            QueryReducer::Top(count) => format!("top({},{})", count, symbol),
+            QueryReducer::Percentile(percent) => format!("p({},{})", symbol, percent),
+            reducer => format!("{}({})", reducer.to_string(), symbol),
+        }
+    }
+}
+
+// Filter over the aggregated rows of a query, applied before sorting and limiting.
+#[derive(Debug, Clone)]
+pub struct QueryHaving {
+    pub filter: QueryFilter
 }
 
 #[derive(Debug, Clone)]
@@ -382,3 +1270,8 @@ impl QuerySortOrdering {
 pub struct QueryLimit {
     pub limit: usize
 }
+
+#[derive(Debug, Clone)]
+pub struct QueryWindow {
+    pub seconds: i64
+}
@@ -0,0 +1,99 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde_json::{self, Map};
+use riplog::query::ScanStats;
+use riplog::table::TableDefinition;
+
+const VERSION: u64 = 1;
+
+// The aggregates of a query over some of the logs, written by --emit-partial, so that those of runs
+// over the others, e.g. on other hosts or on other days, are merged into the query's results by
+// `riplog merge`. The columns of the log format are saved with them, as the results are computed from
+// their types. Partials are gzipped json.
+pub struct Partial {
+    pub query: String,
+    // Name, kind and size of each column of the log format
+    pub columns: Vec<(String, String, usize)>,
+    // Column of the host added by --ssh, by which the query's results are told apart
+    pub host_column: Option<String>,
+    pub aggregates: serde_json::Value,
+    pub stats: ScanStats,
+}
+
+impl Partial {
+    pub fn new<T>(query: &str, definition: &TableDefinition<T>, host_column: Option<String>, aggregates: serde_json::Value, stats: ScanStats) -> Partial {
+        let columns = definition.ordered_columns.iter().map(|name| {
+            let column = &definition.column_map[name];
+            (name.to_owned(), column.kind_name().to_owned(), *column.get_size())
+        }).collect();
+        Partial { query: query.to_owned(), columns: columns, host_column: host_column, aggregates: aggregates, stats: stats }
+    }
+
+    pub fn read(path: &Path) -> Result<Partial, String> {
+        let file = File::open(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        let invalid = || format!("{} is not a partial result written by --emit-partial", path.display());
+        let saved: serde_json::Value = serde_json::from_reader(GzDecoder::new(BufReader::new(file))).map_err(|_| invalid())?;
+        if saved.get("version").and_then(|v| v.as_u64()) != Some(VERSION) {
+            return Err(invalid());
+        }
+        let columns = saved.get("columns").and_then(|c| c.as_array()).ok_or_else(invalid)?.iter().map(|column| Some((
+            column.get("name")?.as_str()?.to_owned(),
+            column.get("type")?.as_str()?.to_owned(),
+            column.get("size")?.as_u64()? as usize,
+        ))).collect::<Option<Vec<(String, String, usize)>>>().ok_or_else(invalid)?;
+        let stats = saved.get("stats").ok_or_else(invalid)?;
+        let count = |name: &str| stats.get(name).and_then(|c| c.as_u64()).ok_or_else(invalid);
+        Ok(Partial {
+            query: saved.get("query").and_then(|q| q.as_str()).ok_or_else(invalid)?.to_owned(),
+            columns: columns,
+            host_column: saved.get("host_column").and_then(|c| c.as_str()).map(|c| c.to_owned()),
+            aggregates: saved.get("aggregates").cloned().ok_or_else(invalid)?,
+            stats: ScanStats {
+                lines: count("lines")?,
+                sampled: stats.get("sampled").and_then(|s| s.as_u64()),
+                matched: count("matched")?,
+                unparsed: count("unparsed")?,
+                files: count("files")?,
+                skipped_files: count("skipped_files")?,
+            },
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let columns: Vec<serde_json::Value> = self.columns.iter().map(|&(ref name, ref kind, size)| {
+            let mut column = Map::new();
+            column.insert("name".to_owned(), serde_json::Value::from(name.to_owned()));
+            column.insert("type".to_owned(), serde_json::Value::from(kind.to_owned()));
+            column.insert("size".to_owned(), serde_json::Value::from(size));
+            serde_json::Value::Object(column)
+        }).collect();
+        let mut stats = Map::new();
+        stats.insert("lines".to_owned(), serde_json::Value::from(self.stats.lines));
+        if let Some(sampled) = self.stats.sampled {
+            stats.insert("sampled".to_owned(), serde_json::Value::from(sampled));
+        }
+        stats.insert("matched".to_owned(), serde_json::Value::from(self.stats.matched));
+        stats.insert("unparsed".to_owned(), serde_json::Value::from(self.stats.unparsed));
+        stats.insert("files".to_owned(), serde_json::Value::from(self.stats.files));
+        stats.insert("skipped_files".to_owned(), serde_json::Value::from(self.stats.skipped_files));
+        let mut saved = Map::new();
+        saved.insert("version".to_owned(), serde_json::Value::from(VERSION));
+        saved.insert("query".to_owned(), serde_json::Value::from(self.query.to_owned()));
+        saved.insert("columns".to_owned(), serde_json::Value::from(columns));
+        if let Some(ref column) = self.host_column {
+            saved.insert("host_column".to_owned(), serde_json::Value::from(column.to_owned()));
+        }
+        saved.insert("stats".to_owned(), serde_json::Value::Object(stats));
+        saved.insert("aggregates".to_owned(), self.aggregates.clone());
+
+        let mut out = GzEncoder::new(BufWriter::new(File::create(path)?), Compression::default());
+        serde_json::to_writer(&mut out, &serde_json::Value::Object(saved))?;
+        out.finish()?.flush()
+    }
+
+}
@@ -0,0 +1,106 @@
+use memchr::memmem::Finder;
+use regex_syntax;
+use regex_syntax::hir::literal::{ExtractKind, Extractor};
+
+use parser::{QueryFilter, QueryFilterBinaryOp, QueryValue};
+use table::{ColumnDefinition, TableDefinition};
+
+// Lines are searched for each alternative, so regexes with many or short prefixes aren't worth it
+const MAX_REGEX_LITERALS: usize = 4;
+const MIN_REGEX_LITERAL_LEN: usize = 3;
+
+// Literal text a line has to contain for the query's filter to possibly match it, checked on the raw
+// line before it's parsed. Each group holds alternatives, at least one of which must be found, e.g.
+// `method = "POST" && status in (500, 502)` requires "POST" and one of "500" or "502", and
+// `path ~ r"^/api/v[12]/"` one of "/api/v1/" or "/api/v2/".
+//
+// Only columns whose values are copied from the line as is (the format's verbatim columns) are used,
+// so a skipped line could never have matched.
+pub struct LinePrefilter {
+    groups: Vec<Vec<Finder<'static>>>,
+}
+
+impl LinePrefilter {
+    pub fn new<T>(filter: Option<&QueryFilter>, definition: &TableDefinition<T>, verbatim_columns: &[&str]) -> LinePrefilter {
+        let literals = filter.map(|f| required_literals(f, definition, verbatim_columns)).unwrap_or(Vec::new());
+        let groups = literals.iter()
+            .map(|group| group.iter().map(|literal| Finder::new(literal.as_bytes()).into_owned()).collect())
+            .collect();
+        LinePrefilter { groups }
+    }
+
+    pub fn matches(&self, line: &[u8]) -> bool {
+        self.groups.iter().all(|group| group.iter().any(|finder| finder.find(line).is_some()))
+    }
+}
+
+fn required_literals<T>(filter: &QueryFilter, definition: &TableDefinition<T>, verbatim_columns: &[&str]) -> Vec<Vec<String>> {
+    match filter {
+        QueryFilter::BinaryOpFilter(operand1, operand2, QueryFilterBinaryOp::Eq) |
+        QueryFilter::BinaryOpFilter(operand1, operand2, QueryFilterBinaryOp::Re) => {
+            match (operand1, operand2) {
+                (QueryValue::Symbol(symbol), QueryValue::Regex(regex, _)) =>
+                    regex_literals(symbol, regex.as_str(), definition, verbatim_columns).map(|l| vec![l]).unwrap_or(Vec::new()),
+                (QueryValue::Symbol(symbol), value) | (value, QueryValue::Symbol(symbol)) =>
+                    column_literal(symbol, value, definition, verbatim_columns).map(|l| vec![vec![l]]).unwrap_or(Vec::new()),
+                _ => Vec::new(),
+            }
+        },
+        QueryFilter::InFilter(QueryValue::Symbol(symbol), values, false) => {
+            let literals: Vec<Option<String>> = values.iter().map(|v| column_literal(symbol, v, definition, verbatim_columns)).collect();
+            if literals.iter().all(|l| l.is_some()) {
+                vec![literals.into_iter().map(|l| l.unwrap()).collect()]
+            } else {
+                Vec::new()
+            }
+        },
+        QueryFilter::AndFilter(filter1, filter2) => {
+            let mut literals = required_literals(filter1, definition, verbatim_columns);
+            literals.extend(required_literals(filter2, definition, verbatim_columns));
+            literals
+        },
+        // Either side matching requires one of the literals of either side
+        QueryFilter::OrFilter(filter1, filter2) => {
+            let literals1 = required_literals(filter1, definition, verbatim_columns);
+            let literals2 = required_literals(filter2, definition, verbatim_columns);
+            match (literals1.first(), literals2.first()) {
+                (Some(group1), Some(group2)) => vec![group1.iter().chain(group2.iter()).cloned().collect()],
+                _ => Vec::new(),
+            }
+        },
+        _ => Vec::new(),
+    }
+}
+
+// Text columns must contain the text compared with, while integers, written in the line as digits,
+// only give a literal when compared with an integer.
+fn column_literal<T>(symbol: &str, value: &QueryValue, definition: &TableDefinition<T>, verbatim_columns: &[&str]) -> Option<String> {
+    if !verbatim_columns.contains(&symbol) {
+        return None;
+    }
+    match (definition.column_map.get(symbol), value) {
+        (Some(ColumnDefinition::Text { .. }), QueryValue::Text(text, _)) | (Some(ColumnDefinition::Ip { .. }), QueryValue::Text(text, _))
+            if !text.is_empty() => Some(text.to_owned()),
+        (Some(ColumnDefinition::Integer { .. }), QueryValue::Int(int, _)) => Some(int.to_string()),
+        _ => None,
+    }
+}
+
+// Every match of a regex starts with one of its prefix literals, so a line has to contain one of
+// them. Patterns with too many or too short prefixes, e.g. (?i)mozilla, give none.
+fn regex_literals<T>(symbol: &str, pattern: &str, definition: &TableDefinition<T>, verbatim_columns: &[&str]) -> Option<Vec<String>> {
+    if !verbatim_columns.contains(&symbol) {
+        return None;
+    }
+    match definition.column_map.get(symbol) {
+        Some(ColumnDefinition::Text { .. }) | Some(ColumnDefinition::Ip { .. }) => (),
+        _ => return None,
+    }
+    let hir = regex_syntax::parse(pattern).ok()?;
+    let prefixes = Extractor::new().kind(ExtractKind::Prefix).limit_total(MAX_REGEX_LITERALS).extract(&hir);
+    let literals = prefixes.literals()?;
+    if literals.is_empty() || literals.iter().any(|l| l.as_bytes().len() < MIN_REGEX_LITERAL_LEN) {
+        return None;
+    }
+    literals.iter().map(|l| String::from_utf8(l.as_bytes().to_vec()).ok()).collect()
+}
@@ -0,0 +1,108 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const RENDER_INTERVAL: Duration = Duration::from_millis(250);
+const LINES_PER_CHECK: u64 = 4096;
+
+// Reports scan progress on stderr, keeping stdout clean for results. Progress is measured in bytes
+// of the files on disk, so compressed files advance by their compressed size.
+pub struct Progress {
+    enabled: bool,
+    total_files: usize,
+    total_bytes: u64,
+    files_done: usize,
+    bytes_done: u64,
+    file_size: u64,
+    file_position: u64,
+    lines: u64,
+    start: Instant,
+    last_render: Instant,
+}
+
+impl Progress {
+    pub fn new(files: &Vec<PathBuf>, enabled: bool) -> Progress {
+        let total_bytes = if enabled { files.iter().map(|f| fs::metadata(f).map(|m| m.len()).unwrap_or(0)).sum() } else { 0 };
+        Progress {
+            enabled: enabled,
+            total_files: files.len(),
+            total_bytes: total_bytes,
+            files_done: 0,
+            bytes_done: 0,
+            file_size: 0,
+            file_position: 0,
+            lines: 0,
+            start: Instant::now(),
+            last_render: Instant::now(),
+        }
+    }
+
+    pub fn start_file(&mut self, file: &Path) {
+        self.file_size = if self.enabled { fs::metadata(file).map(|m| m.len()).unwrap_or(0) } else { 0 };
+        self.file_position = 0;
+    }
+
+    // Called after each batch of lines read, with the position reached in the current file.
+    pub fn lines_read(&mut self, count: u64, position: u64) {
+        if !self.enabled {
+            return;
+        }
+        let checked = self.lines / LINES_PER_CHECK;
+        self.lines += count;
+        self.file_position = position;
+        if self.lines / LINES_PER_CHECK != checked && self.last_render.elapsed() >= RENDER_INTERVAL {
+            self.render();
+        }
+    }
+
+    pub fn finish_file(&mut self) {
+        self.files_done += 1;
+        self.bytes_done += self.file_size;
+        self.file_size = 0;
+        self.file_position = 0;
+        if self.enabled {
+            self.render();
+        }
+    }
+
+    // Clears the progress line so results and the duration are printed on a clean terminal.
+    pub fn finish(&mut self) {
+        if self.enabled {
+            eprint!("\r{:80}\r", "");
+            io::stderr().flush().unwrap();
+        }
+    }
+
+    fn render(&mut self) {
+        let elapsed = seconds(self.start.elapsed());
+        let bytes = self.bytes_done + self.file_position.min(self.file_size);
+        let byte_rate = if elapsed > 0.0 { bytes as f64 / elapsed } else { 0.0 };
+        let line_rate = if elapsed > 0.0 { self.lines as f64 / elapsed } else { 0.0 };
+        let eta =
+            if byte_rate > 0.0 {
+                format_eta(self.total_bytes.saturating_sub(bytes) as f64 / byte_rate)
+            } else {
+                "-".to_owned()
+            };
+        eprint!("\r{}/{} files  {:.1} MB/s  {:.0} lines/s  ETA {}    ",
+                self.files_done, self.total_files, byte_rate / (1024.0 * 1024.0), line_rate, eta);
+        io::stderr().flush().unwrap();
+        self.last_render = Instant::now();
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+fn format_eta(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    if seconds >= 3600 {
+        format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60)
+    } else if seconds >= 60 {
+        format!("{}m{:02}s", seconds / 60, seconds % 60)
+    } else {
+        format!("{}s", seconds)
+    }
+}
@@ -0,0 +1,81 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use chrono::prelude::*;
+use riplog::format::LogFormat;
+use riplog::input::{self, Compression};
+use riplog::query::{self, DateRange};
+use riplog::table::{ColumnDefinition, TableDefinition};
+
+// Bytes read from the end of uncompressed files to find their last line
+const TAIL_BYTES: u64 = 64 * 1024;
+
+// Skips whole files that can't hold records in the date range a query filters on. A file's records
+// are taken to span from its first line to its last, or to its modification time when the last line
+// can't be read cheaply (compressed files), since that is when its last record was written.
+pub struct FilePruner<'a, F: LogFormat + 'a> {
+    format: &'a F,
+    definition: TableDefinition<F::Record>,
+    column: String,
+    range: DateRange,
+}
+
+impl<'a, F: LogFormat> FilePruner<'a, F> {
+    // None when the query doesn't restrict the date column, so nothing can be pruned.
+    pub fn new(format: &'a F, range: &DateRange) -> Option<FilePruner<'a, F>> {
+        if range.from.is_none() && range.to.is_none() {
+            return None;
+        }
+        let definition = format.table_definition();
+        let column = query::find_date_column(&definition)?;
+        Some(FilePruner { format: format, definition: definition, column: column, range: range.clone() })
+    }
+
+    // Files that can't be probed are kept.
+    pub fn may_match(&self, file: &Path) -> bool {
+        let first = self.first_date(file).unwrap_or(None);
+        let last =
+            match self.last_date(file).unwrap_or(None) {
+                Some(last) => Some(last),
+                None => fs::metadata(file).and_then(|m| m.modified()).ok().map(DateTime::<Local>::from),
+            };
+        self.range.from.map(|from| last.map(|last| from <= last).unwrap_or(true)).unwrap_or(true)
+            && self.range.to.map(|to| first.map(|first| to >= first).unwrap_or(true)).unwrap_or(true)
+    }
+
+    fn first_date(&self, file: &Path) -> io::Result<Option<DateTime<Local>>> {
+        let mut reader = input::open_input(file)?;
+        let mut line = Vec::new();
+        if self.format.has_header() {
+            reader.read_until(b'\n', &mut line)?;
+            line.clear();
+        }
+        reader.read_until(b'\n', &mut line)?;
+        Ok(self.line_date(&line))
+    }
+
+    fn last_date(&self, file: &Path) -> io::Result<Option<DateTime<Local>>> {
+        if input::detect_compression(file)? != Compression::Plain {
+            return Ok(None);
+        }
+        let mut reader = File::open(file)?;
+        let len = reader.metadata()?.len();
+        reader.seek(SeekFrom::Start(len.saturating_sub(TAIL_BYTES)))?;
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail)?;
+        let line = tail.split(|b| *b == b'\n').filter(|l| !l.is_empty()).last();
+        Ok(line.and_then(|l| self.line_date(l)))
+    }
+
+    fn line_date(&self, line: &[u8]) -> Option<DateTime<Local>> {
+        let mut record = self.format.empty_record();
+        if !self.format.read_record(line, &mut record) {
+            return None;
+        }
+        match self.definition.column_map.get(&self.column) {
+            Some(ColumnDefinition::Date { extractor, field, .. }) => extractor(&mut record, *field).cloned(),
+            _ => None,
+        }
+    }
+}
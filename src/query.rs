@@ -1,14 +1,34 @@
+use std::fmt;
 use std::result;
-use std::collections::HashMap;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
 use std::rc::Rc;
+use std::cell::RefCell;
 use std::cmp::Ordering;
+use std::mem;
+use std::io::Write;
+use std::net::IpAddr;
 use chrono::prelude::*;
+use serde_json::{self, Map};
 
 use parser::*;
 use table::{ColumnDefinition,TableDefinition};
+use cidr;
+use prefilter::LinePrefilter;
+use format::LogFormat;
+use memchr::{memchr, memmem};
+use output::{self, OutputColumn, OutputOptions, ResultRenderer, create_renderer};
+use value::Value;
+use stage::{Row, StageRenderer, StageTable};
+use sample::Sampler;
+use diagnostics::Diagnostics;
 
 const EMPTY_BYTES: &[u8] = &[];
 
+// Seconds a tumbling window of a followed log is left open for past its end, to lines written late
+const WINDOW_GRACE_SECONDS: i64 = 5;
+
 pub fn validate_riplog_query<T>(query: &RipLogQuery, definition: &TableDefinition<T>) -> Result<()> {
     if query.filter.is_some() {
         validate_riplog_filter(query.filter.as_ref().unwrap(), &definition)?
@@ -22,17 +42,149 @@ pub fn validate_riplog_query<T>(query: &RipLogQuery, definition: &TableDefinitio
         validate_riplog_show(query.show.as_ref().unwrap(), &definition, query.grouping.is_some())?
     }
 
+    if query.having.is_some() {
+        validate_riplog_having(query.having.as_ref().unwrap(), query)?
+    }
+
     if query.sort.is_some() {
-        validate_riplog_sort(query.sort.as_ref().unwrap(), &definition, query.show.as_ref())?
+        validate_riplog_sort(query.sort.as_ref().unwrap(), &definition, query)?
+    }
+
+    if query.every.is_some() {
+        validate_riplog_every(query.every.as_ref().unwrap(), &definition)?
+    }
+
+    // The next stage reads the rows of this one, so its columns are this stage's output columns
+    if let Some(ref next) = query.next {
+        if query.every.is_some() {
+            return Err(QueryValidationError::new("Queries with 'every' can't be followed by another stage".to_owned()));
+        }
+        let mut stage = query.clone();
+        stage.compute_show(definition);
+        validate_riplog_query(next, &StageTable::new(&stage, definition).definition())?
     }
 
     Ok(())
 }
 
+// Validates a query while treating columns missing from the definition as always null. Returns a
+// warning for each missing column instead of failing, so a single query can be run across logs
+// whose formats don't all share the same columns.
+pub fn validate_riplog_query_allow_missing<T>(query: &RipLogQuery, definition: &TableDefinition<T>) -> Vec<String> {
+    let mut missing: Vec<String> = Vec::new();
+    for symbol in query_symbols(query) {
+        if !definition.column_map.contains_key(&symbol) && !missing.contains(&symbol) {
+            missing.push(symbol);
+        }
+    }
+    missing.iter().map(|s| match suggest(s, definition.ordered_columns.iter().map(|c| c.as_str())) {
+        Some(column) => format!("Symbol '{}' is not a valid column (did you mean '{}'?), treating it as null", s, column),
+        None => format!("Symbol '{}' is not a valid column, treating it as null", s),
+    }).collect()
+}
+
+fn query_symbols(query: &RipLogQuery) -> Vec<String> {
+    let mut symbols = Vec::new();
+    if query.filter.is_some() {
+        filter_symbols(query.filter.as_ref().unwrap(), &mut symbols);
+    }
+    if query.grouping.is_some() {
+        for element in &query.grouping.as_ref().unwrap().groupings {
+            match element {
+                QueryGroupingElement::Symbol(symbol) => symbols.push(symbol.to_owned()),
+                QueryGroupingElement::Bucket(QueryBucket { column: Some(column), .. }) => symbols.push(column.to_owned()),
+                QueryGroupingElement::Expression(expression) => expression.symbols(&mut symbols),
+                _ => ()
+            }
+        }
+    }
+    if query.show.is_some() {
+        show_symbols(query.show.as_ref().unwrap(), &mut symbols);
+    }
+    symbols
+}
+
+fn show_symbols(show: &QueryShow, symbols: &mut Vec<String>) {
+    for element in &show.elements {
+        match element {
+            QueryShowElement::Symbol(symbol) => symbols.push(symbol.to_owned()),
+            QueryShowElement::Reducer(reducer, symbol) if symbol != "*" && reducer.reads_column() => symbols.push(symbol.to_owned()),
+            QueryShowElement::Expression(expression) => expression.symbols(symbols),
+            _ => ()
+        }
+    }
+}
+
+// Columns of the format a query, whose show is computed, reads from records: those it names, those
+// of the computed show (every column for '*'), the filters of ratio() and the date column, which
+// reducers such as first() and windows read without it being named. Formats may leave the other
+// columns out of the records they read.
+fn required_columns(query: &RipLogQuery, date_column: Option<&String>) -> Vec<String> {
+    let mut symbols = query_symbols(query);
+    let mut reducers = query_reducers(query);
+    if let Some(ref show) = query.computed_show {
+        show_symbols(show, &mut symbols);
+    }
+    if let Some(ref sort) = query.sort {
+        for sorting in &sort.sortings {
+            match parse_reducer_name(&sorting.field) {
+                Some((reducer, symbol)) => reducers.push((reducer, symbol)),
+                None => symbols.push(sorting.field.to_owned()),
+            }
+        }
+    }
+    for (reducer, symbol) in reducers {
+        match reducer {
+            QueryReducer::Ratio => {
+                if let Some(filter) = parse_ratio_filter(&symbol) {
+                    filter_symbols(&filter, &mut symbols);
+                }
+            },
+            reducer if symbol != "*" && reducer.reads_column() => symbols.push(symbol),
+            _ => (),
+        }
+    }
+    symbols.extend(date_column.cloned());
+    symbols.sort();
+    symbols.dedup();
+    symbols
+}
+
+fn filter_symbols(filter: &QueryFilter, symbols: &mut Vec<String>) {
+    match filter {
+        QueryFilter::BinaryOpFilter(operand1, operand2, _) => {
+            for operand in &[operand1, operand2] {
+                value_symbols(operand, symbols);
+            }
+        },
+        QueryFilter::InFilter(operand, values, _) => {
+            for operand in Some(operand).into_iter().chain(values.iter()) {
+                value_symbols(operand, symbols);
+            }
+        },
+        QueryFilter::CidrFilter(operand, _, _) => value_symbols(operand, symbols),
+        QueryFilter::AndFilter(filter1, filter2) | QueryFilter::OrFilter(filter1, filter2) => {
+            filter_symbols(filter1, symbols);
+            filter_symbols(filter2, symbols);
+        }
+    }
+}
+
+fn value_symbols(value: &QueryValue, symbols: &mut Vec<String>) {
+    match value {
+        QueryValue::Symbol(symbol) => symbols.push(symbol.to_owned()),
+        QueryValue::Expression(expression) => expression.columns(symbols),
+        _ => (),
+    }
+}
+
 fn validate_riplog_filter<T>(filter: &QueryFilter, definition: &TableDefinition<T>) -> Result<()> {
     match filter {
         QueryFilter::BinaryOpFilter(operand1, operand2, op) =>
             validate_filter_operand(&operand1, &definition).and(validate_filter_operand(&operand2, &definition)),
+        QueryFilter::InFilter(operand, values, _) =>
+            values.iter().fold(validate_filter_operand(&operand, &definition), |r, v| r.and(validate_filter_operand(v, &definition))),
+        QueryFilter::CidrFilter(operand, _, _) => validate_cidr_operand(operand, definition),
         QueryFilter::AndFilter(filter1, filter2) =>
             validate_riplog_filter(&filter1, &definition).and(validate_riplog_filter(&filter2, &definition)),
         QueryFilter::OrFilter(filter1, filter2) =>
@@ -47,78 +199,559 @@ fn validate_filter_operand<T>(operand: &QueryValue, definition: &TableDefinition
         QueryValue::Int(int, _) => Ok(()),
         QueryValue::Double(dbl, _) => Ok(()),
         QueryValue::Boolean(boolvalue) => Ok(()),
-        QueryValue::Regex(regex) => Ok(()),
+        QueryValue::Regex(_, _) => Ok(()),
         QueryValue::Date(date) => Ok(()),
+        QueryValue::Expression(expression) => validate_expression(expression, definition, false),
         QueryValue::Null => Ok(()),
     }
 }
 
+// Address ranges match ip columns, and text columns or expressions holding addresses.
+fn validate_cidr_operand<T>(operand: &QueryValue, definition: &TableDefinition<T>) -> Result<()> {
+    match operand {
+        QueryValue::Symbol(symbol) =>
+            match definition.column_map.get(symbol) {
+                Some(ColumnDefinition::Ip { .. }) | Some(ColumnDefinition::Text { .. }) => Ok(()),
+                Some(column) => Err(QueryValidationError::new(format!("'in_cidr' can't be applied to {} column '{}'", column.kind_name(), symbol))),
+                None => validate_symbol(symbol, definition),
+            },
+        QueryValue::Expression(expression) => validate_expression(expression, definition, false),
+        _ => Err(QueryValidationError::new("'in_cidr' must be applied to a column or expression".to_owned())),
+    }
+}
+
 fn validate_symbol<T>(symbol: &str, definition: &TableDefinition<T>) -> Result<()> {
     if definition.column_map.contains_key(symbol) {
         Ok(())
     } else {
-        Err(QueryValidationError { msg: format!("Symbol '{}' is not a valid column", symbol) })
+        Err(unknown_column_error(symbol, definition))
     }
 }
 
+fn unknown_column_error<T>(column: &str, definition: &TableDefinition<T>) -> QueryValidationError {
+    let suggestion = suggest(column, definition.ordered_columns.iter().map(|c| c.as_str())).map(|c| c.to_owned());
+    let unknown = UnknownColumn { column: column.to_owned(), suggestion: suggestion, columns: definition.ordered_columns.clone() };
+    QueryValidationError { msg: format!("Symbol '{}' is not a valid column", column), unknown_column: Some(unknown) }
+}
+
 fn validate_riplog_grouping<T>(grouping: &QueryGrouping, definition: &TableDefinition<T>) -> Result<()> {
-    for s in &grouping.groupings {
-        validate_symbol(s, definition)?
+    for element in &grouping.groupings {
+        match element {
+            QueryGroupingElement::Symbol(symbol) => validate_symbol(symbol, definition)?,
+            QueryGroupingElement::Bucket(bucket) => validate_riplog_bucket(bucket, definition)?,
+            QueryGroupingElement::Expression(expression) => validate_expression(expression, definition, false)?,
+        }
     }
     Ok(())
 }
 
+// Reducers may only appear in expressions shown by a query, where they are evaluated after aggregation.
+fn validate_expression<T>(expression: &QueryExpression, definition: &TableDefinition<T>, aggregate: bool) -> Result<()> {
+    match expression {
+        QueryExpression::Symbol(symbol) => validate_symbol(symbol, definition),
+        QueryExpression::Reducer(reducer, symbol) if !aggregate =>
+            Err(QueryValidationError::new(format!("Reducer '{}' can only be used in 'show' and 'having'", reducer.column_name(symbol)))),
+        QueryExpression::Reducer(reducer, symbol) => validate_reducer(reducer, symbol, definition),
+        QueryExpression::Arithmetic(left, _, right) =>
+            validate_expression(left, definition, aggregate).and(validate_expression(right, definition, aggregate)),
+        QueryExpression::Function(function, arguments) => {
+            let (min, max) = function.arity();
+            if arguments.len() < min || arguments.len() > max {
+                return Err(QueryValidationError::new(format!("'{}' takes {} arguments: {}", function.to_string(),
+                                                             if min == max { min.to_string() } else { format!("{} to {}", min, max) },
+                                                             function.signature())));
+            }
+            for argument in arguments {
+                validate_expression(argument, definition, aggregate)?;
+            }
+            match (function, arguments.first()) {
+                (_, Some(QueryExpression::Symbol(symbol))) if function.is_date_part() =>
+                    match definition.column_map.get(symbol) {
+                        Some(ColumnDefinition::Date { .. }) => Ok(()),
+                        _ => Err(QueryValidationError::new(format!("'{}' requires a date column, '{}' is not a date", function.to_string(), symbol))),
+                    },
+                // Dates are bucketed by durations rather than numbers
+                (QueryFunction::Bucket, Some(QueryExpression::Symbol(symbol))) if definition.column_map.get(symbol).map(|c| c.kind_name() == "date").unwrap_or(false) =>
+                    Err(QueryValidationError::new(format!("'{}' buckets dates by a duration, e.g. bucket({}, 1h)", expression.name(), symbol))),
+                (QueryFunction::Bucket, _) =>
+                    match arguments.get(1) {
+                        Some(QueryExpression::Int(width)) if *width > 0 => Ok(()),
+                        Some(QueryExpression::Double(width)) if *width > 0.0 => Ok(()),
+                        _ => Err(QueryValidationError::new(format!("Bucket width for '{}' must be a number greater than zero", expression.name()))),
+                    },
+                _ => Ok(()),
+            }
+        },
+        _ => Ok(()),
+    }
+}
+
+fn validate_riplog_bucket<T>(bucket: &QueryBucket, definition: &TableDefinition<T>) -> Result<()> {
+    if bucket.seconds <= 0 {
+        return Err(QueryValidationError::new(format!("Bucket duration for '{}' must be greater than zero", bucket.name)))
+    }
+    match bucket.column {
+        Some(ref column) => {
+            validate_symbol(column, definition)?;
+            match definition.column_map.get(column) {
+                Some(ColumnDefinition::Date { .. }) => Ok(()),
+                _ => Err(QueryValidationError::new(format!("'{}' requires a date column, '{}' is not a date", bucket.name, column))),
+            }
+        },
+        None if find_date_column(definition).is_none() =>
+            Err(QueryValidationError::new(format!("'{}' requires a date column", bucket.name))),
+        None => Ok(()),
+    }
+}
+
 fn validate_riplog_show<T>(show: &QueryShow, definition: &TableDefinition<T>, grouped: bool) -> Result<()> {
     for element in &show.elements {
         match element {
             QueryShowElement::Symbol(symbol) => {
                     validate_symbol(symbol, definition)?
             },
-            QueryShowElement::Reducer(_, symbol) =>  {
-                if symbol != "*" {
-                    validate_symbol(symbol, definition)?
-                }
-            }
+            QueryShowElement::Reducer(reducer, symbol) => validate_reducer(reducer, symbol, definition)?,
+            QueryShowElement::Expression(expression) => validate_expression(expression, definition, true)?,
             _ => ()
         }
     }
     Ok(())
 }
 
-// TODO: Validate sorts are valid
-fn validate_riplog_sort<T>(sort: &QuerySort, definition: &TableDefinition<T>, show: Option<&QueryShow>) -> Result<()> {
+fn validate_reducer<T>(reducer: &QueryReducer, symbol: &str, definition: &TableDefinition<T>) -> Result<()> {
+    if let QueryReducer::Percentile(percent) = reducer {
+        if *percent > 100 {
+            return Err(QueryValidationError::new(format!("Reducer 'p' takes a percentile from 0 to 100, not {}", percent)));
+        }
+    }
+    let dated = *reducer == QueryReducer::First || *reducer == QueryReducer::Last || *reducer == QueryReducer::Rate;
+    if dated && find_date_column(definition).is_none() {
+        Err(QueryValidationError::new(format!("Reducer '{}' requires a log format with a date column", reducer.to_string())))
+    } else if *reducer == QueryReducer::Ratio {
+        match parse_ratio_filter(symbol) {
+            Some(filter) => validate_riplog_filter(&filter, definition),
+            None => Err(QueryValidationError::new(format!("Reducer 'ratio' takes a filter such as status >= 500, not '{}'", symbol))),
+        }
+    } else if *reducer == QueryReducer::Rate {
+        match parse_duration_seconds(symbol) {
+            Some(_) => Ok(()),
+            None => Err(QueryValidationError::new(format!("Reducer '{}' takes a duration such as 1s, 1m or 1h, not '{}'", reducer.to_string(), symbol))),
+        }
+    } else if symbol != "*" {
+        validate_symbol(symbol, definition)?;
+        validate_reducer_column(reducer, symbol, definition)
+    } else if reducer.to_string() != "count" {
+        Err(QueryValidationError::new(format!("Reducer '{}' requires a column", reducer.to_string())))
+    } else {
+        Ok(())
+    }
+}
+
+// Sums, averages, deviations and percentiles are taken of numbers, minimums and maximums of numbers or
+// dates, while other reducers take any column.
+fn validate_reducer_column<T>(reducer: &QueryReducer, symbol: &str, definition: &TableDefinition<T>) -> Result<()> {
+    let (accepted, kinds): (fn(&ColumnDefinition<T>) -> bool, &str) =
+        match reducer {
+            QueryReducer::Sum | QueryReducer::Avg | QueryReducer::Stddev | QueryReducer::Var | QueryReducer::Percentile(_) =>
+                (|c| match c { ColumnDefinition::Integer { .. } | ColumnDefinition::Double { .. } => true, _ => false }, "a numeric"),
+            QueryReducer::Min | QueryReducer::Max =>
+                (|c| match c { ColumnDefinition::Integer { .. } | ColumnDefinition::Double { .. } | ColumnDefinition::Date { .. } => true, _ => false }, "a numeric or date"),
+            _ => return Ok(()),
+        };
+    let column = &definition.column_map[symbol];
+    if accepted(column) {
+        return Ok(());
+    }
+    let valid: Vec<&str> = definition.ordered_columns.iter().filter(|c| accepted(&definition.column_map[*c])).map(|c| c.as_str()).collect();
+    let options = if valid.is_empty() { "the log format has none".to_owned() } else { format!("e.g. {}", valid.join(", ")) };
+    let article = if column.kind_name().starts_with(|c| "aeiou".contains(c)) { "an" } else { "a" };
+    Err(QueryValidationError::new(format!("Reducer '{}' requires {} column, '{}' is {} {} column ({})",
+                                          reducer.to_string(), kinds, symbol, article, column.kind_name(), options)))
+}
+
+// Having filters compare the aggregated output columns: the group columns and the reducers shown,
+// count(*) being shown by default for grouped queries.
+fn validate_riplog_having(having: &QueryHaving, query: &RipLogQuery) -> Result<()> {
+    validate_result_filter(&having.filter, query, "'having'")
+}
+
+// Alerts are checked against the result rows of the query, like its having clause.
+pub fn validate_riplog_alert(alert: &QueryFilter, query: &RipLogQuery) -> Result<()> {
+    if query.next.is_some() {
+        return Err(QueryValidationError::new("Alerts aren't supported for queries of several stages".to_owned()));
+    }
+    validate_result_filter(alert, query, "--alert")
+}
+
+// Filters over result rows may only refer to group columns and reducers, named by the clause or
+// option they are given to.
+fn validate_result_filter(filter: &QueryFilter, query: &RipLogQuery, clause: &str) -> Result<()> {
+    let mut columns: Vec<String> = Vec::new();
+    if let Some(ref grouping) = query.grouping {
+        columns.extend(grouping.groupings.iter().map(|g| g.name()));
+    }
+    if let Some(ref show) = query.show {
+        for element in &show.elements {
+            if element.is_aggregate() {
+                columns.push(element.name());
+            }
+        }
+    }
+    let reduced = query.show.as_ref().map(|s| s.elements.iter().any(|e| e.is_aggregate())).unwrap_or(false);
+    if query.grouping.is_none() && !reduced {
+        return Err(QueryValidationError::new(format!("{} requires a grouped or aggregate query", clause)));
+    }
+    if query.grouping.is_some() && !reduced {
+        columns.push("count(*)".to_owned());
+    }
+    let mut symbols = Vec::new();
+    filter_symbols(filter, &mut symbols);
+    for symbol in symbols {
+        if !columns.contains(&symbol) {
+            return Err(QueryValidationError::new(format!("'{}' in {} must be a group column or a reducer shown by the query", symbol, clause)));
+        }
+    }
+    Ok(())
+}
+
+// Queries sort on the columns they show, 'group' (or 'key') for the group columns, and reducers of
+// grouped queries even when they aren't shown, whose columns have to exist. Queries that aren't
+// aggregated can also sort on any column of the log format.
+fn validate_riplog_sort<T>(sort: &QuerySort, definition: &TableDefinition<T>, query: &RipLogQuery) -> Result<()> {
+    let mut computed = query.clone();
+    computed.compute_show(definition);
+    let show = computed.computed_show.as_ref().unwrap();
+    let aggregate = is_aggregate_query(&computed);
+    let shown: Vec<String> = show.elements.iter().map(|e| e.name()).collect();
     for sorting in &sort.sortings {
-        ();
+        let field = &sorting.field;
+        if shown.contains(field) || (query.grouping.is_some() && (field == "group" || field == "key")) {
+            continue;
+        }
+        if let Some((reducer, symbol)) = parse_reducer_name(field) {
+            validate_reducer(&reducer, &symbol, definition)?;
+            if query.grouping.is_some() {
+                continue;
+            }
+        } else if !aggregate && definition.column_map.contains_key(field) {
+            continue;
+        }
+        let mut options: Vec<String> = shown.iter().map(|name| show.display_name(name)).collect();
+        if query.grouping.is_some() {
+            options.push("group".to_owned());
+        }
+        let others = if aggregate { "" } else { ", or any column of the log format" };
+        return Err(QueryValidationError::new(format!("Can't sort on '{}', which the query doesn't show. Sort on one of: {}{}", field, options.join(", "), others)));
     }
     Ok(())
 }
 
+// Parts of a query, whose show is computed, that are valid but have no effect, e.g. columns shown
+// by a grouped query without being grouped on, which are left out of its results.
+fn diagnose_riplog_query(query: &RipLogQuery) -> Diagnostics {
+    let mut diagnostics = Diagnostics::new();
+    for element in query.dropped_show_elements() {
+        let name = element.name();
+        match query.grouping {
+            Some(_) if element.is_star() =>
+                diagnostics.warn("'*' isn't shown, as grouped queries only show their group columns and reducers".to_owned()),
+            Some(_) => diagnostics.warn(format!("'{}' isn't shown, as grouped queries only show their group columns and reducers, e.g. first({})", name, name)),
+            None => diagnostics.warn(format!("'{}' isn't shown, as aggregate queries only show reducers unless grouped, e.g. by 'group {}'", name, name)),
+        }
+    }
+    if query.sort.is_some() && query.grouping.is_none() && is_aggregate_query(query) {
+        diagnostics.warn("'sort' has no effect on an aggregate query that isn't grouped, whose result is a single row");
+    }
+    diagnostics
+}
+
+fn validate_riplog_every<T>(every: &QueryWindow, definition: &TableDefinition<T>) -> Result<()> {
+    if every.seconds <= 0 {
+        Err(QueryValidationError::new("Window duration for 'every' must be greater than zero".to_owned()))
+    } else if find_date_column(definition).is_none() {
+        Err(QueryValidationError::new("'every' requires a date column".to_owned()))
+    } else {
+        Ok(())
+    }
+}
+
+// Dates the filter of a query can match, for files whose lines are all outside of them to be skipped.
+pub fn query_date_range<T>(query: &RipLogQuery, definition: &TableDefinition<T>) -> DateRange {
+    match (query.filter.as_ref(), find_date_column(definition)) {
+        (Some(filter), Some(column)) => filter_date_range(filter, &column),
+        _ => DateRange::default(),
+    }
+}
+
+pub fn find_date_column<T>(definition: &TableDefinition<T>) -> Option<String> {
+    definition.ordered_columns.iter()
+        .find(|c| match definition.column_map.get(*c) { Some(ColumnDefinition::Date { .. }) => true, _ => false })
+        .map(|c| c.to_owned())
+}
+
+// The span of dates a filter can match on the log's date column, used to skip indexed files whose
+// records all fall outside of it. Unbounded ends are None.
+#[derive(Debug, Clone, Default)]
+pub struct DateRange {
+    pub from: Option<DateTime<Local>>,
+    pub to: Option<DateTime<Local>>,
+}
+
+impl DateRange {
+    pub fn overlaps(&self, min: &DateTime<Local>, max: &DateTime<Local>) -> bool {
+        self.from.map(|from| from <= *max).unwrap_or(true) && self.to.map(|to| to >= *min).unwrap_or(true)
+    }
+
+    fn intersect(self, other: DateRange) -> DateRange {
+        DateRange { from: max_option(self.from, other.from), to: min_option(self.to, other.to) }
+    }
+
+    fn union(self, other: DateRange) -> DateRange {
+        DateRange { from: self.from.and_then(|a| other.from.map(|b| a.min(b))),
+                    to: self.to.and_then(|a| other.to.map(|b| a.max(b))) }
+    }
+}
+
+fn max_option(a: Option<DateTime<Local>>, b: Option<DateTime<Local>>) -> Option<DateTime<Local>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn min_option(a: Option<DateTime<Local>>, b: Option<DateTime<Local>>) -> Option<DateTime<Local>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn filter_date_range(filter: &QueryFilter, column: &str) -> DateRange {
+    match filter {
+        QueryFilter::BinaryOpFilter(QueryValue::Symbol(symbol), QueryValue::Date(date), op) if symbol == column =>
+            date_comparison_range(date, op),
+        QueryFilter::BinaryOpFilter(QueryValue::Date(date), QueryValue::Symbol(symbol), op) if symbol == column =>
+            date_comparison_range(date, &op.flip()),
+        QueryFilter::InFilter(QueryValue::Symbol(symbol), values, false) if symbol == column && values.iter().all(|v| v.is_date()) => {
+            let dates: Vec<DateTime<Local>> = values.iter().filter_map(|v| match v { QueryValue::Date(d) => Some(d.clone()), _ => None }).collect();
+            DateRange { from: dates.iter().min().cloned(), to: dates.iter().max().cloned() }
+        },
+        QueryFilter::AndFilter(filter1, filter2) =>
+            filter_date_range(filter1, column).intersect(filter_date_range(filter2, column)),
+        QueryFilter::OrFilter(filter1, filter2) =>
+            filter_date_range(filter1, column).union(filter_date_range(filter2, column)),
+        _ => DateRange::default(),
+    }
+}
+
+fn date_comparison_range(date: &DateTime<Local>, op: &QueryFilterBinaryOp) -> DateRange {
+    match op {
+        QueryFilterBinaryOp::Gt | QueryFilterBinaryOp::Ge => DateRange { from: Some(date.clone()), to: None },
+        QueryFilterBinaryOp::Lt | QueryFilterBinaryOp::Le => DateRange { from: None, to: Some(date.clone()) },
+        QueryFilterBinaryOp::Eq => DateRange { from: Some(date.clone()), to: Some(date.clone()) },
+        _ => DateRange::default(),
+    }
+}
+
+// Counts of what a query scanned. Line counts are kept by the evaluator, file counts are filled in
+// by the caller walking the files.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScanStats {
+    pub lines: u64,
+    // Lines evaluated when sampling, of the lines read
+    pub sampled: Option<u64>,
+    pub matched: u64,
+    pub unparsed: u64,
+    pub files: u64,
+    pub skipped_files: u64,
+}
+
+impl ScanStats {
+    // Lines matched, scaled from the sample to every line read when sampling.
+    pub fn estimated_matches(&self) -> u64 {
+        match self.sampled {
+            Some(sampled) if sampled > 0 => (self.matched as f64 * self.lines as f64 / sampled as f64).round() as u64,
+            _ => self.matched,
+        }
+    }
+
+    // Adds the counts of another scan, e.g. of the partial results of a query being merged.
+    pub fn add(&mut self, other: &ScanStats) {
+        // Every line of a scan that wasn't sampled was evaluated
+        self.sampled = match (self.sampled, other.sampled) {
+            (None, None) => None,
+            (sampled1, sampled2) => Some(sampled1.unwrap_or(self.lines) + sampled2.unwrap_or(other.lines)),
+        };
+        self.lines += other.lines;
+        self.matched += other.matched;
+        self.unparsed += other.unparsed;
+        self.files += other.files;
+        self.skipped_files += other.skipped_files;
+    }
+}
+
+impl fmt::Display for ScanStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Lines read:     {}", self.lines)?;
+        match self.sampled {
+            Some(sampled) => {
+                writeln!(f, "Lines sampled:  {}", sampled)?;
+                writeln!(f, "Lines matched:  ~{} ({} in the sample)", self.estimated_matches(), self.matched)?;
+            },
+            None => writeln!(f, "Lines matched:  {}", self.matched)?,
+        }
+        writeln!(f, "Lines skipped:  {} (parse errors)", self.unparsed)?;
+        writeln!(f, "Files scanned:  {}", self.files)?;
+        write!(f, "Files skipped:  {}", self.skipped_files)
+    }
+}
+
 pub struct QueryEvaluator<T> {
     query: Rc<RipLogQuery>,
     definition: Rc<TableDefinition<T>>,
-    group_map: HashMap<Vec<String>,Reducer<T>>,
+    group_map: HashMap<GroupKey,Reducer<T>>,
     global_reducer: Reducer<T>,
     aggregate: bool,
+    // Queries only counting matching lines skip the reducers, the count being the lines matched
+    count_only: bool,
     record_formatter: RecordFormatter<T>,
     printed_count: usize,
+    date_column: Option<String>,
+    // Columns the query reads, formats able to leaving the others out of the records they read
+    required_columns: Vec<String>,
+    current_window: Option<i64>,
+    // Start of the last tumbling window printed, the aggregates being empty until a later one is
+    // opened. Records of it or of earlier windows read afterwards are late, and left out.
+    printed_window: Option<i64>,
+    late_records: u64,
+    // The latest record date read for tumbling windows, and when it was read, which windows are
+    // closed by when following a log
+    latest_date: Option<(i64, i64)>,
+    // Rows of a sorted query that isn't aggregated, printed in order once every line is read. With a
+    // limit, only that many rows are kept, the rest being dropped whenever twice as many are held.
+    sorted_rows: Vec<Vec<Value>>,
+    // Results thrown away only need to tell whether a line matched, so scanning stops at the first
+    stop_on_match: bool,
+    next: Option<NextStage>,
+    sliding_window: Option<SlidingWindow<T>>,
+    // Condition checked against the result rows each time aggregates are printed, and the rows that
+    // met it since alerts were last taken
+    alert: Option<QueryFilter>,
+    alerts: Vec<String>,
+    // Parts of the query without effect, and why some of the aggregates printed aren't exact, e.g.
+    // sums that overflowed
+    diagnostics: Diagnostics,
+    sampler: Option<Sampler>,
+    stats: ScanStats,
+}
+
+// Aggregates of the last `seconds` when following a log with a sliding window. The records of each
+// second are reduced into a bucket as well as into the evaluator's reducers, and once a second ages
+// out of the window its bucket's states are taken out of those reducers again. Reducers that can't
+// take a state out, e.g. max, are rebuilt from the buckets left instead, once before each summary.
+struct SlidingWindow<T> {
+    seconds: i64,
+    // Oldest first, without the seconds no record matched in
+    buckets: VecDeque<WindowBucket<T>>,
+    // Records of each group left in the window, groups being dropped once it's none
+    counts: HashMap<GroupKey, u64>,
+    // The latest record date read, which the window ends at, so that a follow that starts behind the
+    // log keeps the lines it catches up on
+    latest: Option<i64>,
+    // Whether a bucket that aged out couldn't be taken out of the aggregates, which are then left as
+    // they are until rebuilt
+    stale: bool,
+}
+
+// The records of a second of the sliding window reduced for each group, with how many there were.
+// Queries that aren't grouped have a single group with an empty key.
+struct WindowBucket<T> {
+    second: i64,
+    groups: HashMap<GroupKey, (u64, Reducer<T>)>,
+}
+
+impl<T> SlidingWindow<T> {
+    // Records of the first second read are all kept
+    fn start(&self) -> i64 {
+        self.latest.map(|latest| latest - self.seconds).unwrap_or(i64::MIN)
+    }
+
+    // Records are usually read in time order, so buckets are looked for from the back.
+    fn bucket(&mut self, second: i64) -> &mut WindowBucket<T> {
+        let idx = self.buckets.iter().rposition(|b| b.second <= second).map(|idx| idx + 1).unwrap_or(0);
+        if idx == 0 || self.buckets[idx - 1].second != second {
+            self.buckets.insert(idx, WindowBucket { second: second, groups: HashMap::new() });
+            &mut self.buckets[idx]
+        } else {
+            &mut self.buckets[idx - 1]
+        }
+    }
+
+    fn take_expired(&mut self) -> Vec<WindowBucket<T>> {
+        let start = self.start();
+        let mut expired = Vec::new();
+        while self.buckets.front().map(|b| b.second <= start).unwrap_or(false) {
+            expired.extend(self.buckets.pop_front());
+        }
+        expired
+    }
+}
+
+// The stage after `~>` of a chained query, which reads the rows this stage renders once it's complete.
+struct NextStage {
+    table: StageTable,
+    rows: Rc<RefCell<Vec<Vec<Value>>>>,
+    evaluator: Box<QueryEvaluator<Row>>,
 }
 
 impl<T> QueryEvaluator<T> {
 
-    pub fn new<N>(query: RipLogQuery, definition: TableDefinition<N>) -> QueryEvaluator<N> {
+    pub fn new<N>(query: RipLogQuery, definition: TableDefinition<N>, output: &OutputOptions, out: Box<Write>) -> QueryEvaluator<N> {
         let mut rquery = query;
         rquery.compute_show(&definition);
+        resolve_bucket_columns(&mut rquery, &definition);
+        let next_query = rquery.next.take();
         let query_rc = Rc::new(rquery);
-        let formatter = RecordFormatter::new(&query_rc, &definition);
+        let (renderer, next): (Box<ResultRenderer>, Option<NextStage>) =
+            match next_query {
+                Some(next_query) => {
+                    let table = StageTable::new(&query_rc, &definition);
+                    let rows = Rc::new(RefCell::new(Vec::new()));
+                    let evaluator = QueryEvaluator::<Row>::new(*next_query, table.definition(), output, out);
+                    (Box::new(StageRenderer::new(rows.clone())), Some(NextStage { table: table, rows: rows, evaluator: Box::new(evaluator) }))
+                },
+                None => {
+                    let bounded = is_aggregate_query(&query_rc) || query_rc.limit.is_some() || query_rc.sort.is_some();
+                    (create_renderer(output, out, bounded), None)
+                },
+            };
+        let formatter = RecordFormatter::new(&query_rc, &definition, renderer);
+        let date_column = find_date_column(&definition);
         let mut evaluator =
             QueryEvaluator {
                 query: query_rc.clone(),
                 definition: Rc::new(definition),
                 group_map: HashMap::new(),
-                global_reducer: create_reducer(&query_rc),
+                global_reducer: create_reducer(&query_rc, date_column.as_ref()),
                 aggregate: is_aggregate_query(&query_rc),
+                count_only: is_count_only_query(&query_rc),
                 record_formatter: formatter,
                 printed_count: 0,
+                required_columns: required_columns(&query_rc, date_column.as_ref()),
+                date_column: date_column,
+                current_window: None,
+                printed_window: None,
+                late_records: 0,
+                latest_date: None,
+                sorted_rows: Vec::new(),
+                stop_on_match: output.discard && next.is_none(),
+                next: next,
+                sliding_window: None,
+                alert: None,
+                alerts: Vec::new(),
+                diagnostics: diagnose_riplog_query(&query_rc),
+                sampler: None,
+                stats: ScanStats::default(),
             };
         if !evaluator.aggregate {
             evaluator.record_formatter.format_header_row();
@@ -127,18 +760,160 @@ impl<T> QueryEvaluator<T> {
     }
 
     pub fn evaluate(&mut self, item: &mut T) {
+        if !self.sample_line() {
+            self.skip_sampled();
+            return;
+        }
+        let mut record = Record { definition: self.definition.clone(), item: item };
+        self.evaluate_record(&mut record);
+    }
+
+    // Evaluates only a sample of the lines read, picked before they are parsed or filtered.
+    pub fn sample(&mut self, sampler: Sampler) {
+        self.sampler = Some(sampler);
+        self.stats.sampled = Some(0);
+    }
+
+    // Whether the next line read is part of the sample, every line being when not sampling.
+    fn sample_line(&mut self) -> bool {
+        let keep = self.sampler.as_mut().map(|s| s.keep()).unwrap_or(true);
+        if keep {
+            self.stats.sampled = self.stats.sampled.map(|s| s + 1);
+        }
+        keep
+    }
+
+    // Counts a line left out of the sample, which is read but never parsed.
+    fn skip_sampled(&mut self) {
+        self.stats.lines += 1;
+    }
+
+    // Parses and evaluates a block of whole lines, as read from a file, mapped or decompressed, into
+    // a single reused record. Returns the number of lines read, which is fewer than the block holds
+    // once the limit is reached.
+    pub fn evaluate_batch<F: LogFormat<Record = T>>(&mut self, lines: &[u8], format: &F, prefilter: &LinePrefilter, item: &mut T) -> usize {
         let mut record = Record { definition: self.definition.clone(), item: item };
-        if self.apply_filters(&mut record) {
-            if self.aggregate {
-                self.aggregate(&mut record);
+        let mut count = 0;
+        let mut start = 0;
+        while start < lines.len() && !self.should_stop() {
+            let end = memchr(b'\n', &lines[start..]).map(|idx| start + idx + 1).unwrap_or(lines.len());
+            let line = &lines[start..end];
+            if !self.sample_line() {
+                self.skip_sampled();
+            } else if !prefilter.matches(line) {
+                self.skip_prefiltered();
+            } else if format.read_record(line, record.item) {
+                self.evaluate_record(&mut record);
+            } else {
+                self.skip_unparsed();
+            }
+            count += 1;
+            start = end;
+        }
+        count
+    }
+
+    // Computes the aggregates of a followed log over the records of the last `seconds` only, rather
+    // than every record read, for queries without `every`.
+    pub fn slide_window(&mut self, seconds: i64) {
+        // Counts age out of the window along with the other aggregates, so are kept by the reducers
+        self.count_only = false;
+        self.sliding_window = Some(SlidingWindow { seconds: seconds, buckets: VecDeque::new(), counts: HashMap::new(), latest: None, stale: false });
+    }
+
+    // Checks the result rows of the query against a condition, e.g. `count(*) > 100`, each time
+    // aggregates are printed. Rows meeting it are taken with take_alerts.
+    pub fn set_alert(&mut self, alert: QueryFilter) {
+        // The condition reads the count from the reducers, like having filters do
+        self.count_only = false;
+        self.alert = Some(alert);
+    }
+
+    // The result rows that met the alert condition since alerts were last taken, as name=value pairs.
+    pub fn take_alerts(&mut self) -> Vec<String> {
+        mem::replace(&mut self.alerts, Vec::new())
+    }
+
+    // Issues of the run raised since warnings were last taken, including those of the stages after
+    // this one.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        let mut warnings = self.diagnostics.take();
+        if let Some(ref mut next) = self.next {
+            warnings.extend(next.evaluator.take_warnings());
+        }
+        warnings
+    }
+
+    fn evaluate_record(&mut self, record: &mut Record<T>) {
+        self.stats.lines += 1;
+        if self.sliding_window.is_some() {
+            if self.apply_filters(record) {
+                self.stats.matched += 1;
+                self.slide_record(record);
+            }
+            return;
+        }
+        if self.count_only && self.query.filter.is_none() {
+            self.stats.matched += 1;
+            return;
+        }
+        if self.apply_filters(record) {
+            self.stats.matched += 1;
+            if self.count_only {
+                return;
+            } else if self.aggregate {
+                if self.advance_window(record) {
+                    self.aggregate(record);
+                }
+            } else if self.record_formatter.is_sorted() {
+                let values = self.record_formatter.record_values(record);
+                self.sorted_rows.push(values);
+                let limit = self.query.limit.as_ref().map(|l| l.limit);
+                if limit.map(|l| self.sorted_rows.len() >= 2 * l.max(1)).unwrap_or(false) {
+                    self.truncate_sorted_rows(limit.unwrap());
+                }
             } else {
-                self.record_formatter.format_record(&mut record);
+                self.record_formatter.format_record(record);
                 self.printed_count += 1;
             }
         }
     }
 
+    // Counts a line skipped before parsing as it lacks text the filter requires.
+    pub fn skip_prefiltered(&mut self) {
+        self.stats.lines += 1;
+    }
+
+    pub fn line_prefilter(&self, verbatim_columns: &[&str]) -> LinePrefilter {
+        LinePrefilter::new(self.query.filter.as_ref(), &self.definition, verbatim_columns)
+    }
+
+    pub fn required_columns(&self) -> &Vec<String> {
+        &self.required_columns
+    }
+
+    // Counts a line the log format could not parse, which is skipped rather than evaluated.
+    pub fn skip_unparsed(&mut self) {
+        self.stats.lines += 1;
+        self.stats.unparsed += 1;
+    }
+
+    pub fn stats(&self) -> ScanStats {
+        self.stats
+    }
+
+    pub fn table_definition(&self) -> &TableDefinition<T> {
+        &self.definition
+    }
+
+    pub fn date_range(&self) -> DateRange {
+        query_date_range(&self.query, &self.definition)
+    }
+
     pub fn should_stop(&self) -> bool {
+        if (self.stop_on_match && self.stats.matched > 0) || output::output_closed() {
+            return true;
+        }
         let limit = &self.query.limit.as_ref().map(|l| l.limit.clone());
         limit.is_some() && self.printed_count >= limit.unwrap()
     }
@@ -147,173 +922,535 @@ impl<T> QueryEvaluator<T> {
         if self.query.grouping.is_some() {
             // todo
             let key = create_group_key(&self.query.grouping.as_ref().unwrap().groupings, record);
-            let entry = self.group_map.entry(key).or_insert(create_reducer(&self.query));
+            let (query, date_column) = (&self.query, self.date_column.as_ref());
+            let entry = self.group_map.entry(key).or_insert_with(|| create_reducer(query, date_column));
             entry.apply_record(record);
         } else {
             self.global_reducer.apply_record(record);
         }
     }
 
-    pub fn finalize(&mut self) {
-        let limit = &self.query.limit.as_ref().map(|l| l.limit.clone());
-        if self.aggregate {
-            self.record_formatter.format_header_row();
-            if self.query.grouping.is_some() {
-                if self.record_formatter.sortable() {
-                    let mut results: Vec<(&Vec<String>, &Reducer<T>)> = self.group_map.iter().collect();
-                    results.sort_unstable_by(|a,b| self.record_formatter.sort_grouped(a.0, a.1, b.0, b.1));
-                    if limit.is_some() {
-                        for (keys, reducer) in results.iter().take(limit.unwrap()) {
-                            self.record_formatter.format_grouped_record(keys, reducer);
-                        }
-                    } else {
-                        for (keys, reducer) in results {
-                            self.record_formatter.format_grouped_record(keys, reducer);
-                        }
-                    }
-                } else {
-                    if limit.is_some() {
-                        for (keys, reducer) in self.group_map.iter().take(limit.unwrap()) {
-                            self.record_formatter.format_grouped_record(keys, reducer);
-                        }
+    // Reduces a record into the bucket of its second of the sliding window, and into the aggregates
+    // of the window, unless it's older than the window already. Records without a date can't be
+    // placed in the window, so are left out.
+    fn slide_record(&mut self, record: &mut Record<T>) {
+        let date_column = self.date_column.as_ref();
+        let time = match date_column.and_then(|c| record.get_symbol_date(c)).map(|d| d.timestamp()) {
+            Some(time) => time,
+            None => {
+                self.diagnostics.warn("Lines without a date were left out of the --window summaries");
+                return;
+            },
+        };
+        let mut window = self.sliding_window.take().unwrap();
+        if window.latest.map(|latest| time > latest).unwrap_or(true) {
+            window.latest = Some(time);
+        }
+        if time > window.start() {
+            let key = match self.query.grouping {
+                Some(ref grouping) => create_group_key(&grouping.groupings, record),
+                None => Vec::new(),
+            };
+            let (query, date_column) = (&self.query, self.date_column.as_ref());
+            let entry = window.bucket(time).groups.entry(key.clone()).or_insert_with(|| (0, create_reducer(query, date_column)));
+            entry.0 += 1;
+            entry.1.apply_record(record);
+            *window.counts.entry(key).or_insert(0) += 1;
+            if !window.stale {
+                self.aggregate(record);
+            }
+        }
+        // Seconds that aged out are dropped as the log moves on, rather than only when summaries are
+        // printed, to hold no more than the window.
+        self.evict_sliding_window(&mut window);
+        self.sliding_window = Some(window);
+    }
+
+    // Takes the buckets that aged out of the sliding window out of its aggregates, or marks them to be
+    // rebuilt when one of the reducers can't.
+    fn evict_sliding_window(&mut self, window: &mut SlidingWindow<T>) {
+        for bucket in window.take_expired() {
+            for (key, (count, reducer)) in bucket.groups {
+                let left = {
+                    let left = window.counts.get_mut(&key).unwrap();
+                    *left -= count;
+                    *left
+                };
+                let grouped = self.query.grouping.is_some();
+                if left == 0 {
+                    window.counts.remove(&key);
+                    if grouped {
+                        self.group_map.remove(&key);
                     } else {
-                        for (keys, reducer) in &self.group_map {
-                            self.record_formatter.format_grouped_record(keys, reducer);
-                        }
+                        self.global_reducer = create_reducer(&self.query, self.date_column.as_ref());
                     }
+                } else if !window.stale {
+                    let aggregates = if grouped { self.group_map.get_mut(&key).unwrap() } else { &mut self.global_reducer };
+                    window.stale = aggregates.evict_state(&reducer.state()).is_none();
                 }
-            } else {
-                self.record_formatter.format_reduced_record(&self.global_reducer);
             }
         }
-        self.record_formatter.format_closing_row();
     }
 
-    fn apply_filters(&mut self, record: &mut Record<T>) -> bool {
-        if self.query.filter.is_some() {
-            let query = &self.query.clone();
-            let filter = query.filter.as_ref().unwrap();
-            self.evaluate_filter(filter, record)
-        } else {
-            true
+    // Reduces the aggregates of the sliding window afresh from the buckets left in it.
+    fn rebuild_sliding_window(&mut self, window: &mut SlidingWindow<T>) {
+        if window.stale {
+            window.stale = false;
+            self.group_map.clear();
+            self.global_reducer = create_reducer(&self.query, self.date_column.as_ref());
+            for bucket in &window.buckets {
+                for (key, (_, reducer)) in &bucket.groups {
+                    let (query, date_column) = (&self.query, self.date_column.as_ref());
+                    let aggregates =
+                        if query.grouping.is_some() {
+                            self.group_map.entry(key.clone()).or_insert_with(|| create_reducer(query, date_column))
+                        } else {
+                            &mut self.global_reducer
+                        };
+                    aggregates.merge_state(&reducer.state());
+                }
+            }
         }
     }
 
-    // ip = "1.1.1.1" | group method | show sum(bytes)
-    fn evaluate_filter(&mut self, filter: &QueryFilter, record: &mut Record<T>) -> bool {
-        match filter {
-            QueryFilter::BinaryOpFilter(operand1, operand2, op) =>
-                self.evaluate_binary_filter(&operand1, &operand2, op, record),
-            QueryFilter::AndFilter(filter1, filter2) =>
-                self.evaluate_filter(&filter1, record) && self.evaluate_filter(&filter2, record),
-            QueryFilter::OrFilter(filter1, filter2) =>
-                self.evaluate_filter(&filter1, record) || self.evaluate_filter(&filter2, record),
+    // Emits and resets the aggregates when a record falls in a later tumbling window than the current
+    // one. Returns whether the record is aggregated, those of a window before the current one, or of
+    // one already printed, being late and left out.
+    fn advance_window(&mut self, record: &mut Record<T>) -> bool {
+        if self.query.every.is_none() || self.date_column.is_none() {
+            return true;
+        }
+        let seconds = self.query.every.as_ref().unwrap().seconds;
+        let date_column = self.date_column.clone().unwrap();
+        let date = match record.get_symbol_date(&date_column) {
+            Some(date) => date.timestamp(),
+            None => return true,
+        };
+        if self.latest_date.map(|(latest, _)| date > latest).unwrap_or(true) {
+            self.latest_date = Some((date, Local::now().timestamp()));
+        }
+        let window = date - date.rem_euclid(seconds);
+        match self.current_window.or(self.printed_window) {
+            Some(last) if window < last || (window == last && self.current_window.is_none()) => {
+                self.late_records += 1;
+                false
+            },
+            _ => {
+                if self.current_window.map(|current| window > current).unwrap_or(false) {
+                    self.flush_window();
+                }
+                self.current_window = Some(window);
+                true
+            },
         }
     }
 
-    fn evaluate_binary_filter(&mut self, operand1: &QueryValue, operand2: &QueryValue, op: &QueryFilterBinaryOp, record: &mut Record<T>) -> bool {
-        match op {
-            QueryFilterBinaryOp::Lt => self.evaluate_lt(operand1, operand2, record),
-            QueryFilterBinaryOp::Gt => self.evaluate_gt(operand1, operand2, record),
-            QueryFilterBinaryOp::Eq => self.evaluate_eq(operand1, operand2, record),
-            QueryFilterBinaryOp::Ne => !self.evaluate_eq(operand1, operand2, record),
-            QueryFilterBinaryOp::Re => self.evaluate_re(operand1, operand2, record),
-            QueryFilterBinaryOp::Nr => !self.evaluate_re(operand1, operand2, record),
+    pub fn flush_window(&mut self) {
+        if self.current_window.is_some() {
+            let seconds = self.query.every.as_ref().unwrap().seconds;
+            let start = Local.timestamp(self.current_window.unwrap(), 0);
+            let end = Local.timestamp(self.current_window.unwrap() + seconds, 0);
+            self.record_formatter.format_label(&format!("Window: {} - {}", start, end));
+            self.printed_window = self.current_window;
+            self.warn_late_records();
+        }
+        self.print_aggregates();
+        self.group_map.clear();
+        self.global_reducer = create_reducer(&self.query, self.date_column.as_ref());
+    }
+
+    // Late records are warned about along with the window printed last, which they were read by.
+    fn warn_late_records(&mut self) {
+        if let (Some(start), true) = (self.printed_window, self.late_records > 0) {
+            let end = start + self.query.every.as_ref().unwrap().seconds;
+            self.diagnostics.warn(format!("{} lines read by the end of the window {} - {} were dated in an earlier window, so were left out",
+                                          self.late_records, Local.timestamp(start, 0), Local.timestamp(end, 0)));
+            self.late_records = 0;
         }
     }
 
-    fn evaluate_eq(&mut self, operand1: &QueryValue, operand2: &QueryValue, record: &Record<T>) -> bool {
-        match operand2 {
-            QueryValue::Null => {
-                record.resolve_byte_value(operand1).is_none()
-            },
-            _ => {
-                let op1bytes = record.resolve_byte_value(operand1);
-                let op2bytes = record.resolve_byte_value(operand2);
-                op1bytes.is_some() && op2bytes.is_some() && op1bytes.unwrap() == op2bytes.unwrap()
-            }
+    // Prints the current tumbling window once the log's lines are dated a grace period past its end,
+    // when following a log, rather than waiting for a record of a later window. Lines are written
+    // somewhat out of order, e.g. as requests end, so a window is left open to those the grace period.
+    // A log gone quiet for the grace period is closed by the clock instead.
+    pub fn close_elapsed_window(&mut self) {
+        let end = match (self.current_window, self.query.every.as_ref()) {
+            (Some(start), Some(every)) => start + every.seconds,
+            _ => return,
+        };
+        let now = Local::now().timestamp();
+        let elapsed =
+            match self.latest_date {
+                Some((latest, read)) => latest >= end + WINDOW_GRACE_SECONDS || (now - read >= WINDOW_GRACE_SECONDS && now >= end + WINDOW_GRACE_SECONDS),
+                None => now >= end + WINDOW_GRACE_SECONDS,
+            };
+        if elapsed {
+            self.flush_window();
+            self.current_window = None;
         }
     }
 
-    fn evaluate_lt(&mut self, operand1: &QueryValue, operand2: &QueryValue, record: &mut Record<T>) -> bool {
-        if operand2.is_date() {
-            match (operand1, operand2)  {
-                (QueryValue::Symbol(symbol), QueryValue::Date(date)) => {
-                    let date_value = record.get_symbol_date(symbol);
-                    date_value.is_some() && date_value.unwrap() < date
-                }
-                _ => false
+    pub fn is_aggregate(&self) -> bool {
+        self.aggregate
+    }
+
+    // Whether the log format has a date column, which windows are computed by.
+    pub fn has_date_column(&self) -> bool {
+        self.date_column.is_some()
+    }
+
+    // Whether aggregates are emitted for each tumbling window of `every`.
+    pub fn is_windowed(&self) -> bool {
+        self.query.every.is_some()
+    }
+
+    // Whether the rows of this query are read by another stage.
+    pub fn is_chained(&self) -> bool {
+        self.next.is_some()
+    }
+
+    // The aggregates reduced so far, for a later run to carry on from with merge_snapshot: the key and
+    // reducer states of each group, or those of the whole query when it isn't grouped.
+    pub fn snapshot(&self) -> serde_json::Value {
+        if !self.aggregate {
+            return serde_json::Value::Null;
+        }
+        let groups: Vec<serde_json::Value> = self.group_map.iter().map(|(key, reducer)| {
+            let key: Vec<serde_json::Value> = key.iter().map(|v| v.to_state()).collect();
+            state_object(vec![("key", serde_json::Value::from(key)), ("reducers", reducer.state())])
+        }).collect();
+        let total =
+            if self.count_only {
+                // Lines only counted weren't reduced
+                let count: Box<FieldReducer<T>> = Box::new(CountReducer { symbol: "*".to_owned(), count: self.stats.matched });
+                Reducer { field_reducers: vec![count] }.state()
+            } else {
+                self.global_reducer.state()
+            };
+        state_object(vec![("groups", serde_json::Value::from(groups)), ("total", total)])
+    }
+
+    // Adds the aggregates of a snapshot, taken by the same query, to those reduced so far.
+    pub fn merge_snapshot(&mut self, snapshot: &serde_json::Value) -> result::Result<(), String> {
+        let invalid = || "The saved aggregates don't match the query".to_owned();
+        if snapshot.is_null() {
+            return Ok(());
+        }
+        if !self.aggregate {
+            return Err(invalid());
+        }
+        // The count is then kept by the reducers, which the snapshot's is merged into
+        self.count_only = false;
+        for group in snapshot.get("groups").and_then(|g| g.as_array()).ok_or_else(invalid)? {
+            let key = group.get("key").and_then(|k| k.as_array())
+                .and_then(|key| key.iter().map(Value::from_state).collect::<Option<GroupKey>>())
+                .ok_or_else(invalid)?;
+            let (query, date_column) = (&self.query, self.date_column.as_ref());
+            let reducer = self.group_map.entry(key).or_insert_with(|| create_reducer(query, date_column));
+            group.get("reducers").and_then(|state| reducer.merge_state(state)).ok_or_else(invalid)?;
+        }
+        snapshot.get("total").and_then(|state| self.global_reducer.merge_state(state)).ok_or_else(invalid)
+    }
+
+    // Writes out rows still held in the output buffer, e.g. while waiting for more input.
+    pub fn flush(&mut self) {
+        self.record_formatter.flush();
+        if let Some(ref mut next) = self.next {
+            next.evaluator.flush();
+        }
+    }
+
+    // Prints the aggregates collected so far without resetting them.
+    pub fn print_summary(&mut self) {
+        if self.aggregate {
+            self.print_aggregates();
+        }
+    }
+
+    pub fn finalize(&mut self) {
+        if self.aggregate && self.query.every.is_some() {
+            if self.current_window.is_some() || self.printed_window.is_none() {
+                self.flush_window();
             }
+            self.warn_late_records();
+        } else if self.aggregate {
+            self.print_aggregates();
         } else {
-            let op1bytes = record.resolve_byte_value(operand1);
-            let op2bytes = record.resolve_byte_value(operand2);
-            op1bytes.is_some() && op2bytes.is_some() && op1bytes.unwrap() < op2bytes.unwrap()
+            let limit = self.query.limit.as_ref().map(|l| l.limit).unwrap_or(self.sorted_rows.len());
+            self.truncate_sorted_rows(limit);
+            for values in self.sorted_rows.drain(..) {
+                self.record_formatter.format_values(values);
+            }
+            self.record_formatter.format_closing_row();
+        }
+        if let Some(ref mut next) = self.next {
+            let mut row = Row::empty();
+            for values in next.rows.borrow_mut().drain(..) {
+                if next.evaluator.should_stop() {
+                    break;
+                }
+                next.table.read_row(values, &mut row);
+                next.evaluator.evaluate(&mut row);
+            }
+            next.evaluator.finalize();
+        }
+    }
+
+    // Keeps the first rows in sort order, rows that are tied staying in the order they were read.
+    fn truncate_sorted_rows(&mut self, limit: usize) {
+        let formatter = &self.record_formatter;
+        self.sorted_rows.sort_by(|row1, row2| formatter.sort_rows(row1, row2));
+        self.sorted_rows.truncate(limit);
+    }
+
+    // Rebuilds the aggregates of the sliding window if they are stale, and labels the summary with
+    // the time the window covers.
+    fn summarize_sliding_window(&mut self) {
+        let mut window = match self.sliding_window.take() {
+            Some(window) => window,
+            None => return,
+        };
+        self.rebuild_sliding_window(&mut window);
+        if let Some(end) = window.latest {
+            self.record_formatter.format_label(&format!("Window: {} - {}", Local.timestamp(end - window.seconds, 0), Local.timestamp(end, 0)));
         }
+        self.sliding_window = Some(window);
     }
 
-    fn evaluate_gt(&mut self, operand1: &QueryValue, operand2: &QueryValue, record: &mut Record<T>) -> bool {
-        if operand2.is_date() {
-            match (operand1, operand2)  {
-                (QueryValue::Symbol(symbol), QueryValue::Date(date)) => {
-                    let date_value = record.get_symbol_date(symbol);
-                    date_value.is_some() && date_value.unwrap() > date
+    fn print_aggregates(&mut self) {
+        let limit = &self.query.limit.as_ref().map(|l| l.limit.clone());
+        self.summarize_sliding_window();
+        self.record_formatter.format_header_row();
+        if self.query.grouping.is_some() {
+            let results: Vec<(&GroupKey, &Reducer<T>)> = {
+                let formatter = &self.record_formatter;
+                let groups = self.group_map.iter().filter(|&(keys, reducer)| formatter.matches_having(Some(keys), reducer));
+                match limit {
+                    Some(limit) => top_groups(groups, formatter, *limit),
+                    None => {
+                        let mut results: Vec<(&GroupKey, &Reducer<T>)> = groups.collect();
+                        results.sort_unstable_by(|a,b| formatter.sort_grouped(a.0, a.1, b.0, b.1));
+                        results
+                    },
                 }
-                _ => false
+            };
+            for (keys, reducer) in results {
+                self.record_formatter.format_grouped_record(keys, reducer);
             }
+        } else if self.count_only {
+            self.record_formatter.format_values(vec![Value::Int(self.stats.matched)]);
+        } else if self.record_formatter.matches_having(None, &self.global_reducer) {
+            self.record_formatter.format_reduced_record(&self.global_reducer);
+        }
+        self.record_formatter.format_closing_row();
+        self.check_alert();
+        self.check_overflows();
+    }
+
+    // Each reducer is warned about once, whichever groups it overflowed in
+    fn check_overflows(&mut self) {
+        let reducers = self.group_map.values().chain(Some(&self.global_reducer));
+        for warning in reducers.flat_map(|r| r.field_reducers.iter().filter_map(|f| f.overflow_warning())) {
+            self.diagnostics.warn(warning);
+        }
+    }
+
+    // Issues of the run found by the caller, e.g. files that weren't read, reported along with the
+    // query's.
+    pub fn warn(&mut self, warning: String) {
+        self.diagnostics.warn(warning);
+    }
+
+    // Every row passing the having filter is checked, not only those within the limit.
+    fn check_alert(&mut self) {
+        let alert = match self.alert {
+            Some(ref alert) => alert,
+            None => return,
+        };
+        let formatter = &self.record_formatter;
+        if self.query.grouping.is_some() {
+            let mut rows: Vec<(&GroupKey, &Reducer<T>)> = self.group_map.iter()
+                .filter(|&(key, reducer)| formatter.matches_having(Some(key), reducer) && formatter.evaluate_having(alert, Some(key), reducer))
+                .collect();
+            rows.sort_unstable_by(|a, b| formatter.sort_grouped(a.0, a.1, b.0, b.1));
+            self.alerts.extend(rows.into_iter().map(|(key, reducer)| formatter.describe_row(Some(key), reducer)));
+        } else if formatter.matches_having(None, &self.global_reducer) && formatter.evaluate_having(alert, None, &self.global_reducer) {
+            self.alerts.push(formatter.describe_row(None, &self.global_reducer));
+        }
+    }
+
+    fn apply_filters(&mut self, record: &mut Record<T>) -> bool {
+        if self.query.filter.is_some() {
+            let query = &self.query.clone();
+            let filter = query.filter.as_ref().unwrap();
+            record.matches_filter(filter)
         } else {
-            let op1bytes = record.resolve_byte_value(operand1);
-            let op2bytes = record.resolve_byte_value(operand2);
-            op1bytes.is_some() && op2bytes.is_some() && op1bytes.unwrap() > op2bytes.unwrap()
+            true
         }
     }
+}
 
-    // TODO: Make work with arbitrary values (borrow checker woes)
-    fn evaluate_re(&mut self, operand1: &QueryValue, operand2: &QueryValue, record: &mut Record<T>) -> bool {
-        match (operand1, operand2) {
-            (QueryValue::Symbol(symbol), QueryValue::Regex(regex)) => {
-                let string_value = record.get_symbol_string(symbol);
-                string_value.is_some() && regex.is_match(string_value.unwrap())
-            },
-            (QueryValue::Symbol(symbol), QueryValue::Text(value, _)) => {
-                let string_value1 = record.get_symbol_string(symbol);
-                string_value1.is_some() &&  string_value1.unwrap().contains(value)
-            }
-            _ => false
+// A group ranked by the query's sort, so that the first groups to be printed are the smallest.
+struct RankedGroup<'a, 'b, T: 'a + 'b> {
+    key: &'a GroupKey,
+    reducer: &'a Reducer<T>,
+    formatter: &'b RecordFormatter<T>,
+}
+
+impl<'a, 'b, T> Ord for RankedGroup<'a, 'b, T> {
+    fn cmp(&self, other: &RankedGroup<'a, 'b, T>) -> Ordering {
+        self.formatter.sort_grouped(self.key, self.reducer, other.key, other.reducer)
+    }
+}
+
+impl<'a, 'b, T> PartialOrd for RankedGroup<'a, 'b, T> {
+    fn partial_cmp(&self, other: &RankedGroup<'a, 'b, T>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a, 'b, T> PartialEq for RankedGroup<'a, 'b, T> {
+    fn eq(&self, other: &RankedGroup<'a, 'b, T>) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<'a, 'b, T> Eq for RankedGroup<'a, 'b, T> {}
+
+// Selects the first `limit` groups in sort order with a heap bounded to the limit, rather than
+// sorting every group when only a few of a large number of groups, e.g. ips or paths, are shown.
+fn top_groups<'a, 'b, T, I>(groups: I, formatter: &'b RecordFormatter<T>, limit: usize) -> Vec<(&'a GroupKey, &'a Reducer<T>)>
+    where I: Iterator<Item = (&'a GroupKey, &'a Reducer<T>)> {
+    let mut heap = BinaryHeap::with_capacity(limit + 1);
+    for (key, reducer) in groups {
+        heap.push(RankedGroup { key: key, reducer: reducer, formatter: formatter });
+        if heap.len() > limit {
+            heap.pop();
         }
     }
+    heap.into_sorted_vec().into_iter().map(|g| (g.key, g.reducer)).collect()
+}
+
+// Just a filter and count(*), e.g. 'status = 500 | show count(*)', whose result is the number of
+// lines matched.
+fn is_count_only_query(query: &RipLogQuery) -> bool {
+    let count = match query.computed_show {
+        Some(ref show) => show.elements.len() == 1 && match show.elements[0] {
+            QueryShowElement::Reducer(QueryReducer::Count, ref symbol) => symbol == "*",
+            _ => false,
+        },
+        None => false,
+    };
+    count && query.grouping.is_none() && query.having.is_none() && query.every.is_none()
 }
 
 fn is_aggregate_query(query: &RipLogQuery) -> bool {
     query.grouping.is_some() ||
-        (query.computed_show.is_some() && query.computed_show.as_ref().unwrap().elements.iter().any(|e| e.is_reducer()))
+        (query.computed_show.is_some() && query.computed_show.as_ref().unwrap().elements.iter().any(|e| e.is_aggregate()))
 }
 
-fn create_reducer<T>(query: &RipLogQuery) -> Reducer<T> {
+// Reducers computed for each group: those shown, those used by shown expressions and those only
+// sorted on, each once.
+fn query_reducers(query: &RipLogQuery) -> Vec<(QueryReducer, String)> {
+    let mut reducers: Vec<(QueryReducer, String)> = Vec::new();
     if query.computed_show.is_some() {
-        let mut field_reducers: Vec<Box<FieldReducer<T>>> = Vec::new();
         for element in &query.computed_show.as_ref().unwrap().elements {
+            let mut element_reducers = Vec::new();
             match element {
-                QueryShowElement::Reducer(QueryReducer::Count, symbol) =>
-                    field_reducers.push(Box::new(CountReducer { symbol: symbol.to_owned(), count: 0 })),
-                QueryShowElement::Reducer(QueryReducer::Sum, symbol) =>
-                    field_reducers.push(Box::new(SumReducer { symbol: symbol.to_owned(), sum: 0 })),
-                QueryShowElement::Reducer(QueryReducer::Max, symbol) =>
-                    field_reducers.push(Box::new(MaxReducer { symbol: symbol.to_owned(), max: 0 })),
-                QueryShowElement::Reducer(QueryReducer::Avg, symbol) =>
-                    field_reducers.push(Box::new(AvgReducer { symbol: symbol.to_owned(), count: 0, sum: 0 })),
+                QueryShowElement::Reducer(reducer, symbol) => element_reducers.push((reducer.clone(), symbol.to_owned())),
+                QueryShowElement::Expression(expression) => expression.reducers(&mut element_reducers),
                 _ => (),
             }
+            for reducer in element_reducers {
+                if !reducers.contains(&reducer) {
+                    reducers.push(reducer);
+                }
+            }
+        }
+    }
+    if query.grouping.is_some() {
+        for sorting in query.sort.iter().flat_map(|s| s.sortings.iter()) {
+            if let Some(reducer) = parse_reducer_name(&sorting.field) {
+                if !reducers.contains(&reducer) {
+                    reducers.push(reducer);
+                }
+            }
+        }
+    }
+    reducers
+}
+
+// First and last are ordered by the date column, the format's first one.
+fn create_reducer<T>(query: &RipLogQuery, date_column: Option<&String>) -> Reducer<T> {
+    let mut field_reducers: Vec<Box<FieldReducer<T>>> = Vec::new();
+    for (reducer, symbol) in query_reducers(query) {
+        match reducer {
+            QueryReducer::Count =>
+                field_reducers.push(Box::new(CountReducer { symbol: symbol, count: 0 })),
+            QueryReducer::Sum =>
+                field_reducers.push(Box::new(SumReducer { symbol: symbol, sum: Value::Int(0), overflowed: false })),
+            QueryReducer::Max =>
+                field_reducers.push(Box::new(MaxReducer { symbol: symbol, max: Value::Null })),
+            QueryReducer::Min =>
+                field_reducers.push(Box::new(MinReducer { symbol: symbol, min: Value::Null })),
+            QueryReducer::Avg =>
+                field_reducers.push(Box::new(AvgReducer { symbol: symbol, count: 0, sum: Value::Int(0) })),
+            QueryReducer::Unique | QueryReducer::CountDistinct =>
+                field_reducers.push(Box::new(UniqueReducer { symbol: symbol, values: HashSet::new() })),
+            QueryReducer::Top(count) =>
+                field_reducers.push(Box::new(TopReducer { symbol: symbol, count: count, counts: HashMap::new() })),
+            QueryReducer::ApproxUnique =>
+                field_reducers.push(Box::new(ApproxUniqueReducer { symbol: symbol, registers: vec![0; HLL_REGISTERS] })),
+            QueryReducer::First =>
+                field_reducers.push(Box::new(FirstReducer { symbol: symbol, date_column: date_column.cloned(), first: None, last: false })),
+            QueryReducer::Last =>
+                field_reducers.push(Box::new(FirstReducer { symbol: symbol, date_column: date_column.cloned(), first: None, last: true })),
+            QueryReducer::Stddev =>
+                field_reducers.push(Box::new(VarianceReducer { symbol: symbol, count: 0, mean: 0.0, squares: 0.0, stddev: true })),
+            QueryReducer::Var =>
+                field_reducers.push(Box::new(VarianceReducer { symbol: symbol, count: 0, mean: 0.0, squares: 0.0, stddev: false })),
+            QueryReducer::Ratio => {
+                let filter = parse_ratio_filter(&symbol);
+                field_reducers.push(Box::new(RatioReducer { filter: filter, count: 0, matched: 0 }))
+            },
+            QueryReducer::Percentile(percent) =>
+                field_reducers.push(Box::new(PercentileReducer::new(symbol, percent))),
+            QueryReducer::Rate => {
+                let seconds = parse_duration_seconds(&symbol).unwrap_or(1);
+                field_reducers.push(Box::new(RateReducer { seconds: seconds, date_column: date_column.cloned(), count: 0, span: None }))
+            },
         }
-        Reducer { field_reducers }
-    } else {
-        Reducer { field_reducers: Vec::with_capacity(0) }
     }
+    Reducer { field_reducers }
 }
 
-fn create_group_key<T>(groupings: &Vec<String>, record: &mut Record<T>) -> Vec<String> {
+// Buckets without an explicit column, e.g. time(5m), use the first date column of the log format.
+fn resolve_bucket_columns<T>(query: &mut RipLogQuery, definition: &TableDefinition<T>) {
+    if let Some(ref mut grouping) = query.grouping {
+        for element in &mut grouping.groupings {
+            if let QueryGroupingElement::Bucket(ref mut bucket) = element {
+                if bucket.column.is_none() {
+                    bucket.column = find_date_column(definition);
+                }
+            }
+        }
+    }
+}
+
+// Typed values of the group columns of a record, Null where a value is missing so that it can't be
+// confused with text such as "null".
+type GroupKey = Vec<Value>;
+
+fn create_group_key<T>(groupings: &Vec<QueryGroupingElement>, record: &mut Record<T>) -> GroupKey {
     let mut key = Vec::with_capacity(groupings.len());
     for grouping in groupings {
-        key.push(record.get_symbol_as_string(grouping).unwrap_or("null".to_owned()));
+        let value =
+            match grouping {
+                QueryGroupingElement::Symbol(symbol) => record.get_symbol_value(symbol),
+                QueryGroupingElement::Bucket(bucket) => record.get_bucket_start(bucket).map(Value::Date).unwrap_or(Value::Null),
+                QueryGroupingElement::Expression(expression) => record.evaluate_key_expression(expression),
+            };
+        key.push(value);
     }
     key
 }
@@ -321,222 +1458,1270 @@ fn create_group_key<T>(groupings: &Vec<String>, record: &mut Record<T>) -> Vec<S
 type Result<T> = result::Result<T, QueryValidationError>;
 
 #[derive(Debug, Clone)]
-pub struct QueryValidationError { msg: String }
+pub struct QueryValidationError {
+    msg: String,
+    unknown_column: Option<UnknownColumn>,
+}
+
+// A column a query refers to that the table doesn't have, e.g. a misspelled one, along with the
+// closest column the table has, if any is close, and all of its columns.
+#[derive(Debug, Clone)]
+pub struct UnknownColumn {
+    pub column: String,
+    pub suggestion: Option<String>,
+    pub columns: Vec<String>,
+}
+
+impl QueryValidationError {
+    pub fn new(msg: String) -> QueryValidationError {
+        QueryValidationError { msg: msg, unknown_column: None }
+    }
+
+    pub fn unknown_column(&self) -> Option<&UnknownColumn> {
+        self.unknown_column.as_ref()
+    }
+}
+
+impl fmt::Display for QueryValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)?;
+        if let Some(ref unknown) = self.unknown_column {
+            if let Some(ref suggestion) = unknown.suggestion {
+                write!(f, ", did you mean '{}'?", suggestion)?;
+            }
+            write!(f, "\nAvailable columns: {}", unknown.columns.join(", "))?;
+        }
+        Ok(())
+    }
+}
 
 struct Record<'i, T> {
     item: &'i mut T,
     definition: Rc<TableDefinition<T>>,
 }
 
-impl<'i, T> Record<'i, T> {
+// How two filter operands are compared, driven by the type of the column(s) involved.
+#[derive(Debug, Clone, PartialEq)]
+enum ComparisonKind {
+    Numeric,
+    Chronological,
+    Lexicographic,
+}
+
+impl<'i, T> Record<'i, T> {
+
+    // ip = "1.1.1.1" | group method | show sum(bytes)
+    fn matches_filter(&mut self, filter: &QueryFilter) -> bool {
+        match filter {
+            QueryFilter::BinaryOpFilter(operand1, operand2, op) =>
+                self.evaluate_binary_filter(&operand1, &operand2, op),
+            QueryFilter::InFilter(operand, values, negated) =>
+                values.iter().any(|v| self.evaluate_eq(operand, v)) != *negated,
+            QueryFilter::CidrFilter(operand, ranges, negated) =>
+                self.resolve_address_value(operand).map(|a| ranges.contains(&a)).unwrap_or(false) != *negated,
+            QueryFilter::AndFilter(filter1, filter2) =>
+                self.matches_filter(&filter1) && self.matches_filter(&filter2),
+            QueryFilter::OrFilter(filter1, filter2) =>
+                self.matches_filter(&filter1) || self.matches_filter(&filter2),
+        }
+    }
+
+    fn evaluate_binary_filter(&mut self, operand1: &QueryValue, operand2: &QueryValue, op: &QueryFilterBinaryOp) -> bool {
+        match op {
+            QueryFilterBinaryOp::Lt => self.evaluate_lt(operand1, operand2),
+            QueryFilterBinaryOp::Gt => self.evaluate_gt(operand1, operand2),
+            QueryFilterBinaryOp::Le => self.evaluate_lt(operand1, operand2) || self.evaluate_eq(operand1, operand2),
+            QueryFilterBinaryOp::Ge => self.evaluate_gt(operand1, operand2) || self.evaluate_eq(operand1, operand2),
+            QueryFilterBinaryOp::Eq => self.evaluate_eq(operand1, operand2),
+            QueryFilterBinaryOp::Ne => !self.evaluate_eq(operand1, operand2),
+            QueryFilterBinaryOp::Re => self.evaluate_re(operand1, operand2),
+            QueryFilterBinaryOp::Nr => !self.evaluate_re(operand1, operand2),
+        }
+    }
+
+    fn evaluate_eq(&mut self, operand1: &QueryValue, operand2: &QueryValue) -> bool {
+        match operand2 {
+            QueryValue::Null if operand1.is_expression() => self.resolve_text_value(operand1).is_none(),
+            QueryValue::Null => {
+                self.resolve_value(operand1);
+                self.resolve_byte_value(operand1).is_none()
+            },
+            _ => {
+                self.compare_values(operand1, operand2) == Some(Ordering::Equal)
+            }
+        }
+    }
+
+    fn evaluate_lt(&mut self, operand1: &QueryValue, operand2: &QueryValue) -> bool {
+        self.compare_values(operand1, operand2) == Some(Ordering::Less)
+    }
+
+    fn evaluate_gt(&mut self, operand1: &QueryValue, operand2: &QueryValue) -> bool {
+        self.compare_values(operand1, operand2) == Some(Ordering::Greater)
+    }
+
+    // Text columns are matched on their raw bytes, as with =, so that no string is built per record.
+    fn evaluate_re(&mut self, operand1: &QueryValue, operand2: &QueryValue) -> bool {
+        match (operand1, operand2) {
+            (QueryValue::Symbol(symbol), QueryValue::Regex(_, regex)) =>
+                self.get_text_symbol_bytes(symbol).map(|b| regex.is_match(b)).unwrap_or(false),
+            (QueryValue::Symbol(symbol), QueryValue::Text(_, value)) =>
+                self.get_text_symbol_bytes(symbol).map(|b| memmem::find(b, value).is_some()).unwrap_or(false),
+            (QueryValue::Expression(_), QueryValue::Regex(regex, _)) =>
+                self.resolve_text_value(operand1).map(|t| regex.is_match(&t)).unwrap_or(false),
+            (QueryValue::Expression(_), QueryValue::Text(value, _)) =>
+                self.resolve_text_value(operand1).map(|t| t.contains(value)).unwrap_or(false),
+            _ => false
+        }
+    }
+
+    fn comparison_kind(&self, value: &QueryValue) -> Option<ComparisonKind> {
+        match value {
+            QueryValue::Symbol(symbol) =>
+                match get_symbol_definition(&self.definition, symbol) {
+                    Some(ColumnDefinition::Integer { .. }) | Some(ColumnDefinition::Double { .. }) => Some(ComparisonKind::Numeric),
+                    Some(ColumnDefinition::Date { .. }) => Some(ComparisonKind::Chronological),
+                    Some(_) => Some(ComparisonKind::Lexicographic),
+                    None => None,
+                },
+            QueryValue::Int(_, _) | QueryValue::Double(_, _) => Some(ComparisonKind::Numeric),
+            QueryValue::Date(_) => Some(ComparisonKind::Chronological),
+            QueryValue::Text(_, _) => Some(ComparisonKind::Lexicographic),
+            QueryValue::Expression(expression) if expression.is_numeric() => Some(ComparisonKind::Numeric),
+            QueryValue::Expression(_) => Some(ComparisonKind::Lexicographic),
+            _ => None,
+        }
+    }
+
+    // Compares two operands using the type of the column they reference (a column's type takes
+    // precedence over a literal's), falling back to comparing raw bytes for text.
+    fn compare_values(&mut self, value1: &QueryValue, value2: &QueryValue) -> Option<Ordering> {
+        let kind =
+            match (value1, value2) {
+                (QueryValue::Symbol(_), _) => self.comparison_kind(value1),
+                (_, QueryValue::Symbol(_)) => self.comparison_kind(value2),
+                _ => self.comparison_kind(value1),
+            };
+        match kind {
+            Some(ComparisonKind::Numeric) => {
+                let number1 = self.resolve_number_value(value1);
+                let number2 = self.resolve_number_value(value2);
+                if number1.is_some() && number2.is_some() { number1.unwrap().partial_cmp(&number2.unwrap()) } else { None }
+            },
+            Some(ComparisonKind::Chronological) => {
+                let date1 = self.resolve_date_value(value1).cloned();
+                let date2 = self.resolve_date_value(value2).cloned();
+                if date1.is_some() && date2.is_some() { Some(date1.unwrap().cmp(&date2.unwrap())) } else { None }
+            },
+            _ if value1.is_expression() || value2.is_expression() => {
+                let text1 = self.resolve_text_value(value1);
+                let text2 = self.resolve_text_value(value2);
+                if text1.is_some() && text2.is_some() { Some(text1.unwrap().cmp(&text2.unwrap())) } else { None }
+            },
+            _ => {
+                self.resolve_value(value1);
+                self.resolve_value(value2);
+                let bytes1 = self.resolve_byte_value(value1);
+                let bytes2 = self.resolve_byte_value(value2);
+                if bytes1.is_some() && bytes2.is_some() { Some(bytes1.unwrap().cmp(bytes2.unwrap())) } else { None }
+            },
+        }
+    }
+
+    fn resolve_number_value(&mut self, value: &QueryValue) -> Option<f64> {
+        match value {
+            QueryValue::Int(int, _) => Some(*int as f64),
+            QueryValue::Double(dbl, _) => Some(*dbl),
+            QueryValue::Text(text, _) => text.parse::<f64>().ok(),
+            QueryValue::Symbol(symbol) => self.get_symbol_as_number(symbol),
+            QueryValue::Expression(expression) => self.evaluate_expression(expression).to_number().and_then(|n| n.as_f64()),
+            _ => None
+        }
+    }
+
+    // Text of an operand compared with a computed expression, e.g. lower(method) = "get".
+    fn resolve_text_value(&mut self, value: &QueryValue) -> Option<String> {
+        match value {
+            QueryValue::Expression(expression) =>
+                match self.evaluate_expression(expression) {
+                    Value::Null => None,
+                    value => Some(value.to_display_string()),
+                },
+            QueryValue::Symbol(symbol) => self.get_symbol_as_string(symbol),
+            QueryValue::Text(text, _) => Some(text.to_owned()),
+            QueryValue::Int(_, bytes) | QueryValue::Double(_, bytes) => String::from_utf8(bytes.clone()).ok(),
+            _ => None
+        }
+    }
+
+    fn get_symbol_bytes<'b>(&'b mut self, symbol: &str) -> Option<&'b [u8]> {
+        self.resolve_symbol(symbol);
+        self.get_resolved_symbol_bytes(symbol)
+    }
+
+    fn get_resolved_symbol_bytes<'b>(&'b self, symbol: &str) -> Option<&'b [u8]> {
+        get_symbol_definition(&self.definition, symbol).and_then(|d| d.extract_binary(&self.item))
+    }
+
+    // Virtual columns (e.g. GeoIP lookups) only expose raw bytes once their extractor has run and
+    // cached the value, so run it whenever no bytes are available yet.
+    fn resolve_symbol(&mut self, symbol: &str) {
+        if let Some(definition) = get_symbol_definition(&self.definition, symbol) {
+            if definition.extract_binary(self.item).is_none() {
+                get_column_value_as_string(definition, self.item);
+            }
+        }
+    }
+
+    fn resolve_value(&mut self, value: &QueryValue) {
+        if let QueryValue::Symbol(symbol) = value {
+            self.resolve_symbol(symbol);
+        }
+    }
+
+    fn resolve_byte_value<'a>(&'a self, value: &'a QueryValue) -> Option<&'a [u8]> {
+        match value {
+            QueryValue::Text(_, bytes) => Some(bytes),
+            QueryValue::Int(_, bytes) => Some(bytes),
+            QueryValue::Double(_, bytes) => Some(bytes),
+            QueryValue::Null => Some(EMPTY_BYTES),
+            QueryValue::Symbol(symbol) => self.get_resolved_symbol_bytes(symbol),
+            QueryValue::Date(date) => None,
+            _ => None
+        }
+    }
+
+    fn resolve_date_value<'a>(&'a mut self, value: &'a QueryValue) -> Option<&DateTime<Local>> {
+        match value {
+            QueryValue::Date(date) => Some(date),
+            QueryValue::Symbol(symbol) => self.get_symbol_date(symbol),
+            _ => None
+        }
+    }
+
+    // Ip columns parse their address once per record, while text columns and expressions are parsed
+    // each time they're matched.
+    fn resolve_address_value(&mut self, value: &QueryValue) -> Option<IpAddr> {
+        match value {
+            QueryValue::Symbol(symbol) => {
+                if let Some(ColumnDefinition::Ip { address_extractor, field, .. }) = get_symbol_definition(&self.definition, symbol) {
+                    return address_extractor(self.item, *field);
+                }
+                self.get_text_symbol_bytes(symbol).and_then(cidr::parse_address)
+            },
+            QueryValue::Expression(_) => self.resolve_text_value(value).and_then(|t| t.parse::<IpAddr>().ok()),
+            _ => None
+        }
+    }
+
+    // Raw bytes of a text (or ip) column, None for other columns.
+    fn get_text_symbol_bytes<'b>(&'b mut self, symbol: &str) -> Option<&'b [u8]> {
+        let item = &mut *self.item;
+        match get_symbol_definition(&self.definition, symbol) {
+            Some(definition @ ColumnDefinition::Text { .. }) | Some(definition @ ColumnDefinition::Ip { .. }) => {
+                if definition.extract_binary(item).is_none() {
+                    get_column_value_as_string(definition, item);
+                }
+                definition.extract_binary(item)
+            },
+            _ => None
+        }
+    }
+
+    fn get_symbol_date<'b>(&'b mut self, symbol: &str) -> Option<&'b DateTime<Local>> {
+        match get_symbol_definition(&self.definition, symbol) {
+            Some(ColumnDefinition::Date { extractor, field, .. }) => extractor(self.item, *field),
+            _ => None
+        }
+    }
+
+    fn get_column_value_as_string(&mut self, cdef: &ColumnDefinition<T>) -> Option<String> {
+        get_column_value_as_string(cdef, self.item)
+    }
+
+    fn get_symbol_as_string(&mut self, symbol: &str) -> Option<String> {
+        get_symbol_as_string(&self.definition, self.item, symbol)
+    }
+
+    // Value of a numeric column, integer columns staying integral.
+    fn get_symbol_as_numeric(&mut self, symbol: &str) -> Option<Value> {
+        match get_symbol_definition(&self.definition, symbol) {
+            Some(ColumnDefinition::Integer { extractor, field, .. }) => extractor(self.item, *field).map(Value::Int),
+            Some(ColumnDefinition::Double { extractor, field, .. }) => extractor(self.item, *field).map(Value::Float),
+            _ => None
+        }
+    }
+
+    // Value of a column min() and max() can be taken of: numbers, and dates which stay dates.
+    fn get_symbol_as_ordered(&mut self, symbol: &str) -> Option<Value> {
+        match get_symbol_definition(&self.definition, symbol) {
+            Some(ColumnDefinition::Date { extractor, field, .. }) => extractor(self.item, *field).map(|d| Value::Date(d.clone())),
+            _ => self.get_symbol_as_numeric(symbol),
+        }
+    }
+
+    fn get_bucket_start(&mut self, bucket: &QueryBucket) -> Option<DateTime<Local>> {
+        let column = bucket.column.as_ref()?;
+        let timestamp = self.get_symbol_date(column)?.timestamp();
+        Some(Local.timestamp(timestamp - timestamp.rem_euclid(bucket.seconds), 0))
+    }
+
+    fn get_symbol_value(&mut self, symbol: &str) -> Value {
+        let value =
+            match get_symbol_definition(&self.definition, symbol) {
+                Some(ColumnDefinition::Integer { extractor, field, .. }) => extractor(self.item, *field).map(Value::Int),
+                Some(ColumnDefinition::Double { extractor, field, .. }) => extractor(self.item, *field).map(Value::Float),
+                Some(ColumnDefinition::Text { extractor, field, .. }) | Some(ColumnDefinition::Ip { extractor, field, .. }) =>
+                    extractor(self.item, *field).map(|t| Value::Text(t.to_owned())),
+                Some(ColumnDefinition::Date { extractor, field, .. }) => extractor(self.item, *field).map(|d| Value::Date(d.clone())),
+                Some(ColumnDefinition::Boolean { extractor, field, .. }) => extractor(self.item, *field).map(Value::Bool),
+                None => None,
+            };
+        value.unwrap_or(Value::Null)
+    }
+
+    fn evaluate_expression(&mut self, expression: &QueryExpression) -> Value {
+        evaluate_expression(expression, &mut |operand| match operand {
+            QueryExpression::Symbol(symbol) => self.get_symbol_value(symbol),
+            _ => Value::Null,
+        })
+    }
+
+    fn evaluate_key_expression(&mut self, expression: &QueryExpression) -> Value {
+        evaluate_key_expression(expression, &mut |operand| match operand {
+            QueryExpression::Symbol(symbol) => self.get_symbol_value(symbol),
+            _ => Value::Null,
+        })
+    }
+
+    fn get_symbol_as_number(&mut self, symbol: &str) -> Option<f64> {
+        match get_symbol_definition(&self.definition, symbol) {
+            Some(ColumnDefinition::Integer { extractor, field, .. }) => extractor(self.item, *field).map(|i| i as f64),
+            Some(ColumnDefinition::Double { extractor, field, .. }) => extractor(self.item, *field),
+            _ => None
+        }
+    }
+}
+
+// Evaluates an expression, the values of the columns and reducers it refers to being looked up by
+// `operand`, which differs between records and aggregated rows.
+fn evaluate_expression<F>(expression: &QueryExpression, operand: &mut F) -> Value
+    where F: FnMut(&QueryExpression) -> Value {
+    evaluate(expression, operand, false)
+}
+
+// Evaluates the expression of a group key, integers divided by integers giving the integral part of
+// the quotient, so that e.g. `group status / 100` groups by the class of the status.
+fn evaluate_key_expression<F>(expression: &QueryExpression, operand: &mut F) -> Value
+    where F: FnMut(&QueryExpression) -> Value {
+    evaluate(expression, operand, true)
+}
+
+fn evaluate<F>(expression: &QueryExpression, operand: &mut F, integral_division: bool) -> Value
+    where F: FnMut(&QueryExpression) -> Value {
+    match expression {
+        QueryExpression::Text(text) => Value::Text(text.to_owned()),
+        QueryExpression::Int(int) => Value::Int(*int as u64),
+        QueryExpression::Double(double) => Value::Float(*double),
+        QueryExpression::Function(function, arguments) => {
+            let values: Vec<Value> = arguments.iter().map(|a| evaluate(a, operand, integral_division)).collect();
+            evaluate_function(function, &values)
+        },
+        QueryExpression::Arithmetic(left, op, right) => {
+            let value1 = evaluate(left, operand, integral_division);
+            let value2 = evaluate(right, operand, integral_division);
+            evaluate_arithmetic(op, &value1, &value2, integral_division)
+        },
+        QueryExpression::Symbol(_) | QueryExpression::Reducer(_, _) => operand(expression),
+    }
+}
+
+// Integers stay integral through addition, subtraction and multiplication unless the result doesn't
+// fit, e.g. a negative difference, while division gives a decimal unless it's integral. Null when
+// either value isn't a number or on division by zero.
+fn evaluate_arithmetic(op: &QueryArithmeticOp, value1: &Value, value2: &Value, integral_division: bool) -> Value {
+    let (number1, number2) =
+        match (value1.to_number(), value2.to_number()) {
+            (Some(number1), Some(number2)) => (number1, number2),
+            _ => return Value::Null,
+        };
+    if let (&Value::Int(i1), &Value::Int(i2)) = (&number1, &number2) {
+        let result =
+            match op {
+                QueryArithmeticOp::Add => i1.checked_add(i2),
+                QueryArithmeticOp::Sub => i1.checked_sub(i2),
+                QueryArithmeticOp::Mul => i1.checked_mul(i2),
+                QueryArithmeticOp::Div if integral_division => i1.checked_div(i2),
+                QueryArithmeticOp::Div => None,
+            };
+        if let Some(result) = result {
+            return Value::Int(result);
+        }
+    }
+    let (d1, d2) = (number1.as_f64().unwrap_or(0.0), number2.as_f64().unwrap_or(0.0));
+    match op {
+        QueryArithmeticOp::Add => Value::Float(d1 + d2),
+        QueryArithmeticOp::Sub => Value::Float(d1 - d2),
+        QueryArithmeticOp::Mul => Value::Float(d1 * d2),
+        QueryArithmeticOp::Div if d2 == 0.0 => Value::Null,
+        QueryArithmeticOp::Div => Value::Float(d1 / d2),
+    }
+}
+
+fn evaluate_function(function: &QueryFunction, values: &Vec<Value>) -> Value {
+    if function.is_date_part() {
+        return match values.first() {
+            Some(Value::Date(date)) => Value::Int(evaluate_date_part(function, date)),
+            _ => Value::Null,
+        };
+    }
+    if let QueryFunction::Bucket = function {
+        return match (values.first().and_then(|v| v.to_number()), values.get(1)) {
+            (Some(value), Some(width)) => evaluate_bucket(&value, width),
+            _ => Value::Null,
+        };
+    }
+    let text = match values.first() {
+        Some(Value::Null) | None => return Value::Null,
+        Some(value) => value.to_display_string(),
+    };
+    let integer = |idx: usize| match values.get(idx) { Some(Value::Int(i)) => Some(*i as usize), _ => None };
+    match function {
+        QueryFunction::Lower => Value::Text(text.to_lowercase()),
+        // Positions count characters rather than bytes, so multi-byte text is never split
+        QueryFunction::Substr => {
+            let start = integer(1).unwrap_or(0);
+            let chars = text.chars().skip(start);
+            let substring: String = match integer(2) { Some(length) => chars.take(length).collect(), None => chars.collect() };
+            Value::Text(substring)
+        },
+        QueryFunction::Split => {
+            let separator = match values.get(1) { Some(Value::Text(separator)) => separator.to_owned(), _ => return Value::Null };
+            match integer(2).and_then(|idx| text.split(separator.as_str()).nth(idx)) {
+                Some(part) => Value::Text(part.to_owned()),
+                None => Value::Null,
+            }
+        },
+        _ => Value::Null,
+    }
+}
+
+// Start of the range of the given width a number falls in, e.g. 2048 for 3000 in ranges of 1024.
+// Integers stay integral when the width is. Null for widths that aren't positive.
+fn evaluate_bucket(value: &Value, width: &Value) -> Value {
+    match (value, width) {
+        (Value::Int(i), Value::Int(w)) if *w > 0 => Value::Int(i - i % w),
+        (value, width) => match (value.as_f64(), width.as_f64()) {
+            (Some(f), Some(w)) if w > 0.0 => Value::Float((f / w).floor() * w),
+            _ => Value::Null,
+        },
+    }
+}
+
+// Hour of the day (0-23), day of the week (1 for Monday to 7 for Sunday) or day of the month (1-31)
+// of a date, in local time.
+fn evaluate_date_part(function: &QueryFunction, date: &DateTime<Local>) -> u64 {
+    match function {
+        QueryFunction::Hour => date.hour() as u64,
+        QueryFunction::DayOfWeek => date.weekday().number_from_monday() as u64,
+        _ => date.day() as u64,
+    }
+}
+
+fn get_symbol_definition<'a, T>(tdef: &'a TableDefinition<T>, symbol: &str) -> Option<&'a ColumnDefinition<T>> {
+    tdef.column_map.get(symbol)
+}
+
+fn get_symbol_as_string<T>(tdef: &TableDefinition<T>, item: &mut T, symbol: &str) -> Option<String> {
+    get_symbol_definition(tdef, symbol).and_then(|d| get_column_value_as_string(d, item))
+}
+
+fn get_column_value_as_string<T>(cdef: &ColumnDefinition<T>, item: &mut T) -> Option<String> {
+    match cdef {
+        ColumnDefinition::Integer { extractor, field, .. } => extractor(item, *field).map(|i| i.to_string()),
+        ColumnDefinition::Double { extractor, field, .. } => extractor(item, *field).map(|i| i.to_string()),
+        ColumnDefinition::Text { extractor, field, .. } => extractor(item, *field).map(|i| i.to_string()),
+        ColumnDefinition::Date { extractor, field, .. } => extractor(item, *field).map(|i| i.to_string()),
+        ColumnDefinition::Boolean { extractor, field, .. } => extractor(item, *field).map(|i| i.to_string()),
+        ColumnDefinition::Ip { extractor, field, .. } => extractor(item, *field).map(|i| i.to_string()),
+    }
+}
+
+struct Reducer<T> {
+    field_reducers: Vec<Box<FieldReducer<T>>>
+}
+
+impl<T> Reducer<T> {
+    fn apply_record(&mut self, record: &mut Record<T>) {
+        for reducer in &mut self.field_reducers {
+            reducer.apply_record(record);
+        }
+    }
+
+    fn state(&self) -> serde_json::Value {
+        serde_json::Value::from(self.field_reducers.iter().map(|r| r.state()).collect::<Vec<serde_json::Value>>())
+    }
+
+    fn merge_state(&mut self, state: &serde_json::Value) -> Option<()> {
+        let states = state.as_array().filter(|s| s.len() == self.field_reducers.len())?;
+        for (reducer, state) in self.field_reducers.iter_mut().zip(states) {
+            reducer.merge_state(state)?;
+        }
+        Some(())
+    }
+
+    // None when one of the reducers can't take a state out, leaving the others partly evicted.
+    fn evict_state(&mut self, state: &serde_json::Value) -> Option<()> {
+        let states = state.as_array().filter(|s| s.len() == self.field_reducers.len())?;
+        for (reducer, state) in self.field_reducers.iter_mut().zip(states) {
+            reducer.evict_state(state)?;
+        }
+        Some(())
+    }
+}
+
+trait FieldReducer<T> {
+    fn apply_record(&mut self, record: &mut Record<T>);
+    // Null when no value was reduced, e.g. the max of a column that was always empty.
+    fn value(&self) -> Value;
+    fn get_symbol(&self) -> &str;
+    // What was reduced so far, which merge_state folds into the same reducer of other records, e.g.
+    // those of a later run. None when merging a state that isn't one of this reducer's.
+    fn state(&self) -> serde_json::Value;
+    fn merge_state(&mut self, state: &serde_json::Value) -> Option<()>;
+    // Takes out a state merged earlier, e.g. that of records aging out of a sliding window. None for
+    // reducers that can't, such as max, which are rebuilt from the states left instead.
+    fn evict_state(&mut self, _state: &serde_json::Value) -> Option<()> {
+        None
+    }
+    // Why the value isn't exact, e.g. a sum too large to be held
+    fn overflow_warning(&self) -> Option<String> {
+        None
+    }
+}
+
+fn state_object(fields: Vec<(&str, serde_json::Value)>) -> serde_json::Value {
+    serde_json::Value::Object(fields.into_iter().map(|(name, value)| (name.to_owned(), value)).collect::<Map<String, serde_json::Value>>())
+}
+
+fn state_u64(state: &serde_json::Value, name: &str) -> Option<u64> {
+    state.get(name).and_then(|v| v.as_u64())
+}
+
+fn state_value(state: &serde_json::Value, name: &str) -> Option<Value> {
+    state.get(name).and_then(Value::from_state)
+}
+
+#[derive(Debug, Clone)]
+struct CountReducer {
+    symbol: String,
+    count: u64,
+}
+
+impl<T> FieldReducer<T> for CountReducer {
+    fn apply_record(&mut self, record: &mut Record<T>) {
+        if self.symbol == "*" {
+            self.count += 1;
+        } else {
+            let value = record.get_symbol_bytes(&self.symbol);
+            if value.is_some() {
+                self.count += 1;
+            }
+        }
+    }
+
+    fn value(&self) -> Value {
+        Value::Int(self.count)
+    }
+
+    fn get_symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    fn state(&self) -> serde_json::Value {
+        state_object(vec![("count", serde_json::Value::from(self.count))])
+    }
+
+    fn merge_state(&mut self, state: &serde_json::Value) -> Option<()> {
+        self.count += state_u64(state, "count")?;
+        Some(())
+    }
+
+    fn evict_state(&mut self, state: &serde_json::Value) -> Option<()> {
+        self.count = self.count.checked_sub(state_u64(state, "count")?)?;
+        Some(())
+    }
+}
+            
+#[derive(Debug, Clone)]
+struct SumReducer {
+    symbol: String,
+    sum: Value,
+    // Whether the sum saturated at the largest integer
+    overflowed: bool,
+}
+
+impl SumReducer {
+    fn add(&mut self, value: &Value) {
+        self.overflowed |= self.sum.overflows(value);
+        self.sum = self.sum.add(value);
+    }
+}
+
+impl<T> FieldReducer<T> for SumReducer {
+    fn apply_record(&mut self, record: &mut Record<T>) {
+        if let Some(value) = record.get_symbol_as_numeric(&self.symbol) {
+            self.add(&value);
+        }
+    }
+
+    fn value(&self) -> Value {
+        self.sum.clone()
+    }
+
+    fn get_symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    fn state(&self) -> serde_json::Value {
+        state_object(vec![("sum", self.sum.to_state()), ("overflowed", serde_json::Value::from(self.overflowed))])
+    }
+
+    // States saved before overflows were tracked lack the flag
+    fn merge_state(&mut self, state: &serde_json::Value) -> Option<()> {
+        self.add(&state_value(state, "sum")?);
+        self.overflowed |= state.get("overflowed").and_then(|o| o.as_bool()).unwrap_or(false);
+        Some(())
+    }
+
+    // A sum that saturated no longer tells what was added to it
+    fn evict_state(&mut self, state: &serde_json::Value) -> Option<()> {
+        if self.overflowed {
+            return None;
+        }
+        self.sum = self.sum.subtract(&state_value(state, "sum")?)?;
+        Some(())
+    }
+
+    fn overflow_warning(&self) -> Option<String> {
+        if self.overflowed {
+            Some(format!("sum({}) overflowed, so it is shown as {}, the largest sum that can be held", self.symbol, u64::MAX))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AvgReducer {
+    symbol: String,
+    count: u64,
+    sum: Value
+}
+
+impl AvgReducer {
+    // Averages are floats anyway, so a sum of integers too large to be held is carried on as a float
+    fn add(&mut self, value: &Value) {
+        self.sum =
+            match (self.sum.as_f64(), value.as_f64()) {
+                (Some(sum), Some(added)) if self.sum.overflows(value) => Value::Float(sum + added),
+                _ => self.sum.add(value),
+            };
+    }
+}
+
+impl<T> FieldReducer<T> for AvgReducer {
+    fn apply_record(&mut self, record: &mut Record<T>) {
+        if let Some(value) = record.get_symbol_as_numeric(&self.symbol) {
+            self.add(&value);
+            self.count += 1;
+        }
+    }
+
+    // Averages are floats even of integers, so that e.g. 1.9 isn't shown, or sorted, as 1
+    fn value(&self) -> Value {
+        match self.sum.as_f64() {
+            _ if self.count == 0 => Value::Null,
+            Some(sum) => Value::Float(sum / self.count as f64),
+            None => Value::Null,
+        }
+    }
+    
+    fn get_symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    fn state(&self) -> serde_json::Value {
+        state_object(vec![("count", serde_json::Value::from(self.count)), ("sum", self.sum.to_state())])
+    }
+
+    fn merge_state(&mut self, state: &serde_json::Value) -> Option<()> {
+        self.count += state_u64(state, "count")?;
+        self.add(&state_value(state, "sum")?);
+        Some(())
+    }
+
+    fn evict_state(&mut self, state: &serde_json::Value) -> Option<()> {
+        self.count = self.count.checked_sub(state_u64(state, "count")?)?;
+        self.sum = self.sum.subtract(&state_value(state, "sum")?)?;
+        Some(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MaxReducer {
+    symbol: String,
+    max: Value
+}
+
+impl<T> FieldReducer<T> for MaxReducer {
+    fn apply_record(&mut self, record: &mut Record<T>) {
+        if let Some(value) = record.get_symbol_as_ordered(&self.symbol) {
+            if self.max.is_null() || value.compare(&self.max) == Some(Ordering::Greater) {
+                self.max = value;
+            }
+        }
+    }
+
+    fn value(&self) -> Value {
+        self.max.clone()
+    }
+
+    fn get_symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    fn state(&self) -> serde_json::Value {
+        state_object(vec![("max", self.max.to_state())])
+    }
+
+    fn merge_state(&mut self, state: &serde_json::Value) -> Option<()> {
+        let value = state_value(state, "max")?;
+        if self.max.is_null() || value.compare(&self.max) == Some(Ordering::Greater) {
+            self.max = value;
+        }
+        Some(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MinReducer {
+    symbol: String,
+    min: Value
+}
+
+impl<T> FieldReducer<T> for MinReducer {
+    fn apply_record(&mut self, record: &mut Record<T>) {
+        if let Some(value) = record.get_symbol_as_ordered(&self.symbol) {
+            if self.min.is_null() || value.compare(&self.min) == Some(Ordering::Less) {
+                self.min = value;
+            }
+        }
+    }
+
+    fn value(&self) -> Value {
+        self.min.clone()
+    }
+
+    fn get_symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    fn state(&self) -> serde_json::Value {
+        state_object(vec![("min", self.min.to_state())])
+    }
+
+    fn merge_state(&mut self, state: &serde_json::Value) -> Option<()> {
+        let value = state_value(state, "min")?;
+        if self.min.is_null() || value.compare(&self.min) == Some(Ordering::Less) {
+            self.min = value;
+        }
+        Some(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct UniqueReducer {
+    symbol: String,
+    values: HashSet<Vec<u8>>
+}
+
+impl<T> FieldReducer<T> for UniqueReducer {
+    fn apply_record(&mut self, record: &mut Record<T>) {
+        let value = record.get_symbol_bytes(&self.symbol);
+        if value.is_some() && !self.values.contains(value.unwrap()) {
+            self.values.insert(value.unwrap().to_vec());
+        }
+    }
+
+    fn value(&self) -> Value {
+        Value::Int(self.values.len() as u64)
+    }
+
+    fn get_symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    // Values are kept as text, columns holding UTF-8
+    fn state(&self) -> serde_json::Value {
+        let values: Vec<serde_json::Value> = self.values.iter().map(|v| serde_json::Value::from(String::from_utf8_lossy(v).to_string())).collect();
+        state_object(vec![("values", serde_json::Value::from(values))])
+    }
+
+    fn merge_state(&mut self, state: &serde_json::Value) -> Option<()> {
+        for value in state.get("values")?.as_array()? {
+            self.values.insert(value.as_str()?.as_bytes().to_vec());
+        }
+        Some(())
+    }
+}
+
+// Most frequent values of a column along with how often they occurred. Like unique, every distinct
+// value of the group is counted.
+#[derive(Debug, Clone)]
+struct TopReducer {
+    symbol: String,
+    count: usize,
+    counts: HashMap<Vec<u8>, u64>
+}
+
+impl<T> FieldReducer<T> for TopReducer {
+    fn apply_record(&mut self, record: &mut Record<T>) {
+        if let Some(value) = record.get_symbol_bytes(&self.symbol) {
+            if let Some(count) = self.counts.get_mut(value) {
+                *count += 1;
+                return;
+            }
+            self.counts.insert(value.to_vec(), 1);
+        }
+    }
+
+    fn get_symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    // Not a number, so top values aren't sortable
+    fn value(&self) -> Value {
+        let mut counts: Vec<(&Vec<u8>, &u64)> = self.counts.iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        Value::Counts(counts.into_iter()
+                            .take(self.count)
+                            .map(|(value, count)| (String::from_utf8_lossy(value).to_string(), *count))
+                            .collect())
+    }
+
+    // Every value's count, not only those of the top values, as another top value may add up to more
+    fn state(&self) -> serde_json::Value {
+        let counts = self.counts.iter().map(|(value, count)| (String::from_utf8_lossy(value).to_string(), serde_json::Value::from(*count))).collect();
+        state_object(vec![("counts", serde_json::Value::Object(counts))])
+    }
+
+    fn merge_state(&mut self, state: &serde_json::Value) -> Option<()> {
+        for (value, count) in state.get("counts")?.as_object()? {
+            *self.counts.entry(value.as_bytes().to_vec()).or_insert(0) += count.as_u64()?;
+        }
+        Some(())
+    }
+}
+
+const DIGEST_COMPRESSION: f64 = 100.0;
+const DIGEST_BUFFER: usize = 500;
 
-    fn get_symbol_bytes<'b>(&'b self, symbol: &str) -> Option<&'b [u8]> {
-        get_symbol_definition(&self.definition, symbol).extract_binary(&self.item)
-    }
+// Value at a percentile of a column by the nearest rank, the smallest value that the percent of values
+// are at or below, e.g. p(request_time, 95). Values are kept in a t-digest: they're buffered as read,
+// and once the buffer fills merged into centroids, weighted means of neighbouring values, that are
+// smaller towards either end so that high and low percentiles stay accurate. A group holds at most a
+// few hundred values and centroids, and is exact until its buffer first fills.
+#[derive(Debug, Clone)]
+struct PercentileReducer {
+    symbol: String,
+    percent: usize,
+    // Means and weights of the centroids, sorted by mean
+    centroids: Vec<(f64, u64)>,
+    // Values, or centroids of merged states, not yet merged into the centroids
+    buffer: Vec<(f64, u64)>,
+}
 
-    fn resolve_byte_value<'a>(&'a self, value: &'a QueryValue) -> Option<&'a [u8]> {
-        match value {
-            QueryValue::Text(_, bytes) => Some(bytes),
-            QueryValue::Int(_, bytes) => Some(bytes),
-            QueryValue::Double(_, bytes) => Some(bytes),
-            QueryValue::Null => Some(EMPTY_BYTES),
-            QueryValue::Symbol(symbol) => self.get_symbol_bytes(symbol),
-            QueryValue::Date(date) => None,
-            _ => None
-        }
+impl PercentileReducer {
+    fn new(symbol: String, percent: usize) -> PercentileReducer {
+        PercentileReducer { symbol: symbol, percent: percent, centroids: Vec::new(), buffer: Vec::new() }
     }
 
-    fn resolve_date_value<'a>(&'a mut self, value: &'a QueryValue) -> Option<&DateTime<Local>> {
-        match value {
-            QueryValue::Date(date) => Some(date),
-            QueryValue::Symbol(symbol) => self.get_symbol_date(symbol),
-            _ => None
+    fn add(&mut self, mean: f64, weight: u64) {
+        self.buffer.push((mean, weight));
+        if self.buffer.len() >= DIGEST_BUFFER {
+            self.compress();
         }
     }
 
-    fn resolve_string_value<'a>(&'a mut self, value: &'a QueryValue) -> Option<&'a str> {
-        match value {
-            QueryValue::Text(value, _) => Some(&value),
-            QueryValue::Symbol(symbol) => self.get_symbol_string(symbol),
-            _ => None
+    // Centroids and buffered values together, sorted by mean.
+    fn points(&self) -> Vec<(f64, u64)> {
+        let mut points: Vec<(f64, u64)> = self.centroids.iter().chain(self.buffer.iter()).cloned().collect();
+        points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+        points
+    }
+
+    // Merges neighbouring points while the centroid they make spans at most one unit of the scale
+    // k(q) = compression / 2π · asin(2q - 1), q being the fraction of values below a point.
+    fn compress(&mut self) {
+        let points = self.points();
+        let total = points.iter().map(|p| p.1).sum::<u64>() as f64;
+        let scale = |q: f64| DIGEST_COMPRESSION / (2.0 * ::std::f64::consts::PI) * (2.0 * q - 1.0).asin();
+        let limit = |below: u64| {
+            let k = scale(below as f64 / total) + 1.0;
+            if k >= DIGEST_COMPRESSION / 4.0 { 1.0 } else { ((k * 2.0 * ::std::f64::consts::PI / DIGEST_COMPRESSION).sin() + 1.0) / 2.0 }
+        };
+        let mut centroids: Vec<(f64, u64)> = Vec::new();
+        let mut below = 0;
+        for (mean, weight) in points {
+            let merge =
+                match centroids.last() {
+                    Some(&(_, current)) => (below + current + weight) as f64 / total <= limit(below),
+                    None => false,
+                };
+            if merge {
+                let current = centroids.last_mut().unwrap();
+                current.0 += (mean - current.0) * weight as f64 / (current.1 + weight) as f64;
+                current.1 += weight;
+            } else {
+                below += centroids.last().map(|c| c.1).unwrap_or(0);
+                centroids.push((mean, weight));
+            }
         }
+        self.centroids = centroids;
+        self.buffer.clear();
     }
+}
 
-    fn get_symbol_string<'b>(&'b mut self, symbol: &str) -> Option<&'b str> {
-        match get_symbol_definition(&self.definition, symbol) {
-            ColumnDefinition::Text { extractor, .. } => extractor(self.item),
-            _ => None
+impl<T> FieldReducer<T> for PercentileReducer {
+    fn apply_record(&mut self, record: &mut Record<T>) {
+        if let Some(value) = record.get_symbol_as_numeric(&self.symbol).and_then(|v| v.as_f64()) {
+            self.add(value, 1);
         }
     }
 
-    fn get_symbol_date<'b>(&'b mut self, symbol: &str) -> Option<&'b DateTime<Local>> {
-        match get_symbol_definition(&self.definition, symbol) {
-            ColumnDefinition::Date { extractor, .. } => extractor(self.item),
-            _ => None
+    // Each centroid stands for the middle of the values it merged, and the value of a rank between two
+    // of them is interpolated. Values that weren't merged are their own centroid, so the nearest rank
+    // falls on one of them.
+    fn value(&self) -> Value {
+        let points = self.points();
+        let total: u64 = points.iter().map(|p| p.1).sum();
+        if total == 0 {
+            return Value::Null;
+        }
+        let rank = ((self.percent as f64 / 100.0 * total as f64).ceil()).max(1.0) - 0.5;
+        let mut below = 0.0;
+        let mut previous: Option<(f64, f64)> = None;
+        for &(mean, weight) in &points {
+            let middle = below + weight as f64 / 2.0;
+            if middle >= rank {
+                return match previous {
+                    Some((previous_middle, previous_mean)) =>
+                        Value::Float(previous_mean + (mean - previous_mean) * (rank - previous_middle) / (middle - previous_middle)),
+                    None => Value::Float(mean),
+                };
+            }
+            below += weight as f64;
+            previous = Some((middle, mean));
         }
+        Value::Float(points[points.len() - 1].0)
     }
 
-    fn get_column_value_as_string(&mut self, cdef: &ColumnDefinition<T>) -> Option<String> {
-        get_column_value_as_string(cdef, self.item)
+    fn get_symbol(&self) -> &str {
+        &self.symbol
     }
 
-    fn get_symbol_as_string(&mut self, symbol: &str) -> Option<String> {
-        get_symbol_as_string(&self.definition, self.item, symbol)
+    // Centroids are written as [mean, weight] pairs, buffered values being centroids of one
+    fn state(&self) -> serde_json::Value {
+        let centroids: Vec<serde_json::Value> = self.points().iter().map(|&(mean, weight)| serde_json::Value::from(vec![serde_json::Value::from(mean), serde_json::Value::from(weight)])).collect();
+        state_object(vec![("centroids", serde_json::Value::from(centroids))])
     }
 
-    fn get_symbol_as_integer(&mut self, symbol: &str) -> Option<u64> {
-        get_symbol_as_integer(&self.definition, self.item, symbol)
+    fn merge_state(&mut self, state: &serde_json::Value) -> Option<()> {
+        for centroid in state.get("centroids")?.as_array()? {
+            let centroid = centroid.as_array().filter(|c| c.len() == 2)?;
+            self.add(centroid[0].as_f64()?, centroid[1].as_u64()?);
+        }
+        Some(())
     }
 }
 
-fn get_symbol_definition<'a, T>(tdef: &'a TableDefinition<T>, symbol: &str) -> &'a ColumnDefinition<T> {
-    tdef.column_map.get(symbol).unwrap()
-}
-
-fn get_symbol_as_string<T>(tdef: &TableDefinition<T>, item: &mut T, symbol: &str) -> Option<String> {
-    let definition = get_symbol_definition(tdef, symbol);
-    get_column_value_as_string(definition, item)
+// Sample variance, or its square root for stddev(), computed in one pass with Welford's algorithm,
+// which unlike summing squares doesn't lose precision when values are large compared to their
+// spread. Null for fewer than two values.
+#[derive(Debug, Clone)]
+struct VarianceReducer {
+    symbol: String,
+    count: u64,
+    mean: f64,
+    // Sum of squared differences from the mean
+    squares: f64,
+    stddev: bool,
 }
 
-fn get_symbol_as_integer<T>(tdef: &TableDefinition<T>, item: &mut T, symbol: &str) -> Option<u64> {
-    let definition = get_symbol_definition(tdef, symbol);
-    get_column_value_as_integer(definition, item)
-}
+impl<T> FieldReducer<T> for VarianceReducer {
+    fn apply_record(&mut self, record: &mut Record<T>) {
+        if let Some(value) = record.get_symbol_as_numeric(&self.symbol).and_then(|v| v.as_f64()) {
+            self.count += 1;
+            let delta = value - self.mean;
+            self.mean += delta / self.count as f64;
+            self.squares += delta * (value - self.mean);
+        }
+    }
 
-fn get_column_value_as_string<T>(cdef: &ColumnDefinition<T>, item: &mut T) -> Option<String> {
-    match cdef {
-        ColumnDefinition::Integer { extractor, .. } => extractor(item).map(|i| i.to_string()),
-        ColumnDefinition::Double { extractor, .. } => extractor(item).map(|i| i.to_string()),
-        ColumnDefinition::Text { extractor, .. } => extractor(item).map(|i| i.to_string()),
-        ColumnDefinition::Date { extractor, .. } => extractor(item).map(|i| i.to_string()),
-        ColumnDefinition::Boolean { extractor, .. } => extractor(item).map(|i| i.to_string()),
+    fn value(&self) -> Value {
+        if self.count < 2 {
+            return Value::Null;
+        }
+        let variance = self.squares / (self.count - 1) as f64;
+        Value::Float(if self.stddev { variance.sqrt() } else { variance })
     }
-}
 
-fn get_column_value_as_integer<T>(cdef: &ColumnDefinition<T>, item: &mut T) -> Option<u64> {
-    match cdef {
-        ColumnDefinition::Integer { extractor, .. } => extractor(item),
-        _ => None
+    fn get_symbol(&self) -> &str {
+        &self.symbol
     }
-}
 
-struct Reducer<T> {
-    field_reducers: Vec<Box<FieldReducer<T>>>
-}
+    fn state(&self) -> serde_json::Value {
+        state_object(vec![("count", serde_json::Value::from(self.count)), ("mean", serde_json::Value::from(self.mean)),
+                          ("squares", serde_json::Value::from(self.squares))])
+    }
 
-impl<T> Reducer<T> {
-    fn apply_record(&mut self, record: &mut Record<T>) {
-        for reducer in &mut self.field_reducers {
-            reducer.apply_record(record);
+    // The means and squared differences of both sets of values are combined as by Chan et al.
+    fn merge_state(&mut self, state: &serde_json::Value) -> Option<()> {
+        let count = state_u64(state, "count")?;
+        let (mean, squares) = (state.get("mean")?.as_f64()?, state.get("squares")?.as_f64()?);
+        if count == 0 {
+            return Some(());
         }
+        let total = (self.count + count) as f64;
+        let delta = mean - self.mean;
+        self.squares += squares + delta * delta * self.count as f64 * count as f64 / total;
+        self.mean += delta * count as f64 / total;
+        self.count += count;
+        Some(())
     }
 }
 
-trait FieldReducer<T> {
-    fn apply_record(&mut self, record: &mut Record<T>);
-    fn result(&self) -> u64;
-    fn get_symbol(&self) -> &str;
-}
-            
+const HLL_PRECISION: u32 = 14;
+const HLL_REGISTERS: usize = 1 << HLL_PRECISION;
+
+// HyperLogLog estimate of the number of distinct values, using a fixed 16KB of registers per
+// group regardless of cardinality (~0.8% standard error).
 #[derive(Debug, Clone)]
-struct CountReducer {
+struct ApproxUniqueReducer {
     symbol: String,
-    count: u64,
+    registers: Vec<u8>
 }
 
-impl<T> FieldReducer<T> for CountReducer {
+impl<T> FieldReducer<T> for ApproxUniqueReducer {
     fn apply_record(&mut self, record: &mut Record<T>) {
-        if self.symbol == "*" {
-            self.count += 1;
-        } else {
-            let value = record.get_symbol_bytes(&self.symbol);
-            if value.is_some() {
-                self.count += 1;
+        let value = record.get_symbol_bytes(&self.symbol);
+        if value.is_some() {
+            let mut hasher = DefaultHasher::new();
+            hasher.write(value.unwrap());
+            let hash = hasher.finish();
+            let idx = (hash >> (64 - HLL_PRECISION)) as usize;
+            let rank = ((hash << HLL_PRECISION) | (1 << (HLL_PRECISION - 1))).leading_zeros() as u8 + 1;
+            if rank > self.registers[idx] {
+                self.registers[idx] = rank;
             }
         }
     }
 
-    fn result(&self) -> u64 {
-        self.count
+    fn value(&self) -> Value {
+        let m = HLL_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|r| 1.0 / (1u64 << r) as f64).sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            // Linear counting is more accurate for small cardinalities
+            Value::Int((m * (m / zeros as f64).ln()).round() as u64)
+        } else {
+            Value::Int(estimate.round() as u64)
+        }
     }
 
     fn get_symbol(&self) -> &str {
         &self.symbol
     }
+
+    // Registers are written in hex, two digits each
+    fn state(&self) -> serde_json::Value {
+        let registers: String = self.registers.iter().map(|r| format!("{:02x}", r)).collect();
+        state_object(vec![("registers", serde_json::Value::from(registers))])
+    }
+
+    // The union of two sketches keeps the highest rank of each register.
+    fn merge_state(&mut self, state: &serde_json::Value) -> Option<()> {
+        let registers = state.get("registers")?.as_str().filter(|r| r.len() == 2 * HLL_REGISTERS)?;
+        for (idx, register) in self.registers.iter_mut().enumerate() {
+            let rank = u8::from_str_radix(registers.get(2 * idx..2 * idx + 2)?, 16).ok()?;
+            *register = (*register).max(rank);
+        }
+        Some(())
+    }
 }
-            
+
+// Value of the record with the earliest date, or the latest one for last(). Records without a date
+// or value are skipped, and of records with the same date the first read is kept by first() and the
+// last read by last(), logs being written in order.
 #[derive(Debug, Clone)]
-struct SumReducer {
+struct FirstReducer {
     symbol: String,
-    sum: u64
+    date_column: Option<String>,
+    first: Option<(DateTime<Local>, Value)>,
+    last: bool,
 }
 
-impl<T> FieldReducer<T> for SumReducer {
+impl<T> FieldReducer<T> for FirstReducer {
     fn apply_record(&mut self, record: &mut Record<T>) {
-        let value = record.get_symbol_as_integer(&self.symbol);
-        if value.is_some() {
-            self.sum += value.unwrap();
+        let date = match self.date_column.as_ref().and_then(|c| record.get_symbol_date(c)) {
+            Some(date) => date.clone(),
+            None => return,
+        };
+        let replace =
+            match self.first {
+                Some((ref first, _)) if self.last => date >= *first,
+                Some((ref first, _)) => date < *first,
+                None => true,
+            };
+        if replace {
+            let value = record.get_symbol_value(&self.symbol);
+            if !value.is_null() {
+                self.first = Some((date, value));
+            }
         }
     }
 
-    fn result(&self) -> u64 {
-        self.sum
+    fn value(&self) -> Value {
+        self.first.as_ref().map(|&(_, ref value)| value.clone()).unwrap_or(Value::Null)
     }
 
     fn get_symbol(&self) -> &str {
         &self.symbol
     }
+
+    fn state(&self) -> serde_json::Value {
+        match self.first {
+            Some((ref date, ref value)) => state_object(vec![("date", Value::Date(date.clone()).to_state()), ("value", value.to_state())]),
+            None => serde_json::Value::Null,
+        }
+    }
+
+    // The state merged is of records read before those reduced since, as when resuming a run.
+    fn merge_state(&mut self, state: &serde_json::Value) -> Option<()> {
+        if state.is_null() {
+            return Some(());
+        }
+        let date = match state_value(state, "date")? { Value::Date(date) => date, _ => return None };
+        let value = state_value(state, "value")?;
+        let replace =
+            match self.first {
+                Some((ref first, _)) if self.last => date > *first,
+                Some((ref first, _)) => date <= *first,
+                None => true,
+            };
+        if replace {
+            self.first = Some((date, value));
+        }
+        Some(())
+    }
 }
 
+// Fraction of the group's records matching a filter, e.g. ratio(status >= 500) for the error rate.
 #[derive(Debug, Clone)]
-struct AvgReducer {
-    symbol: String,
+struct RatioReducer {
+    filter: Option<QueryFilter>,
     count: u64,
-    sum: u64
+    matched: u64,
 }
 
-impl<T> FieldReducer<T> for AvgReducer {
+impl<T> FieldReducer<T> for RatioReducer {
     fn apply_record(&mut self, record: &mut Record<T>) {
-        let value = record.get_symbol_as_integer(&self.symbol);
-        if value.is_some() {
-            self.sum += value.unwrap();
-            self.count += 1;
+        self.count += 1;
+        if self.filter.as_ref().map(|f| record.matches_filter(f)).unwrap_or(false) {
+            self.matched += 1;
         }
     }
 
-    fn result(&self) -> u64 {
-        if self.count > 0 {
-            self.sum / self.count
-        } else {
-            0
+    fn value(&self) -> Value {
+        if self.count == 0 {
+            return Value::Null;
         }
+        Value::Float(self.matched as f64 / self.count as f64)
     }
-    
+
+    // Every record of the group is counted, as for count(*)
     fn get_symbol(&self) -> &str {
-        &self.symbol
+        "*"
+    }
+
+    fn state(&self) -> serde_json::Value {
+        state_object(vec![("count", serde_json::Value::from(self.count)), ("matched", serde_json::Value::from(self.matched))])
+    }
+
+    fn merge_state(&mut self, state: &serde_json::Value) -> Option<()> {
+        self.count += state_u64(state, "count")?;
+        self.matched += state_u64(state, "matched")?;
+        Some(())
     }
 }
 
+// Records per duration over the time the group's records span, e.g. rate(1m) for requests per
+// minute. Log dates have a resolution of a second, so the span runs from the start of the earliest
+// record's second to the end of the latest's, and records without a date are left out.
 #[derive(Debug, Clone)]
-struct MaxReducer {
-    symbol: String,
-    max: u64
+struct RateReducer {
+    seconds: i64,
+    date_column: Option<String>,
+    count: u64,
+    span: Option<(i64, i64)>,
 }
 
-impl<T> FieldReducer<T> for MaxReducer {
+impl<T> FieldReducer<T> for RateReducer {
     fn apply_record(&mut self, record: &mut Record<T>) {
-        let value = record.get_symbol_as_integer(&self.symbol);
-        if value.is_some() && value.unwrap() > self.max {
-            self.max = value.unwrap();
-        }
+        let timestamp = match self.date_column.as_ref().and_then(|c| record.get_symbol_date(c)) {
+            Some(date) => date.timestamp(),
+            None => return,
+        };
+        self.count += 1;
+        self.span =
+            match self.span {
+                Some((first, last)) => Some((first.min(timestamp), last.max(timestamp))),
+                None => Some((timestamp, timestamp)),
+            };
     }
 
-    fn result(&self) -> u64 {
-        self.max
+    fn value(&self) -> Value {
+        match self.span {
+            Some((first, last)) => Value::Float(self.count as f64 * self.seconds as f64 / (last - first + 1) as f64),
+            None => Value::Null,
+        }
     }
 
+    // Every record of the group is counted, as for count(*)
     fn get_symbol(&self) -> &str {
-        &self.symbol
+        "*"
+    }
+
+    fn state(&self) -> serde_json::Value {
+        let span = self.span.map(|(first, last)| serde_json::Value::from(vec![first, last])).unwrap_or(serde_json::Value::Null);
+        state_object(vec![("count", serde_json::Value::from(self.count)), ("span", span)])
+    }
+
+    fn merge_state(&mut self, state: &serde_json::Value) -> Option<()> {
+        self.count += state_u64(state, "count")?;
+        let span = state.get("span")?;
+        if !span.is_null() {
+            let (first, last) = (span.get(0)?.as_i64()?, span.get(1)?.as_i64()?);
+            self.span =
+                match self.span {
+                    Some((f, l)) => Some((f.min(first), l.max(last))),
+                    None => Some((first, last)),
+                };
+        }
+        Some(())
     }
 }
 
@@ -558,107 +2743,254 @@ impl<T> ResultsPrinter<T> {
     }
 }
 
+// Columns of sizes in bytes are named so by every log format, e.g. bytes, body_bytes_sent, object_size
+// or request_length.
+fn holds_bytes(column: &str) -> bool {
+    column.contains("bytes") || column == "size" || column.ends_with("_size") || column.ends_with("_length")
+}
+
 struct RecordFormatter<T> {
     fields: Vec<Box<OutputField<T>>>,
-    sort: Option<(Box<OutputField<T>>,QuerySortOrdering)>,
+    // Output names of the fields, their aliases when given one. Fields past the names are only
+    // sorted on.
+    names: Vec<String>,
+    // Indexes of the fields sorted on, in order of precedence
+    sort: Vec<(usize, QuerySortOrdering)>,
+    // Indexes of the group column fields, in the order of the grouping
+    groups: Vec<usize>,
+    // Indexes of the fields holding numbers of bytes
+    bytes: Vec<usize>,
+    having: Option<QueryFilter>,
+    renderer: Box<ResultRenderer>,
 }
 
 impl<T> RecordFormatter<T> {
 
-    pub fn new(query: &RipLogQuery, definition: &TableDefinition<T>) -> RecordFormatter<T> {
+    pub fn new(query: &RipLogQuery, definition: &TableDefinition<T>, renderer: Box<ResultRenderer>) -> RecordFormatter<T> {
         let mut fields: Vec<Box<OutputField<T>>> = Vec::new();
-        let mut sort: Option<(Box<OutputField<T>>,QuerySortOrdering)> = None;
-        let sort_value = query.sort.as_ref().and_then(|e| e.sortings.first().clone());
+        let mut groups: Vec<usize> = Vec::new();
+        let mut bytes: Vec<usize> = Vec::new();
         for element in &query.computed_show.as_ref().unwrap().elements {
             match element {
                 QueryShowElement::Symbol(symbol) => {
+                    if holds_bytes(symbol) {
+                        bytes.push(fields.len());
+                    }
                     let group_idx = get_group_idx(&symbol, query);
-                    let size = definition.column_map.get(symbol).map(|d| d.get_size().clone()).unwrap_or(10);
+                    let bucket = group_idx.map(|idx| query.grouping.as_ref().unwrap().groupings[idx].is_bucket()).unwrap_or(false);
+                    let size = if bucket { 26 } else { definition.column_map.get(symbol).map(|d| d.get_size().clone()).unwrap_or(10) };
                     if group_idx.is_some() {
-                        let field: Box<OutputField<T>> = Box::new(GroupOutputField { symbol: symbol.clone(), idx: group_idx.unwrap(), size: size });
-                        if sort_value.is_some() && sort_value.unwrap().field == field.name() {
-                            sort = Some((Box::new(GroupOutputField { symbol: symbol.clone(), idx: group_idx.unwrap(), size: size }), sort_value.unwrap().order.clone()));
-                        }
-                        fields.push(field);
+                        groups.push(fields.len());
+                        fields.push(Box::new(GroupOutputField { symbol: symbol.clone(), idx: group_idx.unwrap(), size: size }));
                     } else {
                         fields.push(Box::new(SymbolOutputField { symbol: symbol.clone(), size: size }));
                     }
                 },
                 QueryShowElement::Reducer(reducer, symbol) => {
                     let reduce_idx = get_reduce_idx(&symbol, &reducer, query);
+                    // Dates are as wide as the column they are taken from
+                    let size =
+                        match (reducer, definition.column_map.get(symbol)) {
+                            (QueryReducer::Min, Some(ColumnDefinition::Date { size, .. })) |
+                            (QueryReducer::Max, Some(ColumnDefinition::Date { size, .. })) |
+                            (QueryReducer::First, Some(ColumnDefinition::Date { size, .. })) |
+                            (QueryReducer::Last, Some(ColumnDefinition::Date { size, .. })) => *size,
+                            _ => 10,
+                        };
                     if reduce_idx.is_some() {
-                        let field: Box<OutputField<T>> = Box::new(ReducedOutputField { reducer: reducer.to_string().to_owned(), symbol: symbol.clone(), idx: reduce_idx.unwrap(), size: 10 });
-                        if sort_value.is_some() && sort_value.unwrap().field == field.name() {
-                            sort = Some((Box::new(ReducedOutputField { reducer: reducer.to_string().to_owned(), symbol: symbol.clone(), idx: reduce_idx.unwrap(), size: 10 }), sort_value.unwrap().order.clone()));
+                        match reducer {
+                            QueryReducer::Sum | QueryReducer::Avg | QueryReducer::Min | QueryReducer::Max |
+                            QueryReducer::First | QueryReducer::Last | QueryReducer::Stddev | QueryReducer::Percentile(_) if holds_bytes(symbol) => bytes.push(fields.len()),
+                            _ => (),
                         }
-                        fields.push(field);
+                        fields.push(Box::new(ReducedOutputField { name: reducer.column_name(&symbol), idx: reduce_idx.unwrap(), size: size }));
                     }
-                }
+                },
+                QueryShowElement::Expression(expression) => {
+                    fields.push(Box::new(create_expression_field(expression, query)));
+                },
                 _ => ()
             }
         }
 
-        RecordFormatter { fields: fields, sort: sort }
+        let show = query.computed_show.as_ref().unwrap();
+        let names = fields.iter().map(|f| show.display_name(&f.name())).collect();
+
+        // 'sort group' (or 'sort key') sorts on every group column, unless a column has that name.
+        // Reducers, and columns of queries that aren't aggregated, sorted on without being shown get a
+        // field of their own, which isn't rendered.
+        let mut sort = Vec::new();
+        for sorting in query.sort.iter().flat_map(|s| s.sortings.iter()) {
+            match fields.iter().position(|f| f.name() == sorting.field) {
+                Some(idx) => sort.push((idx, sorting.order.clone())),
+                None if sorting.field == "group" || sorting.field == "key" =>
+                    sort.extend(groups.iter().map(|&idx| (idx, sorting.order.clone()))),
+                None => {
+                    let reduce_idx = parse_reducer_name(&sorting.field).and_then(|(reducer, symbol)| get_reduce_idx(&symbol, &reducer, query));
+                    if let Some(idx) = reduce_idx {
+                        sort.push((fields.len(), sorting.order.clone()));
+                        fields.push(Box::new(ReducedOutputField { name: sorting.field.to_owned(), idx: idx, size: 10 }));
+                    } else if !is_aggregate_query(query) && definition.column_map.contains_key(&sorting.field) {
+                        sort.push((fields.len(), sorting.order.clone()));
+                        fields.push(Box::new(SymbolOutputField { symbol: sorting.field.to_owned(), size: 10 }));
+                    }
+                },
+            }
+        }
+
+        let having = query.having.as_ref().map(|h| h.filter.clone());
+        RecordFormatter { fields: fields, names: names, sort: sort, groups: groups, bytes: bytes, having: having, renderer: renderer }
+    }
+
+    // Whether an aggregated row passes the query's having filter.
+    pub fn matches_having(&self, key: Option<&GroupKey>, reducer: &Reducer<T>) -> bool {
+        match self.having {
+            Some(ref filter) => self.evaluate_having(filter, key, reducer),
+            None => true,
+        }
     }
 
-    pub fn sort_grouped(&self, key1: &Vec<String>, reducer1: &Reducer<T>, key2: &Vec<String>, reducer2: &Reducer<T>) -> Ordering {
-        match self.sort {
-            Some((ref field, QuerySortOrdering::ASC)) => {
-                field.compare(None, Some(key1), Some(reducer1), None, Some(key2), Some(reducer2), false)
+    fn evaluate_having(&self, filter: &QueryFilter, key: Option<&GroupKey>, reducer: &Reducer<T>) -> bool {
+        match filter {
+            QueryFilter::BinaryOpFilter(operand1, QueryValue::Regex(regex, _), op) => {
+                let matched = match self.having_value(operand1, key, reducer) {
+                    Value::Null => false,
+                    value => regex.is_match(&value.to_display_string()),
+                };
+                match op {
+                    QueryFilterBinaryOp::Nr => !matched,
+                    _ => matched,
+                }
+            },
+            QueryFilter::BinaryOpFilter(operand1, operand2, op) => {
+                let value1 = self.having_value(operand1, key, reducer);
+                let value2 = self.having_value(operand2, key, reducer);
+                let order = value1.compare(&value2);
+                match op {
+                    QueryFilterBinaryOp::Lt => order == Some(Ordering::Less),
+                    QueryFilterBinaryOp::Gt => order == Some(Ordering::Greater),
+                    QueryFilterBinaryOp::Le => order == Some(Ordering::Less) || order == Some(Ordering::Equal),
+                    QueryFilterBinaryOp::Ge => order == Some(Ordering::Greater) || order == Some(Ordering::Equal),
+                    QueryFilterBinaryOp::Eq => order == Some(Ordering::Equal),
+                    QueryFilterBinaryOp::Ne => order != Some(Ordering::Equal),
+                    QueryFilterBinaryOp::Re => value1.to_display_string().contains(&value2.to_display_string()),
+                    QueryFilterBinaryOp::Nr => !value1.to_display_string().contains(&value2.to_display_string()),
+                }
+            },
+            QueryFilter::InFilter(operand, values, negated) => {
+                let value = self.having_value(operand, key, reducer);
+                values.iter().any(|v| value.compare(&self.having_value(v, key, reducer)) == Some(Ordering::Equal)) != *negated
             },
-            Some((ref field, QuerySortOrdering::DESC)) => {
-                field.compare(None, Some(key1), Some(reducer1), None, Some(key2), Some(reducer2), true)
+            QueryFilter::CidrFilter(operand, ranges, negated) => {
+                let address = match self.having_value(operand, key, reducer) {
+                    Value::Text(text) => text.parse::<IpAddr>().ok(),
+                    _ => None,
+                };
+                address.map(|a| ranges.contains(&a)).unwrap_or(false) != *negated
             },
-            _ => Ordering::Equal
+            QueryFilter::AndFilter(filter1, filter2) =>
+                self.evaluate_having(filter1, key, reducer) && self.evaluate_having(filter2, key, reducer),
+            QueryFilter::OrFilter(filter1, filter2) =>
+                self.evaluate_having(filter1, key, reducer) || self.evaluate_having(filter2, key, reducer),
         }
     }
 
-    pub fn sortable(&self) -> bool {
-        self.sort.is_some()
+    fn having_value(&self, operand: &QueryValue, key: Option<&GroupKey>, reducer: &Reducer<T>) -> Value {
+        match operand {
+            QueryValue::Symbol(symbol) =>
+                self.fields.iter().find(|f| &f.name() == symbol).map(|f| f.value(None, key, Some(reducer))).unwrap_or(Value::Null),
+            QueryValue::Text(text, _) => Value::Text(text.to_owned()),
+            QueryValue::Int(int, _) => Value::Float(*int as f64),
+            QueryValue::Double(double, _) => Value::Float(*double),
+            QueryValue::Boolean(boolean) => Value::Bool(*boolean),
+            QueryValue::Date(date) => Value::Date(date.clone()),
+            QueryValue::Expression(expression) =>
+                evaluate_expression(expression, &mut |operand| self.having_value(&QueryValue::Symbol(operand.name()), key, reducer)),
+            QueryValue::Regex(_, _) | QueryValue::Null => Value::Null,
+        }
     }
-    
-    pub fn format_record(&mut self, record: &mut Record<T>) {
-        print!("|");
-        for field in &mut self.fields {
-            print!("{}|", field.format_field(Some(record), None, None));
+
+    // Ties, and every group when the query has no sort, are ordered by the group columns so that
+    // results are the same from one run to the next.
+    pub fn sort_grouped(&self, key1: &GroupKey, reducer1: &Reducer<T>, key2: &GroupKey, reducer2: &Reducer<T>) -> Ordering {
+        let groups = self.groups.iter().map(|&idx| (idx, QuerySortOrdering::ASC));
+        for (idx, order) in self.sort.iter().cloned().chain(groups) {
+            let desc = match order { QuerySortOrdering::DESC => true, QuerySortOrdering::ASC => false };
+            let ordering = self.fields[idx].compare(None, Some(key1), Some(reducer1), None, Some(key2), Some(reducer2), desc);
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
         }
-        println!("");
+        Ordering::Equal
+    }
+
+    pub fn format_record(&mut self, record: &mut Record<T>) {
+        let values = self.fields.iter().take(self.names.len()).map(|f| f.value(Some(record), None, None)).collect();
+        output::check_written(self.renderer.render_row(&values));
+    }
+
+    // A result row as name=value pairs, e.g. for an alert about it.
+    pub fn describe_row(&self, key: Option<&GroupKey>, reducer: &Reducer<T>) -> String {
+        self.fields.iter().zip(&self.names)
+            .map(|(f, name)| format!("{}={}", name, f.value(None, key, Some(reducer)).to_display_string()))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    pub fn format_grouped_record(&mut self, key: &GroupKey, reducer: &Reducer<T>) {
+        let values = self.fields.iter().take(self.names.len()).map(|f| f.value(None, Some(key), Some(reducer))).collect();
+        output::check_written(self.renderer.render_row(&values));
+    }
+
+    pub fn is_sorted(&self) -> bool {
+        !self.sort.is_empty()
+    }
+
+    // Values of every field of a record, including those only sorted on, for rows sorted once every
+    // line is read.
+    pub fn record_values(&self, record: &mut Record<T>) -> Vec<Value> {
+        self.fields.iter().map(|f| f.value(Some(record), None, None)).collect()
     }
 
-    pub fn format_grouped_record(&mut self, key: &Vec<String>, reducer: &Reducer<T>) {
-        print!("|");
-        for field in &mut self.fields {
-            print!("{}|", field.format_field(None, Some(key), Some(reducer)));
+    pub fn sort_rows(&self, values1: &Vec<Value>, values2: &Vec<Value>) -> Ordering {
+        for &(idx, ref order) in &self.sort {
+            let desc = match order { QuerySortOrdering::DESC => true, QuerySortOrdering::ASC => false };
+            let ordering = compare_sorted_values(&values1[idx], &values2[idx], desc);
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
         }
-        println!("");
+        Ordering::Equal
+    }
+
+    // Values past the names are only sorted on and aren't rendered.
+    pub fn format_values(&mut self, mut values: Vec<Value>) {
+        values.truncate(self.names.len());
+        output::check_written(self.renderer.render_row(&values));
     }
 
     pub fn format_reduced_record(&mut self, reducer: &Reducer<T>) {
-        print!("|");
-        for field in &mut self.fields {
-            print!("{}|", field.format_field(None, None, Some(reducer)));
-        }
-        println!("");
+        let values = self.fields.iter().take(self.names.len()).map(|f| f.value(None, None, Some(reducer))).collect();
+        output::check_written(self.renderer.render_row(&values));
     }
 
     pub fn format_header_row(&mut self) {
-        let mut header_row = "|".to_owned();
-        for field in &mut self.fields {
-            header_row += &format!("{}|", field.header());
-        }
-        let pad = (0..header_row.len()-2).map(|_| "-").collect::<String>();
-        println!("+{}+", pad);
-        println!("{}", header_row);
-        println!("|{}|", pad);
+        let columns = self.fields.iter().zip(&self.names).enumerate()
+            .map(|(idx, (f, name))| OutputColumn { name: name.to_owned(), size: f.size(), group: self.groups.contains(&idx), bytes: self.bytes.contains(&idx) })
+            .collect();
+        output::check_written(self.renderer.render_header(&columns));
     }
 
     pub fn format_closing_row(&mut self) {
-        let mut len = 1;
-        for field in &mut self.fields {
-            len += field.size()+3
-        }
-        let pad = (0..len-2).map(|_| "-").collect::<String>();
-        println!("+{}+", pad);
+        output::check_written(self.renderer.render_footer());
+    }
+
+    pub fn format_label(&mut self, label: &str) {
+        output::check_written(self.renderer.render_label(label));
+    }
+
+    pub fn flush(&mut self) {
+        output::check_written(self.renderer.flush());
     }
 }
 
@@ -668,7 +3000,7 @@ fn get_group_idx(symbol: &str, query: &RipLogQuery) -> Option<usize> {
         let mut idx = 0;
         let mut found_idx: Option<usize> = None;
         for group in &query.grouping.as_ref().unwrap().groupings {
-            if group == symbol {
+            if group.name() == symbol {
                 found_idx = Some(idx);
                 break;
             }
@@ -680,36 +3012,17 @@ fn get_group_idx(symbol: &str, query: &RipLogQuery) -> Option<usize> {
     }
 }
 
-// TODO: better way to line up indexes
 fn get_reduce_idx(symbol: &str, reducer: &QueryReducer, query: &RipLogQuery) -> Option<usize> {
-    if query.computed_show.is_some() {
-        let mut idx = 0;
-        let mut found_idx: Option<usize> = None;
-        for element in query.computed_show.as_ref().unwrap().elements.iter().filter(|e| e.is_reducer()) {
-            match element {
-                QueryShowElement::Reducer(curr_reducer, curr_symbol) => {
-                    if curr_reducer.to_string() == reducer.to_string() && (symbol == "*" || curr_symbol == symbol) {
-                        found_idx = Some(idx);
-                        break;
-                    }
-                },
-                _ => ()
-            }
-            idx += 1;
-        }
-        found_idx
-    } else {
-        None
-    }
+    query_reducers(query).iter().position(|&(ref curr_reducer, ref curr_symbol)| curr_reducer == reducer && (symbol == "*" || curr_symbol == symbol))
 }
 
+// Extracts the typed value of a single output column, leaving the rendering to a ResultRenderer.
 trait OutputField<T> {
     fn name(&self) -> String;
-    fn header(&mut self) -> String;
-    fn format_field(&mut self, record: Option<&mut Record<T>>, group_key: Option<&Vec<String>>, reducer: Option<&Reducer<T>>) -> String;
+    fn value(&self, record: Option<&mut Record<T>>, group_key: Option<&GroupKey>, reducer: Option<&Reducer<T>>) -> Value;
     fn size(&self) -> usize;
-    fn compare(&self, record1: Option<&mut Record<T>>, group_key1: Option<&Vec<String>>, reducer1: Option<&Reducer<T>>,
-                      record2: Option<&mut Record<T>>, group_key2: Option<&Vec<String>>, reducer2: Option<&Reducer<T>>, asc: bool) -> Ordering;
+    fn compare(&self, record1: Option<&mut Record<T>>, group_key1: Option<&GroupKey>, reducer1: Option<&Reducer<T>>,
+                      record2: Option<&mut Record<T>>, group_key2: Option<&GroupKey>, reducer2: Option<&Reducer<T>>, asc: bool) -> Ordering;
 }
 
 struct SymbolOutputField {
@@ -722,29 +3035,78 @@ impl<T> OutputField<T> for SymbolOutputField {
         self.symbol.clone()
     }
 
-    fn header(&mut self) -> String {
-        if self.size < self.symbol.len() {
-            self.size = self.symbol.len();
+    fn value(&self, record: Option<&mut Record<T>>, group_key: Option<&GroupKey>, reducer: Option<&Reducer<T>>) -> Value {
+        if record.is_some() {
+            record.unwrap().get_symbol_value(&self.symbol)
+        } else {
+            Value::Null
         }
-        format!(" {:width$} ", self.symbol, width = self.size)
     }
 
-    fn format_field(&mut self, record: Option<&mut Record<T>>, group_key: Option<&Vec<String>>, reducer: Option<&Reducer<T>>) -> String {
-        let output =
-            if record.is_some() {
-                record.unwrap().get_symbol_as_string(&self.symbol).unwrap_or("null".to_owned())
-            } else {
-                "null".to_owned()
-            };
-        if self.size < output.len() && self.size < 50 {
-            self.size = output.len();
+    fn compare(&self, record1: Option<&mut Record<T>>, group_key1: Option<&GroupKey>, reducer1: Option<&Reducer<T>>,
+               record2: Option<&mut Record<T>>, group_key2: Option<&GroupKey>, reducer2: Option<&Reducer<T>>, desc: bool) -> Ordering {
+        Ordering::Equal
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+}
+
+// Value of an expression evaluated against each record, e.g. lower(path), or against the aggregated
+// rows of a query, e.g. sum(bytes)/1024, where its columns are group columns.
+#[derive(Clone)]
+struct ExpressionOutputField {
+    expression: QueryExpression,
+    // Indexes of the reducers used by the expression, by column name
+    reducers: HashMap<String, usize>,
+    // Indexes of the group columns, by name
+    groups: HashMap<String, usize>,
+    size: usize,
+}
+
+fn create_expression_field(expression: &QueryExpression, query: &RipLogQuery) -> ExpressionOutputField {
+    let mut reducers = Vec::new();
+    expression.reducers(&mut reducers);
+    let reducer_idxs = reducers.iter()
+        .filter_map(|&(ref reducer, ref symbol)| get_reduce_idx(symbol, reducer, query).map(|idx| (reducer.column_name(symbol), idx)))
+        .collect();
+    let mut groups = HashMap::new();
+    if let Some(ref grouping) = query.grouping {
+        for (idx, group) in grouping.groupings.iter().enumerate() {
+            groups.insert(group.name(), idx);
         }
-        format!(" {:width$} ", output, width = self.size)
     }
+    ExpressionOutputField { expression: expression.clone(), reducers: reducer_idxs, groups: groups, size: 20 }
+}
 
-    fn compare(&self, record1: Option<&mut Record<T>>, group_key1: Option<&Vec<String>>, reducer1: Option<&Reducer<T>>,
-               record2: Option<&mut Record<T>>, group_key2: Option<&Vec<String>>, reducer2: Option<&Reducer<T>>, desc: bool) -> Ordering {
-        Ordering::Equal
+impl<T> OutputField<T> for ExpressionOutputField {
+    fn name(&self) -> String {
+        self.expression.name()
+    }
+
+    fn value(&self, record: Option<&mut Record<T>>, group_key: Option<&GroupKey>, reducer: Option<&Reducer<T>>) -> Value {
+        match record {
+            Some(record) => record.evaluate_expression(&self.expression),
+            None => evaluate_expression(&self.expression, &mut |operand| {
+                let value =
+                    match operand {
+                        QueryExpression::Reducer(_, _) =>
+                            self.reducers.get(&operand.name()).and_then(|idx| reducer.and_then(|r| r.field_reducers.get(*idx))).map(|r| r.value()),
+                        QueryExpression::Symbol(symbol) =>
+                            self.groups.get(symbol).and_then(|idx| group_key.and_then(|k| k.get(*idx))).cloned(),
+                        _ => None,
+                    };
+                value.unwrap_or(Value::Null)
+            }),
+        }
+    }
+
+    fn compare(&self, record1: Option<&mut Record<T>>, group_key1: Option<&GroupKey>, reducer1: Option<&Reducer<T>>,
+               record2: Option<&mut Record<T>>, group_key2: Option<&GroupKey>, reducer2: Option<&Reducer<T>>, desc: bool) -> Ordering {
+        let value1 = self.value(record1, group_key1, reducer1);
+        let value2 = self.value(record2, group_key2, reducer2);
+        compare_sorted_values(&value1, &value2, desc)
     }
 
     fn size(&self) -> usize {
@@ -763,64 +3125,15 @@ impl<T> OutputField<T> for GroupOutputField {
         self.symbol.clone()
     }
 
-    fn header(&mut self) -> String {
-        if self.size < self.symbol.len() {
-            self.size = self.symbol.len();
-        }
-        format!(" {:width$} ", self.symbol, width = self.size)
-    }
-
-    fn format_field(&mut self, record: Option<&mut Record<T>>, group_key: Option<&Vec<String>>, reducer: Option<&Reducer<T>>) -> String {
-        let output =
-            if group_key.is_some() && group_key.unwrap().len() >= (self.idx+1) {
-                group_key.unwrap()[self.idx].clone()
-            } else {
-                "null".to_owned()
-            };
-        if self.size < output.len() && self.size < 50 {
-            self.size = output.len();
-        }
-        format!(" {:width$} ", output, width = self.size)
+    fn value(&self, record: Option<&mut Record<T>>, group_key: Option<&GroupKey>, reducer: Option<&Reducer<T>>) -> Value {
+        group_key.and_then(|k| k.get(self.idx)).cloned().unwrap_or(Value::Null)
     }
 
-    fn compare(&self, record1: Option<&mut Record<T>>, group_key1: Option<&Vec<String>>, reducer1: Option<&Reducer<T>>,
-               record2: Option<&mut Record<T>>, group_key2: Option<&Vec<String>>, reducer2: Option<&Reducer<T>>, desc: bool) -> Ordering {
-        let value1 = 
-            if group_key1.is_some() && group_key1.unwrap().len() >= (self.idx+1) {
-                Some(&group_key1.unwrap()[self.idx])
-            } else {
-                None
-            };
-
-        let value2 = 
-            if group_key2.is_some() && group_key2.unwrap().len() >= (self.idx+1) {
-                Some(&group_key2.unwrap()[self.idx])
-            } else {
-                None
-            };
-
-        if value1.is_some() && value2.is_some() {
-            let order = value1.unwrap().cmp(&value2.unwrap());
-            if desc {
-                order.reverse()
-            }  else {
-                order
-            }
-        } else if value1.is_some() {
-            if desc {
-                Ordering::Less
-            } else {
-                Ordering::Greater
-            }
-        } else if value2.is_some() {
-            if desc {
-                Ordering::Greater
-            } else {
-                Ordering::Less
-            }
-        } else {
-            Ordering::Equal
-        }
+    fn compare(&self, record1: Option<&mut Record<T>>, group_key1: Option<&GroupKey>, reducer1: Option<&Reducer<T>>,
+               record2: Option<&mut Record<T>>, group_key2: Option<&GroupKey>, reducer2: Option<&Reducer<T>>, desc: bool) -> Ordering {
+        let value1 = group_key1.and_then(|k| k.get(self.idx)).unwrap_or(&Value::Null);
+        let value2 = group_key2.and_then(|k| k.get(self.idx)).unwrap_or(&Value::Null);
+        compare_sorted_values(value1, value2, desc)
     }
 
     fn size(&self) -> usize {
@@ -828,77 +3141,43 @@ impl<T> OutputField<T> for GroupOutputField {
     }
 }
 
+// Nulls sort first, and last when descending. Values that can't be compared, e.g. the values counted
+// by top(), are left in place.
+fn compare_sorted_values(value1: &Value, value2: &Value, desc: bool) -> Ordering {
+    let order =
+        match (value1, value2) {
+            (Value::Null, Value::Null) => Ordering::Equal,
+            (Value::Null, _) => Ordering::Less,
+            (_, Value::Null) => Ordering::Greater,
+            _ => value1.compare(value2).unwrap_or(Ordering::Equal),
+        };
+    if desc { order.reverse() } else { order }
+}
+
 struct ReducedOutputField {
-    reducer: String,
-    symbol: String,
+    name: String,
     idx: usize,
     size: usize,
 }
 
 impl<T> OutputField<T> for ReducedOutputField {
     fn name(&self) -> String {
-        format!("{}({})", self.reducer, self.symbol)
+        self.name.clone()
     }
     
-    fn header(&mut self) -> String {
-        let name = format!("{}({})", self.reducer, self.symbol);
-        if self.size < name.len() {
-            self.size = name.len();
-        }
-        format!(" {:width$} ", name, width = self.size)
-    }
-
-    fn format_field(&mut self, record: Option<&mut Record<T>>, group_key: Option<&Vec<String>>, reducer: Option<&Reducer<T>>) -> String {
-        let output =
-            if reducer.is_some() && reducer.unwrap().field_reducers.len() >= (self.idx+1) {
-                reducer.unwrap().field_reducers[self.idx].result().to_string()
-            } else {
-                "null".to_owned()
-            };
-        if self.size < output.len() && self.size < 50 {
-            self.size = output.len();
+    fn value(&self, record: Option<&mut Record<T>>, group_key: Option<&GroupKey>, reducer: Option<&Reducer<T>>) -> Value {
+        if reducer.is_some() && reducer.unwrap().field_reducers.len() >= (self.idx+1) {
+            reducer.unwrap().field_reducers[self.idx].value()
+        } else {
+            Value::Null
         }
-        format!(" {:width$} ", output, width = self.size)
     }
 
-    fn compare(&self, record1: Option<&mut Record<T>>, group_key1: Option<&Vec<String>>, reducer1: Option<&Reducer<T>>,
-               record2: Option<&mut Record<T>>, group_key2: Option<&Vec<String>>, reducer2: Option<&Reducer<T>>, desc: bool) -> Ordering {
-        let value1 = 
-            if reducer1.is_some() && reducer1.unwrap().field_reducers.len() >= (self.idx+1) {
-                Some(reducer1.unwrap().field_reducers[self.idx].result())
-            } else {
-                None
-            };
-
-        let value2 = 
-            if reducer2.is_some() && reducer2.unwrap().field_reducers.len() >= (self.idx+1) {
-                Some(reducer2.unwrap().field_reducers[self.idx].result())
-            } else {
-                None
-            };
-
-        if value1.is_some() && value2.is_some() {
-            let order = value1.unwrap().cmp(&value2.unwrap());
-            if desc {
-                order.reverse()
-            }  else {
-                order
-            }
-        } else if value1.is_some() {
-            if desc {
-                Ordering::Less
-            } else {
-                Ordering::Greater
-            }
-        } else if value2.is_some() {
-            if desc {
-                Ordering::Greater
-            } else {
-                Ordering::Less
-            }
-        } else {
-            Ordering::Equal
-        }
+    fn compare(&self, record1: Option<&mut Record<T>>, group_key1: Option<&GroupKey>, reducer1: Option<&Reducer<T>>,
+               record2: Option<&mut Record<T>>, group_key2: Option<&GroupKey>, reducer2: Option<&Reducer<T>>, desc: bool) -> Ordering {
+        let value1 = self.value(record1, group_key1, reducer1);
+        let value2 = self.value(record2, group_key2, reducer2);
+        compare_sorted_values(&value1, &value2, desc)
     }
 
     fn size(&self) -> usize {
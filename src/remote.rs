@@ -0,0 +1,199 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+
+use discovery::{DiscoveredFiles, FileSelector};
+use format::LogFormat;
+use index::INDEX_DIR;
+use input;
+use parser::{QueryGrouping, QueryGroupingElement, QueryShowElement, RipLogQuery};
+use table::{self, ColumnDefinition, TableDefinition, WrappedRecord};
+
+// Logs on other hosts are queried over ssh, e.g. with `--ssh web1,web2`. Their files are listed and
+// streamed back by the remote shell as they are stored, compressed or not, and parsed and evaluated
+// here, so hosts need nothing more than ssh, find and cat. The ssh command can be replaced, e.g. with
+// options of its own, through RIPLOG_SSH.
+
+const HOST_COLUMN: &str = "host";
+// Name of the host column for formats that have a host column of their own, e.g. syslog
+const REMOTE_HOST_COLUMN: &str = "remote_host";
+
+// Lists each path given, the files of directories and of directories matched by globs being marked
+// to be selected by name: d for those, f for files named directly and m for paths matching nothing.
+const LIST_FUNCTION: &str = "riplog_list() { for p in \"$@\"; do \
+                             if [ -d \"$p\" ]; then find -L \"$p\" -name INDEX_DIR -prune -o -type f -print | sed 's/^/d /'; \
+                             elif [ -e \"$p\" ]; then printf 'f %s\\n' \"$p\"; \
+                             else printf 'm %s\\n' \"$p\"; fi; done; }";
+
+// Finds the files of the paths on a host, selected as discover_files selects local files. Globs are
+// expanded by the remote shell, so they are left unquoted.
+pub fn discover_files(host: &str, paths: &Vec<PathBuf>, selector: &FileSelector) -> Result<DiscoveredFiles, String> {
+    let arguments: Vec<String> = paths.iter().map(|p| {
+        let path = p.to_string_lossy();
+        if is_glob(&path) { path.to_string() } else { shell_quote(&path) }
+    }).collect();
+    let script = format!("{}; riplog_list {}", LIST_FUNCTION.replace("INDEX_DIR", INDEX_DIR), arguments.join(" "));
+    let output = ssh_command(host, &script).stdin(Stdio::null()).stderr(Stdio::piped()).output()
+        .map_err(|e| format!("Unable to run ssh: {}", e))?;
+    if !output.status.success() {
+        let error = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+        return Err(format!("Unable to list files on {}: {}", host, if error.is_empty() { format!("ssh exited with {}", output.status) } else { error }));
+    }
+    let mut discovered = DiscoveredFiles::default();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let (mark, path) = (&line[0..1.min(line.len())], PathBuf::from(line.get(2..).unwrap_or("")));
+        match mark {
+            "d" if selector.is_selected(&path) => discovered.files.push(path),
+            "f" if !selector.is_excluded(&path) => discovered.files.push(path),
+            "d" | "f" => discovered.skipped += 1,
+            _ if is_glob(&path.to_string_lossy()) => return Err(format!("No files match '{}' on {}", path.display(), host)),
+            _ => return Err(format!("No such file or directory '{}' on {}", path.display(), host)),
+        }
+    }
+    Ok(discovered)
+}
+
+// Streams a file of a host, decompressed here when it's stored compressed, along with a counter of
+// the bytes received.
+pub fn open_remote_input(host: &str, file: &Path) -> io::Result<(Box<BufRead>, Rc<Cell<u64>>)> {
+    let command = ssh_command(host, &format!("cat -- {}", shell_quote(&file.to_string_lossy())));
+    let name = PathBuf::from(format!("{}:{}", host, file.display()));
+    input::open_command_input(command, &format!("ssh to {}", host), &name)
+}
+
+fn ssh_command(host: &str, script: &str) -> Command {
+    let ssh = env::var("RIPLOG_SSH").ok().filter(|s| !s.trim().is_empty()).unwrap_or("ssh -o BatchMode=yes -C".to_owned());
+    let mut words = ssh.split_whitespace();
+    let mut command = Command::new(words.next().unwrap());
+    command.args(words).arg(host).arg(script);
+    command
+}
+
+fn is_glob(path: &str) -> bool {
+    path.contains(|c| c == '*' || c == '?' || c == '[')
+}
+
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+// Rows are told apart by the host they come from: grouped queries are grouped by host first, other
+// aggregates by host alone, and lines shown start with their host.
+pub fn add_host_column(query: &mut RipLogQuery, column: &str) {
+    let aggregate = query.show.as_ref().map(|s| s.elements.iter().any(|e| e.is_aggregate())).unwrap_or(false);
+    if let Some(ref mut grouping) = query.grouping {
+        if !grouping.groupings.iter().any(|g| g.name() == column) {
+            grouping.groupings.insert(0, QueryGroupingElement::Symbol(column.to_owned()));
+        }
+    } else if aggregate {
+        query.grouping = Some(QueryGrouping { groupings: vec![QueryGroupingElement::Symbol(column.to_owned())] });
+    } else if let Some(ref mut show) = query.show {
+        let shown = show.elements.iter().any(|e| match e { QueryShowElement::Symbol(symbol) => symbol == column, e => e.is_star() });
+        if !shown {
+            show.elements.insert(0, QueryShowElement::Symbol(column.to_owned()));
+        }
+    }
+}
+
+// Adds the host each record was read from as the first column. Hosts are read one after the other,
+// records made while a host is read being of that host.
+pub struct HostFormat<F: LogFormat> {
+    format: F,
+    columns: Rc<Vec<ColumnDefinition<F::Record>>>,
+    names: Vec<String>,
+    column: String,
+    host: RefCell<Rc<String>>,
+}
+
+#[derive(Clone)]
+pub struct HostRecord<R> {
+    record: R,
+    columns: Rc<Vec<ColumnDefinition<R>>>,
+    host: Rc<String>,
+}
+
+impl<F: LogFormat> HostFormat<F> {
+    pub fn new(format: F) -> HostFormat<F> {
+        let mut definition = format.table_definition();
+        let column = if definition.column_map.contains_key(HOST_COLUMN) { REMOTE_HOST_COLUMN } else { HOST_COLUMN };
+        let names = definition.ordered_columns.clone();
+        let columns = names.iter().map(|n| definition.column_map.remove(n).unwrap()).collect();
+        HostFormat { format: format, columns: Rc::new(columns), names: names, column: column.to_owned(), host: RefCell::new(Rc::new(String::new())) }
+    }
+
+    pub fn host_column(&self) -> &str {
+        &self.column
+    }
+
+    pub fn read_host(&self, host: &str) {
+        *self.host.borrow_mut() = Rc::new(host.to_owned());
+    }
+}
+
+impl<R> WrappedRecord for HostRecord<R> {
+    type Inner = R;
+
+    fn inner(&self) -> &R {
+        &self.record
+    }
+
+    fn inner_mut(&mut self) -> &mut R {
+        &mut self.record
+    }
+
+    fn inner_columns(&self) -> &Vec<ColumnDefinition<R>> {
+        &self.columns
+    }
+}
+
+impl<F: LogFormat> LogFormat for HostFormat<F> {
+    type Record = HostRecord<F::Record>;
+
+    // Records keep their host as lines are read into them
+    fn empty_record(&self) -> HostRecord<F::Record> {
+        HostRecord { record: self.format.empty_record(), columns: self.columns.clone(), host: self.host.borrow().clone() }
+    }
+
+    fn read_record(&self, line: &[u8], record: &mut HostRecord<F::Record>) -> bool {
+        self.format.read_record(line, &mut record.record)
+    }
+
+    fn project_record(&self, columns: &Vec<String>, record: &mut HostRecord<F::Record>) {
+        self.format.project_record(columns, &mut record.record);
+    }
+
+    fn table_definition(&self) -> TableDefinition<HostRecord<F::Record>> {
+        let mut column_map = HashMap::new();
+        let mut ordered_columns = vec![self.column.to_owned()];
+        column_map.insert(self.column.to_owned(), ColumnDefinition::Text { name: self.column.to_owned(), size: 20, field: 0, binary_extractor: host_bytes, extractor: host_text });
+        for (idx, column) in self.columns.iter().enumerate() {
+            column_map.insert(self.names[idx].to_owned(), table::wrap_column(column, idx));
+            ordered_columns.push(self.names[idx].to_owned());
+        }
+        TableDefinition { column_map: column_map, ordered_columns: ordered_columns }
+    }
+
+    fn has_header(&self) -> bool {
+        self.format.has_header()
+    }
+
+    fn verbatim_columns(&self) -> Vec<&'static str> {
+        self.format.verbatim_columns()
+    }
+
+    fn is_log_file(file: &Path) -> bool {
+        F::is_log_file(file)
+    }
+}
+
+fn host_bytes<R>(r: &HostRecord<R>, _: usize) -> Option<&[u8]> {
+    Some(r.host.as_bytes()).filter(|h| !h.is_empty())
+}
+
+fn host_text<R>(r: &mut HostRecord<R>, _: usize) -> Option<&str> {
+    Some(r.host.as_str()).filter(|h| !h.is_empty())
+}
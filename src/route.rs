@@ -0,0 +1,89 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use regex::Regex;
+
+// Normalizes request paths into routes by replacing variable path segments with placeholders,
+// e.g. /users/12345/avatar becomes /users/:id/avatar. Custom patterns are tried against each
+// segment before the built-in id, uuid and hash rules.
+#[derive(Debug, Default)]
+pub struct RouteNormalizer {
+    patterns: Vec<(Regex, String)>,
+}
+
+impl RouteNormalizer {
+    // Reads patterns from a file containing one `<regex> <replacement>` pair per line. Regexes must
+    // match a whole path segment, blank lines and lines starting with # are ignored.
+    pub fn from_file(path: &str) -> Result<RouteNormalizer, RouteError> {
+        let file = File::open(Path::new(path)).map_err(|e| RouteError { msg: format!("Unable to open route patterns '{}': {}", path, e) })?;
+        let mut patterns = Vec::new();
+        for (idx, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| RouteError { msg: format!("Unable to read route patterns '{}': {}", path, e) })?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let pattern = parts.next().unwrap();
+            let replacement = parts.next().map(|r| r.trim()).unwrap_or("");
+            if replacement.is_empty() {
+                return Err(RouteError { msg: format!("Missing replacement for route pattern on line {} of '{}'", idx + 1, path) });
+            }
+            let regex = Regex::new(&format!("^(?:{})$", pattern))
+                .map_err(|e| RouteError { msg: format!("Invalid route pattern on line {} of '{}': {}", idx + 1, path, e) })?;
+            patterns.push((regex, replacement.to_owned()));
+        }
+        Ok(RouteNormalizer { patterns: patterns })
+    }
+
+    pub fn normalize(&self, path: &str) -> String {
+        path.split('/').map(|segment| self.normalize_segment(segment)).collect::<Vec<&str>>().join("/")
+    }
+
+    fn normalize_segment<'a>(&'a self, segment: &'a str) -> &'a str {
+        if segment.is_empty() {
+            return segment;
+        }
+        for (regex, replacement) in &self.patterns {
+            if regex.is_match(segment) {
+                return replacement;
+            }
+        }
+        if segment.bytes().all(|b| b.is_ascii_digit()) {
+            ":id"
+        } else if is_uuid(segment) {
+            ":uuid"
+        } else if is_hash(segment) {
+            ":hash"
+        } else {
+            segment
+        }
+    }
+}
+
+fn is_uuid(segment: &str) -> bool {
+    let bytes = segment.as_bytes();
+    bytes.len() == 36 && bytes.iter().enumerate().all(|(idx, b)| {
+        match idx {
+            8 | 13 | 18 | 23 => *b == b'-',
+            _ => b.is_ascii_hexdigit(),
+        }
+    })
+}
+
+// Hex digests (md5, sha1, object ids, ...) are at least 16 characters and contain a digit, so that
+// long words made only of the letters a-f are left alone.
+fn is_hash(segment: &str) -> bool {
+    segment.len() >= 16 && segment.bytes().all(|b| b.is_ascii_hexdigit()) && segment.bytes().any(|b| b.is_ascii_digit())
+}
+
+#[derive(Debug)]
+pub struct RouteError { msg: String }
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
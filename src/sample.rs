@@ -0,0 +1,39 @@
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Which lines are read when sampling: each with a probability, or every nth.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sampling {
+    Rate(f64),
+    Every(u64),
+}
+
+// Picks the lines evaluated when sampling, before they are parsed or filtered. Random sampling uses a
+// xorshift generator seeded from the time and process, so runs sample different lines.
+pub struct Sampler {
+    sampling: Sampling,
+    lines: u64,
+    state: u64,
+}
+
+impl Sampler {
+    pub fn new(sampling: Sampling) -> Sampler {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+        let seed = nanos ^ ((process::id() as u64) << 32);
+        Sampler { sampling: sampling, lines: 0, state: if seed == 0 { 0x9e3779b97f4a7c15 } else { seed } }
+    }
+
+    // Whether the next line is part of the sample.
+    pub fn keep(&mut self) -> bool {
+        self.lines += 1;
+        match self.sampling {
+            Sampling::Every(n) => (self.lines - 1) % n == 0,
+            Sampling::Rate(rate) => {
+                self.state ^= self.state << 13;
+                self.state ^= self.state >> 7;
+                self.state ^= self.state << 17;
+                ((self.state >> 11) as f64 / (1u64 << 53) as f64) < rate
+            },
+        }
+    }
+}
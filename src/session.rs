@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+
+use chrono::prelude::*;
+use format::LogFormat;
+use query;
+use table::{self, ColumnDefinition, TableDefinition, WrappedRecord};
+
+// Columns added by sessionizing, in the order they follow the log format's columns
+const SESSION_COLUMNS: [&str; 4] = ["session_id", "session_start", "session_duration", "session_requests"];
+const SESSION_ID: usize = 0;
+const SESSION_START: usize = 1;
+const SESSION_DURATION: usize = 2;
+const SESSION_REQUESTS: usize = 3;
+
+// Splits the records of a log into visits: records of the same client (ip and user agent) belong to
+// one session until the client is inactive for longer than the gap. Sessions can only be told once
+// every record has been read, so records are buffered and evaluated in time order once sessionized.
+pub struct SessionFormat<F: LogFormat> {
+    format: F,
+    gap: i64,
+    columns: Rc<Vec<ColumnDefinition<F::Record>>>,
+    names: Vec<String>,
+    date: usize,
+    ip: usize,
+    user_agent: Option<usize>,
+}
+
+// A session shared by the records it spans, with its columns' text for comparisons on their bytes.
+#[derive(Debug)]
+struct Session {
+    id: u64,
+    start: DateTime<Local>,
+    end: DateTime<Local>,
+    requests: u64,
+    texts: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct SessionRecord<R> {
+    record: R,
+    columns: Rc<Vec<ColumnDefinition<R>>>,
+    session: Option<Rc<Session>>,
+}
+
+impl<F: LogFormat> SessionFormat<F> {
+    // The log format needs a date column and an ip column, named ip or typed ip, to sessionize.
+    pub fn new(format: F, gap: i64) -> Result<SessionFormat<F>, String> {
+        let mut definition = format.table_definition();
+        if let Some(column) = SESSION_COLUMNS.iter().find(|c| definition.column_map.contains_key(**c)) {
+            return Err(format!("Unable to sessionize logs that already have a '{}' column", column));
+        }
+        let date = query::find_date_column(&definition).ok_or("Unable to sessionize logs without a date column".to_owned())?;
+        let ip = definition.ordered_columns.iter()
+            .find(|c| c.as_str() == "ip")
+            .or_else(|| definition.ordered_columns.iter().find(|c| match definition.column_map.get(*c) { Some(ColumnDefinition::Ip { .. }) => true, _ => false }))
+            .cloned()
+            .ok_or("Unable to sessionize logs without an ip column".to_owned())?;
+        let position = |name: &str| definition.ordered_columns.iter().position(|c| c == name);
+        let (date, ip, user_agent) = (position(&date).unwrap(), position(&ip).unwrap(), position("user_agent"));
+        let names = definition.ordered_columns.clone();
+        let columns = names.iter().map(|n| definition.column_map.remove(n).unwrap()).collect();
+        Ok(SessionFormat { format: format, gap: gap, columns: Rc::new(columns), names: names, date: date, ip: ip, user_agent: user_agent })
+    }
+
+    // Orders the records chronologically, records read at the same second keeping the order they
+    // were read in, and assigns their sessions. Records without a date or ip are in no session.
+    pub fn sessionize(&self, records: Vec<SessionRecord<F::Record>>) -> Vec<SessionRecord<F::Record>> {
+        let mut records: Vec<(Option<DateTime<Local>>, SessionRecord<F::Record>)> =
+            records.into_iter().map(|mut r| (self.record_date(&mut r), r)).collect();
+        records.sort_by_key(|&(ref date, _)| date.map(|d| d.timestamp()));
+
+        let mut sessions: Vec<Session> = Vec::new();
+        let mut open: HashMap<Vec<u8>, usize> = HashMap::new();
+        let mut assigned = Vec::with_capacity(records.len());
+        for &(ref date, ref record) in &records {
+            let (date, key) =
+                match (date, self.client_key(record)) {
+                    (&Some(date), Some(key)) => (date, key),
+                    _ => {
+                        assigned.push(None);
+                        continue;
+                    },
+                };
+            let idx =
+                match open.get(&key) {
+                    Some(&idx) if (date - sessions[idx].end).num_seconds() <= self.gap => idx,
+                    _ => {
+                        sessions.push(Session { id: sessions.len() as u64 + 1, start: date, end: date, requests: 0, texts: Vec::new() });
+                        open.insert(key, sessions.len() - 1);
+                        sessions.len() - 1
+                    },
+                };
+            sessions[idx].end = date;
+            sessions[idx].requests += 1;
+            assigned.push(Some(idx));
+        }
+
+        let sessions: Vec<Rc<Session>> = sessions.into_iter().map(|mut session| {
+            session.texts = vec![session.id.to_string(), session.start.to_string(), session.duration().to_string(), session.requests.to_string()];
+            Rc::new(session)
+        }).collect();
+        records.into_iter().zip(assigned).map(|((_, mut record), idx)| {
+            record.session = idx.map(|idx| sessions[idx].clone());
+            record
+        }).collect()
+    }
+
+    fn record_date(&self, record: &mut SessionRecord<F::Record>) -> Option<DateTime<Local>> {
+        match self.columns[self.date] {
+            ColumnDefinition::Date { extractor, field, .. } => extractor(&mut record.record, field).cloned(),
+            _ => None,
+        }
+    }
+
+    fn client_key(&self, record: &SessionRecord<F::Record>) -> Option<Vec<u8>> {
+        let mut key = self.columns[self.ip].extract_binary(&record.record)?.to_vec();
+        if let Some(user_agent) = self.user_agent {
+            key.push(0);
+            key.extend_from_slice(self.columns[user_agent].extract_binary(&record.record).unwrap_or(&[]));
+        }
+        Some(key)
+    }
+}
+
+impl<R> WrappedRecord for SessionRecord<R> {
+    type Inner = R;
+
+    fn inner(&self) -> &R {
+        &self.record
+    }
+
+    fn inner_mut(&mut self) -> &mut R {
+        &mut self.record
+    }
+
+    fn inner_columns(&self) -> &Vec<ColumnDefinition<R>> {
+        &self.columns
+    }
+}
+
+impl Session {
+    fn duration(&self) -> u64 {
+        (self.end - self.start).num_seconds() as u64
+    }
+}
+
+impl<F: LogFormat> LogFormat for SessionFormat<F> {
+    type Record = SessionRecord<F::Record>;
+
+    fn empty_record(&self) -> SessionRecord<F::Record> {
+        SessionRecord { record: self.format.empty_record(), columns: self.columns.clone(), session: None }
+    }
+
+    fn read_record(&self, line: &[u8], record: &mut SessionRecord<F::Record>) -> bool {
+        record.session = None;
+        self.format.read_record(line, &mut record.record)
+    }
+
+    fn table_definition(&self) -> TableDefinition<SessionRecord<F::Record>> {
+        let mut column_map = HashMap::new();
+        let mut ordered_columns = Vec::new();
+        for (idx, column) in self.columns.iter().enumerate() {
+            column_map.insert(self.names[idx].to_owned(), table::wrap_column(column, idx));
+            ordered_columns.push(self.names[idx].to_owned());
+        }
+        for (idx, name) in SESSION_COLUMNS.iter().enumerate() {
+            column_map.insert(name.to_string(), session_column(name, idx));
+            ordered_columns.push(name.to_string());
+        }
+        TableDefinition { column_map: column_map, ordered_columns: ordered_columns }
+    }
+
+    fn has_header(&self) -> bool {
+        self.format.has_header()
+    }
+
+    fn is_log_file(file: &Path) -> bool {
+        F::is_log_file(file)
+    }
+}
+
+fn session_column<R>(name: &str, idx: usize) -> ColumnDefinition<SessionRecord<R>> {
+    let name = name.to_owned();
+    match idx {
+        SESSION_START => ColumnDefinition::Date { name: name, size: 26, field: idx, binary_extractor: session_bytes, extractor: session_start },
+        _ => ColumnDefinition::Integer { name: name, size: 10, field: idx, binary_extractor: session_bytes, extractor: session_integer },
+    }
+}
+
+fn session_bytes<R>(r: &SessionRecord<R>, idx: usize) -> Option<&[u8]> {
+    r.session.as_ref().map(|s| s.texts[idx].as_bytes())
+}
+
+fn session_integer<R>(r: &mut SessionRecord<R>, idx: usize) -> Option<u64> {
+    r.session.as_ref().and_then(|s| match idx {
+        SESSION_ID => Some(s.id),
+        SESSION_DURATION => Some(s.duration()),
+        SESSION_REQUESTS => Some(s.requests),
+        _ => None,
+    })
+}
+
+fn session_start<R>(r: &mut SessionRecord<R>, _: usize) -> Option<&DateTime<Local>> {
+    r.session.as_ref().map(|s| &s.start)
+}
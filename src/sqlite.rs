@@ -0,0 +1,124 @@
+use std::io;
+
+use rusqlite::{self, Connection};
+use rusqlite::types::Value as SqlValue;
+
+use output::{OutputColumn, ResultRenderer};
+use value::Value;
+
+const TABLE: &str = "results";
+
+// Opens the database results are written to, creating it when it doesn't exist.
+pub fn open_database(path: &str) -> Result<Connection, String> {
+    Connection::open(path).map_err(|e| format!("Unable to open sqlite database {}: {}", path, e))
+}
+
+// Writes result rows to a table of a sqlite database, replacing the table if it already exists.
+// Column types are those of the values of the first row, as aggregates only have a type once
+// computed.
+pub struct SqliteRenderer {
+    connection: Connection,
+    names: Vec<String>,
+    created: bool,
+    in_transaction: bool,
+}
+
+impl SqliteRenderer {
+    pub fn new(path: &str) -> SqliteRenderer {
+        SqliteRenderer { connection: open_database(path).unwrap(), names: Vec::new(), created: false, in_transaction: false }
+    }
+
+    fn create_table(&mut self, values: Option<&Vec<Value>>) -> rusqlite::Result<()> {
+        let columns: Vec<String> = self.names.iter().enumerate().map(|(idx, name)| {
+            match values.and_then(|v| v.get(idx)).and_then(column_type) {
+                Some(column_type) => format!("{} {}", quote_identifier(name), column_type),
+                None => quote_identifier(name),
+            }
+        }).collect();
+        self.connection.execute_batch(&format!("DROP TABLE IF EXISTS {}; CREATE TABLE {} ({});", TABLE, TABLE, columns.join(", ")))?;
+        self.created = true;
+        Ok(())
+    }
+
+    fn insert(&mut self, values: &Vec<Value>) -> rusqlite::Result<()> {
+        if !self.created {
+            self.create_table(Some(values))?;
+        }
+        // Rows are inserted in a single transaction, as committing each one is very slow
+        if !self.in_transaction {
+            self.connection.execute_batch("BEGIN")?;
+            self.in_transaction = true;
+        }
+        let placeholders: Vec<&str> = values.iter().map(|_| "?").collect();
+        let mut statement = self.connection.prepare_cached(&format!("INSERT INTO {} VALUES ({})", TABLE, placeholders.join(", ")))?;
+        statement.execute(rusqlite::params_from_iter(values.iter().map(sql_value)))?;
+        Ok(())
+    }
+
+    fn commit(&mut self) -> rusqlite::Result<()> {
+        if self.in_transaction {
+            self.connection.execute_batch("COMMIT")?;
+            self.in_transaction = false;
+        }
+        Ok(())
+    }
+}
+
+impl ResultRenderer for SqliteRenderer {
+    // Headers are rendered again for each window of a query using 'every', whose rows all go to
+    // the same table.
+    fn render_header(&mut self, columns: &Vec<OutputColumn>) -> io::Result<()> {
+        self.names = columns.iter().map(|c| c.name.to_owned()).collect();
+        Ok(())
+    }
+
+    fn render_row(&mut self, values: &Vec<Value>) -> io::Result<()> {
+        self.insert(values).map_err(sqlite_error)
+    }
+
+    fn render_footer(&mut self) -> io::Result<()> {
+        if !self.created {
+            self.create_table(None).map_err(sqlite_error)?;
+        }
+        self.commit().map_err(sqlite_error)
+    }
+
+    fn render_label(&mut self, _label: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.commit().map_err(sqlite_error)
+    }
+}
+
+fn sqlite_error(error: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error.to_string())
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn column_type(value: &Value) -> Option<&'static str> {
+    match value {
+        Value::Int(_) | Value::Bool(_) => Some("INTEGER"),
+        Value::Float(_) => Some("REAL"),
+        Value::Text(_) | Value::Date(_) | Value::Counts(_) => Some("TEXT"),
+        Value::Null => None,
+    }
+}
+
+// Dates are stored as ISO-8601 text and the values counted by top() as a json array.
+fn sql_value(value: &Value) -> SqlValue {
+    match value {
+        Value::Int(i) if *i <= i64::max_value() as u64 => SqlValue::Integer(*i as i64),
+        Value::Int(i) => SqlValue::Real(*i as f64),
+        Value::Float(d) => SqlValue::Real(*d),
+        Value::Text(t) => SqlValue::Text(t.to_owned()),
+        Value::Date(d) => SqlValue::Text(d.to_rfc3339()),
+        Value::Bool(b) => SqlValue::Integer(*b as i64),
+        Value::Counts(_) => SqlValue::Text(value.to_json().to_string()),
+        Value::Null => SqlValue::Null,
+    }
+}
@@ -0,0 +1,232 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::rc::Rc;
+
+use chrono::prelude::*;
+use output::{OutputColumn, ResultRenderer};
+use parser::{QueryExpression, QueryGroupingElement, QueryReducer, QueryShowElement, RipLogQuery};
+use table::{ColumnDefinition, TableDefinition};
+use value::Value;
+
+// Stages of a chained query, e.g. `group ip | show count(*) as hits ~> hits > 1000`, after the
+// first are evaluated over the rows of the stage before them: a table whose columns are that stage's
+// output columns, under their aliases, typed by what they hold.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Integer,
+    Double,
+    Text,
+    Date,
+    Boolean,
+}
+
+// A result row of the previous stage. Values are converted to the type of their column, and kept as
+// text too since text columns, and comparisons with null, read a column's bytes.
+#[derive(Debug, Clone)]
+pub struct Row {
+    values: Vec<Value>,
+    texts: Vec<String>,
+}
+
+impl Row {
+    pub fn empty() -> Row {
+        Row { values: Vec::new(), texts: Vec::new() }
+    }
+
+    fn set_values(&mut self, values: Vec<Value>, kinds: &Vec<Kind>) {
+        self.values = values.into_iter().zip(kinds).map(|(value, kind)| convert(value, *kind)).collect();
+        self.texts = self.values.iter().map(|v| if v.is_null() { String::new() } else { v.to_display_string() }).collect();
+    }
+
+    fn bytes(&self, idx: usize) -> Option<&[u8]> {
+        Some(self.texts[idx].as_bytes()).filter(|b| !b.is_empty())
+    }
+
+    fn integer(&mut self, idx: usize) -> Option<u64> {
+        match self.values[idx] { Value::Int(i) => Some(i), _ => None }
+    }
+
+    fn double(&mut self, idx: usize) -> Option<f64> {
+        match self.values[idx] { Value::Float(f) => Some(f), _ => None }
+    }
+
+    fn text(&mut self, idx: usize) -> Option<&str> {
+        match self.values[idx] { Value::Text(ref t) if !t.is_empty() => Some(t), _ => None }
+    }
+
+    fn date(&mut self, idx: usize) -> Option<&DateTime<Local>> {
+        match self.values[idx] { Value::Date(ref d) => Some(d), _ => None }
+    }
+
+    fn boolean(&mut self, idx: usize) -> Option<bool> {
+        match self.values[idx] { Value::Bool(b) => Some(b), _ => None }
+    }
+}
+
+fn convert(value: Value, kind: Kind) -> Value {
+    match (value, kind) {
+        (Value::Null, _) => Value::Null,
+        (Value::Float(f), Kind::Integer) if f >= 0.0 => Value::Int(f as u64),
+        (Value::Int(i), Kind::Double) => Value::Float(i as f64),
+        (value @ Value::Int(_), Kind::Integer) | (value @ Value::Float(_), Kind::Double) |
+        (value @ Value::Date(_), Kind::Date) | (value @ Value::Bool(_), Kind::Boolean) => value,
+        (value, Kind::Text) => Value::Text(value.to_display_string()),
+        _ => Value::Null,
+    }
+}
+
+// The table of rows a stage gives the next: its output columns, named by their alias if they have
+// one, e.g. ip and hits for `group ip | show count(*) as hits`.
+pub struct StageTable {
+    columns: Vec<(String, Kind)>,
+}
+
+impl StageTable {
+    // The query is the stage whose rows are read, its show already computed.
+    pub fn new<T>(query: &RipLogQuery, definition: &TableDefinition<T>) -> StageTable {
+        let show = query.computed_show.as_ref().unwrap();
+        let columns = show.elements.iter()
+            .filter(|e| !e.is_star())
+            .map(|e| (show.display_name(&e.name()), element_kind(e, query, definition)))
+            .collect();
+        StageTable { columns: columns }
+    }
+
+    pub fn definition(&self) -> TableDefinition<Row> {
+        let mut column_map = HashMap::new();
+        let mut ordered_columns = Vec::new();
+        for (idx, &(ref name, kind)) in self.columns.iter().enumerate() {
+            let size = match kind { Kind::Date => 26, _ => 10 };
+            column_map.insert(name.to_owned(), column_definition(name, size, idx, kind));
+            ordered_columns.push(name.to_owned());
+        }
+        TableDefinition { column_map: column_map, ordered_columns: ordered_columns }
+    }
+
+    pub fn read_row(&self, values: Vec<Value>, row: &mut Row) {
+        let kinds: Vec<Kind> = self.columns.iter().map(|&(_, kind)| kind).collect();
+        row.set_values(values, &kinds);
+    }
+}
+
+// A table of rows with the columns described by name, kind and size, e.g. those of a log format saved
+// with the aggregates of a query, for the query to be evaluated over rows of them. Ip columns are read
+// as text. None when a kind is unknown.
+pub fn described_table(columns: &Vec<(String, String, usize)>) -> Option<TableDefinition<Row>> {
+    let mut column_map = HashMap::new();
+    let mut ordered_columns = Vec::new();
+    for (idx, &(ref name, ref kind, size)) in columns.iter().enumerate() {
+        let kind =
+            match kind.as_str() {
+                "integer" => Kind::Integer,
+                "double" => Kind::Double,
+                "text" | "ip" => Kind::Text,
+                "date" => Kind::Date,
+                "boolean" => Kind::Boolean,
+                _ => return None,
+            };
+        column_map.insert(name.to_owned(), column_definition(name, size, idx, kind));
+        ordered_columns.push(name.to_owned());
+    }
+    Some(TableDefinition { column_map: column_map, ordered_columns: ordered_columns })
+}
+
+fn column_definition(name: &str, size: usize, idx: usize, kind: Kind) -> ColumnDefinition<Row> {
+    let name = name.to_owned();
+    let binary_extractor = row_bytes;
+    match kind {
+        Kind::Integer => ColumnDefinition::Integer { name, size, field: idx, binary_extractor, extractor: |r: &mut Row, idx| r.integer(idx) },
+        Kind::Double => ColumnDefinition::Double { name, size, field: idx, binary_extractor, extractor: |r: &mut Row, idx| r.double(idx) },
+        Kind::Text => ColumnDefinition::Text { name, size, field: idx, binary_extractor, extractor: |r: &mut Row, idx| r.text(idx) },
+        Kind::Date => ColumnDefinition::Date { name, size, field: idx, binary_extractor, extractor: |r: &mut Row, idx| r.date(idx) },
+        Kind::Boolean => ColumnDefinition::Boolean { name, size, field: idx, binary_extractor, extractor: |r: &mut Row, idx| r.boolean(idx) },
+    }
+}
+
+fn row_bytes(row: &Row, idx: usize) -> Option<&[u8]> {
+    row.bytes(idx)
+}
+
+fn element_kind<T>(element: &QueryShowElement, query: &RipLogQuery, definition: &TableDefinition<T>) -> Kind {
+    match element {
+        QueryShowElement::Symbol(symbol) => {
+            let group = query.grouping.as_ref().and_then(|g| g.groupings.iter().find(|g| &g.name() == symbol));
+            match group {
+                Some(QueryGroupingElement::Bucket(_)) => Kind::Date,
+                Some(QueryGroupingElement::Expression(expression)) => expression_kind(expression, definition),
+                _ => column_kind(symbol, definition),
+            }
+        },
+        QueryShowElement::Reducer(reducer, symbol) => reducer_kind(reducer, symbol, definition),
+        QueryShowElement::Expression(expression) => expression_kind(expression, definition),
+        QueryShowElement::All => Kind::Text,
+    }
+}
+
+fn column_kind<T>(symbol: &str, definition: &TableDefinition<T>) -> Kind {
+    match definition.column_map.get(symbol) {
+        Some(ColumnDefinition::Integer { .. }) => Kind::Integer,
+        Some(ColumnDefinition::Double { .. }) => Kind::Double,
+        Some(ColumnDefinition::Date { .. }) => Kind::Date,
+        Some(ColumnDefinition::Boolean { .. }) => Kind::Boolean,
+        _ => Kind::Text,
+    }
+}
+
+fn reducer_kind<T>(reducer: &QueryReducer, symbol: &str, definition: &TableDefinition<T>) -> Kind {
+    match reducer {
+        QueryReducer::Count | QueryReducer::Unique | QueryReducer::CountDistinct | QueryReducer::ApproxUnique => Kind::Integer,
+        QueryReducer::Sum if column_kind(symbol, definition) == Kind::Integer => Kind::Integer,
+        QueryReducer::Min | QueryReducer::Max | QueryReducer::First | QueryReducer::Last => column_kind(symbol, definition),
+        QueryReducer::Top(_) => Kind::Text,
+        _ => Kind::Double,
+    }
+}
+
+fn expression_kind<T>(expression: &QueryExpression, definition: &TableDefinition<T>) -> Kind {
+    match expression {
+        QueryExpression::Symbol(symbol) => column_kind(symbol, definition),
+        QueryExpression::Reducer(reducer, symbol) => reducer_kind(reducer, symbol, definition),
+        QueryExpression::Function(function, _) if function.is_date_part() => Kind::Integer,
+        expression if expression.is_numeric() => Kind::Double,
+        _ => Kind::Text,
+    }
+}
+
+// Keeps the rows a stage renders for the next stage to read. Each header starts the rows afresh, as
+// aggregates are rendered whole each time.
+pub struct StageRenderer {
+    rows: Rc<RefCell<Vec<Vec<Value>>>>,
+}
+
+impl StageRenderer {
+    pub fn new(rows: Rc<RefCell<Vec<Vec<Value>>>>) -> StageRenderer {
+        StageRenderer { rows: rows }
+    }
+}
+
+impl ResultRenderer for StageRenderer {
+    fn render_header(&mut self, _columns: &Vec<OutputColumn>) -> io::Result<()> {
+        self.rows.borrow_mut().clear();
+        Ok(())
+    }
+
+    fn render_row(&mut self, values: &Vec<Value>) -> io::Result<()> {
+        self.rows.borrow_mut().push(values.clone());
+        Ok(())
+    }
+
+    fn render_footer(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn render_label(&mut self, _label: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use chrono::prelude::*;
+use glob::{MatchOptions, Pattern};
+
+use discovery::{DiscoveredFiles, FileSelector};
+use input;
+
+// Logs archived in object storage are read in place, e.g. `riplog '...' s3://bucket/logs/`. Objects are
+// listed and streamed by the aws and gsutil commands, which take their credentials from the environment
+// as they otherwise do, and decompressed here. The commands can be replaced through RIPLOG_AWS and
+// RIPLOG_GSUTIL.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Service {
+    S3,
+    Gcs,
+}
+
+impl Service {
+    fn of(url: &str) -> Option<Service> {
+        if url.starts_with("s3://") {
+            Some(Service::S3)
+        } else if url.starts_with("gs://") {
+            Some(Service::Gcs)
+        } else {
+            None
+        }
+    }
+
+    fn command(&self, arguments: &[&str]) -> Command {
+        let (variable, default) = match self { Service::S3 => ("RIPLOG_AWS", "aws"), Service::Gcs => ("RIPLOG_GSUTIL", "gsutil") };
+        let program = env::var(variable).ok().filter(|p| !p.trim().is_empty()).unwrap_or(default.to_owned());
+        let mut words = program.split_whitespace();
+        let mut command = Command::new(words.next().unwrap());
+        command.args(words).args(arguments);
+        command
+    }
+
+    fn program(&self) -> &'static str {
+        match self { Service::S3 => "aws", Service::Gcs => "gsutil" }
+    }
+}
+
+pub fn is_object_url(path: &Path) -> bool {
+    path.to_str().and_then(Service::of).is_some()
+}
+
+// Objects listed under urls, with the times they were last modified at for them to be ordered by.
+#[derive(Debug, Default)]
+pub struct ListedObjects {
+    pub objects: DiscoveredFiles,
+    pub modified: HashMap<PathBuf, SystemTime>,
+}
+
+// Finds the objects of a url, selected as discover_files selects local files: an object named by the
+// url or matched by a glob in it is read unless excluded, while those under a prefix, e.g.
+// s3://bucket/logs/, must also be selected.
+pub fn list_objects(url: &Path, selector: &FileSelector, listed: &mut ListedObjects) -> Result<(), String> {
+    let url = url.to_str().ok_or_else(|| format!("Invalid object url '{}'", url.display()))?;
+    let service = Service::of(url).ok_or_else(|| format!("Invalid object url '{}'", url))?;
+    let (bucket, key) = split_url(url).ok_or_else(|| format!("Invalid object url '{}', expected a bucket as in s3://bucket/prefix/", url))?;
+    let pattern = if is_glob(key) { Some(Pattern::new(key).map_err(|e| format!("Invalid pattern '{}': {}", url, e))?) } else { None };
+    let prefix = &key[0..key.find(|c| c == '*' || c == '?' || c == '[').unwrap_or(key.len())];
+    let directory = if key.is_empty() || key.ends_with('/') { key.to_owned() } else { format!("{}/", key) };
+    let options = MatchOptions { require_literal_separator: true, ..MatchOptions::default() };
+    let mut found = false;
+    for (object_key, modified) in list_keys(service, bucket, prefix)? {
+        let path = PathBuf::from(format!("{}://{}/{}", &url[0..2], bucket, object_key));
+        let selected =
+            match pattern {
+                Some(ref pattern) if pattern.matches_with(&object_key, options) => !selector.is_excluded(&path),
+                Some(_) => continue,
+                None if object_key == key => !selector.is_excluded(&path),
+                None if object_key.starts_with(&directory) => selector.is_selected(&path),
+                None => continue,
+            };
+        found = true;
+        if selected {
+            listed.modified.insert(path.clone(), modified);
+            listed.objects.files.push(path);
+        } else {
+            listed.objects.skipped += 1;
+        }
+    }
+    if !found {
+        return Err(format!("No objects found at '{}'", url));
+    }
+    Ok(())
+}
+
+// Streams an object, decompressed here when it's stored compressed, along with a counter of the bytes
+// received.
+pub fn open_object_input(url: &Path) -> io::Result<(Box<BufRead>, Rc<Cell<u64>>)> {
+    let text = url.to_string_lossy();
+    let service = Service::of(&text).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not an object url"))?;
+    let command =
+        match service {
+            Service::S3 => service.command(&["s3", "cp", "--quiet", &text, "-"]),
+            Service::Gcs => service.command(&["cat", &text]),
+        };
+    input::open_command_input(command, service.program(), url)
+}
+
+fn split_url(url: &str) -> Option<(&str, &str)> {
+    let path = &url[5..];
+    let (bucket, key) = match path.find('/') { Some(idx) => (&path[0..idx], &path[idx + 1..]), None => (path, "") };
+    Some((bucket, key)).filter(|&(bucket, _)| !bucket.is_empty())
+}
+
+fn is_glob(key: &str) -> bool {
+    key.contains(|c| c == '*' || c == '?' || c == '[')
+}
+
+// Keys of the objects of a bucket starting with the prefix, with their modification times. A prefix
+// matching nothing lists nothing rather than being an error.
+fn list_keys(service: Service, bucket: &str, prefix: &str) -> Result<Vec<(String, SystemTime)>, String> {
+    let mut command =
+        match service {
+            Service::S3 => service.command(&["s3", "ls", "--recursive", &format!("s3://{}/{}", bucket, prefix)]),
+            Service::Gcs => service.command(&["ls", "-l", &format!("gs://{}/{}**", bucket, prefix)]),
+        };
+    let output = command.stdin(Stdio::null()).stderr(Stdio::piped()).output()
+        .map_err(|e| format!("Unable to run {}: {}", service.program(), e))?;
+    let error = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+    if !output.status.success() && !error.is_empty() && !error.contains("matched no objects") {
+        return Err(format!("Unable to list objects of {}: {}", bucket, error));
+    }
+    let listing = String::from_utf8_lossy(&output.stdout);
+    Ok(listing.lines().filter_map(|line| match service {
+        Service::S3 => parse_s3_line(line),
+        Service::Gcs => parse_gcs_line(line, bucket),
+    }).collect())
+}
+
+// e.g. `2019-03-01 10:00:00      12345 logs/access.log.1.gz`, the time being local
+fn parse_s3_line(line: &str) -> Option<(String, SystemTime)> {
+    let mut rest = line.trim_start();
+    let mut fields = Vec::new();
+    for _ in 0..3 {
+        let end = rest.find(char::is_whitespace)?;
+        fields.push(&rest[0..end]);
+        rest = rest[end..].trim_start();
+    }
+    let modified = Local.datetime_from_str(&format!("{} {}", fields[0], fields[1]), "%Y-%m-%d %H:%M:%S").ok()?;
+    Some((rest.to_owned(), modified.into())).filter(|&(ref key, _)| !key.is_empty() && !key.ends_with('/'))
+}
+
+// e.g. `     12345  2019-03-01T10:00:00Z  gs://bucket/logs/access.log.1.gz`, followed by a total
+fn parse_gcs_line(line: &str, bucket: &str) -> Option<(String, SystemTime)> {
+    let mut fields = line.split_whitespace();
+    let (_, modified) = (fields.next()?, fields.next()?);
+    let url = fields.collect::<Vec<&str>>().join(" ");
+    let key = url.trim_start_matches(&format!("gs://{}/", bucket) as &str);
+    let modified = DateTime::parse_from_rfc3339(modified).ok()?;
+    Some((key.to_owned(), modified.into())).filter(|&(ref key, _)| url.starts_with("gs://") && !key.is_empty() && !key.ends_with('/'))
+}
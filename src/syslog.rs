@@ -0,0 +1,368 @@
+use std::str;
+use std::path::Path;
+use std::collections::HashMap;
+
+use chrono::prelude::*;
+use table::{TableDefinition, ColumnDefinition};
+use format::LogFormat;
+use discovery;
+use nginx::{index_of, or_empty, empty_opt};
+
+const SEVERITIES: [&str; 8] = ["emerg", "alert", "crit", "err", "warning", "notice", "info", "debug"];
+
+const FACILITIES: [&str; 24] = ["kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron",
+                                "authpriv", "ftp", "ntp", "security", "console", "solaris-cron", "local0", "local1",
+                                "local2", "local3", "local4", "local5", "local6", "local7"];
+
+// Syslog messages in the BSD (RFC3164) or IETF (RFC5424) formats, with or without the leading
+// <priority> that is usually dropped when messages are written to files. RFC3164 timestamps carry
+// no year, so the year is taken from the current date, or the previous year for months still ahead.
+pub struct SyslogFormat {
+    year: i32,
+    month: u32,
+}
+
+impl SyslogFormat {
+    pub fn new() -> SyslogFormat {
+        let now = Local::now();
+        SyslogFormat { year: now.year(), month: now.month() }
+    }
+}
+
+impl LogFormat for SyslogFormat {
+    type Record = BinarySyslogRecord;
+
+    fn empty_record(&self) -> BinarySyslogRecord {
+        let mut record = BinarySyslogRecord::empty();
+        record.year = self.year;
+        record.month = self.month;
+        record
+    }
+
+    fn read_record(&self, line: &[u8], record: &mut BinarySyslogRecord) -> bool {
+        read_log_record_binary(line, record)
+    }
+
+    fn table_definition(&self) -> TableDefinition<BinarySyslogRecord> {
+        create_syslog_record_table_definition()
+    }
+
+    // Severity and facility are named from the priority rather than copied from the line
+    fn verbatim_columns(&self) -> Vec<&'static str> {
+        vec!["host", "app", "message"]
+    }
+
+    fn is_log_file(file: &Path) -> bool {
+        discovery::is_syslog(file)
+    }
+}
+
+// Returns false, leaving the record untouched, when the line is not a syslog message.
+pub fn read_log_record_binary(line: &[u8], record: &mut BinarySyslogRecord) -> bool {
+    let working = trim_line_ending(line);
+    let (priority, working) = split_priority(working);
+    let parsed =
+        if working.starts_with(b"1 ") {
+            split_rfc5424(&working[2..])
+        } else {
+            split_rfc3164(working)
+        };
+    match parsed {
+        Some((kind, fields)) => {
+            record.priority = priority;
+            record.timestamp_kind = kind;
+            record.set_fields(&fields);
+            true
+        },
+        None => false,
+    }
+}
+
+fn split_priority(working: &[u8]) -> (Option<u8>, &[u8]) {
+    if working.first() != Some(&b'<') {
+        return (None, working);
+    }
+    let end = match index_of(&working[..working.len().min(5)], b'>') {
+        Some(end) => end,
+        None => return (None, working),
+    };
+    match str::from_utf8(&working[1..end]).ok().and_then(|p| p.parse::<u8>().ok()) {
+        Some(priority) if priority < 192 => (Some(priority), &working[end+1..]),
+        _ => (None, working),
+    }
+}
+
+// <pri>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG
+fn split_rfc5424(working: &[u8]) -> Option<(TimestampKind, [&[u8]; FIELD_COUNT])> {
+    let (timestamp, working) = next_token(working)?;
+    let (host, working) = next_token(working)?;
+    let (app, working) = next_token(working)?;
+    let (pid, working) = next_token(working)?;
+    let (_, working) = next_token(working)?;
+    let working = skip_structured_data(working)?;
+    let message = if working.first() == Some(&b' ') { &working[1..] } else { working };
+    let message = if message.starts_with(b"\xEF\xBB\xBF") { &message[3..] } else { message };
+    Some((TimestampKind::Rfc3339, [or_empty(timestamp), or_empty(host), or_empty(app), or_empty(pid), message]))
+}
+
+// Mmm dd hh:mm:ss HOSTNAME TAG[PID]: MSG, where the timestamp may also be RFC3339 as written by
+// rsyslog's high precision file format.
+fn split_rfc3164(working: &[u8]) -> Option<(TimestampKind, [&[u8]; FIELD_COUNT])> {
+    let (kind, timestamp, working) =
+        if is_bsd_timestamp(working) {
+            (TimestampKind::Rfc3164, &working[0..15], &working[16..])
+        } else {
+            let (timestamp, working) = next_token(working)?;
+            if timestamp.len() < 10 || timestamp[4] != b'-' || !timestamp[0..4].iter().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            (TimestampKind::Rfc3339, timestamp, working)
+        };
+    let (host, working) = next_token(working)?;
+    let (tag, rest) = next_token(working).unwrap_or((working, &[]));
+    let empty: &[u8] = &[];
+    let (app, pid, message) =
+        if tag.last() == Some(&b':') {
+            let tag = &tag[0..tag.len()-1];
+            match index_of(tag, b'[') {
+                Some(idx) if tag.last() == Some(&b']') => (&tag[0..idx], &tag[idx+1..tag.len()-1], rest),
+                _ => (tag, empty, rest),
+            }
+        } else {
+            (empty, empty, working)
+        };
+    Some((kind, [timestamp, host, app, pid, message]))
+}
+
+fn is_bsd_timestamp(working: &[u8]) -> bool {
+    working.len() > 15 && working[0].is_ascii_uppercase() && working[3] == b' ' && working[6] == b' '
+        && working[9] == b':' && working[12] == b':' && working[15] == b' '
+}
+
+fn next_token(working: &[u8]) -> Option<(&[u8], &[u8])> {
+    match index_of(working, b' ') {
+        Some(0) => None,
+        Some(idx) => Some((&working[0..idx], &working[idx+1..])),
+        None if working.is_empty() => None,
+        None => Some((working, &[])),
+    }
+}
+
+// Skips the nil value or one or more [id param="value"] elements, where values may contain
+// escaped quotes and brackets.
+fn skip_structured_data(working: &[u8]) -> Option<&[u8]> {
+    if working.first() == Some(&b'-') {
+        return Some(&working[1..]);
+    }
+    let mut idx = 0;
+    while working.get(idx) == Some(&b'[') {
+        let mut quoted = false;
+        idx += 1;
+        loop {
+            match *working.get(idx)? {
+                b'\\' if quoted => idx += 1,
+                b'"' => quoted = !quoted,
+                b']' if !quoted => break,
+                _ => (),
+            }
+            idx += 1;
+        }
+        idx += 1;
+    }
+    if idx == 0 {
+        None
+    } else {
+        Some(&working[idx..])
+    }
+}
+
+fn trim_line_ending(working: &[u8]) -> &[u8] {
+    let mut len = working.len();
+    while len > 0 && (working[len-1] == b'\n' || working[len-1] == b'\r') {
+        len -= 1;
+    }
+    &working[0..len]
+}
+
+const FIELD_COUNT: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TimestampKind {
+    Rfc3164,
+    Rfc3339,
+}
+
+#[derive(Debug, Clone)]
+pub struct BinarySyslogRecord {
+    buffer: Vec<u8>,
+    spans: [(usize, usize); FIELD_COUNT],
+    priority: Option<u8>,
+    timestamp_kind: TimestampKind,
+    year: i32,
+    month: u32,
+    parsed_timestamp: Option<Option<DateTime<Local>>>,
+    parsed_host: Option<String>,
+    parsed_app: Option<String>,
+    parsed_message: Option<String>,
+}
+
+impl BinarySyslogRecord {
+    pub fn empty() -> BinarySyslogRecord {
+        BinarySyslogRecord {
+            buffer: Vec::new(),
+            spans: [(0, 0); FIELD_COUNT],
+            priority: None,
+            timestamp_kind: TimestampKind::Rfc3164,
+            year: 1970,
+            month: 1,
+            parsed_timestamp: None,
+            parsed_host: None,
+            parsed_app: None,
+            parsed_message: None,
+        }
+    }
+
+    // Replaces the fields of the record, in the order timestamp, host, app, pid and message.
+    fn set_fields(&mut self, fields: &[&[u8]]) {
+        self.buffer.clear();
+        for (span, field) in self.spans.iter_mut().zip(fields) {
+            let start = self.buffer.len();
+            self.buffer.extend_from_slice(field);
+            *span = (start, self.buffer.len());
+        }
+        self.parsed_timestamp = None;
+        self.parsed_host = None;
+        self.parsed_app = None;
+        self.parsed_message = None;
+    }
+
+    fn field(&self, idx: usize) -> &[u8] {
+        let (start, end) = self.spans[idx];
+        &self.buffer[start..end]
+    }
+
+    pub fn timestamp(&self) -> &[u8] {
+        self.field(0)
+    }
+
+    pub fn host(&self) -> &[u8] {
+        self.field(1)
+    }
+
+    pub fn app(&self) -> &[u8] {
+        self.field(2)
+    }
+
+    pub fn pid(&self) -> &[u8] {
+        self.field(3)
+    }
+
+    pub fn message(&self) -> &[u8] {
+        self.field(4)
+    }
+
+    pub fn severity(&self) -> Option<&'static str> {
+        self.priority.map(|p| SEVERITIES[(p % 8) as usize])
+    }
+
+    pub fn facility(&self) -> Option<&'static str> {
+        self.priority.map(|p| FACILITIES[(p / 8) as usize])
+    }
+
+    pub fn parsed_timestamp(&mut self) -> Option<&DateTime<Local>> {
+        if self.parsed_timestamp.is_none() {
+            let text = String::from_utf8_lossy(self.timestamp()).to_string();
+            let timestamp =
+                match self.timestamp_kind {
+                    TimestampKind::Rfc3339 => DateTime::parse_from_rfc3339(&text).ok().map(|d| d.with_timezone(&Local)),
+                    TimestampKind::Rfc3164 => self.parse_bsd_timestamp(&text),
+                };
+            self.parsed_timestamp = Some(timestamp);
+        }
+        self.parsed_timestamp.as_ref().unwrap().as_ref()
+    }
+
+    fn parse_bsd_timestamp(&self, text: &str) -> Option<DateTime<Local>> {
+        let parse = |year: i32| NaiveDateTime::parse_from_str(&format!("{} {}", year, text), "%Y %b %e %H:%M:%S").ok();
+        let date = parse(self.year)?;
+        let date = if date.month() > self.month { parse(self.year - 1)? } else { date };
+        Local.from_local_datetime(&date).earliest()
+    }
+
+    pub fn parsed_host(&mut self) -> Option<&str> {
+        if self.parsed_host.is_none() {
+            self.parsed_host = Some(String::from_utf8_lossy(self.host()).to_string());
+        }
+        self.parsed_host.as_ref().map(|s| s.as_str()).filter(|s| !s.is_empty())
+    }
+
+    pub fn parsed_app(&mut self) -> Option<&str> {
+        if self.parsed_app.is_none() {
+            self.parsed_app = Some(String::from_utf8_lossy(self.app()).to_string());
+        }
+        self.parsed_app.as_ref().map(|s| s.as_str()).filter(|s| !s.is_empty())
+    }
+
+    pub fn parsed_pid(&mut self) -> Option<u64> {
+        str::from_utf8(self.pid()).ok().and_then(|p| p.parse::<u64>().ok())
+    }
+
+    pub fn parsed_message(&mut self) -> Option<&str> {
+        if self.parsed_message.is_none() {
+            self.parsed_message = Some(String::from_utf8_lossy(self.message()).to_string());
+        }
+        self.parsed_message.as_ref().map(|s| s.as_str()).filter(|s| !s.is_empty())
+    }
+}
+
+pub fn create_syslog_record_table_definition() -> TableDefinition<BinarySyslogRecord> {
+    let mut column_map = HashMap::new();
+    column_map.insert("timestamp".to_owned(),
+                      ColumnDefinition::Date { name: "timestamp".to_owned(),
+                                               size: 26,
+                                               field: 0,
+                                               binary_extractor: |r: &BinarySyslogRecord, _| empty_opt(r.timestamp()),
+                                               extractor: |r: &mut BinarySyslogRecord, _| r.parsed_timestamp() });
+    column_map.insert("host".to_owned(),
+                      ColumnDefinition::Text { name: "host".to_owned(),
+                                               size: 15,
+                                               field: 0,
+                                               binary_extractor: |r: &BinarySyslogRecord, _| empty_opt(r.host()),
+                                               extractor: |r: &mut BinarySyslogRecord, _| r.parsed_host() });
+    column_map.insert("app".to_owned(),
+                      ColumnDefinition::Text { name: "app".to_owned(),
+                                               size: 15,
+                                               field: 0,
+                                               binary_extractor: |r: &BinarySyslogRecord, _| empty_opt(r.app()),
+                                               extractor: |r: &mut BinarySyslogRecord, _| r.parsed_app() });
+    column_map.insert("pid".to_owned(),
+                      ColumnDefinition::Integer { name: "pid".to_owned(),
+                                                  size: 7,
+                                                  field: 0,
+                                                  binary_extractor: |r: &BinarySyslogRecord, _| empty_opt(r.pid()),
+                                                  extractor: |r: &mut BinarySyslogRecord, _| r.parsed_pid() });
+    column_map.insert("severity".to_owned(),
+                      ColumnDefinition::Text { name: "severity".to_owned(),
+                                               size: 8,
+                                               field: 0,
+                                               binary_extractor: |r: &BinarySyslogRecord, _| r.severity().map(|s| s.as_bytes()),
+                                               extractor: |r: &mut BinarySyslogRecord, _| r.severity() });
+    column_map.insert("facility".to_owned(),
+                      ColumnDefinition::Text { name: "facility".to_owned(),
+                                               size: 8,
+                                               field: 0,
+                                               binary_extractor: |r: &BinarySyslogRecord, _| r.facility().map(|s| s.as_bytes()),
+                                               extractor: |r: &mut BinarySyslogRecord, _| r.facility() });
+    column_map.insert("message".to_owned(),
+                      ColumnDefinition::Text { name: "message".to_owned(),
+                                               size: 50,
+                                               field: 0,
+                                               binary_extractor: |r: &BinarySyslogRecord, _| empty_opt(r.message()),
+                                               extractor: |r: &mut BinarySyslogRecord, _| r.parsed_message() });
+
+    TableDefinition {
+        column_map: column_map,
+        ordered_columns: vec!["timestamp".to_owned(), "host".to_owned(), "app".to_owned(), "pid".to_owned(),
+                              "severity".to_owned(), "facility".to_owned(), "message".to_owned()],
+    }
+}
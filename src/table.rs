@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::net::IpAddr;
 use chrono::prelude::*;
 
 pub struct TableDefinition<T> {
@@ -6,27 +7,43 @@ pub struct TableDefinition<T> {
     pub ordered_columns: Vec<String>,
 }
 
+// Columns read their values with plain functions of the record rather than boxed closures, so that
+// reading a value is a direct call the compiler can see through. Formats whose columns are
+// configured (csv, json, custom and aws) share one function per type and tell fields apart by their
+// position, passed to the functions as field. Formats with fixed fields ignore it.
 pub enum ColumnDefinition<T> {
-    Integer { name: &'static str,
+    Integer { name: String,
               size: usize,
-              binary_extractor: Box<Fn(&T) -> Option<&[u8]>>,
-              extractor: Box<Fn(&mut T) -> Option<u64>> },
-    Double { name: &'static str,
+              field: usize,
+              binary_extractor: fn(&T, usize) -> Option<&[u8]>,
+              extractor: fn(&mut T, usize) -> Option<u64> },
+    Double { name: String,
              size: usize,
-             binary_extractor: Box<Fn(&T) -> Option<&[u8]>>,
-             extractor: Box<Fn(&mut T) -> Option<f64>> },
-    Text { name: &'static str,
+             field: usize,
+             binary_extractor: fn(&T, usize) -> Option<&[u8]>,
+             extractor: fn(&mut T, usize) -> Option<f64> },
+    Text { name: String,
            size: usize,
-           binary_extractor: Box<Fn(&T) -> Option<&[u8]>>,
-           extractor: Box<Fn(&mut T) -> Option<&str>> },
-    Date { name: &'static str,
+           field: usize,
+           binary_extractor: fn(&T, usize) -> Option<&[u8]>,
+           extractor: fn(&mut T, usize) -> Option<&str> },
+    Date { name: String,
            size: usize,
-           binary_extractor: Box<Fn(&T) -> Option<&[u8]>>,
-           extractor: Box<Fn(&mut T) -> Option<&DateTime<Local>>> },
-    Boolean { name: &'static str,
+           field: usize,
+           binary_extractor: fn(&T, usize) -> Option<&[u8]>,
+           extractor: fn(&mut T, usize) -> Option<&DateTime<Local>> },
+    Boolean { name: String,
               size: usize,
-              binary_extractor: Box<Fn(&T) -> Option<&[u8]>>,
-              extractor: Box<Fn(&mut T) -> Option<bool>> }
+              field: usize,
+              binary_extractor: fn(&T, usize) -> Option<&[u8]>,
+              extractor: fn(&mut T, usize) -> Option<bool> },
+    // Addresses are read like text, and parsed (once per record) only by filters on address ranges
+    Ip { name: String,
+         size: usize,
+         field: usize,
+         binary_extractor: fn(&T, usize) -> Option<&[u8]>,
+         extractor: fn(&mut T, usize) -> Option<&str>,
+         address_extractor: fn(&mut T, usize) -> Option<IpAddr> }
 }
 
 impl<T> ColumnDefinition<T> {
@@ -37,16 +54,30 @@ impl<T> ColumnDefinition<T> {
             ColumnDefinition::Text { name, .. } => name,
             ColumnDefinition::Date { name, .. } => name,
             ColumnDefinition::Boolean { name, .. } => name,
+            ColumnDefinition::Ip { name, .. } => name,
+        }
+    }
+
+    // Type of the column as named in queries and --types
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            ColumnDefinition::Integer { .. } => "integer",
+            ColumnDefinition::Double { .. } => "double",
+            ColumnDefinition::Text { .. } => "text",
+            ColumnDefinition::Date { .. } => "date",
+            ColumnDefinition::Boolean { .. } => "boolean",
+            ColumnDefinition::Ip { .. } => "ip",
         }
     }
 
     pub fn extract_binary<'b>(&self, record: &'b T) -> Option<&'b [u8]> {
         match self {
-            ColumnDefinition::Text { binary_extractor, ..} => binary_extractor(record),
-            ColumnDefinition::Double { binary_extractor, ..} => binary_extractor(record),
-            ColumnDefinition::Integer { binary_extractor, ..} => binary_extractor(record),
-            ColumnDefinition::Boolean { binary_extractor, ..} => binary_extractor(record),
-            ColumnDefinition::Date { binary_extractor, ..} => binary_extractor(record),
+            ColumnDefinition::Text { binary_extractor, field, ..} => binary_extractor(record, *field),
+            ColumnDefinition::Double { binary_extractor, field, ..} => binary_extractor(record, *field),
+            ColumnDefinition::Integer { binary_extractor, field, ..} => binary_extractor(record, *field),
+            ColumnDefinition::Boolean { binary_extractor, field, ..} => binary_extractor(record, *field),
+            ColumnDefinition::Date { binary_extractor, field, ..} => binary_extractor(record, *field),
+            ColumnDefinition::Ip { binary_extractor, field, ..} => binary_extractor(record, *field),
         }
     }
 
@@ -57,6 +88,82 @@ impl<T> ColumnDefinition<T> {
             ColumnDefinition::Integer { size, ..} => size,
             ColumnDefinition::Boolean { size, ..} => size,
             ColumnDefinition::Date { size, ..} => size,
+            ColumnDefinition::Ip { size, ..} => size,
         }
     }
 }
+
+// Records of a format wrapping another one, e.g. to add columns or mask values, which read the
+// columns of the wrapped format through its own column definitions.
+pub trait WrappedRecord {
+    type Inner;
+    fn inner(&self) -> &Self::Inner;
+    fn inner_mut(&mut self) -> &mut Self::Inner;
+    fn inner_columns(&self) -> &Vec<ColumnDefinition<Self::Inner>>;
+}
+
+// A column of the wrapped format, the idx-th of the wrapper's inner columns.
+pub fn wrap_column<W: WrappedRecord>(column: &ColumnDefinition<W::Inner>, idx: usize) -> ColumnDefinition<W> {
+    let name = column.name().to_owned();
+    let size = *column.get_size();
+    match column {
+        ColumnDefinition::Integer { .. } =>
+            ColumnDefinition::Integer { name: name, size: size, field: idx, binary_extractor: wrapped_bytes, extractor: wrapped_integer },
+        ColumnDefinition::Double { .. } =>
+            ColumnDefinition::Double { name: name, size: size, field: idx, binary_extractor: wrapped_bytes, extractor: wrapped_double },
+        ColumnDefinition::Text { .. } =>
+            ColumnDefinition::Text { name: name, size: size, field: idx, binary_extractor: wrapped_bytes, extractor: wrapped_text },
+        ColumnDefinition::Date { .. } =>
+            ColumnDefinition::Date { name: name, size: size, field: idx, binary_extractor: wrapped_bytes, extractor: wrapped_date },
+        ColumnDefinition::Boolean { .. } =>
+            ColumnDefinition::Boolean { name: name, size: size, field: idx, binary_extractor: wrapped_bytes, extractor: wrapped_boolean },
+        ColumnDefinition::Ip { .. } =>
+            ColumnDefinition::Ip { name: name, size: size, field: idx, binary_extractor: wrapped_bytes, extractor: wrapped_text, address_extractor: wrapped_address },
+    }
+}
+
+pub fn wrapped_bytes<W: WrappedRecord>(r: &W, idx: usize) -> Option<&[u8]> {
+    r.inner_columns()[idx].extract_binary(r.inner())
+}
+
+fn wrapped_integer<W: WrappedRecord>(r: &mut W, idx: usize) -> Option<u64> {
+    match r.inner_columns()[idx] {
+        ColumnDefinition::Integer { extractor, field, .. } => extractor(r.inner_mut(), field),
+        _ => None,
+    }
+}
+
+fn wrapped_double<W: WrappedRecord>(r: &mut W, idx: usize) -> Option<f64> {
+    match r.inner_columns()[idx] {
+        ColumnDefinition::Double { extractor, field, .. } => extractor(r.inner_mut(), field),
+        _ => None,
+    }
+}
+
+pub fn wrapped_text<W: WrappedRecord>(r: &mut W, idx: usize) -> Option<&str> {
+    match r.inner_columns()[idx] {
+        ColumnDefinition::Text { extractor, field, .. } | ColumnDefinition::Ip { extractor, field, .. } => extractor(r.inner_mut(), field),
+        _ => None,
+    }
+}
+
+pub fn wrapped_date<W: WrappedRecord>(r: &mut W, idx: usize) -> Option<&DateTime<Local>> {
+    match r.inner_columns()[idx] {
+        ColumnDefinition::Date { extractor, field, .. } => extractor(r.inner_mut(), field),
+        _ => None,
+    }
+}
+
+fn wrapped_boolean<W: WrappedRecord>(r: &mut W, idx: usize) -> Option<bool> {
+    match r.inner_columns()[idx] {
+        ColumnDefinition::Boolean { extractor, field, .. } => extractor(r.inner_mut(), field),
+        _ => None,
+    }
+}
+
+pub fn wrapped_address<W: WrappedRecord>(r: &mut W, idx: usize) -> Option<IpAddr> {
+    match r.inner_columns()[idx] {
+        ColumnDefinition::Ip { address_extractor, field, .. } => address_extractor(r.inner_mut(), field),
+        _ => None,
+    }
+}
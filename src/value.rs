@@ -0,0 +1,225 @@
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+
+use chrono::prelude::*;
+use serde_json::{self, Map};
+
+// A single typed value, read from a record's columns, computed by an expression or reducer, or used
+// as a group key, and only turned into text when a result row is rendered.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Int(u64),
+    Float(f64),
+    Text(String),
+    Date(DateTime<Local>),
+    Bool(bool),
+    // Values of a column with their number of occurrences, most frequent first
+    Counts(Vec<(String, u64)>),
+    Null,
+}
+
+impl Value {
+    pub fn is_null(&self) -> bool {
+        match self {
+            Value::Null => true,
+            _ => false,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(i) => Some(*i as f64),
+            Value::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    // The value as a number, text being parsed, e.g. to compute an expression over a text column.
+    pub fn to_number(&self) -> Option<Value> {
+        match self {
+            Value::Int(_) | Value::Float(_) => Some(self.clone()),
+            Value::Text(text) => text.parse::<u64>().map(Value::Int).ok().or_else(|| text.parse::<f64>().ok().map(Value::Float)),
+            _ => None,
+        }
+    }
+
+    // Sum of two numbers, which stays an integer when both are. Null unless both are numbers.
+    // Integers too large to be added saturate at the largest integer, see overflows.
+    pub fn add(&self, other: &Value) -> Value {
+        match (self, other) {
+            (Value::Int(i1), Value::Int(i2)) => Value::Int(i1.saturating_add(*i2)),
+            _ => match (self.as_f64(), other.as_f64()) {
+                (Some(f1), Some(f2)) => Value::Float(f1 + f2),
+                _ => Value::Null,
+            },
+        }
+    }
+
+    // Takes out a number added earlier, staying an integer when both are and the difference isn't
+    // negative. None unless both are numbers.
+    pub fn subtract(&self, other: &Value) -> Option<Value> {
+        match (self, other) {
+            (Value::Int(i1), Value::Int(i2)) if i1 >= i2 => Some(Value::Int(i1 - i2)),
+            _ => match (self.as_f64(), other.as_f64()) {
+                (Some(f1), Some(f2)) => Some(Value::Float(f1 - f2)),
+                _ => None,
+            },
+        }
+    }
+
+    // Whether the sum of two integers is too large to be one.
+    pub fn overflows(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Int(i1), Value::Int(i2)) => i1.checked_add(*i2).is_none(),
+            _ => false,
+        }
+    }
+
+    // Orders values of the same kind, numbers comparing across integers and floats. Null only
+    // equals null.
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Int(i1), Value::Int(i2)) => Some(i1.cmp(i2)),
+            (Value::Int(i), Value::Float(f)) => (*i as f64).partial_cmp(f),
+            (Value::Float(f), Value::Int(i)) => f.partial_cmp(&(*i as f64)),
+            (Value::Float(f1), Value::Float(f2)) => f1.partial_cmp(f2),
+            (Value::Text(t1), Value::Text(t2)) => Some(t1.cmp(t2)),
+            (Value::Date(d1), Value::Date(d2)) => Some(d1.cmp(d2)),
+            (Value::Bool(b1), Value::Bool(b2)) => Some(b1.cmp(b2)),
+            (Value::Null, Value::Null) => Some(Ordering::Equal),
+            _ => None,
+        }
+    }
+
+    pub fn to_display_string(&self) -> String {
+        match self {
+            Value::Int(i) => i.to_string(),
+            Value::Float(f) => format_float(*f),
+            Value::Text(t) => t.to_owned(),
+            Value::Date(d) => d.to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Counts(counts) =>
+                counts.iter().map(|&(ref value, count)| format!("{} ({})", value, count)).collect::<Vec<String>>().join(", "),
+            Value::Null => "null".to_owned(),
+        }
+    }
+
+    // The value as text, floats being shown with the number of decimals given rather than up to six.
+    pub fn to_precision_string(&self, precision: Option<usize>) -> String {
+        match (self, precision) {
+            (Value::Float(f), Some(precision)) if f.is_finite() => {
+                let formatted = format!("{:.*}", precision, f);
+                // Negative numbers rounded to zero aren't shown as -0.00
+                if formatted.starts_with('-') && formatted[1..].chars().all(|c| c == '0' || c == '.') { formatted[1..].to_owned() } else { formatted }
+            },
+            _ => self.to_display_string(),
+        }
+    }
+
+    // Floats rounded to the number of decimals given, e.g. for results written as json.
+    pub fn round(&self, precision: usize) -> Value {
+        match self {
+            Value::Float(f) if f.is_finite() => Value::Float(format!("{:.*}", precision, f).parse().unwrap_or(*f)),
+            value => value.clone(),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Int(i) => serde_json::Value::from(*i),
+            Value::Float(f) => serde_json::Value::from(*f),
+            Value::Text(t) => serde_json::Value::from(t.to_owned()),
+            Value::Date(d) => serde_json::Value::from(d.to_rfc3339()),
+            Value::Bool(b) => serde_json::Value::from(*b),
+            Value::Counts(counts) =>
+                serde_json::Value::from(counts.iter().map(|&(ref value, count)| {
+                    let mut object = Map::new();
+                    object.insert("value".to_owned(), serde_json::Value::from(value.to_owned()));
+                    object.insert("count".to_owned(), serde_json::Value::from(count));
+                    serde_json::Value::Object(object)
+                }).collect::<Vec<serde_json::Value>>()),
+            Value::Null => serde_json::Value::Null,
+        }
+    }
+
+    // The value tagged with its kind, e.g. {"date": "2019-03-01T10:00:00+01:00"}, so that unlike with
+    // to_json, dates and text read back as what they were. Used to save the state of aggregates.
+    pub fn to_state(&self) -> serde_json::Value {
+        let (kind, value) =
+            match self {
+                Value::Int(i) => ("int", serde_json::Value::from(*i)),
+                Value::Float(f) => ("float", serde_json::Value::from(*f)),
+                Value::Text(t) => ("text", serde_json::Value::from(t.to_owned())),
+                Value::Date(d) => ("date", serde_json::Value::from(d.to_rfc3339())),
+                Value::Bool(b) => ("bool", serde_json::Value::from(*b)),
+                Value::Counts(_) => ("counts", self.to_json()),
+                Value::Null => return serde_json::Value::Null,
+            };
+        let mut object = Map::new();
+        object.insert(kind.to_owned(), value);
+        serde_json::Value::Object(object)
+    }
+
+    // None when the state wasn't written by to_state.
+    pub fn from_state(state: &serde_json::Value) -> Option<Value> {
+        let object = match state {
+            serde_json::Value::Null => return Some(Value::Null),
+            serde_json::Value::Object(object) if object.len() == 1 => object,
+            _ => return None,
+        };
+        let (kind, value) = object.iter().next().unwrap();
+        match kind.as_str() {
+            "int" => value.as_u64().map(Value::Int),
+            // Json has no NaN, which serde_json writes as null
+            "float" => if value.is_null() { Some(Value::Float(::std::f64::NAN)) } else { value.as_f64().map(Value::Float) },
+            "text" => value.as_str().map(|t| Value::Text(t.to_owned())),
+            "date" => value.as_str().and_then(|d| DateTime::parse_from_rfc3339(d).ok()).map(|d| Value::Date(d.with_timezone(&Local))),
+            "bool" => value.as_bool().map(Value::Bool),
+            "counts" => value.as_array().and_then(|counts| counts.iter().map(|c| {
+                Some((c.get("value")?.as_str()?.to_owned(), c.get("count")?.as_u64()?))
+            }).collect::<Option<Vec<(String, u64)>>>()).map(Value::Counts),
+            _ => None,
+        }
+    }
+}
+
+// Floats are shown with at most six decimals, so sums and averages don't print floating point
+// noise like 0.30000000000000004.
+pub fn format_float(value: f64) -> String {
+    let formatted = format!("{:.6}", value);
+    let trimmed = formatted.trim_end_matches('0').trim_end_matches('.');
+    if trimmed == "-0" { "0".to_owned() } else { trimmed.to_owned() }
+}
+
+// Values are group keys, so floats are equal, and hash the same, when their bits are; unlike with
+// compare, an integer never equals a float.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Int(i1), Value::Int(i2)) => i1 == i2,
+            (Value::Float(f1), Value::Float(f2)) => f1.to_bits() == f2.to_bits(),
+            (Value::Text(t1), Value::Text(t2)) => t1 == t2,
+            (Value::Date(d1), Value::Date(d2)) => d1 == d2,
+            (Value::Bool(b1), Value::Bool(b2)) => b1 == b2,
+            (Value::Counts(c1), Value::Counts(c2)) => c1 == c2,
+            (Value::Null, Value::Null) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Value::Int(i) => { 0u8.hash(state); i.hash(state) },
+            Value::Float(f) => { 1u8.hash(state); f.to_bits().hash(state) },
+            Value::Text(t) => { 2u8.hash(state); t.hash(state) },
+            Value::Date(d) => { 3u8.hash(state); d.hash(state) },
+            Value::Bool(b) => { 4u8.hash(state); b.hash(state) },
+            Value::Counts(c) => { 5u8.hash(state); c.hash(state) },
+            Value::Null => 6u8.hash(state),
+        }
+    }
+}
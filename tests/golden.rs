@@ -0,0 +1,491 @@
+// Runs queries over the nginx logs of tests/fixtures through the riplog binary, comparing what it
+// writes with the golden files of tests/golden. Golden files are written rather than compared when
+// RIPLOG_BLESS is set, e.g. `RIPLOG_BLESS=1 cargo test --test golden`, to be reviewed as any change.
+
+extern crate chrono;
+extern crate rusqlite;
+extern crate serde_json;
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+
+const FIXTURE: &str = "access.log";
+
+// The fixture's format written as an nginx log_format, to read it with --log-format
+const LOG_FORMAT: &str = "$remote_addr - $remote_user [$time_local] \"$request\" $status $body_bytes_sent \"$http_referer\" \"$http_user_agent\" $request_time $upstream_response_time";
+
+// Name of the golden file, and arguments given before the fixture
+const CASES: &[(&str, &[&str])] = &[
+    ("show_columns", &["show ip, method, path, status, bytes"]),
+    ("filter_and_or", &["(status >= 500 || status = 404) && method != \"GET\" | show date, method, path, status"]),
+    ("filter_in", &["method in (\"PUT\", \"DELETE\") | show ip, method, status"]),
+    ("filter_regex", &["path ~ r\"^/api/\" | show path, route, status"]),
+    ("filter_null", &["username is not null | show username, path"]),
+    ("group_status_class", &["group status_class | show count(*), sum(bytes) | sort status_class"]),
+    ("group_route", &["group route | show count(*), avg(request_time), max(bytes) | sort count(*) desc, route | limit 5"]),
+    ("group_time", &["group time(10m) | show count(*), unique(method)"]),
+    ("group_bucket", &["group bucket(bytes, 10000) | show count(*) | sort group"]),
+    ("having", &["group ip | show count(*) as hits, sum(bytes) | having hits > 8 | sort hits desc"]),
+    ("aggregate", &["show count(*), sum(bytes), min(request_time), max(request_time), count_distinct(ip)"]),
+    ("rate", &["group method | show count(*), rate(1m), rate(1h) | sort method"]),
+    ("ratio", &["group method | show count(*), ratio(status >= 500), ratio(status = 404 && bytes > 10000) | sort method"]),
+    ("percentile", &["group method | show count(*), p(request_time, 50), p(request_time, 95), p(upstream_time, 95), p(bytes, 99) | sort p(request_time, 95) desc"]),
+    ("nginx_time", &["upstream_time is not null | show request_time, upstream_time, nginx_time | sort nginx_time desc | limit 6"]),
+    ("saved_builtin", &["--saved", "slow-upstreams"]),
+    ("saved_builtin_log_format", &["--log-format", LOG_FORMAT, "--saved", "slow-upstreams"]),
+    ("expression", &["status >= 400 | show path, bytes / 1024 as kb, lower(method) | limit 6"]),
+    ("stage", &["group ip, method | show count(*) ~> group ip | show count(*) as methods | sort ip"]),
+    ("stage_sorted", &["group ip | show count(*) as hits ~> hits > 8 | sort hits desc"]),
+    ("output_json", &["--output", "json", "group method | show count(*) | sort method"]),
+    ("output_ndjson", &["--output", "ndjson", "status = 200 | show ip, path, bytes | limit 5"]),
+    ("filter_cidr", &["ip not in_cidr (\"10.0.0.0/8\", \"192.168.1.20/16\", \"fd00::/8\") || ip in_cidr \"10.0.0.7\" | group ip | show count(*) | sort ip"]),
+    ("filter_cidr_integer", &["status in_cidr \"10.0.0.0/8\" | show count(*)"]),
+    ("sessionize", &["--sessionize", "5m", "ip in_cidr \"10.0.0.0/8\" | show date, ip, session_id, session_start, session_requests, session_duration"]),
+    ("output_json_nested", &["--output", "json", "--nest", "group method, status_class | show count(*), sum(bytes) | sort method, status_class"]),
+    ("output_json_nested_leaf", &["--output", "json", "--nest", "group status_class, method | show count(*) | sort count(*) desc, method"]),
+    ("sample_every", &["--sample-every", "4", "--stats", "status >= 400 | group status_class | show count(*) | sort status_class"]),
+    ("human", &["--human", "group status_class | show sum(bytes) | sort status_class"]),
+];
+
+// Name of the golden file, fixture read, and arguments given before it
+const FIXTURE_CASES: &[(&str, &str, &[&str])] = &[
+    ("every_late_lines", "late.log", &["show count(*) every 1m"]),
+    ("gzip_two_members", "two_members.log.gz", &["show count(*), count_distinct(ip)"]),
+    ("gzip_truncated_trailer", "truncated.log.gz", &["show count(*), count_distinct(ip)"]),
+];
+
+fn fixture_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures")
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden").join(format!("{}.txt", name))
+}
+
+// Runs riplog as it's run from a shell, in UTC and without the user's configuration, returning its
+// exit code and what it wrote to stdout and stderr.
+fn run_riplog(arguments: &[&str]) -> (i32, String, String) {
+    run_riplog_on(FIXTURE, arguments)
+}
+
+fn run_riplog_on(fixture: &str, arguments: &[&str]) -> (i32, String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_riplog"))
+        .args(arguments)
+        .arg(fixture_dir().join(fixture))
+        .current_dir(fixture_dir())
+        .env("TZ", "UTC")
+        .env("XDG_CONFIG_HOME", fixture_dir().join("config"))
+        .output()
+        .expect("riplog runs");
+    (output.status.code().unwrap_or(-1), String::from_utf8_lossy(&output.stdout).to_string(), String::from_utf8_lossy(&output.stderr).to_string())
+}
+
+// What a run is compared by: the rows written, and warnings or errors if there were any.
+fn transcript(code: i32, stdout: &str, stderr: &str) -> String {
+    let mut transcript = stdout.to_owned();
+    if !stderr.is_empty() {
+        transcript.push_str("--- stderr\n");
+        transcript.push_str(&stderr.replace(&fixture_dir().to_string_lossy() as &str, "<fixtures>"));
+    }
+    if code != 0 {
+        transcript.push_str(&format!("--- exit code {}\n", code));
+    }
+    transcript
+}
+
+fn check_golden(name: &str, actual: &str) {
+    let path = golden_path(name);
+    if env::var_os("RIPLOG_BLESS").is_some() {
+        fs::write(&path, actual).expect("golden file is written");
+        return;
+    }
+    let expected = fs::read_to_string(&path).unwrap_or_else(|_| panic!("missing golden file {}, written with RIPLOG_BLESS=1", path.display()));
+    if expected != actual {
+        panic!("output of '{}' differs from {}\n--- expected\n{}--- actual\n{}", name, path.display(), expected, actual);
+    }
+}
+
+#[test]
+fn queries_match_golden_output() {
+    let mut failures = Vec::new();
+    let cases = CASES.iter().map(|&(name, arguments)| (name, FIXTURE, arguments)).chain(FIXTURE_CASES.iter().cloned());
+    for (name, fixture, arguments) in cases {
+        let (code, stdout, stderr) = run_riplog_on(fixture, arguments);
+        let actual = transcript(code, &stdout, &stderr);
+        if let Err(e) = ::std::panic::catch_unwind(|| check_golden(name, &actual)) {
+            failures.push(e.downcast_ref::<String>().cloned().unwrap_or(name.to_owned()));
+        }
+    }
+    let total = CASES.len() + FIXTURE_CASES.len();
+    assert!(failures.is_empty(), "{} of {} golden outputs differ:\n\n{}", failures.len(), total, failures.join("\n\n"));
+}
+
+#[test]
+fn unknown_column_is_reported() {
+    let (code, stdout, stderr) = run_riplog(&["group stauts | show count(*)"]);
+    check_golden("unknown_column", &transcript(code, &stdout, &stderr));
+}
+
+#[test]
+fn dropped_show_column_is_warned_about() {
+    let (code, stdout, stderr) = run_riplog(&["group status_class | show method, count(*) | sort status_class"]);
+    check_golden("dropped_show_column", &transcript(code, &stdout, &stderr));
+}
+
+#[test]
+fn quiet_exits_by_whether_a_line_matched() {
+    assert_eq!(run_riplog(&["-q", "status = 502"]), (0, String::new(), String::new()));
+    assert_eq!(run_riplog(&["-q", "status = 418"]), (1, String::new(), String::new()));
+}
+
+// Output written so far by a riplog run, read on another thread so it can be checked while running
+fn collect_output<R: Read + Send + 'static>(mut stream: R) -> Arc<Mutex<String>> {
+    let output = Arc::new(Mutex::new(String::new()));
+    let collected = output.clone();
+    thread::spawn(move || {
+        let mut buf = [0; 4096];
+        while let Ok(size) = stream.read(&mut buf) {
+            if size == 0 {
+                break;
+            }
+            collected.lock().unwrap().push_str(&String::from_utf8_lossy(&buf[..size]));
+        }
+    });
+    output
+}
+
+fn wait_for_output(output: &Arc<Mutex<String>>, text: &str, timeout: Duration) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if output.lock().unwrap().contains(text) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    false
+}
+
+#[test]
+fn followed_windows_are_printed_once_they_end() {
+    let path = env::temp_dir().join(format!("riplog-follow-{}.log", std::process::id()));
+    fs::write(&path, "").unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_riplog"))
+        .args(&["--follow", "--interval", "1", "group method | show count(*) | every 1m"])
+        .arg(&path)
+        .env("TZ", "UTC")
+        .env("XDG_CONFIG_HOME", fixture_dir().join("config"))
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("riplog runs");
+    let output = collect_output(child.stdout.take().unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    // Lines of a window that has already ended, which nothing later in the log closes
+    let date = (Utc::now() - chrono::Duration::minutes(2)).format("%d/%b/%Y:%H:%M:00 +0000");
+    let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+    for _ in 0..3 {
+        writeln!(log, "10.0.0.1 - - [{}] \"GET / HTTP/1.1\" 200 5 \"-\" \"curl/8.4.0\" 0.1 0.1", date).unwrap();
+    }
+    let printed = wait_for_output(&output, "Window:", Duration::from_secs(10));
+    thread::sleep(Duration::from_millis(1500));
+
+    Command::new("kill").arg("-INT").arg(child.id().to_string()).status().unwrap();
+    child.wait().unwrap();
+    fs::remove_file(&path).unwrap();
+    let output = output.lock().unwrap().clone();
+    assert!(printed, "the window wasn't printed while following:\n{}", output);
+    assert!(output.starts_with("Window:"), "rows were printed before the window's label:\n{}", output);
+    assert_eq!(output.matches("Window:").count(), 1, "the window was printed more than once:\n{}", output);
+    assert_eq!(output.matches("| GET    | 3 ").count(), 1, "{}", output);
+}
+
+#[test]
+fn followed_sliding_windows_keep_the_last_lines_of_the_log() {
+    let path = env::temp_dir().join(format!("riplog-window-{}.log", std::process::id()));
+    fs::write(&path, "").unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_riplog"))
+        .args(&["--follow", "--interval", "1", "--window", "5m", "group method | show count(*), sum(bytes), max(bytes)"])
+        .arg(&path)
+        .env("TZ", "UTC")
+        .env("XDG_CONFIG_HOME", fixture_dir().join("config"))
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("riplog runs");
+    let output = collect_output(child.stdout.take().unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    // Lines written an hour behind the clock, which the window is kept at, then lines seven minutes
+    // later that the first ones age out before. Sums are taken out of the window as lines age out,
+    // while maximums are recomputed.
+    let write_line = |method: &str, minutes: i64, bytes: u64| {
+        let date = (Utc::now() - chrono::Duration::minutes(minutes)).format("%d/%b/%Y:%H:%M:%S +0000");
+        let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(log, "10.0.0.1 - - [{}] \"{} / HTTP/1.1\" 200 {} \"-\" \"curl/8.4.0\" 0.1 0.1", date, method, bytes).unwrap();
+    };
+    let rows = |output: &Arc<Mutex<String>>| {
+        let output = output.lock().unwrap().clone();
+        let last = output.rfind("Window:").map(|idx| output[idx..].to_owned()).unwrap_or(String::new());
+        last.split_whitespace().collect::<Vec<&str>>().join(" ")
+    };
+    let wait_for_rows = |text: &str| {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) && !rows(&output).contains(text) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        rows(&output)
+    };
+    write_line("GET", 60, 900);
+    write_line("DELETE", 60, 5);
+    write_line("GET", 57, 20);
+    let first = wait_for_rows("| GET | 2 | 920 | 900 |");
+    write_line("GET", 53, 40);
+    write_line("POST", 53, 40);
+    let second = wait_for_rows("| POST | 1 | 40 | 40 |");
+
+    Command::new("kill").arg("-INT").arg(child.id().to_string()).status().unwrap();
+    child.wait().unwrap();
+    fs::remove_file(&path).unwrap();
+    assert!(first.contains("| DELETE | 1 | 5 | 5 | | GET | 2 | 920 | 900 |"), "the lines behind the clock weren't kept in the window:\n{}", first);
+    assert!(second.contains("| GET | 2 | 60 | 40 | | POST | 1 | 40 | 40 |"), "the lines that aged out were still summarized:\n{}", second);
+    assert!(!second.contains("DELETE"), "a group whose lines aged out was still summarized:\n{}", second);
+}
+
+#[test]
+fn relative_dates_are_resolved_against_the_clock() {
+    // Lines whose method tells how long ago they were written
+    let path = env::temp_dir().join(format!("riplog-relative-{}.log", std::process::id()));
+    let now = Utc::now();
+    let ages = [("OPTIONS", 49 * 60), ("PUT", 3 * 60), ("POST", 90), ("GET", 5)];
+    let lines = ages.iter().map(|&(method, minutes)| {
+        let date = (now - chrono::Duration::minutes(minutes)).format("%d/%b/%Y:%H:%M:%S +0000");
+        format!("10.0.0.1 - - [{}] \"{} / HTTP/1.1\" 200 5 \"-\" \"curl/8.4.0\" 0.1 0.1\n", date, method)
+    });
+    fs::write(&path, lines.collect::<String>()).unwrap();
+
+    let today = ages.iter().filter(|&&(_, minutes)| (now - chrono::Duration::minutes(minutes)).date() == now.date()).map(|&(method, _)| method);
+    let cases = vec![
+        ("d\"-1h\"", vec!["GET"]),
+        ("d\"-2h\"", vec!["POST", "GET"]),
+        ("d\"-2d\"", vec!["PUT", "POST", "GET"]),
+        ("d\"yesterday\"", vec!["PUT", "POST", "GET"]),
+        ("d\"today\"", today.collect()),
+    ];
+    for (date, expected) in cases {
+        let (code, stdout, stderr) = run_riplog_on(&path.to_string_lossy(), &["--output", "ndjson", &format!("date >= {} | show method", date)]);
+        let rows = stdout.lines().map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap()).collect::<Vec<_>>();
+        let methods = rows.iter().map(|r| r["method"].as_str().unwrap()).collect::<Vec<&str>>();
+        assert_eq!((code, methods, stderr.as_str()), (0, expected, ""), "date >= {}", date);
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn percentiles_of_many_values_are_kept_in_a_bounded_sketch() {
+    let dir = env::temp_dir().join(format!("riplog-percentile-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let log = dir.join("access.log");
+    let partial = dir.join("partial.json");
+
+    // Request times of 0.000 to 19.999 seconds, in an order that isn't sorted
+    let mut lines = String::new();
+    for idx in 0..20000u64 {
+        let time = (idx * 7919 % 20000) as f64 / 1000.0;
+        lines.push_str(&format!("10.0.0.1 - - [16/Oct/2026:10:00:00 +0000] \"GET / HTTP/1.1\" 200 5 \"-\" \"curl/8.4.0\" {:.3} {:.3}\n", time, time));
+    }
+    fs::write(&log, lines).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_riplog"))
+        .args(&["--emit-partial"]).arg(&partial)
+        .arg("show p(request_time, 50) as p50, p(request_time, 99) as p99")
+        .arg(&log)
+        .env("XDG_CONFIG_HOME", fixture_dir().join("config"))
+        .status()
+        .expect("riplog runs");
+    let merged = Command::new(env!("CARGO_BIN_EXE_riplog"))
+        .args(&["merge", "--output", "ndjson"]).arg(&partial)
+        .env("XDG_CONFIG_HOME", fixture_dir().join("config"))
+        .output()
+        .expect("riplog runs");
+    let size = fs::metadata(&partial).unwrap().len();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(status.success());
+    assert!(size < 32 * 1024, "the partial state of 20000 values is {} bytes", size);
+    let row: serde_json::Value = serde_json::from_slice(&merged.stdout).expect("merged row is json");
+    let (p50, p99) = (row["p50"].as_f64().unwrap(), row["p99"].as_f64().unwrap());
+    assert!((p50 - 9.999).abs() < 0.1, "p50 is {}", p50);
+    assert!((p99 - 19.799).abs() < 0.05, "p99 is {}", p99);
+}
+
+#[test]
+fn indexed_logs_are_queried_like_the_plain_log() {
+    let dir = env::temp_dir().join(format!("riplog-index-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let log = dir.join(FIXTURE);
+    fs::copy(fixture_dir().join(FIXTURE), &log).unwrap();
+    let run = |arguments: &[&str]| {
+        let (code, stdout, stderr) = run_riplog_on(&dir.to_string_lossy(), arguments);
+        transcript(code, &stdout, &stderr).replace(&dir.to_string_lossy() as &str, "<dir>")
+    };
+    let query = "group method | show count(*), sum(bytes) | sort method";
+    let (_, plain, _) = run_riplog(&[query]);
+
+    // Indexed once, and left alone while the log doesn't change, the index answering queries and
+    // pruning the log from those of dates it doesn't hold
+    let mut actual = run(&["index"]);
+    actual.push_str(&run(&["index"]));
+    assert!(dir.join(".riplog").join("access.log.idx").exists());
+    assert_eq!(run(&[query]), plain);
+    actual.push_str(&run(&["--stats", "date >= d\"10-17-2026 00:00:00 +0000\" | show count(*)"]));
+
+    // Rebuilt once the log changes, which until then is read as if it had no index
+    let mut appended = OpenOptions::new().append(true).open(&log).unwrap();
+    writeln!(appended, "10.0.0.1 - - [17/Oct/2026:00:00:01 +0000] \"GET / HTTP/1.1\" 200 5 \"-\" \"curl/8.4.0\" 0.1 0.1").unwrap();
+    actual.push_str(&run(&["date >= d\"10-17-2026 00:00:00 +0000\" | show count(*)"]));
+    actual.push_str(&run(&["index"]));
+    fs::remove_dir_all(&dir).unwrap();
+    check_golden("index", &actual);
+}
+
+#[test]
+fn sqlite_output_writes_the_rows_to_a_results_table() {
+    let path = env::temp_dir().join(format!("riplog-sqlite-{}.db", std::process::id()));
+    let output = format!("sqlite:{}", path.display());
+    let query = "group method | show count(*) as hits, avg(request_time), max(date) as last, max(upstream_time) | sort method";
+    // Run twice, the table being replaced rather than appended to
+    run_riplog(&["--output", &output, "status = 200 | show path"]);
+    let (code, stdout, stderr) = run_riplog(&["--output", &output, query]);
+    let mut actual = transcript(code, &stdout, &stderr);
+
+    // The table's definition, then its rows with the type each value is stored as
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    let schema: String = connection.query_row("SELECT sql FROM sqlite_master WHERE name = 'results'", [], |row| row.get(0)).unwrap();
+    actual.push_str(&format!("{}\n", schema));
+    let mut statement = connection.prepare("SELECT * FROM results").unwrap();
+    let columns = statement.column_count();
+    let mut rows = statement.query([]).unwrap();
+    while let Some(row) = rows.next().unwrap() {
+        let values = (0..columns).map(|idx| format!("{:?}", row.get::<_, rusqlite::types::Value>(idx).unwrap())).collect::<Vec<String>>();
+        actual.push_str(&format!("{}\n", values.join(" | ")));
+    }
+    drop(rows);
+    drop(statement);
+    drop(connection);
+    fs::remove_file(&path).unwrap();
+    check_golden("output_sqlite", &actual);
+}
+
+#[test]
+fn compressed_logs_are_read_like_the_plain_log() {
+    let query = "group method | show count(*), sum(bytes), count_distinct(ip) | sort method";
+    let (_, expected, _) = run_riplog(&[query]);
+    assert!(expected.contains("| GET "), "{}", expected);
+
+    // By extension, and by their magic bytes once rotated to a name without one
+    let dir = env::temp_dir().join(format!("riplog-compressed-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for (rotation, fixture) in ["two_members.log.gz", "access.log.zst", "access.log.bz2", "access.log.xz"].iter().enumerate() {
+        let rotated = dir.join(format!("access.log.{}", rotation + 1));
+        fs::copy(fixture_dir().join(fixture), &rotated).unwrap();
+        for path in &[fixture_dir().join(fixture), rotated] {
+            let (code, stdout, stderr) = run_riplog_on(&path.to_string_lossy(), &[query]);
+            assert_eq!((code, stdout.as_str(), stderr.as_str()), (0, expected.as_str(), ""), "reading {}", path.display());
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn local_logs_and_bucket_objects_are_queried_together() {
+    let dir = env::temp_dir().join(format!("riplog-objects-{}", std::process::id()));
+    fs::create_dir_all(dir.join("nginx")).unwrap();
+    fs::copy(fixture_dir().join(FIXTURE), dir.join("nginx").join(FIXTURE)).unwrap();
+
+    // An aws command listing one ALB log under s3://logs/alb/ and streaming it, of a 502 the target
+    // answered and two it never did
+    let alb_line = |target_status: &str| format!("https 2026-10-16T10:00:47.000000Z app/my-lb/50dc6c495c0c9188 192.168.131.39:2817 10.0.0.1:80 0.000 0.001 0.000 502 {} 34 366 \
+                                                  \"GET https://www.example.com:443/index.html HTTP/1.1\" \"curl/8.4.0\" ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2 - \"Root=1-58337262\" \"www.example.com\"\n", target_status);
+    fs::write(dir.join("alb.log"), [alb_line("502"), alb_line("-"), alb_line("-")].concat()).unwrap();
+    fs::write(dir.join("aws.sh"), format!("case \"$1 $2\" in\n\
+                                           \"s3 ls\") echo '2026-10-16 10:05:00       1098 alb/123456789012_elasticloadbalancing_us-east-1_app.my-lb.log' ;;\n\
+                                           \"s3 cp\") cat '{}' ;;\n\
+                                           esac\n", dir.join("alb.log").display())).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_riplog"))
+        .args(&["--format", "auto", "--output", "ndjson", "status = 502 | group format | show count(*), count_distinct(target_status_code) | sort format"])
+        .arg(dir.join("nginx"))
+        .arg("s3://logs/alb/")
+        .env("RIPLOG_AWS", format!("sh {}", dir.join("aws.sh").display()))
+        .env("XDG_CONFIG_HOME", fixture_dir().join("config"))
+        .output()
+        .expect("riplog runs");
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+    assert_eq!(String::from_utf8_lossy(&output.stdout),
+               "{\"format\":\"alb\",\"count(*)\":3,\"count_distinct(target_status_code)\":1}\n\
+                {\"format\":\"nginx\",\"count(*)\":3,\"count_distinct(target_status_code)\":0}\n");
+}
+
+#[test]
+fn checkpoints_leave_a_line_still_being_written_for_the_next_run() {
+    let line = "10.0.0.1 - - [16/Oct/2026:10:00:00 +0000] \"GET / HTTP/1.1\" 200 5 \"-\" \"curl/8.4.0\" 0.1 0.1\n";
+    // Both with the file read on another thread and on the thread evaluating it
+    for threads in &["1", "4"] {
+        let dir = env::temp_dir().join(format!("riplog-checkpoint-{}-{}", std::process::id(), threads));
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("access.log");
+        let checkpoint = dir.join("checkpoint.json");
+        let run = || {
+            let output = Command::new(env!("CARGO_BIN_EXE_riplog"))
+                .args(&["--threads", threads, "--output", "ndjson", "--checkpoint"]).arg(&checkpoint)
+                .arg("show count(*)")
+                .arg(&log)
+                .env("XDG_CONFIG_HOME", fixture_dir().join("config"))
+                .output()
+                .expect("riplog runs");
+            (String::from_utf8_lossy(&output.stdout).to_string(), String::from_utf8_lossy(&output.stderr).to_string())
+        };
+
+        // Three lines and half of a fourth, whose rest is appended between the runs
+        let (first, rest) = line.split_at(40);
+        fs::write(&log, [line, line, line, first].concat()).unwrap();
+        let before = run();
+        OpenOptions::new().append(true).open(&log).unwrap().write_all(rest.as_bytes()).unwrap();
+        let after = run();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(before, ("{\"count(*)\":3}\n".to_owned(), String::new()), "with {} threads", threads);
+        assert_eq!(after, ("{\"count(*)\":4}\n".to_owned(), String::new()), "with {} threads", threads);
+    }
+}
+
+#[test]
+fn results_piped_to_a_reader_that_exits_stop_cleanly() {
+    let path = env::temp_dir().join(format!("riplog-pipe-{}.log", std::process::id()));
+    let fixture = fs::read_to_string(fixture_dir().join(FIXTURE)).unwrap();
+    fs::write(&path, fixture.repeat(300)).unwrap();
+    // As with `| head -1`, the reader takes the first line and goes away
+    for output in &["table", "ndjson", "json"] {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_riplog"))
+            .args(&["--no-pager", "--unbuffered", "--stream", "--output", output, "show ip, path"])
+            .arg(&path)
+            .env("XDG_CONFIG_HOME", fixture_dir().join("config"))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("riplog runs");
+        let mut first = [0; 1];
+        child.stdout.take().unwrap().read_exact(&mut first).unwrap();
+        let result = child.wait_with_output().unwrap();
+        assert_eq!((result.status.code(), String::from_utf8_lossy(&result.stderr).to_string()), (Some(0), String::new()), "with --output {}", output);
+    }
+    fs::remove_file(&path).unwrap();
+}
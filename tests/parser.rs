@@ -0,0 +1,616 @@
+// Property tests of the query parser: queries generated at random are parsed, written back as query
+// text from their syntax tree and parsed again, which must result in the same tree. Queries are
+// generated from fixed seeds so that failures can be reproduced, the seed being part of the message.
+// Log formats are tested below, by the columns read from their lines.
+
+extern crate chrono;
+extern crate riplog;
+
+use std::sync::Arc;
+
+use chrono::prelude::*;
+use riplog::apache::ApacheFormat;
+use riplog::auto::AutoFormat;
+use riplog::aws::{AwsFormat, AwsLog};
+use riplog::container::ContainerFormat;
+use riplog::csv::CsvFormat;
+use riplog::custom::CustomFormat;
+use riplog::format::LogFormat;
+use riplog::json::{self, JsonFormat};
+use riplog::nginx::NginxFormat;
+use riplog::nginx_error::NginxErrorFormat;
+use riplog::syslog::SyslogFormat;
+use riplog::parser::*;
+use riplog::route::RouteNormalizer;
+use riplog::table::{ColumnDefinition, TableDefinition};
+
+const CASES: u64 = 1000;
+
+// xorshift64*, enough to generate queries without depending on a crate of random numbers
+struct Generator {
+    state: u64,
+}
+
+impl Generator {
+    fn new(seed: u64) -> Generator {
+        Generator { state: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1 }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+
+    fn chance(&mut self, percent: u64) -> bool {
+        self.below(100) < percent
+    }
+
+    fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+        items[self.below(items.len() as u64) as usize]
+    }
+}
+
+const COLUMNS: &[&str] = &["ip", "username", "date", "method", "path", "status", "bytes", "request_time", "route", "status_class"];
+const TEXTS: &[&str] = &["GET", "/api/users", "", "a b", "x=1&y=2", "Mozilla/5.0 (X11)", "ünïcödé"];
+const REGEXES: &[&str] = &["^/api/", "\\.php$", "(?i)bot", "^[0-9]+$", "a|b"];
+const DATES: &[&str] = &["10-16-2026", "10-16-2026 10:00:00", "10-16-2026 10:00:00 +0200"];
+const OPERATORS: &[&str] = &["<", ">", "<=", ">=", "=", "!=", "~", "!~"];
+const REDUCERS: &[&str] = &["count", "sum", "max", "min", "avg", "unique", "count_distinct", "approx_unique", "first", "last", "stddev", "var"];
+const FUNCTIONS: &[(&str, usize)] = &[("lower", 1), ("substr", 2), ("substr", 3), ("split", 3), ("hour", 1), ("dayofweek", 1), ("day", 1), ("bucket", 2)];
+const DURATIONS: &[&str] = &["30s", "5m", "1h", "1d"];
+
+fn generate_expression(generator: &mut Generator, depth: u32, reducers: bool) -> String {
+    let choice = if depth == 0 { generator.below(4) } else { generator.below(7) };
+    match choice {
+        0 => generator.pick(COLUMNS).to_owned(),
+        1 => generator.below(100000).to_string(),
+        2 => format!("{}.{}", generator.below(1000), generator.below(100)),
+        3 if reducers => format!("{}({})", generator.pick(REDUCERS), generator.pick(COLUMNS)),
+        3 => format!("\"{}\"", generator.pick(TEXTS)),
+        4 | 5 => {
+            let op = generator.pick(&["+", "-", "*", "/"]);
+            let left = generate_expression(generator, depth - 1, reducers);
+            let right = generate_expression(generator, depth - 1, reducers);
+            if generator.chance(30) { format!("({} {} {})", left, op, right) } else { format!("{} {} {}", left, op, right) }
+        },
+        _ => {
+            let &(function, arity) = &FUNCTIONS[generator.below(FUNCTIONS.len() as u64) as usize];
+            let arguments: Vec<String> = (0..arity).map(|_| generate_expression(generator, depth - 1, reducers)).collect();
+            format!("{}({})", function, arguments.join(", "))
+        },
+    }
+}
+
+// Expressions compute a value, as plain columns and literals are parsed as such rather than as expressions
+fn generate_computed_expression(generator: &mut Generator, reducers: bool) -> String {
+    let left = generate_expression(generator, 2, reducers);
+    let right = generate_expression(generator, 2, reducers);
+    format!("{} {} {}", left, generator.pick(&["+", "-", "*", "/"]), right)
+}
+
+fn generate_operand(generator: &mut Generator) -> String {
+    match generator.below(9) {
+        0 | 1 => generator.pick(COLUMNS).to_owned(),
+        2 => format!("\"{}\"", generator.pick(TEXTS)),
+        3 => format!("r\"{}\"", generator.pick(REGEXES)),
+        4 => format!("d\"{}\"", generator.pick(DATES)),
+        5 => generator.pick(&["true", "false", "null"]).to_owned(),
+        6 => generator.below(1000).to_string(),
+        7 => format!("{}.{}", generator.below(100), generator.below(1000)),
+        _ => generate_computed_expression(generator, false),
+    }
+}
+
+fn generate_filter(generator: &mut Generator, depth: u32) -> String {
+    let choice = if depth == 0 { generator.below(3) } else { generator.below(6) };
+    match choice {
+        0 => format!("{} {} {}", generate_operand(generator), generator.pick(OPERATORS), generate_operand(generator)),
+        1 => {
+            let values: Vec<String> = (0..generator.below(3) + 1).map(|_| generate_operand(generator)).collect();
+            format!("{} {}in ({})", generator.pick(COLUMNS), if generator.chance(50) { "not " } else { "" }, values.join(", "))
+        },
+        2 => format!("{} is {}null", generator.pick(COLUMNS), if generator.chance(50) { "not " } else { "" }),
+        3 => format!("({})", generate_filter(generator, depth - 1)),
+        4 => format!("{} {} {}", generate_filter(generator, depth - 1), generator.pick(&["and", "&&", "AND"]), generate_filter(generator, depth - 1)),
+        _ => format!("{} {} {}", generate_filter(generator, depth - 1), generator.pick(&["or", "||", "OR"]), generate_filter(generator, depth - 1)),
+    }
+}
+
+fn generate_reducer(generator: &mut Generator) -> String {
+    match generator.below(6) {
+        0 => format!("top({}, {})", generator.below(10) + 1, generator.pick(COLUMNS)),
+        4 => format!("p({}, {})", generator.pick(COLUMNS), generator.below(101)),
+        1 => format!("rate({})", generator.pick(DURATIONS)),
+        2 => format!("ratio({})", generate_filter(generator, 1)),
+        3 => "count(*)".to_owned(),
+        _ => format!("{}({})", generator.pick(REDUCERS), generator.pick(COLUMNS)),
+    }
+}
+
+fn generate_query(generator: &mut Generator, stages: u32) -> String {
+    let mut clauses = Vec::new();
+    if generator.chance(70) {
+        clauses.push(generate_filter(generator, 3));
+    }
+    let grouped = generator.chance(50);
+    if grouped {
+        let groupings: Vec<String> = (0..generator.below(3) + 1).map(|_| match generator.below(4) {
+            0 => format!("time({})", generator.pick(DURATIONS)),
+            1 => format!("bucket(date, {})", generator.pick(DURATIONS)),
+            2 => generate_computed_expression(generator, false),
+            _ => generator.pick(COLUMNS).to_owned(),
+        }).collect();
+        clauses.push(format!("group {}", groupings.join(", ")));
+    }
+    if generator.chance(80) {
+        let elements: Vec<String> = (0..generator.below(4) + 1).map(|idx| {
+            let element =
+                match generator.below(5) {
+                    0 if idx == 0 && !grouped => "*".to_owned(),
+                    0 | 1 => generate_reducer(generator),
+                    2 => {
+                        let reducers = generator.chance(50);
+                        generate_computed_expression(generator, reducers)
+                    },
+                    _ => generator.pick(COLUMNS).to_owned(),
+                };
+            if generator.chance(20) { format!("{} as alias_{}", element, idx) } else { element }
+        }).collect();
+        clauses.push(format!("show {}", elements.join(", ")));
+    }
+    if generator.chance(30) {
+        clauses.push(format!("having {}", generate_filter(generator, 1)));
+    }
+    if generator.chance(40) {
+        let sortings: Vec<String> = (0..generator.below(2) + 1).map(|_| {
+            let field =
+                match generator.below(4) {
+                    0 | 1 => "count(*)".to_owned(),
+                    2 => format!("p({}, {})", generator.pick(COLUMNS), generator.below(101)),
+                    _ => generator.pick(COLUMNS).to_owned(),
+                };
+            format!("{}{}", field, generator.pick(&["", " asc", " desc", " DESC"]))
+        }).collect();
+        clauses.push(format!("sort {}", sortings.join(", ")));
+    }
+    if generator.chance(30) {
+        clauses.push(format!("limit {}", generator.below(1000)));
+    }
+    if generator.chance(10) {
+        clauses.push(format!("every {}", generator.pick(DURATIONS)));
+    }
+    let query = clauses.join(" | ");
+    if stages > 0 && generator.chance(15) {
+        format!("{} ~> {}", query, generate_query(generator, stages - 1))
+    } else {
+        query
+    }
+}
+
+// Writes a query back as query text, each clause as the parser reads it.
+fn query_text(query: &RipLogQuery) -> String {
+    let mut clauses = Vec::new();
+    if let Some(ref filter) = query.filter {
+        clauses.push(filter_text(filter));
+    }
+    if let Some(ref grouping) = query.grouping {
+        clauses.push(format!("group {}", grouping.groupings.iter().map(|g| g.name()).collect::<Vec<String>>().join(", ")));
+    }
+    if let Some(ref show) = query.show {
+        let elements: Vec<String> = show.elements.iter().map(|e| match show.aliases.get(&e.name()) {
+            Some(alias) => format!("{} as {}", e.name(), alias),
+            None => e.name(),
+        }).collect();
+        clauses.push(format!("show {}", elements.join(", ")));
+    }
+    if let Some(ref having) = query.having {
+        clauses.push(format!("having {}", filter_text(&having.filter)));
+    }
+    if let Some(ref sort) = query.sort {
+        let sortings: Vec<String> = sort.sortings.iter().map(|s| match s.order {
+            QuerySortOrdering::ASC => format!("{} asc", s.field),
+            QuerySortOrdering::DESC => format!("{} desc", s.field),
+        }).collect();
+        clauses.push(format!("sort {}", sortings.join(", ")));
+    }
+    if let Some(ref limit) = query.limit {
+        clauses.push(format!("limit {}", limit.limit));
+    }
+    if let Some(ref every) = query.every {
+        clauses.push(format!("every {}s", every.seconds));
+    }
+    match query.next {
+        Some(ref next) => format!("{} ~> {}", clauses.join(" | "), query_text(next)),
+        None => clauses.join(" | "),
+    }
+}
+
+// Operands of and/or are parenthesized unless they'd be parsed the same without, as both associate
+// to the right and and binds tighter than or.
+fn filter_text(filter: &QueryFilter) -> String {
+    match filter {
+        QueryFilter::BinaryOpFilter(left, right, op) => format!("{} {} {}", value_text(left), operator_text(op), value_text(right)),
+        QueryFilter::InFilter(value, values, negated) =>
+            format!("{} {}in ({})", value_text(value), if *negated { "not " } else { "" }, values.iter().map(value_text).collect::<Vec<String>>().join(", ")),
+        QueryFilter::CidrFilter(_, _, _) => panic!("cidr filters aren't generated"),
+        QueryFilter::AndFilter(left, right) => {
+            let left = match **left { QueryFilter::AndFilter(_, _) | QueryFilter::OrFilter(_, _) => format!("({})", filter_text(left)), _ => filter_text(left) };
+            let right = match **right { QueryFilter::OrFilter(_, _) => format!("({})", filter_text(right)), _ => filter_text(right) };
+            format!("{} and {}", left, right)
+        },
+        QueryFilter::OrFilter(left, right) => {
+            let left = match **left { QueryFilter::OrFilter(_, _) => format!("({})", filter_text(left)), _ => filter_text(left) };
+            format!("{} or {}", left, filter_text(right))
+        },
+    }
+}
+
+fn value_text(value: &QueryValue) -> String {
+    match value {
+        QueryValue::Symbol(symbol) => symbol.to_owned(),
+        QueryValue::Text(text, _) => format!("\"{}\"", text),
+        QueryValue::Regex(regex, _) => format!("r\"{}\"", regex.as_str()),
+        QueryValue::Int(_, text) | QueryValue::Double(_, text) => String::from_utf8(text.clone()).unwrap(),
+        QueryValue::Boolean(boolean) => boolean.to_string(),
+        QueryValue::Date(date) => format!("d\"{}\"", date.format("%m-%d-%Y %H:%M:%S %z")),
+        QueryValue::Expression(expression) => expression.name(),
+        QueryValue::Null => "null".to_owned(),
+    }
+}
+
+fn operator_text(op: &QueryFilterBinaryOp) -> &'static str {
+    match op {
+        QueryFilterBinaryOp::Lt => "<",
+        QueryFilterBinaryOp::Gt => ">",
+        QueryFilterBinaryOp::Le => "<=",
+        QueryFilterBinaryOp::Ge => ">=",
+        QueryFilterBinaryOp::Eq => "=",
+        QueryFilterBinaryOp::Ne => "!=",
+        QueryFilterBinaryOp::Re => "~",
+        QueryFilterBinaryOp::Nr => "!~",
+    }
+}
+
+// The tree of a query without its aliases, which are held in a map whose debug output isn't ordered
+fn query_tree(query: &RipLogQuery) -> String {
+    let mut query = query.clone();
+    let mut stage = Some(&mut query);
+    while let Some(query) = stage {
+        if let Some(ref mut show) = query.show {
+            show.aliases.clear();
+        }
+        stage = query.next.as_mut().map(|next| &mut **next);
+    }
+    format!("{:?}", query)
+}
+
+fn parse(text: &str, seed: u64) -> RipLogQuery {
+    parse_query(text.to_owned()).unwrap_or_else(|e| panic!("seed {}: failed to parse '{}': {}", seed, text, e))
+}
+
+#[test]
+fn generated_queries_round_trip() {
+    for seed in 0..CASES {
+        let text = generate_query(&mut Generator::new(seed), 2);
+        let parsed = parse(&text, seed);
+        let written = query_text(&parsed);
+        let reparsed = parse(&written, seed);
+        assert_eq!(query_tree(&parsed), query_tree(&reparsed), "seed {}: '{}' was written back as '{}'", seed, text, written);
+        assert_eq!(written, query_text(&reparsed), "seed {}: '{}' isn't written back the same once reparsed", seed, text);
+    }
+}
+
+#[test]
+fn generated_queries_parse_the_same_with_comments_and_line_breaks() {
+    for seed in 0..CASES {
+        let text = generate_query(&mut Generator::new(seed), 2);
+        let commented = format!("# generated from seed {}\n{}\n# end", seed, text.replace(" | ", "\n  | "));
+        assert_eq!(query_tree(&parse(&text, seed)), query_tree(&parse(&commented, seed)), "seed {}: '{}'", seed, commented);
+    }
+}
+
+#[test]
+fn arithmetic_is_parsed_by_precedence() {
+    let names = [
+        ("bytes + 1 * 2", "bytes+1*2"),
+        ("(bytes + 1) * 2", "(bytes+1)*2"),
+        ("bytes - (1 + 2)", "bytes-(1+2)"),
+        ("bytes - 1 + 2", "bytes-1+2"),
+        ("bytes / 2 / 4", "bytes/2/4"),
+        ("bytes / (2 / 4)", "bytes/(2/4)"),
+        ("sum(bytes) / count(*)", "sum(bytes)/count(*)"),
+    ];
+    for &(expression, name) in &names {
+        let query = parse(&format!("show {}", expression), 0);
+        assert_eq!(query.show.unwrap().elements[0].name(), name, "{}", expression);
+    }
+}
+
+#[test]
+fn and_binds_tighter_than_or() {
+    let query = parse("status = 500 or status = 404 and method = \"GET\"", 0);
+    match query.filter {
+        Some(QueryFilter::OrFilter(_, ref right)) => match **right {
+            QueryFilter::AndFilter(_, _) => (),
+            ref other => panic!("expected an and filter, got {:?}", other),
+        },
+        other => panic!("expected an or filter, got {:?}", other),
+    }
+}
+
+#[test]
+fn dates_keep_their_offset() {
+    let query = parse("date > d\"10-16-2026 10:00:00 +0200\"", 0);
+    match query.filter {
+        Some(QueryFilter::BinaryOpFilter(_, QueryValue::Date(date), _)) => assert_eq!(date.with_timezone(&Utc).hour(), 8),
+        other => panic!("expected a date filter, got {:?}", other),
+    }
+}
+
+// Value of a column of a record as text, dates as rfc3339 in UTC, or None when null
+fn column_value<T>(definition: &TableDefinition<T>, record: &mut T, column: &str) -> Option<String> {
+    match definition.column_map.get(column).unwrap_or_else(|| panic!("no column {}", column)) {
+        ColumnDefinition::Integer { extractor, field, .. } => extractor(record, *field).map(|v| v.to_string()),
+        ColumnDefinition::Double { extractor, field, .. } => extractor(record, *field).map(|v| v.to_string()),
+        ColumnDefinition::Text { extractor, field, .. } | ColumnDefinition::Ip { extractor, field, .. } => extractor(record, *field).map(|v| v.to_owned()),
+        ColumnDefinition::Date { extractor, field, .. } => extractor(record, *field).map(|d| d.with_timezone(&Utc).to_rfc3339()),
+        ColumnDefinition::Boolean { extractor, field, .. } => extractor(record, *field).map(|v| v.to_string()),
+    }
+}
+
+// Reads lines as those of one file are read, with one record, giving the values of the columns of
+// each line read, or None for lines that aren't.
+fn read_lines<F: LogFormat>(format: &F, lines: &[&str], columns: &[&str]) -> Vec<Option<Vec<Option<String>>>> {
+    let definition = format.table_definition();
+    let mut record = format.empty_record();
+    lines.iter().map(|line| {
+        if format.read_record(line.as_bytes(), &mut record) {
+            Some(columns.iter().map(|column| column_value(&definition, &mut record, column)).collect())
+        } else {
+            None
+        }
+    }).collect()
+}
+
+fn values(values: &[Option<&str>]) -> Option<Vec<Option<String>>> {
+    Some(values.iter().map(|v| v.map(|v| v.to_owned())).collect())
+}
+
+fn nginx_format() -> NginxFormat {
+    NginxFormat { geoip: None, routes: Arc::new(RouteNormalizer::default()) }
+}
+
+fn auto_format() -> AutoFormat {
+    AutoFormat::new(nginx_format())
+}
+
+fn sample(lines: &[&str]) -> Vec<Vec<u8>> {
+    lines.iter().map(|l| l.as_bytes().to_vec()).collect()
+}
+
+const NGINX_LINE: &str = "203.0.113.42 - bob [16/Oct/2026:10:00:47 +0000] \"DELETE /static/app.css HTTP/1.1\" 500 30083 \"-\" \"curl/8.4.0\" 0.142 0.142";
+const JSON_LINE: &str = "{\"time\": \"2026-10-16T10:00:47Z\", \"remote_addr\": \"10.0.0.1\", \"level\": \"warn\", \"msg\": \"slow\", \"status_code\": 504}";
+const SYSLOG_LINE: &str = "<11>1 2026-10-16T10:00:47Z web1 sshd 12 - - Failed password";
+const ALB_LINE: &str = "https 2026-10-16T10:00:47.000000Z app/my-lb/50dc6c495c0c9188 192.168.131.39:2817 10.0.0.1:80 0.000 0.001 0.000 502 502 34 366 \
+                        \"GET https://www.example.com:443/index.html HTTP/1.1\" \"curl/8.4.0\" ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2 - \"Root=1-58337262\" \"www.example.com\"";
+
+#[test]
+fn auto_format_is_sniffed_from_the_first_line_of_a_file() {
+    let format = auto_format();
+    assert_eq!(read_lines(&format, &[NGINX_LINE, JSON_LINE, SYSLOG_LINE, NGINX_LINE], &["format"]),
+               vec![values(&[Some("nginx")]), None, None, values(&[Some("nginx")])]);
+    assert_eq!(read_lines(&format, &["not a log line", JSON_LINE, NGINX_LINE], &["format"]), vec![None, values(&[Some("json")]), None]);
+    assert_eq!(read_lines(&format, &[SYSLOG_LINE, JSON_LINE], &["format"]), vec![values(&[Some("syslog")]), None]);
+    assert_eq!(read_lines(&format, &[ALB_LINE, NGINX_LINE], &["format"]), vec![values(&[Some("alb")]), None]);
+}
+
+#[test]
+fn auto_format_maps_the_columns_of_each_format_to_one_schema() {
+    let format = auto_format();
+    let columns = &["date", "host", "ip", "app", "severity", "method", "status", "bytes", "message"];
+    assert_eq!(read_lines(&format, &[NGINX_LINE], columns),
+               vec![values(&[Some("2026-10-16T10:00:47+00:00"), None, Some("203.0.113.42"), None, None, Some("DELETE"), Some("500"), Some("30083"), None])]);
+    assert_eq!(read_lines(&format, &[JSON_LINE], columns),
+               vec![values(&[Some("2026-10-16T10:00:47+00:00"), None, Some("10.0.0.1"), None, Some("warn"), None, Some("504"), None, Some("slow")])]);
+    assert_eq!(read_lines(&format, &[SYSLOG_LINE], columns),
+               vec![values(&[Some("2026-10-16T10:00:47+00:00"), Some("web1"), None, Some("sshd"), Some("err"), None, None, None, Some("Failed password")])]);
+    assert_eq!(read_lines(&format, &[ALB_LINE], columns),
+               vec![values(&[Some("2026-10-16T10:00:47+00:00"), None, Some("192.168.131.39"), None, None, Some("GET"), Some("502"), Some("366"), None])]);
+}
+
+#[test]
+fn auto_format_schema_is_the_union_of_the_columns_of_each_format() {
+    let format = auto_format();
+    let columns = &["request_time", "route", "target_status_code", "target_processing_time", "pid"];
+    assert_eq!(read_lines(&format, &[NGINX_LINE], columns), vec![values(&[Some("0.142"), Some("/static/app.css"), None, None, None])]);
+    assert_eq!(read_lines(&format, &[ALB_LINE], columns), vec![values(&[None, None, Some("502"), Some("0.001"), None])]);
+    assert_eq!(read_lines(&format, &[SYSLOG_LINE], columns), vec![values(&[None, None, None, None, Some("12")])]);
+    assert_eq!(read_lines(&format, &["{\"target_status_code\": 504, \"request_time\": 1.5}"], columns), vec![values(&[Some("1.5"), None, Some("504"), None, None])]);
+
+    // Columns named differently by a format are read under the name of the others
+    let definition = format.table_definition();
+    for renamed in &["time", "timestamp", "client_ip", "elb_status_code", "sent_bytes"] {
+        assert!(!definition.column_map.contains_key(*renamed), "{} is in the schema", renamed);
+    }
+}
+
+#[test]
+fn auto_format_reads_numeric_json_dates_as_epoch_times() {
+    let lines = &["{\"ts\": 1792144847, \"msg\": \"seconds\"}", "{\"time\": 1792144847250, \"msg\": \"milliseconds\"}", "{\"ts\": 1792144847.5}"];
+    assert_eq!(read_lines(&auto_format(), lines, &["date"]),
+               vec![values(&[Some("2026-10-16T10:00:47+00:00")]), values(&[Some("2026-10-16T10:00:47.250+00:00")]), values(&[Some("2026-10-16T10:00:47.500+00:00")])]);
+}
+
+#[test]
+fn auto_format_lines_malformed_for_the_sniffed_format_are_not_read() {
+    let format = auto_format();
+    let bad_date = NGINX_LINE.replace("16/Oct/2026:10:00:47", "16/Oct/2026:99:00:47");
+    assert_eq!(read_lines(&format, &[NGINX_LINE, &bad_date, "203.0.113.42 - - [16/Oct/2026:10:00:47 +0000] \"GET /trunc"], &["date", "status"]),
+               vec![values(&[Some("2026-10-16T10:00:47+00:00"), Some("500")]), values(&[None, Some("500")]), None]);
+    assert_eq!(read_lines(&format, &[JSON_LINE, "{\"time\": \"yesterday\", \"status_code\": 200}", "[200]", "{\"time\": "], &["date", "status"]),
+               vec![values(&[Some("2026-10-16T10:00:47+00:00"), Some("504")]), values(&[None, Some("200")]), None, None]);
+}
+
+#[test]
+fn nginx_lines_with_a_bad_date_are_read_with_a_null_date() {
+    let lines = &[NGINX_LINE, "203.0.113.42 - - [16/Oct/2026:99:00:47 +0000] \"GET / HTTP/1.1\" 200 5 \"-\" \"curl/8.4.0\" 0.1 0.1",
+                  "203.0.113.42 - - [16/Oct/2026:10:00:47 +0000] \"GET /trunc"];
+    assert_eq!(read_lines(&nginx_format(), lines, &["date", "status"]),
+               vec![values(&[Some("2026-10-16T10:00:47+00:00"), Some("500")]), values(&[None, Some("200")]), None]);
+}
+
+#[test]
+fn nginx_time_is_the_request_time_spent_outside_the_upstreams() {
+    let line = |times: &str| format!("203.0.113.42 - - [16/Oct/2026:10:00:47 +0000] \"GET / HTTP/1.1\" 200 5 \"-\" \"curl/8.4.0\" {}\n", times);
+    let lines = [line("0.5 0.125, 0.25"), line("0.75 0.5"), line("0.25 -"), line("0.25")];
+    let lines: Vec<&str> = lines.iter().map(|l| l.as_str()).collect();
+    let expected = vec![values(&[Some("0.5"), Some("0.375"), Some("0.125")]), values(&[Some("0.75"), Some("0.5"), Some("0.25")]),
+                        values(&[Some("0.25"), None, None]), values(&[Some("0.25"), None, None])];
+    let columns = &["request_time", "upstream_time", "nginx_time"];
+    assert_eq!(read_lines(&nginx_format(), &lines, columns), expected);
+    let custom = CustomFormat::parse("$remote_addr - $remote_user [$time_local] \"$request\" $status $body_bytes_sent \"$http_referer\" \"$http_user_agent\" $request_time $upstream_response_time").unwrap();
+    assert_eq!(read_lines(&custom, &lines[0..3], columns), expected[0..3].to_vec());
+
+    // Upstream times of requests that weren't passed to one are null, rather than unparseable
+    let format = nginx_format();
+    let mut record = format.empty_record();
+    assert!(format.read_record(lines[2].as_bytes(), &mut record));
+    assert_eq!(format.table_definition().column_map["upstream_time"].extract_binary(&record), None);
+}
+
+#[test]
+fn json_columns_are_read_by_key() {
+    let lines = &["{\"time\": \"2026-10-16T10:00:47Z\", \"status\": 200, \"took\": 0.5, \"ok\": true, \"msg\": \"done\"}",
+                  "{\"time\": \"yesterday\", \"status\": 500, \"took\": 2, \"ok\": false}"];
+    let format = JsonFormat { columns: json::discover_json_columns(&sample(&lines[0..1])) };
+    assert_eq!(read_lines(&format, lines, &["time", "status", "took", "ok", "msg"]),
+               vec![values(&[Some("2026-10-16T10:00:47+00:00"), Some("200"), Some("0.5"), Some("true"), Some("done")]),
+                    values(&[None, Some("500"), Some("2"), Some("false"), None])]);
+}
+
+#[test]
+fn json_lines_that_are_not_objects_are_not_read() {
+    let format = JsonFormat { columns: json::discover_json_columns(&sample(&["{\"status\": 200}"])) };
+    assert_eq!(read_lines(&format, &["{\"status\": 200}", "\"status\"", "[200]", "{\"status\": 2", ""], &["status"]),
+               vec![values(&[Some("200")]), None, None, None, None]);
+    assert!(json::discover_json_columns(&sample(&["\"status\"", "[200]", "{}"])).is_empty());
+}
+
+#[test]
+fn apache_lines_are_read_with_and_without_a_vhost() {
+    let lines = &["127.0.0.1 - frank [10/Oct/2026:13:55:36 -0700] \"GET /apache_pb.gif?x=1 HTTP/1.0\" 200 2326 \"http://example.com/\" \"Mozilla/4.08\"",
+                  "example.com 10.0.0.1 - - [10/Oct/2026:13:55:37 -0700] \"POST /login HTTP/1.1\" 302 - \"-\" \"curl/8.4.0\"",
+                  "127.0.0.1 - - [10/Oct/2026:13:55:38 -0700] \"GET / HTTP/1.0\" 200 10"];
+    assert_eq!(read_lines(&ApacheFormat { routes: Arc::new(RouteNormalizer::default()) }, lines, &["vhost", "ip", "username", "date", "method", "path", "query", "status", "bytes"]),
+               vec![values(&[None, Some("127.0.0.1"), Some("frank"), Some("2026-10-10T20:55:36+00:00"), Some("GET"), Some("/apache_pb.gif"), Some("?x=1"), Some("200"), Some("2326")]),
+                    values(&[Some("example.com"), Some("10.0.0.1"), None, Some("2026-10-10T20:55:37+00:00"), Some("POST"), Some("/login"), None, Some("302"), None]),
+                    values(&[None, Some("127.0.0.1"), None, Some("2026-10-10T20:55:38+00:00"), Some("GET"), Some("/"), None, Some("200"), Some("10")])]);
+}
+
+#[test]
+fn apache_lines_with_a_bad_date_or_truncated_are_not_read() {
+    let lines = &["127.0.0.1 - - [oops] \"GET / HTTP/1.0\" 200 10", "[oops", "127.0.0.1 - - [10/Oct/2026:13:55:38 -0700] \"GET /trunc",
+                  "127.0.0.1 - - [10/Oct/2026:13:55:38 -0700] \"GET / HTTP/1.0\" 2"];
+    assert_eq!(read_lines(&ApacheFormat { routes: Arc::new(RouteNormalizer::default()) }, lines, &["status"]), vec![None, None, None, None]);
+}
+
+#[test]
+fn syslog_messages_are_read_in_both_formats() {
+    let lines = &["<165>1 2026-10-16T10:00:47Z web1 sshd 12 - - Failed password", "<13>Oct 16 10:00:47 web1 cron[34]: job done", "Oct 16 10:00:48 web2 kernel: oom"];
+    assert_eq!(read_lines(&SyslogFormat::new(), lines, &["host", "app", "pid", "severity", "facility", "message"]),
+               vec![values(&[Some("web1"), Some("sshd"), Some("12"), Some("notice"), Some("local4"), Some("Failed password")]),
+                    values(&[Some("web1"), Some("cron"), Some("34"), Some("notice"), Some("user"), Some("job done")]),
+                    values(&[Some("web2"), Some("kernel"), None, None, None, Some("oom")])]);
+    assert_eq!(read_lines(&SyslogFormat::new(), &[lines[0]], &["timestamp"]), vec![values(&[Some("2026-10-16T10:00:47+00:00")])]);
+}
+
+#[test]
+fn syslog_lines_without_a_timestamp_or_truncated_are_not_read() {
+    assert_eq!(read_lines(&SyslogFormat::new(), &["16/10/2026 web1 sshd: x", "Oct 16 10:0", "<13>", "1 oops"], &["message"]), vec![None, None, None, None]);
+    assert_eq!(read_lines(&SyslogFormat::new(), &["Foo 16 10:00:47 web1 sshd: x"], &["timestamp", "message"]), vec![values(&[None, Some("x")])]);
+}
+
+#[test]
+fn csv_columns_are_named_by_the_header() {
+    let lines = &["date,ip,status,took", "2026-10-16T10:00:47Z,10.0.0.1,200,0.5", "2026-10-16T10:00:48Z,10.0.0.2,500,1.5"];
+    let format = CsvFormat::new(b',', &None, &None, &sample(lines)).unwrap();
+    assert!(format.has_header());
+    assert_eq!(read_lines(&format, &lines[1..], &["date", "ip", "status", "took"]),
+               vec![values(&[Some("2026-10-16T10:00:47+00:00"), Some("10.0.0.1"), Some("200"), Some("0.5")]),
+                    values(&[Some("2026-10-16T10:00:48+00:00"), Some("10.0.0.2"), Some("500"), Some("1.5")])]);
+}
+
+#[test]
+fn csv_rows_of_the_wrong_length_are_not_read() {
+    let format = CsvFormat::new(b'\t', &Some("ip,status".to_owned()), &None, &sample(&["10.0.0.1\t200"])).unwrap();
+    assert!(!format.has_header());
+    assert_eq!(read_lines(&format, &["10.0.0.1\t200", "10.0.0.1", "10.0.0.1\t200\textra"], &["ip", "status"]),
+               vec![values(&[Some("10.0.0.1"), Some("200")]), None, None]);
+}
+
+#[test]
+fn csv_headers_missing_a_name_are_refused() {
+    assert_eq!(CsvFormat::new(b',', &None, &None, &sample(&["ip,,status", "10.0.0.1,x,200"])).err(), Some("Column 2 has no name".to_owned()));
+    assert_eq!(CsvFormat::new(b',', &None, &None, &sample(&["ip,ip", "10.0.0.1,10.0.0.2"])).err(), Some("Column 'ip' appears more than once".to_owned()));
+}
+
+#[test]
+fn nginx_error_lines_are_read_with_their_trailing_fields() {
+    let lines = &["2026/10/16 10:00:47 [error] 1234#0: *42 open() \"/srv/favicon.ico\" failed, client: 10.0.0.1, server: example.com, request: \"GET /favicon.ico HTTP/1.1\", host: \"example.com\"",
+                  "2026/10/16 10:00:48 [notice] 1#1: signal process started"];
+    assert_eq!(read_lines(&NginxErrorFormat, lines, &["level", "pid", "tid", "connection", "message", "client", "server", "request", "host"]),
+               vec![values(&[Some("error"), Some("1234"), Some("0"), Some("42"), Some("open() \"/srv/favicon.ico\" failed"), Some("10.0.0.1"), Some("example.com"),
+                             Some("GET /favicon.ico HTTP/1.1"), Some("example.com")]),
+                    values(&[Some("notice"), Some("1"), Some("1"), None, Some("signal process started"), None, None, None, None])]);
+}
+
+#[test]
+fn nginx_error_lines_with_a_bad_timestamp_or_truncated_are_not_read() {
+    let lines = &["oops/10/16 10:00:47 [error] 1#0: x", "2026/10/16 10:00:47 [error", "2026/10/16 10:00:47 [error] 1234", "upstream sent too big header"];
+    assert_eq!(read_lines(&NginxErrorFormat, lines, &["level"]), vec![None, None, None, None]);
+}
+
+#[test]
+fn aws_lines_are_read_by_log_type() {
+    assert_eq!(read_lines(&AwsFormat::new(AwsLog::Alb), &[ALB_LINE], &["time", "client_ip", "client_port", "target_processing_time", "elb_status_code", "method", "path", "domain_name"]),
+               vec![values(&[Some("2026-10-16T10:00:47+00:00"), Some("192.168.131.39"), Some("2817"), Some("0.001"), Some("502"), Some("GET"), Some("/index.html"), Some("www.example.com")])]);
+    let s3 = "79a5 awsexamplebucket1 [06/Feb/2026:00:00:38 +0000] 192.0.2.3 79a5 3E57427F3EXAMPLE REST.GET.VERSIONING - \"GET /awsexamplebucket1?versioning HTTP/1.1\" \
+              200 - 113 - 7 - \"-\" \"S3Console/0.4\" - s9lzHYrFp76ZVxRcpX9+5cjAnEH2ROuNkd2BHfIa6UkFVdtjf5mKR3/eTPFvsiP/XV/VLi31234= SigV4";
+    assert_eq!(read_lines(&AwsFormat::new(AwsLog::S3), &[s3], &["bucket", "time", "remote_ip", "operation", "method", "path", "query", "http_status", "bytes_sent"]),
+               vec![values(&[Some("awsexamplebucket1"), Some("2026-02-06T00:00:38+00:00"), Some("192.0.2.3"), Some("REST.GET.VERSIONING"), Some("GET"),
+                             Some("/awsexamplebucket1"), Some("?versioning"), Some("200"), Some("113")])]);
+}
+
+#[test]
+fn aws_lines_truncated_are_not_read_and_bad_times_are_null() {
+    let format = AwsFormat::new(AwsLog::Alb);
+    let truncated = "https 2026-10-16T10:00:47.000000Z app/my-lb/50dc6c495c0c9188 192.168.131.39:2817 10.0.0.1:80 0.000 0.001";
+    assert_eq!(read_lines(&format, &[truncated, &ALB_LINE.replace("2026-10-16T10:00:47.000000Z", "yesterday")], &["time", "elb_status_code"]),
+               vec![None, values(&[None, Some("502")])]);
+}
+
+#[test]
+fn container_lines_are_unwrapped_for_the_wrapped_format() {
+    let docker = format!("{{\"log\":{},\"stream\":\"stdout\",\"time\":\"2026-10-16T10:00:48.5Z\"}}", json_string(&format!("{}\n", NGINX_LINE)));
+    let (head, tail) = NGINX_LINE.split_at(40);
+    let cri_head = format!("2026-10-16T10:00:49Z stderr P {}", head);
+    let cri_tail = format!("2026-10-16T10:00:49Z stderr F {}", tail);
+    let lines = &[docker.as_str(), cri_head.as_str(), cri_tail.as_str(), NGINX_LINE, "2026-10-16T10:00:50Z stdout F not nginx"];
+    assert_eq!(read_lines(&ContainerFormat::new(nginx_format()), lines, &["stream", "container_time", "ip", "status"]),
+               vec![values(&[Some("stdout"), Some("2026-10-16T10:00:48.500+00:00"), Some("203.0.113.42"), Some("500")]),
+                    None,
+                    values(&[Some("stderr"), Some("2026-10-16T10:00:49+00:00"), Some("203.0.113.42"), Some("500")]),
+                    None,
+                    None]);
+}
+
+// A json string holding the text, quotes and line breaks escaped
+fn json_string(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+}